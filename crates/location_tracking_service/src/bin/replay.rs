@@ -0,0 +1,103 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Replays a JSONL capture of location traffic against a local Redis.
+//!
+//! location-tracking-replay <capture.jsonl> [--speed N] [--time-warp none|shift|scale]
+//!     [--special-locations file.json] [--settle-secs N] [--output report.json]
+//!
+//! The dhall config is read from `DHALL_CONFIG` as for the service; its Redis
+//! settings should point at a disposable local instance.
+
+use location_tracking_service::{
+    common::utils::read_dhall_config,
+    tools::replay::{run_replay, ReplayOptions, TimeWarp},
+};
+use shared::tools::logger::setup_tracing;
+use std::{env::var, str::FromStr, time::Duration};
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: location-tracking-replay <capture.jsonl> [--speed N] [--time-warp none|shift|scale] \
+         [--special-locations file.json] [--settle-secs N] [--output report.json]"
+    );
+    std::process::exit(2);
+}
+
+#[actix_web::main]
+async fn main() {
+    let mut capture_path = None;
+    let mut speed = 1.0;
+    let mut time_warp = TimeWarp::Shift;
+    let mut special_locations_path = None;
+    let mut settle = Duration::from_secs(5);
+    let mut output = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--speed" => {
+                speed = args
+                    .next()
+                    .and_then(|speed| speed.parse::<f64>().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--time-warp" => {
+                time_warp = args
+                    .next()
+                    .and_then(|time_warp| TimeWarp::from_str(&time_warp).ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--special-locations" => {
+                special_locations_path = Some(args.next().unwrap_or_else(|| usage()))
+            }
+            "--settle-secs" => {
+                settle = args
+                    .next()
+                    .and_then(|secs| secs.parse::<u64>().ok())
+                    .map(Duration::from_secs)
+                    .unwrap_or_else(|| usage())
+            }
+            "--output" => output = Some(args.next().unwrap_or_else(|| usage())),
+            _ if capture_path.is_none() && !arg.starts_with("--") => capture_path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let capture_path = capture_path.unwrap_or_else(|| usage());
+
+    let dhall_config_path = var("DHALL_CONFIG")
+        .unwrap_or_else(|_| "./dhall-configs/dev/location_tracking_service.dhall".to_string());
+    let app_config = read_dhall_config(&dhall_config_path).unwrap_or_else(|err| {
+        println!("Dhall Config Reading Error : {}", err);
+        std::process::exit(1);
+    });
+    let _guard = setup_tracing(app_config.logger_cfg.clone());
+
+    let report = run_replay(
+        app_config,
+        ReplayOptions {
+            capture_path,
+            speed,
+            time_warp,
+            special_locations_path,
+            settle,
+        },
+    )
+    .await
+    .unwrap_or_else(|err| {
+        eprintln!("Replay failed : {} - {}", err, err.message());
+        std::process::exit(1);
+    });
+
+    let report_json = serde_json::to_string_pretty(&report).expect("Failed to serialize report");
+    match output {
+        Some(path) => std::fs::write(&path, report_json).expect("Failed to write report"),
+        None => println!("{}", report_json),
+    }
+    eprintln!("{}", report.summary());
+}
@@ -0,0 +1,202 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Broadcaster-scoped token manager for external trace providers.
+//!
+//! Every entity relaying through the same broadcaster reauths with the same API
+//! key against the same `ny_reauth_url` for the same operating city, so the
+//! token is cached once per such scope instead of inside each entity's
+//! `BroadcastTraceConfig`. Refreshes
+//! are single-flight under a Redis lock, happen ahead of expiry, and back off
+//! exponentially while the reauth endpoint keeps failing.
+
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use shared::redis::types::RedisConnectionPool;
+use tracing::{error, info};
+
+use crate::common::types::*;
+use crate::outbound::external::refresh_external_trace_token;
+use crate::redis::{commands::*, keys::broadcaster_token_lock_key};
+use crate::tools::error::AppError;
+
+/// Tokens expiring within this window are refreshed before the ping instead of after a 401.
+pub const TOKEN_REFRESH_AHEAD_SECS: i64 = 60;
+const TOKEN_REFRESH_LOCK_SECS: i64 = 10;
+const TOKEN_REFRESH_WAIT_ATTEMPTS: u32 = 10;
+const TOKEN_REFRESH_WAIT_INTERVAL: Duration = Duration::from_millis(200);
+const BACKOFF_BASE_SECS: i64 = 5;
+const BACKOFF_MAX_SECS: i64 = 300;
+/// How long a failure count is remembered after the last failure.
+const BACKOFF_MEMORY_SECS: u32 = 3600;
+
+/// Entities share a token only when they share the broadcaster, API key, reauth
+/// endpoint and operating city. The key is hashed so it never appears in Redis keys.
+pub fn token_scope(cfg: &BroadcastTraceConfig) -> String {
+    let api_key_hash = hex::encode(Sha256::digest(cfg.ny_api_key.as_bytes()));
+    format!(
+        "{}:{}:{}:{}",
+        cfg.broadcaster_id,
+        &api_key_hash[..16],
+        cfg.merchant_operating_city_id,
+        cfg.ny_reauth_url
+    )
+}
+
+/// Delay before the next reauth attempt after `failures` consecutive failures.
+pub fn backoff_secs(failures: u32) -> i64 {
+    BACKOFF_BASE_SECS
+        .saturating_mul(1_i64 << failures.saturating_sub(1).min(16))
+        .min(BACKOFF_MAX_SECS)
+}
+
+fn newer_token(cfg: &BroadcastTraceConfig, cached: Option<BroadcasterToken>) -> BroadcasterToken {
+    match cached {
+        Some(cached) if cached.token_expires_at >= cfg.token_expires_at => cached,
+        _ => BroadcasterToken {
+            access_token: cfg.access_token.to_owned(),
+            token_expires_at: cfg.token_expires_at,
+        },
+    }
+}
+
+/// Token to use for the next ping: the newer of the shared cache and `cfg`, refreshed
+/// first if it is about to expire.
+pub async fn get_valid_token(
+    redis: &RedisConnectionPool,
+    cfg: &BroadcastTraceConfig,
+    now_secs: i64,
+) -> Result<BroadcasterToken, AppError> {
+    let scope = token_scope(cfg);
+    let current = newer_token(cfg, get_broadcaster_token(redis, &scope).await?);
+    if current.token_expires_at > now_secs + TOKEN_REFRESH_AHEAD_SECS {
+        return Ok(current);
+    }
+    info!(
+        tag = "[Broadcaster Token]",
+        "Token for {} expires at {}, refreshing ahead of expiry", scope, current.token_expires_at
+    );
+    refresh_shared_token(redis, cfg, &scope, &current.access_token, now_secs).await
+}
+
+/// Called after the provider rejected `rejected_token`. Reuses a token some other worker
+/// already refreshed, otherwise refreshes.
+pub async fn refresh_rejected_token(
+    redis: &RedisConnectionPool,
+    cfg: &BroadcastTraceConfig,
+    rejected_token: &str,
+    now_secs: i64,
+) -> Result<BroadcasterToken, AppError> {
+    let scope = token_scope(cfg);
+    if let Some(cached) = get_broadcaster_token(redis, &scope).await? {
+        if cached.access_token != rejected_token && cached.token_expires_at > now_secs {
+            return Ok(cached);
+        }
+    }
+    refresh_shared_token(redis, cfg, &scope, rejected_token, now_secs).await
+}
+
+async fn refresh_shared_token(
+    redis: &RedisConnectionPool,
+    cfg: &BroadcastTraceConfig,
+    scope: &str,
+    stale_token: &str,
+    now_secs: i64,
+) -> Result<BroadcasterToken, AppError> {
+    if let Some(backoff) = get_broadcaster_token_backoff(redis, scope).await? {
+        if backoff.retry_after > now_secs {
+            return Err(AppError::ExternalAPICallError(format!(
+                "Token refresh for {} backing off until {} after {} failures",
+                scope, backoff.retry_after, backoff.failures
+            )));
+        }
+    }
+
+    let lock_key = broadcaster_token_lock_key(scope);
+    let acquired = redis
+        .setnx_with_expiry(&lock_key, true, TOKEN_REFRESH_LOCK_SECS)
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    if !acquired {
+        return wait_for_refreshed_token(redis, scope, stale_token).await;
+    }
+
+    // Released on every exit path; the lock expiry only covers a crashed worker.
+    let result = refresh_and_store_token(redis, cfg, scope, now_secs).await;
+    if let Err(err) = redis.delete_key(&lock_key).await {
+        error!(
+            tag = "[Broadcaster Token]",
+            "Failed to release token refresh lock for {}: {:?}", scope, err
+        );
+    }
+    result
+}
+
+async fn refresh_and_store_token(
+    redis: &RedisConnectionPool,
+    cfg: &BroadcastTraceConfig,
+    scope: &str,
+    now_secs: i64,
+) -> Result<BroadcasterToken, AppError> {
+    let result = refresh_external_trace_token(
+        &cfg.ny_reauth_url,
+        &cfg.ny_api_key,
+        &cfg.merchant_operating_city_id,
+    )
+    .await;
+    match result {
+        Ok(resp) => {
+            let token = BroadcasterToken {
+                access_token: resp.access_token,
+                token_expires_at: resp.expires_at,
+            };
+            let expiry = (token.token_expires_at - now_secs).max(1) as u32;
+            set_broadcaster_token(redis, scope, &token, expiry).await?;
+            delete_broadcaster_token_backoff(redis, scope).await?;
+            Ok(token)
+        }
+        Err(err) => {
+            let failures = get_broadcaster_token_backoff(redis, scope)
+                .await?
+                .map_or(0, |backoff| backoff.failures)
+                + 1;
+            let backoff = BroadcasterTokenBackoff {
+                failures,
+                retry_after: now_secs + backoff_secs(failures),
+            };
+            error!(
+                tag = "[Broadcaster Token]",
+                "Token refresh for {} failed ({} in a row), retrying after {}: {}",
+                scope,
+                failures,
+                backoff.retry_after,
+                err.message()
+            );
+            set_broadcaster_token_backoff(redis, scope, &backoff, BACKOFF_MEMORY_SECS).await?;
+            Err(err)
+        }
+    }
+}
+
+/// Another worker holds the refresh lock; wait briefly for it to publish a new token.
+async fn wait_for_refreshed_token(
+    redis: &RedisConnectionPool,
+    scope: &str,
+    stale_token: &str,
+) -> Result<BroadcasterToken, AppError> {
+    for _ in 0..TOKEN_REFRESH_WAIT_ATTEMPTS {
+        tokio::time::sleep(TOKEN_REFRESH_WAIT_INTERVAL).await;
+        if let Some(token) = get_broadcaster_token(redis, scope).await? {
+            if token.access_token != stale_token {
+                return Ok(token);
+            }
+        }
+    }
+    Err(AppError::UnderProcessing(broadcaster_token_lock_key(scope)))
+}
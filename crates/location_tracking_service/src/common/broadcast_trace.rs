@@ -0,0 +1,577 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Broadcast trace delivery to external providers.
+//!
+//! Pings go out from two places: the inbound location path, on every point a
+//! person with an active broadcaster sends, and a background scheduler that
+//! walks every registered `BroadcastTraceTarget` and resends the last known
+//! location once `polling_interval_secs` has passed without a trace. The
+//! scheduler is what keeps an SOS trace alive when the person's app has been
+//! killed; locations older than the configured thresholds are flagged
+//! `Stale` / `SignalLost`. Both paths share a per-broadcaster send lock held for
+//! `polling_interval_secs`, so at most one trace goes out per interval across
+//! pods, and stop once `expires_at` has passed. Delivery times are recorded in
+//! their own hash rather than in the person's entity details. Targets for
+//! broadcasters started before the scheduler was deployed are backfilled once
+//! on startup.
+//!
+//! While Redis is being migrated, target, delivery and audit writes are mirrored
+//! and reads go to the serving pool. Locks stay on the primary pool, which every
+//! pod agrees on whatever phase it has picked up.
+
+use std::{str::FromStr, sync::Arc, time::Duration, time::Instant};
+
+use chrono::Utc;
+use futures::future::join_all;
+use shared::redis::types::RedisConnectionPool;
+use tracing::{error, info, warn};
+
+use crate::common::{
+    broadcast_token::{get_valid_token, refresh_rejected_token},
+    types::*,
+};
+use crate::domain::types::ui::location::PersonType;
+use crate::environment::BroadcastTraceSchedulerConfig;
+use crate::outbound::provider::{
+    make_provider, provider_name, ExternalLocationProvider, TracePing,
+};
+use crate::redis::{commands::*, keys::*, migration::RedisMigration};
+use crate::redis_migration_write;
+use crate::tools::{error::AppError, prometheus::BROADCAST_TRACE_ATTEMPTS};
+
+const BROADCAST_TRACE_BACKFILL_LOCK_SECS: i64 = 30 * 60;
+
+/// Location to relay in a broadcast trace ping.
+#[derive(Clone, Copy, Debug)]
+pub struct BroadcastLocation {
+    pub lat: f64,
+    pub lon: f64,
+    /// Epoch seconds of the location fix.
+    pub timestamp: i64,
+    pub signal: TraceSignal,
+}
+
+/// Freshness flag for a last known location that is `location_age_secs` old.
+pub fn trace_signal(location_age_secs: i64, config: &BroadcastTraceSchedulerConfig) -> TraceSignal {
+    if location_age_secs >= config.signal_lost_after_secs {
+        TraceSignal::SignalLost
+    } else if location_age_secs >= config.stale_after_secs {
+        TraceSignal::Stale
+    } else {
+        TraceSignal::Live
+    }
+}
+
+fn is_expired(cfg: &BroadcastTraceConfig, now_secs: i64) -> bool {
+    cfg.expires_at
+        .is_some_and(|expires_at| expires_at <= now_secs)
+}
+
+/// Whether `polling_interval_secs` has passed since the later of the config's own
+/// `last_trace_ts` and the last delivery recorded in the last-sent hash.
+pub fn is_trace_due(cfg: &BroadcastTraceConfig, last_sent: Option<i64>, now_secs: i64) -> bool {
+    match cfg.last_trace_ts.max(last_sent) {
+        None => true,
+        Some(last_ts) => now_secs - last_ts >= cfg.polling_interval_secs as i64,
+    }
+}
+
+/// Trace targets for every broadcaster of `entry` that has both an id and a config.
+pub fn broadcast_trace_targets(
+    merchant_id: &MerchantId,
+    person_type: PersonType,
+    person_id: &PersonId,
+    entity_type: &str,
+    entry: &EntityEntry,
+) -> Vec<BroadcastTraceTarget> {
+    entry
+        .broadcaster_configs
+        .iter()
+        .filter(|cfg| entry.broadcaster_ids.contains(&cfg.broadcaster_id))
+        .map(|cfg| BroadcastTraceTarget {
+            merchant_id: merchant_id.to_owned(),
+            person_type,
+            person_id: person_id.to_owned(),
+            entity_type: entity_type.to_string(),
+            entity_id: entry.entity_id.to_owned(),
+            broadcaster_id: cfg.broadcaster_id.to_owned(),
+        })
+        .collect()
+}
+
+/// Recovers the person from an `entity_details_key`, given the merchant stored in
+/// the map under it. Person ids may themselves contain `:`.
+pub fn parse_entity_details_key(
+    key: &str,
+    MerchantId(merchant_id): &MerchantId,
+) -> Option<(PersonType, PersonId)> {
+    let (person_type, person_id) = key
+        .strip_prefix("lts:entity_details:")?
+        .strip_prefix(merchant_id.as_str())?
+        .strip_prefix(':')?
+        .split_once(':')?;
+    let person_type = PersonType::from_str(person_type).ok()?;
+    Some((person_type, PersonId(person_id.to_string())))
+}
+
+async fn release_send_lock(redis: &RedisConnectionPool, target: &BroadcastTraceTarget) {
+    if let Err(err) = redis
+        .delete_key(&broadcast_trace_send_lock_key(
+            &target.entity_id,
+            &target.broadcaster_id,
+        ))
+        .await
+    {
+        error!(
+            tag = "[Broadcast Trace]",
+            "Failed to release send lock for broadcaster {}: {:?}", target.broadcaster_id, err
+        );
+    }
+}
+
+/// Fire-and-forget: rate-limits on the broadcaster's send lock, takes the bearer token from
+/// the shared broadcaster token cache (refreshing ahead of expiry and again on 401),
+/// and POSTs a location trace via the appropriate ExternalLocationProvider.
+pub async fn try_send_broadcast_trace(
+    redis: Arc<RedisConnectionPool>,
+    redis_migration: Arc<RedisMigration>,
+    target: BroadcastTraceTarget,
+    mut cfg: BroadcastTraceConfig,
+    location: BroadcastLocation,
+) {
+    let now_secs = Utc::now().timestamp();
+    let broadcaster_id = &target.broadcaster_id;
+
+    if is_expired(&cfg, now_secs) || !is_trace_due(&cfg, None, now_secs) {
+        return;
+    }
+
+    // The inbound path and the scheduler pods race for the same target;
+    // whoever takes the lock sends, everyone else waits for the next interval.
+    match redis
+        .setnx_with_expiry(
+            &broadcast_trace_send_lock_key(&target.entity_id, broadcaster_id),
+            true,
+            (cfg.polling_interval_secs as i64).max(1),
+        )
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(err) => {
+            error!(
+                tag = "[Broadcast Trace]",
+                "Failed to take send lock for broadcaster {}: {:?}", broadcaster_id, err
+            );
+            return;
+        }
+    }
+
+    // time_diff_secs shifts the UTC epoch into city-local wall-clock time so the provider
+    // receives a human-readable local datetime string in YYYY-MM-DD HH:MM:SS format.
+    let local_ts = chrono::DateTime::from_timestamp(location.timestamp + cfg.time_diff_secs, 0)
+        .unwrap_or_else(Utc::now);
+    let datetime_str = local_ts.format("%Y-%m-%d %H:%M:%S").to_string();
+    let ping = TracePing {
+        lat: location.lat,
+        lon: location.lon,
+        datetime: &datetime_str,
+        timestamp: location.timestamp,
+        signal: location.signal,
+    };
+
+    // Tokens are only a cache, kept on the serving pool without mirroring.
+    let serving_redis = redis_migration.serving(&redis);
+    // Proactive token refresh: use the broadcaster-scoped token, renewed ahead of expiry.
+    match get_valid_token(&serving_redis, &cfg, now_secs).await {
+        Ok(token) => {
+            cfg.access_token = token.access_token;
+            cfg.token_expires_at = token.token_expires_at;
+        }
+        Err(e) => {
+            warn!(
+                "[broadcast trace] broadcaster_id={} | step: proactive token refresh failed, using current token: {}",
+                broadcaster_id,
+                e
+            );
+        }
+    }
+
+    let mut provider = match make_provider(&cfg) {
+        Ok(p) => p,
+        Err(e) => {
+            error!(
+                "Failed to build provider for broadcaster {}: {}",
+                broadcaster_id, e
+            );
+            release_send_lock(&redis, &target).await;
+            return;
+        }
+    };
+
+    let provider_label = provider_name(&cfg.provider);
+    let (trace_result, mut first_attempt) =
+        timed_ping(provider.as_ref(), &ping, 1, broadcaster_id, provider_label).await;
+
+    let trace_result = match trace_result {
+        Err(AppError::TraceTokenExpired) => {
+            // Reactive token refresh: provider returned 401 — refresh and retry once.
+            match refresh_rejected_token(&serving_redis, &cfg, &cfg.access_token, now_secs).await {
+                Ok(token) => {
+                    first_attempt.retry_outcome = TraceRetryOutcome::RetriedAfterTokenRefresh;
+                    record_trace_attempt(
+                        &redis,
+                        &redis_migration,
+                        &target.entity_id,
+                        &first_attempt,
+                    )
+                    .await;
+                    cfg.access_token = token.access_token;
+                    cfg.token_expires_at = token.token_expires_at;
+                    provider.update_token(cfg.access_token.clone(), cfg.token_expires_at);
+                    let (retry_result, retry_attempt) =
+                        timed_ping(provider.as_ref(), &ping, 2, broadcaster_id, provider_label)
+                            .await;
+                    record_trace_attempt(
+                        &redis,
+                        &redis_migration,
+                        &target.entity_id,
+                        &retry_attempt,
+                    )
+                    .await;
+                    retry_result
+                }
+                Err(e) => {
+                    first_attempt.retry_outcome = TraceRetryOutcome::TokenRefreshFailed;
+                    record_trace_attempt(
+                        &redis,
+                        &redis_migration,
+                        &target.entity_id,
+                        &first_attempt,
+                    )
+                    .await;
+                    error!(
+                        "External token refresh failed for broadcaster {}: {}",
+                        broadcaster_id, e
+                    );
+                    release_send_lock(&redis, &target).await;
+                    return;
+                }
+            }
+        }
+        other => {
+            record_trace_attempt(&redis, &redis_migration, &target.entity_id, &first_attempt).await;
+            other
+        }
+    };
+
+    match trace_result {
+        Ok(_) => {
+            if let Err(e) =
+                redis_migration_write!(redis_migration, "broadcast_trace", &redis, |redis| {
+                    set_broadcast_trace_last_sent(
+                        &redis,
+                        &target.entity_id,
+                        broadcaster_id,
+                        now_secs,
+                    )
+                })
+            {
+                error!(
+                    "Failed to record last trace time for broadcaster {}: {}",
+                    broadcaster_id, e
+                );
+            }
+        }
+        Err(e) => {
+            error!(
+                "Broadcast trace failed for broadcaster {}: {}",
+                broadcaster_id, e
+            );
+            release_send_lock(&redis, &target).await;
+        }
+    }
+}
+
+/// Sends one ping and builds its audit entry. The retry outcome defaults to
+/// `NotRetried`; the caller updates it when a 401 leads to a token refresh.
+async fn timed_ping(
+    provider: &dyn ExternalLocationProvider,
+    ping: &TracePing<'_>,
+    attempt: u8,
+    broadcaster_id: &str,
+    provider_label: &str,
+) -> (Result<Option<u16>, AppError>, BroadcastTraceAttempt) {
+    let ts = TimeStamp(Utc::now());
+    let start = Instant::now();
+    let result = provider.send_ping(ping).await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+    let http_status = match &result {
+        Ok(status) => *status,
+        Err(AppError::TraceTokenExpired) => Some(401),
+        Err(AppError::TraceDeliveryFailed(status)) => Some(*status),
+        Err(_) => None,
+    };
+    let audit = BroadcastTraceAttempt {
+        ts,
+        broadcaster_id: broadcaster_id.to_string(),
+        provider: provider_label.to_string(),
+        attempt,
+        signal: ping.signal,
+        delivered: result.is_ok(),
+        http_status,
+        latency_ms,
+        retry_outcome: TraceRetryOutcome::NotRetried,
+        error: result.as_ref().err().map(AppError::message),
+    };
+    (result, audit)
+}
+
+/// Counts the attempt in `BROADCAST_TRACE_ATTEMPTS` and appends it to the entity's
+/// audit log. Audit failures are logged and never block the trace itself.
+async fn record_trace_attempt(
+    redis: &Arc<RedisConnectionPool>,
+    redis_migration: &RedisMigration,
+    entity_id: &EntityId,
+    attempt: &BroadcastTraceAttempt,
+) {
+    let outcome = match (attempt.delivered, attempt.http_status) {
+        (true, _) => "delivered",
+        (false, Some(401)) => "token_expired",
+        (false, _) => "failed",
+    };
+    BROADCAST_TRACE_ATTEMPTS
+        .with_label_values(&[attempt.provider.as_str(), outcome])
+        .inc();
+    if let Err(e) =
+        redis_migration_write!(redis_migration, "broadcast_trace_audit", redis, |redis| {
+            append_broadcast_trace_attempt(&redis, entity_id, attempt)
+        })
+    {
+        error!(
+            "Failed to record broadcast trace attempt for broadcaster {}: {}",
+            attempt.broadcaster_id, e
+        );
+    }
+}
+
+/// One scheduler pass for a target: drops it once the entity or broadcaster is gone or
+/// `expires_at` has passed, otherwise resends the last known location when a trace is due.
+async fn poll_broadcast_trace_target(
+    redis: Arc<RedisConnectionPool>,
+    redis_migration: Arc<RedisMigration>,
+    target: BroadcastTraceTarget,
+    config: &BroadcastTraceSchedulerConfig,
+) -> Result<(), AppError> {
+    let now_secs = Utc::now().timestamp();
+    let serving_redis = redis_migration.serving(&redis);
+    let cfg = get_entity_details_for_person(
+        &serving_redis,
+        &target.merchant_id,
+        target.person_type,
+        &target.person_id,
+    )
+    .await?
+    .and_then(|mut map| map.entities.remove(&target.entity_type))
+    .filter(|entry| {
+        entry.entity_id == target.entity_id
+            && entry.broadcaster_ids.contains(&target.broadcaster_id)
+    })
+    .and_then(|entry| {
+        entry
+            .broadcaster_configs
+            .into_iter()
+            .find(|cfg| cfg.broadcaster_id == target.broadcaster_id)
+    });
+
+    let cfg = match cfg {
+        Some(cfg) if !is_expired(&cfg, now_secs) => cfg,
+        _ => {
+            info!(
+                tag = "[Broadcast Trace]",
+                "Broadcaster {} for {:?} ended or expired, no longer polling",
+                target.broadcaster_id,
+                target.entity_id
+            );
+            return redis_migration_write!(redis_migration, "broadcast_trace", &redis, |redis| {
+                delete_broadcast_trace_target(&redis, &target.entity_id, &target.broadcaster_id)
+            });
+        }
+    };
+
+    let last_sent =
+        get_broadcast_trace_last_sent(&serving_redis, &target.entity_id, &target.broadcaster_id)
+            .await?;
+    if !is_trace_due(&cfg, last_sent, now_secs) {
+        return Ok(());
+    }
+
+    let last_known_location =
+        match get_person_detail(&serving_redis, target.person_type, &target.person_id).await? {
+            Some(details) => details.last_known_location,
+            None => {
+                warn!(
+                    tag = "[Broadcast Trace]",
+                    "No last known location for {:?}, nothing to send for broadcaster {}",
+                    target.person_id,
+                    target.broadcaster_id
+                );
+                return Ok(());
+            }
+        };
+    let location_ts = last_known_location.timestamp.0.timestamp();
+    let location = BroadcastLocation {
+        lat: last_known_location.location.lat.inner(),
+        lon: last_known_location.location.lon.inner(),
+        timestamp: location_ts,
+        signal: trace_signal(now_secs - location_ts, config),
+    };
+
+    try_send_broadcast_trace(redis, redis_migration, target, cfg, location).await;
+    Ok(())
+}
+
+/// Runs one scheduler pass over every registered broadcaster and returns how many
+/// were polled. A failure on one broadcaster is logged and does not stop the others.
+pub async fn run_broadcast_trace_polls(
+    redis: &Arc<RedisConnectionPool>,
+    redis_migration: &Arc<RedisMigration>,
+    config: &BroadcastTraceSchedulerConfig,
+) -> Result<usize, AppError> {
+    let targets = get_all_broadcast_trace_targets(&redis_migration.serving(redis)).await?;
+    let count = targets.len();
+    join_all(targets.into_iter().map(|target| async move {
+        let broadcaster_id = target.broadcaster_id.to_owned();
+        if let Err(err) =
+            poll_broadcast_trace_target(redis.clone(), redis_migration.clone(), target, config)
+                .await
+        {
+            error!(
+                tag = "[Broadcast Trace]",
+                "Scheduled trace failed for broadcaster {}: {}",
+                broadcaster_id,
+                err.message()
+            );
+        }
+    }))
+    .await;
+    Ok(count)
+}
+
+/// Registers a trace target for every active broadcaster found in the persons'
+/// entity details, so broadcasters started before the scheduler existed are polled
+/// too. Registering an existing target again only rewrites the same field.
+pub async fn backfill_broadcast_trace_targets(
+    redis: &Arc<RedisConnectionPool>,
+    redis_migration: &RedisMigration,
+) -> Result<usize, AppError> {
+    let serving_redis = redis_migration.serving(redis);
+    let mut count = 0;
+    for key in scan_keys(&serving_redis, &entity_details_key_pattern()).await? {
+        let map = match serving_redis.get_key::<PersonEntityDetailsMap>(&key).await {
+            Ok(Some(map)) => map,
+            Ok(None) => continue,
+            Err(err) => {
+                warn!(
+                    tag = "[Broadcast Trace]",
+                    "Skipping unreadable entity details {} in backfill: {:?}", key, err
+                );
+                continue;
+            }
+        };
+        let Some((person_type, person_id)) = parse_entity_details_key(&key, &map.merchant_id)
+        else {
+            continue;
+        };
+        for (entity_type, entry) in &map.entities {
+            for target in broadcast_trace_targets(
+                &map.merchant_id,
+                person_type,
+                &person_id,
+                entity_type,
+                entry,
+            ) {
+                redis_migration_write!(redis_migration, "broadcast_trace", redis, |redis| {
+                    set_broadcast_trace_target(&redis, &target)
+                })?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+pub async fn start_broadcast_trace_scheduler(
+    redis: Arc<RedisConnectionPool>,
+    redis_migration: Arc<RedisMigration>,
+    config: BroadcastTraceSchedulerConfig,
+) {
+    let interval_secs = config.interval_secs.max(1);
+
+    // One pod per rollout backfills; the lock outlives a typical rolling deploy.
+    match redis
+        .setnx_with_expiry(
+            &broadcast_trace_backfill_lock_key(),
+            true,
+            BROADCAST_TRACE_BACKFILL_LOCK_SECS,
+        )
+        .await
+    {
+        Ok(true) => match backfill_broadcast_trace_targets(&redis, &redis_migration).await {
+            Ok(count) => info!(
+                tag = "[Broadcast Trace]",
+                "Backfilled {} broadcast trace targets", count
+            ),
+            Err(err) => error!(
+                tag = "[Broadcast Trace]",
+                "Failed to backfill broadcast trace targets: {}",
+                err.message()
+            ),
+        },
+        Ok(false) => {}
+        Err(err) => error!(
+            tag = "[Broadcast Trace]",
+            "Failed to take broadcast trace backfill lock: {:?}", err
+        ),
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+    loop {
+        interval.tick().await;
+        // Pod tickers are not aligned, so the lock is per window rather than per tick;
+        // the per-broadcaster send lock covers a run that overlaps the next window.
+        let window = Utc::now().timestamp() / interval_secs as i64;
+        match redis
+            .setnx_with_expiry(
+                &broadcast_trace_scheduler_lock_key(window),
+                true,
+                2 * interval_secs as i64,
+            )
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(err) => {
+                error!(
+                    tag = "[Broadcast Trace]",
+                    "Failed to take broadcast trace scheduler lock: {:?}", err
+                );
+                continue;
+            }
+        }
+        match run_broadcast_trace_polls(&redis, &redis_migration, &config).await {
+            Ok(count) => info!(tag = "[Broadcast Trace]", "Polled {} broadcasters", count),
+            Err(err) => error!(
+                tag = "[Broadcast Trace]",
+                "Failed to run broadcast trace polls: {}",
+                err.message()
+            ),
+        }
+    }
+}
@@ -0,0 +1,284 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use shared::redis::types::RedisConnectionPool;
+use tokio::sync::RwLock;
+use tracing::{error, info};
+
+use crate::common::types::*;
+use crate::environment::StopDetectionConfig;
+use crate::redis::commands::get_all_config_overrides;
+use crate::tools::error::AppError;
+
+pub type DetectionConfigMap =
+    HashMap<VehicleType, HashMap<RideStatus, HashMap<DetectionType, ViolationDetectionConfig>>>;
+
+pub type StopDetectionConfigMap = HashMap<VehicleType, HashMap<RideStatus, StopDetectionConfig>>;
+
+/// Scope a runtime override applies to. Both unset is the global scope, which
+/// still sits above the static dhall config. Stored as the field name of the
+/// `lts:config_overrides` hash (see `ConfigOverrideScope::as_field`).
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigOverrideScope {
+    pub merchant_id: Option<MerchantId>,
+    pub city: Option<CityName>,
+}
+
+impl ConfigOverrideScope {
+    pub fn new(merchant_id: Option<MerchantId>, city: Option<CityName>) -> Self {
+        ConfigOverrideScope { merchant_id, city }
+    }
+
+    pub fn as_field(&self) -> String {
+        match (&self.merchant_id, &self.city) {
+            (Some(MerchantId(merchant_id)), Some(CityName(city))) => {
+                format!("merchant:{merchant_id}:city:{city}")
+            }
+            (Some(MerchantId(merchant_id)), None) => format!("merchant:{merchant_id}"),
+            (None, Some(CityName(city))) => format!("city:{city}"),
+            (None, None) => "global".to_string(),
+        }
+    }
+
+    /// Lookup order for a merchant operating in a city, most specific first:
+    /// merchant+city → merchant → city → global.
+    pub fn layers(merchant_id: Option<&MerchantId>, city: Option<&CityName>) -> Vec<Self> {
+        let mut layers = Vec::with_capacity(4);
+        if let (Some(merchant_id), Some(city)) = (merchant_id, city) {
+            layers.push(Self::new(
+                Some(merchant_id.to_owned()),
+                Some(city.to_owned()),
+            ));
+        }
+        if let Some(merchant_id) = merchant_id {
+            layers.push(Self::new(Some(merchant_id.to_owned()), None));
+        }
+        if let Some(city) = city {
+            layers.push(Self::new(None, Some(city.to_owned())));
+        }
+        layers.push(Self::default());
+        layers
+    }
+}
+
+/// Subset of `AppConfig` that can be overridden at runtime. `None` means
+/// "inherit from the next scope / static config".
+///
+/// The config maps are behind `Arc` because every driver ping resolves the
+/// override, and layering would otherwise deep-copy them each time.
+///
+/// `bucket_size` is deliberately not overridable: it names the geo buckets
+/// that the drainer writes and nearby-driver queries read, so a change that
+/// reached pods at different refresh ticks would split one time window across
+/// two sets of bucket keys. Change it through the static config and a restart.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigOverride {
+    pub pickup_notification_threshold: Option<f64>,
+    pub arriving_notification_threshold: Option<f64>,
+    pub pickup_instruction_notification_threshold: Option<f64>,
+    pub driver_location_accuracy_buffer: Option<f64>,
+    pub driver_reached_destination_buffer: Option<f64>,
+    pub driver_source_departed_buffer: Option<f64>,
+    pub min_location_accuracy: Option<Accuracy>,
+    pub location_update_limit: Option<usize>,
+    pub blacklist_merchants: Option<Vec<MerchantId>>,
+    pub stop_detection: Option<Arc<StopDetectionConfigMap>>,
+    pub detection_violation_config: Option<Arc<DetectionConfigMap>>,
+    pub detection_anti_violation_config: Option<Arc<DetectionConfigMap>>,
+}
+
+impl ConfigOverride {
+    pub fn validate(&self) -> Result<(), AppError> {
+        let thresholds = [
+            (
+                "pickupNotificationThreshold",
+                self.pickup_notification_threshold,
+            ),
+            (
+                "arrivingNotificationThreshold",
+                self.arriving_notification_threshold,
+            ),
+            (
+                "pickupInstructionNotificationThreshold",
+                self.pickup_instruction_notification_threshold,
+            ),
+            (
+                "driverLocationAccuracyBuffer",
+                self.driver_location_accuracy_buffer,
+            ),
+            (
+                "driverReachedDestinationBuffer",
+                self.driver_reached_destination_buffer,
+            ),
+            (
+                "driverSourceDepartedBuffer",
+                self.driver_source_departed_buffer,
+            ),
+            (
+                "minLocationAccuracy",
+                self.min_location_accuracy.map(|acc| acc.inner()),
+            ),
+        ];
+        for (name, threshold) in thresholds
+            .into_iter()
+            .filter_map(|(name, threshold)| threshold.map(|threshold| (name, threshold)))
+        {
+            if !threshold.is_finite() || threshold <= 0.0 {
+                return Err(AppError::InvalidRequest(format!(
+                    "{name} must be a positive number, got {threshold}"
+                )));
+            }
+        }
+        if self.location_update_limit == Some(0) {
+            return Err(AppError::InvalidRequest(
+                "locationUpdateLimit must be greater than 0".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fills every unset field of `self` from `fallback`.
+    pub fn or(self, fallback: &ConfigOverride) -> ConfigOverride {
+        ConfigOverride {
+            pickup_notification_threshold: self
+                .pickup_notification_threshold
+                .or(fallback.pickup_notification_threshold),
+            arriving_notification_threshold: self
+                .arriving_notification_threshold
+                .or(fallback.arriving_notification_threshold),
+            pickup_instruction_notification_threshold: self
+                .pickup_instruction_notification_threshold
+                .or(fallback.pickup_instruction_notification_threshold),
+            driver_location_accuracy_buffer: self
+                .driver_location_accuracy_buffer
+                .or(fallback.driver_location_accuracy_buffer),
+            driver_reached_destination_buffer: self
+                .driver_reached_destination_buffer
+                .or(fallback.driver_reached_destination_buffer),
+            driver_source_departed_buffer: self
+                .driver_source_departed_buffer
+                .or(fallback.driver_source_departed_buffer),
+            min_location_accuracy: self
+                .min_location_accuracy
+                .or(fallback.min_location_accuracy),
+            location_update_limit: self
+                .location_update_limit
+                .or(fallback.location_update_limit),
+            blacklist_merchants: self
+                .blacklist_merchants
+                .or_else(|| fallback.blacklist_merchants.clone()),
+            stop_detection: self
+                .stop_detection
+                .or_else(|| fallback.stop_detection.clone()),
+            detection_violation_config: self
+                .detection_violation_config
+                .or_else(|| fallback.detection_violation_config.clone()),
+            detection_anti_violation_config: self
+                .detection_anti_violation_config
+                .or_else(|| fallback.detection_anti_violation_config.clone()),
+        }
+    }
+}
+
+/// One version of an override. The current entry lives in the
+/// `lts:config_overrides` hash; every version (including the current one) is
+/// also pushed to the per-scope history list so it can be rolled back to.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigOverrideEntry {
+    pub scope: ConfigOverrideScope,
+    pub version: u64,
+    pub config: ConfigOverride,
+    pub updated_at: TimeStamp,
+    pub updated_by: Option<String>,
+    pub reason: Option<String>,
+}
+
+/// In-memory copy of `lts:config_overrides`, keyed by `ConfigOverrideScope::as_field`.
+pub type ConfigOverrideCache = Arc<RwLock<FxHashMap<String, ConfigOverrideEntry>>>;
+
+/// Resolves the overrides applicable to a merchant / city by walking
+/// `ConfigOverrideScope::layers`; the first layer that sets a field wins.
+/// Fields no layer sets stay `None` and fall back to the static config.
+pub fn resolve_from_entries(
+    entries: &FxHashMap<String, ConfigOverrideEntry>,
+    merchant_id: Option<&MerchantId>,
+    city: Option<&CityName>,
+) -> ConfigOverride {
+    ConfigOverrideScope::layers(merchant_id, city)
+        .iter()
+        .filter_map(|scope| entries.get(&scope.as_field()))
+        .fold(ConfigOverride::default(), |resolved, entry| {
+            resolved.or(&entry.config)
+        })
+}
+
+pub async fn resolve_config_override(
+    cache: &ConfigOverrideCache,
+    merchant_id: &MerchantId,
+    city: &CityName,
+) -> ConfigOverride {
+    resolve_from_entries(&*cache.read().await, Some(merchant_id), Some(city))
+}
+
+/// Resolves a single field through the same layers as `resolve_from_entries`
+/// without cloning the rest of the override. Used on hot paths that only
+/// need one scalar.
+pub async fn resolve_config_field<T>(
+    cache: &ConfigOverrideCache,
+    merchant_id: &MerchantId,
+    city: &CityName,
+    field: impl Fn(&ConfigOverride) -> Option<T>,
+) -> Option<T> {
+    let guard = cache.read().await;
+    ConfigOverrideScope::layers(Some(merchant_id), Some(city))
+        .iter()
+        .filter_map(|scope| guard.get(&scope.as_field()))
+        .find_map(|entry| field(&entry.config))
+}
+
+/// Reloads the override cache from Redis so that every pod converges on the
+/// same overrides within one refresh interval.
+pub async fn refresh_config_override_cache(
+    redis: &RedisConnectionPool,
+    cache: &ConfigOverrideCache,
+) -> Result<usize, AppError> {
+    let overrides = get_all_config_overrides(redis).await?;
+    let count = overrides.len();
+    let mut guard = cache.write().await;
+    *guard = overrides;
+    Ok(count)
+}
+
+pub async fn start_config_override_refresh_task(
+    redis: Arc<RedisConnectionPool>,
+    cache: ConfigOverrideCache,
+    refresh_interval_secs: u64,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(refresh_interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+        match refresh_config_override_cache(&redis, &cache).await {
+            Ok(count) => info!(
+                tag = "[Config Override Refresh]",
+                "Loaded {} config overrides", count
+            ),
+            Err(err) => error!(
+                tag = "[Config Override Refresh]",
+                "Failed to refresh config overrides: {}",
+                err.message()
+            ),
+        }
+    }
+}
@@ -15,9 +15,9 @@ use crate::{
     common::types::*,
     common::utils::{distance_between_in_meters, get_upcoming_stops_by_route_code},
     outbound::types::{
-        DetectionData, OppositeDirectionDetectionData, OverSpeedingDetectionData,
-        RouteDeviationDetectionData, SafetyCheckDetectionData, StoppedDetectionData,
-        ViolationDetectionReq,
+        DetectionData, DetectionOutcome, DriverDivergenceDetectionData,
+        OppositeDirectionDetectionData, OverSpeedingDetectionData, RouteDeviationDetectionData,
+        SafetyCheckDetectionData, StoppedDetectionData, SuddenJumpDetectionData,
     },
 };
 
@@ -35,7 +35,7 @@ pub fn check(
     Option<ViolationDetectionState>,
     Option<ViolationDetectionState>,
     Option<DetectionStatus>,
-    Option<ViolationDetectionReq>,
+    Option<DetectionOutcome>,
 ) {
     let violation_state_and_trigger =
         violation_check(violation_config, &context, detection_state.to_owned());
@@ -672,6 +672,14 @@ fn violation_check(
             min_stop_duration,
             false,
         ),
+        DetectionConfig::SuddenJumpDetection(SuddenJumpConfig {
+            max_speed,
+            min_jump_distance,
+        }) => handle_sudden_jump_check(state, context, max_speed, min_jump_distance, false),
+        DetectionConfig::DriverDivergenceDetection(DriverDivergenceConfig {
+            max_distance,
+            sample_size,
+        }) => handle_driver_divergence_check(state, context, max_distance, sample_size, false),
     }
 }
 /*
@@ -812,6 +820,88 @@ fn handle_ride_stop_reached_check(
     ))
 }
 
+/*
+Detects a sudden large jump between two consecutive points, i.e. a move of more
+than min_jump_distance meters at an implied speed above max_speed. The state
+only carries the previous point, so the anti-violation side resolves on the
+first normal move after a jump.
+*/
+fn handle_sudden_jump_check(
+    state: Option<ViolationDetectionState>,
+    context: &DetectionContext,
+    max_speed: f64,
+    min_jump_distance: u32,
+    is_anti_violation: bool,
+) -> Option<(ViolationDetectionState, Option<bool>)> {
+    let next_state = |jump_distance| {
+        ViolationDetectionState::SuddenJump(SuddenJumpState {
+            last_location: context.location.to_owned(),
+            last_timestamp: context.timestamp,
+            jump_distance,
+        })
+    };
+
+    if let Some(ViolationDetectionState::SuddenJump(SuddenJumpState {
+        last_location,
+        last_timestamp,
+        ..
+    })) = state
+    {
+        let distance = distance_between_in_meters(&last_location, &context.location);
+        let elapsed_secs =
+            (context.timestamp.0 - last_timestamp.0).num_milliseconds() as f64 / 1000.0;
+        let is_jump = distance > min_jump_distance as f64
+            && (elapsed_secs <= 0.0 || distance / elapsed_secs > max_speed);
+        Some((next_state(distance), Some(is_jump != is_anti_violation)))
+    } else {
+        Some((next_state(0.0), None))
+    }
+}
+
+/*
+Detects a rider drifting away from the driver of their ride. Counts consecutive
+points on one side of max_distance from context.counterpart_location and
+triggers once sample_size of them are seen in a row.
+*/
+fn handle_driver_divergence_check(
+    state: Option<ViolationDetectionState>,
+    context: &DetectionContext,
+    max_distance: u32,
+    sample_size: u32,
+    is_anti_violation: bool,
+) -> Option<(ViolationDetectionState, Option<bool>)> {
+    let Some(counterpart_location) = context.counterpart_location.as_ref() else {
+        return state.map(|state| (state, None));
+    };
+
+    let distance = distance_between_in_meters(&context.location, counterpart_location);
+    let is_counted = (distance > max_distance as f64) != is_anti_violation;
+    let total_datapoints = match state {
+        Some(ViolationDetectionState::DriverDivergence(DriverDivergenceState {
+            total_datapoints,
+            ..
+        })) if is_counted => total_datapoints + 1,
+        _ if is_counted => 1,
+        _ => 0,
+    };
+
+    let trigger = if total_datapoints >= sample_size as u64 {
+        Some(true)
+    } else if is_counted {
+        None
+    } else {
+        Some(false)
+    };
+
+    Some((
+        ViolationDetectionState::DriverDivergence(DriverDivergenceState {
+            total_datapoints,
+            distance,
+        }),
+        trigger,
+    ))
+}
+
 /// Checks if a vehicle has deviated from the route by using the average of the points in the list
 /// first we get the route from the route_code in the context, and then we get the polyline for the route
 /// and then we project the current point on the polyline and check if the distance between the projected point and the current point is less than the deviation threshold
@@ -1295,6 +1385,14 @@ fn anti_violation_check(
             min_stop_duration,
             true,
         ),
+        DetectionConfig::SuddenJumpDetection(SuddenJumpConfig {
+            max_speed,
+            min_jump_distance,
+        }) => handle_sudden_jump_check(state, context, max_speed, min_jump_distance, true),
+        DetectionConfig::DriverDivergenceDetection(DriverDivergenceConfig {
+            max_distance,
+            sample_size,
+        }) => handle_driver_divergence_check(state, context, max_distance, sample_size, true),
     }
 }
 
@@ -1303,7 +1401,7 @@ fn get_triggered_state_req(
     curr_anti_violation_state: Option<&ViolationDetectionState>,
     is_violated: Option<DetectionStatus>,
     context: DetectionContext,
-) -> Option<ViolationDetectionReq> {
+) -> Option<DetectionOutcome> {
     match is_violated {
         Some(DetectionStatus::Violated) => match curr_violation_state {
             Some(ViolationDetectionState::Overspeeding(OverspeedingState {
@@ -1312,9 +1410,7 @@ fn get_triggered_state_req(
             })) => {
                 let average_speed = avg_speed_record.iter().map(|c| c.0).sum::<f64>()
                     / avg_speed_record.len() as f64;
-                Some(ViolationDetectionReq {
-                    ride_id: context.ride_id,
-                    driver_id: context.driver_id,
+                Some(DetectionOutcome {
                     is_violated: true,
                     detection_data: DetectionData::OverSpeedingDetection(
                         OverSpeedingDetectionData {
@@ -1327,9 +1423,7 @@ fn get_triggered_state_req(
             Some(ViolationDetectionState::RouteDeviation(RouteDeviationState {
                 deviation_distance,
                 ..
-            })) => Some(ViolationDetectionReq {
-                ride_id: context.ride_id,
-                driver_id: context.driver_id,
+            })) => Some(DetectionOutcome {
                 is_violated: true,
                 detection_data: DetectionData::RouteDeviationDetection(
                     RouteDeviationDetectionData {
@@ -1339,9 +1433,7 @@ fn get_triggered_state_req(
                 ),
             }),
             Some(ViolationDetectionState::StopDetection(StopDetectionState { .. })) => {
-                Some(ViolationDetectionReq {
-                    ride_id: context.ride_id,
-                    driver_id: context.driver_id,
+                Some(DetectionOutcome {
                     is_violated: true,
                     detection_data: DetectionData::StoppedDetection(StoppedDetectionData {
                         location: context.location,
@@ -1349,9 +1441,7 @@ fn get_triggered_state_req(
                 })
             }
             Some(ViolationDetectionState::OppositeDirection(OppositeDirectionState { .. })) => {
-                Some(ViolationDetectionReq {
-                    ride_id: context.ride_id,
-                    driver_id: context.driver_id,
+                Some(DetectionOutcome {
                     is_violated: true,
                     detection_data: DetectionData::OppositeDirectionDetection(
                         OppositeDirectionDetectionData {
@@ -1361,9 +1451,7 @@ fn get_triggered_state_req(
                 })
             }
             Some(ViolationDetectionState::SafetyCheck(SafetyCheckState { .. })) => {
-                Some(ViolationDetectionReq {
-                    ride_id: context.ride_id,
-                    driver_id: context.driver_id,
+                Some(DetectionOutcome {
                     is_violated: true,
                     detection_data: DetectionData::SafetyCheckDetection(SafetyCheckDetectionData {
                         location: context.location,
@@ -1388,9 +1476,7 @@ fn get_triggered_state_req(
 
                     if last_reached_stop_index < ride_stops.len() {
                         let reached_stop = &ride_stops[last_reached_stop_index];
-                        Some(ViolationDetectionReq {
-                            ride_id: context.ride_id,
-                            driver_id: context.driver_id,
+                        Some(DetectionOutcome {
                             is_violated: true,
                             detection_data: DetectionData::RideStopReachedDetection(
                                 RideStopReachedDetectionData {
@@ -1409,6 +1495,27 @@ fn get_triggered_state_req(
                     None
                 }
             }
+            Some(ViolationDetectionState::SuddenJump(SuddenJumpState {
+                jump_distance, ..
+            })) => Some(DetectionOutcome {
+                is_violated: true,
+                detection_data: DetectionData::SuddenJumpDetection(SuddenJumpDetectionData {
+                    location: context.location,
+                    distance: *jump_distance,
+                }),
+            }),
+            Some(ViolationDetectionState::DriverDivergence(DriverDivergenceState {
+                distance,
+                ..
+            })) => Some(DetectionOutcome {
+                is_violated: true,
+                detection_data: DetectionData::DriverDivergenceDetection(
+                    DriverDivergenceDetectionData {
+                        location: context.location,
+                        distance: *distance,
+                    },
+                ),
+            }),
             _ => None,
         },
         Some(DetectionStatus::AntiViolated) => match curr_anti_violation_state {
@@ -1418,9 +1525,7 @@ fn get_triggered_state_req(
             })) => {
                 let average_speed = avg_speed_record.iter().map(|c| c.0).sum::<f64>()
                     / avg_speed_record.len() as f64;
-                Some(ViolationDetectionReq {
-                    ride_id: context.ride_id,
-                    driver_id: context.driver_id,
+                Some(DetectionOutcome {
                     is_violated: false,
                     detection_data: DetectionData::OverSpeedingDetection(
                         OverSpeedingDetectionData {
@@ -1433,9 +1538,7 @@ fn get_triggered_state_req(
             Some(ViolationDetectionState::RouteDeviation(RouteDeviationState {
                 deviation_distance,
                 ..
-            })) => Some(ViolationDetectionReq {
-                ride_id: context.ride_id,
-                driver_id: context.driver_id,
+            })) => Some(DetectionOutcome {
                 is_violated: false,
                 detection_data: DetectionData::RouteDeviationDetection(
                     RouteDeviationDetectionData {
@@ -1445,9 +1548,7 @@ fn get_triggered_state_req(
                 ),
             }),
             Some(ViolationDetectionState::StopDetection(StopDetectionState { .. })) => {
-                Some(ViolationDetectionReq {
-                    ride_id: context.ride_id,
-                    driver_id: context.driver_id,
+                Some(DetectionOutcome {
                     is_violated: false,
                     detection_data: DetectionData::StoppedDetection(StoppedDetectionData {
                         location: context.location,
@@ -1455,9 +1556,7 @@ fn get_triggered_state_req(
                 })
             }
             Some(ViolationDetectionState::OppositeDirection(OppositeDirectionState { .. })) => {
-                Some(ViolationDetectionReq {
-                    ride_id: context.ride_id,
-                    driver_id: context.driver_id,
+                Some(DetectionOutcome {
                     is_violated: false,
                     detection_data: DetectionData::OppositeDirectionDetection(
                         OppositeDirectionDetectionData {
@@ -1467,9 +1566,7 @@ fn get_triggered_state_req(
                 })
             }
             Some(ViolationDetectionState::SafetyCheck(SafetyCheckState { .. })) => {
-                Some(ViolationDetectionReq {
-                    ride_id: context.ride_id,
-                    driver_id: context.driver_id,
+                Some(DetectionOutcome {
                     is_violated: false,
                     detection_data: DetectionData::SafetyCheckDetection(SafetyCheckDetectionData {
                         location: context.location,
@@ -1484,9 +1581,7 @@ fn get_triggered_state_req(
                 if let Some(ride_stops) = context.ride_stops {
                     if *current_stop_index < ride_stops.len() {
                         let current_stop = &ride_stops[*current_stop_index];
-                        Some(ViolationDetectionReq {
-                            ride_id: context.ride_id,
-                            driver_id: context.driver_id,
+                        Some(DetectionOutcome {
                             is_violated: false,
                             detection_data: DetectionData::RideStopReachedDetection(
                                 RideStopReachedDetectionData {
@@ -1505,6 +1600,27 @@ fn get_triggered_state_req(
                     None
                 }
             }
+            Some(ViolationDetectionState::SuddenJump(SuddenJumpState {
+                jump_distance, ..
+            })) => Some(DetectionOutcome {
+                is_violated: false,
+                detection_data: DetectionData::SuddenJumpDetection(SuddenJumpDetectionData {
+                    location: context.location,
+                    distance: *jump_distance,
+                }),
+            }),
+            Some(ViolationDetectionState::DriverDivergence(DriverDivergenceState {
+                distance,
+                ..
+            })) => Some(DetectionOutcome {
+                is_violated: false,
+                detection_data: DetectionData::DriverDivergenceDetection(
+                    DriverDivergenceDetectionData {
+                        location: context.location,
+                        distance: *distance,
+                    },
+                ),
+            }),
             _ => None,
         },
         _ => None,
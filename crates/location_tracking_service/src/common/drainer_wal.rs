@@ -0,0 +1,243 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Append-only write-ahead log for the drainer buffer.
+//!
+//! Every entry the drainer receives is appended (one JSON line) before it is
+//! buffered. Whatever is left in the file on startup was buffered but never drained,
+//! so `run_drainer` replays it. Writes go through a dedicated writer thread, so the
+//! drainer task never blocks on the file; the thread fsyncs at most
+//! `DRAINER_WAL_SYNC_INTERVAL` after a write, which bounds what the loss of the node
+//! can take with it.
+//!
+//! The file is truncated after every successful drain. A failed drain leaves it as
+//! is: the drainer keeps the failed batch buffered and retries it with the entries
+//! received since, so the next successful drain covers everything in the file.
+//! Appends are refused once the file reaches its size cap, counted in
+//! `drainer_wal_refused_appends_total`.
+//!
+//! Entries set aside in the `DrainerOverflow` are appended by the senders through
+//! a `WalAppender`, so they are durable while they wait for the drainer as well.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use tracing::error;
+
+use crate::common::types::*;
+use crate::tools::error::AppError;
+use crate::tools::prometheus::DRAINER_WAL_REFUSED_APPENDS;
+
+/// How far the on-disk WAL may lag behind the last write.
+pub const DRAINER_WAL_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Size cap used by `run_drainer`. Entries beyond it are still drained, just not logged.
+pub const DRAINER_WAL_MAX_BYTES: u64 = 256 * 1024 * 1024;
+
+/// What the location handlers send to the drainer: dimensions, position, server
+/// timestamp, ping timestamp and driver.
+pub type DrainerEntry = (
+    Dimensions,
+    Latitude,
+    Longitude,
+    TimeStamp,
+    TimeStamp,
+    DriverId,
+);
+
+enum WalOp {
+    Append(Vec<u8>),
+    Truncate(u64),
+    Stop,
+}
+
+/// Appends to the WAL from outside the drainer task. Cheap to clone.
+#[derive(Clone)]
+pub struct WalAppender {
+    path: PathBuf,
+    ops: mpsc::Sender<WalOp>,
+    /// Bytes appended so far, as the writer thread will have them.
+    len: Arc<AtomicU64>,
+    max_bytes: u64,
+}
+
+impl WalAppender {
+    pub fn append(&self, entry: &DrainerEntry) -> Result<(), AppError> {
+        let mut line = serde_json::to_vec(entry)
+            .map_err(|err| AppError::SerializationError(err.to_string()))?;
+        line.push(b'\n');
+        let line_len = line.len() as u64;
+        self.len
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |len| {
+                (len + line_len <= self.max_bytes).then_some(len + line_len)
+            })
+            .map_err(|len| {
+                DRAINER_WAL_REFUSED_APPENDS.inc();
+                AppError::InternalError(format!(
+                    "Drainer WAL {:?} is full at {} bytes",
+                    self.path, len
+                ))
+            })?;
+        // One write per entry, so a crash can tear at most the last line.
+        self.send(WalOp::Append(line)).inspect_err(|_| {
+            DRAINER_WAL_REFUSED_APPENDS.inc();
+            self.len.fetch_sub(line_len, Ordering::AcqRel);
+        })
+    }
+
+    fn send(&self, op: WalOp) -> Result<(), AppError> {
+        self.ops.send(op).map_err(|_| {
+            AppError::InternalError(format!("Drainer WAL writer {:?} has stopped", self.path))
+        })
+    }
+}
+
+pub struct DrainerWal {
+    appender: WalAppender,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl DrainerWal {
+    /// Opens (or creates) the WAL at `path`, returning the entries a previous run
+    /// left unacknowledged. They stay in the file until the next acknowledgement.
+    /// A line that fails to decode (e.g. torn by the crash) is skipped.
+    pub fn open(
+        path: impl Into<PathBuf>,
+        max_bytes: u64,
+    ) -> Result<(Self, Vec<DrainerEntry>), AppError> {
+        let path = path.into();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|err| {
+                AppError::InternalError(format!("Failed to open drainer WAL {path:?} : {err}"))
+            })?;
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).map_err(|err| {
+            AppError::InternalError(format!("Failed to read drainer WAL {path:?} : {err}"))
+        })?;
+
+        let mut pending = Vec::new();
+        for line in contents.lines().filter(|line| !line.is_empty()) {
+            match serde_json::from_str::<DrainerEntry>(line) {
+                Ok(entry) => pending.push(entry),
+                Err(err) => {
+                    error!(tag = "[Drainer WAL]", path = ?path, error = %err, "Skipping undecodable entry")
+                }
+            }
+        }
+
+        // Terminate a torn last line so the next append starts on its own line.
+        let mut len = contents.len() as u64;
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            file.write_all(b"\n").map_err(|err| {
+                AppError::InternalError(format!("Failed to append to drainer WAL {path:?} : {err}"))
+            })?;
+            len += 1;
+        }
+
+        let (ops, rx) = mpsc::channel();
+        let writer_path = path.clone();
+        let writer = std::thread::Builder::new()
+            .name("drainer-wal".to_string())
+            .spawn(move || run_wal_writer(writer_path, file, rx))
+            .map_err(|err| {
+                AppError::InternalError(format!(
+                    "Failed to start drainer WAL writer {path:?} : {err}"
+                ))
+            })?;
+
+        Ok((
+            Self {
+                appender: WalAppender {
+                    path,
+                    ops,
+                    len: Arc::new(AtomicU64::new(len)),
+                    max_bytes,
+                },
+                writer: Some(writer),
+            },
+            pending,
+        ))
+    }
+
+    pub fn appender(&self) -> WalAppender {
+        self.appender.clone()
+    }
+
+    pub fn append(&mut self, entry: &DrainerEntry) -> Result<(), AppError> {
+        self.appender.append(entry)
+    }
+
+    /// Drops every entry appended so far, once they are drained to Redis. Entries
+    /// appended through a `WalAppender` that are not drained yet must be appended
+    /// again afterwards.
+    pub fn acknowledge(&mut self) -> Result<(), AppError> {
+        if self.appender.len.load(Ordering::Acquire) == 0 {
+            return Ok(());
+        }
+        self.appender.send(WalOp::Truncate(0))?;
+        self.appender.len.store(0, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl Drop for DrainerWal {
+    /// Stops the writer once it has flushed and fsynced what is queued. Appenders
+    /// still held elsewhere fail from then on.
+    fn drop(&mut self) {
+        let _ = self.appender.send(WalOp::Stop);
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn run_wal_writer(path: PathBuf, mut file: File, ops: mpsc::Receiver<WalOp>) {
+    let mut unsynced = false;
+    let mut last_sync = Instant::now();
+    loop {
+        let disconnected = match ops.recv_timeout(DRAINER_WAL_SYNC_INTERVAL) {
+            Ok(WalOp::Append(line)) => {
+                if let Err(err) = file.write_all(&line) {
+                    error!(tag = "[Drainer WAL]", path = ?path, error = %err, "Failed to append");
+                }
+                unsynced = true;
+                false
+            }
+            Ok(WalOp::Truncate(len)) => {
+                if let Err(err) = file.set_len(len) {
+                    error!(tag = "[Drainer WAL]", path = ?path, error = %err, "Failed to truncate");
+                }
+                unsynced = true;
+                false
+            }
+            Ok(WalOp::Stop) | Err(RecvTimeoutError::Disconnected) => true,
+            Err(RecvTimeoutError::Timeout) => false,
+        };
+        if unsynced && (disconnected || last_sync.elapsed() >= DRAINER_WAL_SYNC_INTERVAL) {
+            if let Err(err) = file.sync_data() {
+                error!(tag = "[Drainer WAL]", path = ?path, error = %err, "Failed to fsync");
+            }
+            unsynced = false;
+            last_sync = Instant::now();
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
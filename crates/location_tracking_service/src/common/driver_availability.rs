@@ -0,0 +1,517 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Explicit availability state of a driver, persisted per driver.
+//!
+//! The state is derived from what LTS already sees: the mode and ride status
+//! of each ping, queue membership, ride lifecycle calls and blocks. Every
+//! change is stored with the server time it was entered and published to
+//! `driver_availability_topic` with the time spent in the previous state.
+//!
+//! Ride lifecycle calls and blocks come from the services that own rides and
+//! blocks, so they are always applied; one that breaks the rules of
+//! `can_transition_to` (say a missed ride start, seen as `OnlineIdle` to
+//! `OnRide`) is flagged `valid: false`. A state derived from pings is only a
+//! guess and is rejected when it breaks the rules, leaving the stored state
+//! as it is until a lifecycle call moves it.
+//!
+//! An online driver who stops pinging is moved to `Offline` by a sweep once
+//! they have not been seen for `driver_availability_ping_timeout_secs`.
+
+use std::time::Duration;
+
+use actix_web::web::Data;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::common::kafka::push_to_kafka;
+use crate::common::types::*;
+use crate::environment::AppState;
+use crate::redis::commands::{
+    get_driver_availability, get_drivers_last_seen_before, set_driver_availability,
+    set_driver_availability_if_unchanged, set_driver_availability_last_seen,
+};
+use crate::redis::keys::driver_availability_sweep_lock_key;
+use crate::redis::migration::record_mirror_write;
+use crate::tools::error::AppError;
+use crate::tools::prometheus::DRIVER_AVAILABILITY_TRANSITIONS;
+use crate::{redis_migration_read, redis_migration_write};
+
+/// Attempts at a compare-and-set of the record before giving up to a driver
+/// whose record keeps changing under us.
+const DRIVER_AVAILABILITY_WRITE_ATTEMPTS: usize = 3;
+
+/// Drivers moved to `Offline` per sweep batch.
+const DRIVER_AVAILABILITY_SWEEP_BATCH: i64 = 500;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum DriverAvailability {
+    Offline,
+    OnlineIdle,
+    Silent,
+    Queued,
+    OnPickup,
+    OnRide,
+    Blocked,
+}
+
+impl DriverAvailability {
+    /// State reported by a ping. An assigned ride outranks the mode; an online
+    /// driver without a ride is `OnlineIdle`, or `Queued` once the caller has
+    /// found them in a special location queue.
+    pub fn from_ping(mode: &DriverMode, ride_status: Option<&RideStatus>) -> Self {
+        match (ride_status, mode) {
+            (Some(RideStatus::NEW), _) => DriverAvailability::OnPickup,
+            (Some(RideStatus::INPROGRESS), _) => DriverAvailability::OnRide,
+            (_, DriverMode::OFFLINE) => DriverAvailability::Offline,
+            (_, DriverMode::SILENT) => DriverAvailability::Silent,
+            (_, DriverMode::ONLINE) => DriverAvailability::OnlineIdle,
+        }
+    }
+
+    /// A driver can go offline, idle, silent or be blocked from anywhere. Only
+    /// an available driver joins a queue or gets a ride, and a ride starts
+    /// from its pickup.
+    pub fn can_transition_to(self, next: DriverAvailability) -> bool {
+        use DriverAvailability::*;
+        match next {
+            Offline | OnlineIdle | Silent | Blocked => self != next,
+            Queued => matches!(self, OnlineIdle | Silent),
+            OnPickup => matches!(self, OnlineIdle | Silent | Queued | OnRide),
+            OnRide => self == OnPickup,
+        }
+    }
+
+    /// Whether the driver counts as online for session accounting.
+    pub fn is_online(self) -> bool {
+        !matches!(
+            self,
+            DriverAvailability::Offline | DriverAvailability::Blocked
+        )
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DriverAvailability::Offline => "OFFLINE",
+            DriverAvailability::OnlineIdle => "ONLINE_IDLE",
+            DriverAvailability::Silent => "SILENT",
+            DriverAvailability::Queued => "QUEUED",
+            DriverAvailability::OnPickup => "ON_PICKUP",
+            DriverAvailability::OnRide => "ON_RIDE",
+            DriverAvailability::Blocked => "BLOCKED",
+        }
+    }
+}
+
+/// Where a transition comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransitionSource {
+    /// Ride lifecycle calls and blocks, applied even when they break the rules.
+    Lifecycle,
+    /// A ping, or the lack of one for the ping timeout.
+    Ping,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DriverAvailabilityRecord {
+    pub state: DriverAvailability,
+    pub since: TimeStamp,
+    pub merchant_id: MerchantId,
+    /// Last time the state was confirmed. Refreshed at most every
+    /// `refresh_secs` while it does not change.
+    pub last_seen: TimeStamp,
+}
+
+/// What a transition does to the stored record.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DriverAvailabilityChange {
+    /// Same state, confirmed recently enough: nothing is written.
+    Unchanged,
+    /// Same state: `last_seen` and the expiry are refreshed.
+    Refresh(DriverAvailabilityRecord),
+    /// A new state, `valid` if it follows the rules.
+    Transition {
+        record: DriverAvailabilityRecord,
+        valid: bool,
+    },
+    /// A ping-derived state that breaks the rules.
+    Rejected,
+}
+
+impl DriverAvailabilityChange {
+    /// Change from `current` to `next` as of `at`. A state held unchanged is
+    /// refreshed once `refresh_secs` have passed since it was last confirmed.
+    pub fn plan(
+        current: Option<&DriverAvailabilityRecord>,
+        merchant_id: &MerchantId,
+        next: DriverAvailability,
+        source: TransitionSource,
+        at: TimeStamp,
+        refresh_secs: i64,
+    ) -> Self {
+        match current {
+            Some(current) if current.state == next => {
+                if (at.inner() - current.last_seen.inner()).num_seconds() < refresh_secs {
+                    DriverAvailabilityChange::Unchanged
+                } else {
+                    DriverAvailabilityChange::Refresh(DriverAvailabilityRecord {
+                        state: next,
+                        since: current.since,
+                        merchant_id: merchant_id.to_owned(),
+                        last_seen: at,
+                    })
+                }
+            }
+            Some(current)
+                if source == TransitionSource::Ping && !current.state.can_transition_to(next) =>
+            {
+                DriverAvailabilityChange::Rejected
+            }
+            _ => DriverAvailabilityChange::Transition {
+                record: DriverAvailabilityRecord {
+                    state: next,
+                    since: at,
+                    merchant_id: merchant_id.to_owned(),
+                    last_seen: at,
+                },
+                valid: current.map_or(true, |current| current.state.can_transition_to(next)),
+            },
+        }
+    }
+}
+
+/// Published on every change of state. `from` is `None` for a driver with no
+/// stored state, i.e. seen for the first time or after the record expired.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DriverAvailabilityEvent {
+    pub driver_id: DriverId,
+    pub merchant_id: MerchantId,
+    pub from: Option<DriverAvailability>,
+    pub from_since: Option<TimeStamp>,
+    pub to: DriverAvailability,
+    pub at: TimeStamp,
+    pub time_in_previous_state_secs: Option<i64>,
+    pub valid: bool,
+}
+
+/// Moves the driver to `next` as of now, publishing the transition if the
+/// state changed. Best effort: failures are logged and never fail the caller.
+pub async fn transition_driver_availability(
+    data: &AppState,
+    merchant_id: &MerchantId,
+    driver_id: &DriverId,
+    next: DriverAvailability,
+    source: TransitionSource,
+) {
+    if data.driver_availability_topic.is_none() {
+        return;
+    }
+    let at = TimeStamp(Utc::now());
+    if let Err(err) = apply_driver_availability(data, driver_id, source, |_| {
+        Some((merchant_id.to_owned(), next, at))
+    })
+    .await
+    {
+        error!(tag = "[Driver Availability]", driver_id = %driver_id.0, error = %err.message());
+    }
+}
+
+/// An unchanged state is confirmed often enough for the ping timeout sweep to
+/// see live drivers, and for the record to outlive its expiry.
+fn refresh_secs(data: &AppState, state: DriverAvailability) -> i64 {
+    let refresh_secs = data.redis_expiry as i64 / 2;
+    if state.is_online() {
+        refresh_secs.min((data.driver_availability_ping_timeout_secs as i64 / 4).max(1))
+    } else {
+        refresh_secs
+    }
+}
+
+/// Applies the change `target` asks for given the stored record: the driver's
+/// merchant, the new state and when it was entered, or `None` to leave it. The
+/// record is compare-and-set against what was read, so a concurrent change is
+/// re-read and decided again rather than overwritten.
+async fn apply_driver_availability(
+    data: &AppState,
+    driver_id: &DriverId,
+    source: TransitionSource,
+    target: impl Fn(
+        Option<&DriverAvailabilityRecord>,
+    ) -> Option<(MerchantId, DriverAvailability, TimeStamp)>,
+) -> Result<(), AppError> {
+    for _ in 0..DRIVER_AVAILABILITY_WRITE_ATTEMPTS {
+        let current = redis_migration_read!(
+            data.redis_migration,
+            "driver_availability",
+            &data.redis,
+            |redis| get_driver_availability(&redis, driver_id)
+        )?;
+        let (current, stored) = match current {
+            Some((current, stored)) => (Some(current), Some(stored)),
+            None => (None, None),
+        };
+        let Some((merchant_id, next, at)) = target(current.as_ref()) else {
+            return Ok(());
+        };
+
+        let change = DriverAvailabilityChange::plan(
+            current.as_ref(),
+            &merchant_id,
+            next,
+            source,
+            at,
+            refresh_secs(data, next),
+        );
+        let record = match &change {
+            DriverAvailabilityChange::Unchanged => return Ok(()),
+            DriverAvailabilityChange::Rejected => {
+                let from = current.as_ref().map(|current| current.state);
+                DRIVER_AVAILABILITY_TRANSITIONS
+                    .with_label_values(&[
+                        from.map_or("NONE", DriverAvailability::as_str),
+                        next.as_str(),
+                        "rejected",
+                    ])
+                    .inc();
+                warn!(
+                    tag = "[Driver Availability]",
+                    driver_id = %driver_id.0,
+                    from = ?from,
+                    to = ?next,
+                    "Rejected availability transition"
+                );
+                return Ok(());
+            }
+            DriverAvailabilityChange::Refresh(record)
+            | DriverAvailabilityChange::Transition { record, .. } => record,
+        };
+
+        let serving = data.redis_migration.serving(&data.redis);
+        if !set_driver_availability_if_unchanged(
+            &serving,
+            &data.redis_expiry,
+            driver_id,
+            stored.as_deref(),
+            record,
+        )
+        .await?
+        {
+            continue;
+        }
+        if let Some(mirror) = data.redis_migration.mirror(&data.redis) {
+            let mirrored =
+                set_driver_availability(&mirror, &data.redis_expiry, driver_id, record).await;
+            record_mirror_write("driver_availability", mirrored.as_ref().err());
+        }
+        let last_seen = record.state.is_online().then_some(&record.last_seen);
+        redis_migration_write!(
+            data.redis_migration,
+            "driver_availability",
+            &data.redis,
+            |redis| set_driver_availability_last_seen(&redis, driver_id, last_seen)
+        )?;
+
+        if let DriverAvailabilityChange::Transition { record, valid } = &change {
+            publish_driver_availability(data, driver_id, current.as_ref(), record, *valid).await;
+        }
+        return Ok(());
+    }
+    Err(AppError::InternalError(format!(
+        "Availability of driver {} kept changing while being written",
+        driver_id.0
+    )))
+}
+
+async fn publish_driver_availability(
+    data: &AppState,
+    driver_id: &DriverId,
+    previous: Option<&DriverAvailabilityRecord>,
+    record: &DriverAvailabilityRecord,
+    valid: bool,
+) {
+    let Some(topic) = data.driver_availability_topic.as_ref() else {
+        return;
+    };
+    let from = previous.map(|previous| previous.state);
+    DRIVER_AVAILABILITY_TRANSITIONS
+        .with_label_values(&[
+            from.map_or("NONE", DriverAvailability::as_str),
+            record.state.as_str(),
+            if valid { "true" } else { "false" },
+        ])
+        .inc();
+    if !valid {
+        warn!(
+            tag = "[Driver Availability]",
+            driver_id = %driver_id.0,
+            from = ?from,
+            to = ?record.state,
+            "Unexpected availability transition"
+        );
+    }
+
+    let event = DriverAvailabilityEvent {
+        driver_id: driver_id.to_owned(),
+        merchant_id: record.merchant_id.to_owned(),
+        from,
+        from_since: previous.map(|previous| previous.since),
+        to: record.state,
+        at: record.since,
+        time_in_previous_state_secs: previous.map(|previous| {
+            (record.since.inner() - previous.since.inner())
+                .num_seconds()
+                .max(0)
+        }),
+        valid,
+    };
+    if let Err(err) = push_to_kafka(
+        &data.producer,
+        &data.secondary_producer,
+        &data.kafka_sink,
+        topic,
+        &driver_id.0,
+        event,
+    )
+    .await
+    {
+        error!(tag = "[Driver Availability Kafka]", driver_id = %driver_id.0, error = %err);
+    }
+}
+
+/// Moves every online driver not seen since before the ping timeout to
+/// `Offline`, as of when they were last seen, and returns how many were
+/// checked. A driver seen again meanwhile keeps their state.
+pub async fn run_driver_availability_sweep(data: &AppState) -> Result<usize, AppError> {
+    let cutoff = TimeStamp(
+        Utc::now() - chrono::Duration::seconds(data.driver_availability_ping_timeout_secs as i64),
+    );
+    let redis = data.redis_migration.serving(&data.redis);
+    let mut checked = 0;
+    loop {
+        let driver_ids =
+            get_drivers_last_seen_before(&redis, &cutoff, DRIVER_AVAILABILITY_SWEEP_BATCH).await?;
+        if driver_ids.is_empty() {
+            break;
+        }
+        // Each driver handled leaves the swept range: moved offline, dropped,
+        // or re-scored from a newer record. Failed ones are retried by the
+        // next batch only while others make progress.
+        let mut progressed = false;
+        for driver_id in driver_ids {
+            checked += 1;
+            match sweep_driver_availability(data, &driver_id, &cutoff).await {
+                Ok(()) => progressed = true,
+                Err(err) => {
+                    error!(tag = "[Driver Availability Sweep]", driver_id = %driver_id.0, error = %err.message())
+                }
+            }
+        }
+        if !progressed {
+            break;
+        }
+    }
+    Ok(checked)
+}
+
+/// Compared in whole seconds, as the last seen drivers are scored, so a driver
+/// found by the sweep is either timed out or re-scored out of its range.
+fn is_timed_out(record: &DriverAvailabilityRecord, cutoff: &TimeStamp) -> bool {
+    record.last_seen.inner().timestamp() <= cutoff.inner().timestamp()
+}
+
+async fn sweep_driver_availability(
+    data: &AppState,
+    driver_id: &DriverId,
+    cutoff: &TimeStamp,
+) -> Result<(), AppError> {
+    let current = redis_migration_read!(
+        data.redis_migration,
+        "driver_availability",
+        &data.redis,
+        |redis| get_driver_availability(&redis, driver_id)
+    )?
+    .map(|(current, _)| current);
+    match current {
+        Some(current) if current.state.is_online() && !is_timed_out(&current, cutoff) => {
+            redis_migration_write!(
+                data.redis_migration,
+                "driver_availability",
+                &data.redis,
+                |redis| set_driver_availability_last_seen(
+                    &redis,
+                    driver_id,
+                    Some(&current.last_seen)
+                )
+            )
+        }
+        Some(current) if current.state.is_online() => {
+            apply_driver_availability(data, driver_id, TransitionSource::Ping, |current| {
+                current
+                    .filter(|current| current.state.is_online() && is_timed_out(current, cutoff))
+                    .map(|current| {
+                        (
+                            current.merchant_id.to_owned(),
+                            DriverAvailability::Offline,
+                            current.last_seen,
+                        )
+                    })
+            })
+            .await
+        }
+        _ => redis_migration_write!(
+            data.redis_migration,
+            "driver_availability",
+            &data.redis,
+            |redis| set_driver_availability_last_seen(&redis, driver_id, None)
+        ),
+    }
+}
+
+/// Sweeps for ping timeouts four times per timeout. The lock is per window,
+/// so one pod sweeps each window; a sweep that overruns into the next one is
+/// harmless, as every change is compare-and-set.
+pub async fn start_driver_availability_sweep_task(data: Data<AppState>) {
+    if data.driver_availability_topic.is_none() {
+        return;
+    }
+    let interval_secs = (data.driver_availability_ping_timeout_secs / 4).max(1);
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+    loop {
+        interval.tick().await;
+        let window = Utc::now().timestamp() / interval_secs as i64;
+        match data
+            .redis
+            .setnx_with_expiry(
+                &driver_availability_sweep_lock_key(window),
+                true,
+                2 * interval_secs as i64,
+            )
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(err) => {
+                error!(
+                    tag = "[Driver Availability Sweep]",
+                    "Failed to take sweep lock: {:?}", err
+                );
+                continue;
+            }
+        }
+        match run_driver_availability_sweep(&data).await {
+            Ok(checked) => info!(
+                tag = "[Driver Availability Sweep]",
+                "Checked {} drivers", checked
+            ),
+            Err(err) => error!(tag = "[Driver Availability Sweep]", error = %err.message()),
+        }
+    }
+}
@@ -0,0 +1,503 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Daily online-hours accounting per driver.
+//!
+//! Each ping closes the interval since the driver's previous ping
+//! (`DriverSessionCursor`) and books it to the availability the previous ping
+//! reported: idle, silent, on pickup or on ride, which together make up the
+//! online time.
+//! Distance between accurate points is booked as driven while online. An
+//! interval is dropped when the previous ping was offline or the gap is longer
+//! than `ping_gap_threshold_secs`; the next online ping then starts a new
+//! session. Intervals crossing midnight of the accounting day are split.
+//!
+//! Days are summed in Redis and, once a day is over and the gap threshold has
+//! passed, one pod publishes every driver's `DriverDaySummary` to
+//! `day_close_topic`. Publication is at least once: drivers are marked
+//! published as their summaries are produced, and a day is only marked closed
+//! once none failed.
+//!
+//! The cursor is moved with a compare-and-set, so concurrent batches of the
+//! same driver never book an interval twice.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use actix_web::web::Data;
+use chrono::{DateTime, FixedOffset, NaiveDate, Offset, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use shared::redis::types::RedisConnectionPool;
+use tracing::{error, info};
+
+use crate::common::driver_availability::DriverAvailability;
+use crate::common::kafka::push_to_kafka;
+use crate::common::types::*;
+use crate::common::utils::distance_between_in_meters;
+use crate::environment::{AppState, DriverSessionConfig};
+use crate::redis::commands::*;
+use crate::redis::keys::driver_session_day_close_lock_key;
+use crate::redis::migration::record_mirror_write;
+use crate::tools::error::AppError;
+use crate::tools::prometheus::DRIVER_SESSION_DAY_SUMMARIES;
+
+/// A pod that dies while publishing a day leaves it to another after this long.
+/// Renewed for every page of drivers published.
+const DAY_CLOSE_LOCK_SECS: i64 = 3600;
+/// Attempts at moving a session cursor before giving up to a driver whose
+/// cursor keeps moving under us.
+const SESSION_CURSOR_ATTEMPTS: usize = 3;
+/// Drivers read per round trip at day close.
+const DAY_CLOSE_BATCH_SIZE: usize = 500;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DriverSessionCursor {
+    pub timestamp: TimeStamp,
+    /// Last point counted for distance.
+    pub location: Point,
+    pub availability: DriverAvailability,
+}
+
+/// What one day of pings adds up to. Durations are kept in milliseconds so
+/// frequent pings do not lose their fractions of a second.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DriverDayAccounting {
+    pub idle_ms: i64,
+    pub silent_ms: i64,
+    pub on_pickup_ms: i64,
+    pub on_ride_ms: i64,
+    pub distance_meters: f64,
+    pub sessions: i64,
+}
+
+impl DriverDayAccounting {
+    fn add_duration(&mut self, availability: DriverAvailability, ms: i64) {
+        match availability {
+            DriverAvailability::OnlineIdle | DriverAvailability::Queued => self.idle_ms += ms,
+            DriverAvailability::Silent => self.silent_ms += ms,
+            DriverAvailability::OnPickup => self.on_pickup_ms += ms,
+            DriverAvailability::OnRide => self.on_ride_ms += ms,
+            DriverAvailability::Offline | DriverAvailability::Blocked => {}
+        }
+    }
+
+    /// Reads back the hash written by `record_driver_session`; missing or
+    /// unreadable fields count as zero.
+    pub fn from_fields(fields: &HashMap<String, String>) -> Self {
+        let int = |field: &str| {
+            fields
+                .get(field)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0)
+        };
+        DriverDayAccounting {
+            idle_ms: int("idle_ms"),
+            silent_ms: int("silent_ms"),
+            on_pickup_ms: int("on_pickup_ms"),
+            on_ride_ms: int("on_ride_ms"),
+            distance_meters: fields
+                .get("distance_meters")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0.0),
+            sessions: int("sessions"),
+        }
+    }
+}
+
+/// Answer of the internal endpoint and payload published at day close.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DriverDaySummary {
+    pub driver_id: DriverId,
+    pub merchant_id: Option<MerchantId>,
+    pub day: NaiveDate,
+    pub online_secs: i64,
+    pub idle_secs: i64,
+    pub silent_secs: i64,
+    pub on_pickup_secs: i64,
+    pub on_ride_secs: i64,
+    pub distance_meters: f64,
+    pub sessions: i64,
+}
+
+impl DriverDaySummary {
+    pub fn new(
+        driver_id: DriverId,
+        merchant_id: Option<MerchantId>,
+        day: NaiveDate,
+        accounting: &DriverDayAccounting,
+    ) -> Self {
+        let online_ms = accounting.idle_ms
+            + accounting.silent_ms
+            + accounting.on_pickup_ms
+            + accounting.on_ride_ms;
+        DriverDaySummary {
+            driver_id,
+            merchant_id,
+            day,
+            online_secs: online_ms / 1000,
+            idle_secs: accounting.idle_ms / 1000,
+            silent_secs: accounting.silent_ms / 1000,
+            on_pickup_secs: accounting.on_pickup_ms / 1000,
+            on_ride_secs: accounting.on_ride_ms / 1000,
+            distance_meters: accounting.distance_meters,
+            sessions: accounting.sessions,
+        }
+    }
+}
+
+fn accounting_offset(config: &DriverSessionConfig) -> FixedOffset {
+    FixedOffset::east_opt(config.utc_offset_minutes * 60).unwrap_or_else(|| Utc.fix())
+}
+
+pub fn accounting_day(ts: DateTime<Utc>, offset: FixedOffset) -> NaiveDate {
+    ts.with_timezone(&offset).date_naive()
+}
+
+/// Start of the accounting day after `day`, in UTC.
+fn next_day_start(day: NaiveDate, offset: FixedOffset) -> Option<DateTime<Utc>> {
+    day.succ_opt()?
+        .and_hms_opt(0, 0, 0)?
+        .and_local_timezone(offset)
+        .single()
+        .map(|start| start.with_timezone(&Utc))
+}
+
+/// Folds a batch of pings, sorted by time, into the cursor and returns the
+/// per-day amounts to add. Each ping is `(point, timestamp, counts for
+/// distance)`; points filtered out as inaccurate only move the clock.
+pub fn account_driver_pings(
+    mut cursor: Option<DriverSessionCursor>,
+    pings: &[(Point, TimeStamp, bool)],
+    availability: DriverAvailability,
+    ping_gap_threshold_secs: i64,
+    offset: FixedOffset,
+) -> (
+    Option<DriverSessionCursor>,
+    BTreeMap<NaiveDate, DriverDayAccounting>,
+) {
+    let mut days: BTreeMap<NaiveDate, DriverDayAccounting> = BTreeMap::new();
+
+    for (point, TimeStamp(ts), counts_distance) in pings {
+        let location = match cursor.as_ref() {
+            Some(previous) if *ts <= previous.timestamp.inner() => continue,
+            Some(previous)
+                if previous.availability.is_online()
+                    && (*ts - previous.timestamp.inner()).num_seconds()
+                        <= ping_gap_threshold_secs =>
+            {
+                let mut start = previous.timestamp.inner();
+                while start < *ts {
+                    let day = accounting_day(start, offset);
+                    let end = next_day_start(day, offset)
+                        .filter(|day_end| day_end < ts)
+                        .unwrap_or(*ts);
+                    days.entry(day)
+                        .or_default()
+                        .add_duration(previous.availability, (end - start).num_milliseconds());
+                    start = end;
+                }
+                if *counts_distance {
+                    days.entry(accounting_day(*ts, offset))
+                        .or_default()
+                        .distance_meters += distance_between_in_meters(&previous.location, point);
+                    point.to_owned()
+                } else {
+                    previous.location.to_owned()
+                }
+            }
+            _ => {
+                if availability.is_online() {
+                    days.entry(accounting_day(*ts, offset))
+                        .or_default()
+                        .sessions += 1;
+                }
+                point.to_owned()
+            }
+        };
+        cursor = Some(DriverSessionCursor {
+            timestamp: TimeStamp(*ts),
+            location,
+            availability,
+        });
+    }
+
+    (cursor, days)
+}
+
+/// Books a batch of pings for the driver on `redis` and returns the cursor and
+/// days booked, `None` if the batch added nothing. The cursor is moved with a
+/// compare-and-set before the days are added, so of two batches racing from
+/// the same cursor the second is folded again from where the first left off
+/// and no interval is booked twice.
+pub async fn book_driver_session(
+    redis: &RedisConnectionPool,
+    config: &DriverSessionConfig,
+    merchant_id: &MerchantId,
+    driver_id: &DriverId,
+    availability: DriverAvailability,
+    pings: &[(Point, TimeStamp, bool)],
+) -> Result<Option<(DriverSessionCursor, Vec<(String, DriverDayAccounting)>)>, AppError> {
+    for _ in 0..SESSION_CURSOR_ATTEMPTS {
+        let (cursor, stored) = match get_driver_session_cursor(redis, driver_id).await? {
+            Some((cursor, stored)) => (Some(cursor), Some(stored)),
+            None => (None, None),
+        };
+        let (Some(next_cursor), days) = account_driver_pings(
+            cursor.to_owned(),
+            pings,
+            availability,
+            config.ping_gap_threshold_secs,
+            accounting_offset(config),
+        ) else {
+            return Ok(None);
+        };
+        if cursor.as_ref() == Some(&next_cursor) {
+            return Ok(None);
+        }
+        if !set_driver_session_cursor_if_unchanged(
+            redis,
+            config.ping_gap_threshold_secs,
+            driver_id,
+            stored.as_deref(),
+            &next_cursor,
+        )
+        .await?
+        {
+            continue;
+        }
+
+        let days: Vec<(String, DriverDayAccounting)> = days
+            .into_iter()
+            .map(|(day, accounting)| (day.to_string(), accounting))
+            .collect();
+        add_driver_session_days(
+            redis,
+            config.retention_days as i64 * 86400,
+            merchant_id,
+            driver_id,
+            &days,
+        )
+        .await?;
+        return Ok(Some((next_cursor, days)));
+    }
+    Err(AppError::InternalError(format!(
+        "Session cursor of driver {} kept moving while being booked",
+        driver_id.0
+    )))
+}
+
+/// Books a batch of pings for the driver on the serving pool and mirrors what
+/// was booked. Best effort: failures are logged and never fail the ping.
+pub async fn account_driver_session(
+    data: &AppState,
+    merchant_id: &MerchantId,
+    driver_id: &DriverId,
+    availability: DriverAvailability,
+    pings: &[(Point, TimeStamp, bool)],
+) {
+    let Some(config) = data.driver_session.as_ref() else {
+        return;
+    };
+
+    let booked = match book_driver_session(
+        &data.redis_migration.serving(&data.redis),
+        config,
+        merchant_id,
+        driver_id,
+        availability,
+        pings,
+    )
+    .await
+    {
+        Ok(Some(booked)) => booked,
+        Ok(None) => return,
+        Err(err) => {
+            error!(tag = "[Driver Session]", driver_id = %driver_id.0, error = %err);
+            return;
+        }
+    };
+
+    if let Some(mirror) = data.redis_migration.mirror(&data.redis) {
+        let (cursor, days) = booked;
+        let mirrored = record_driver_session(
+            &mirror,
+            config.ping_gap_threshold_secs,
+            config.retention_days as i64 * 86400,
+            merchant_id,
+            driver_id,
+            &cursor,
+            &days,
+        )
+        .await;
+        record_mirror_write("driver_session", mirrored.as_ref().err());
+    }
+}
+
+pub async fn get_driver_day_summary(
+    redis: &RedisConnectionPool,
+    driver_id: &DriverId,
+    day: NaiveDate,
+) -> Result<DriverDaySummary, AppError> {
+    let fields = get_driver_day_session(redis, &day.to_string(), driver_id).await?;
+    Ok(DriverDaySummary::new(
+        driver_id.to_owned(),
+        fields.get("merchant_id").cloned().map(MerchantId),
+        day,
+        &DriverDayAccounting::from_fields(&fields),
+    ))
+}
+
+/// Days that are over, including the gap threshold for their last intervals,
+/// and may still need publishing. Two days back covers a missed day close.
+pub fn closable_days(now: DateTime<Utc>, config: &DriverSessionConfig) -> Vec<NaiveDate> {
+    let offset = accounting_offset(config);
+    let grace = chrono::Duration::seconds(config.ping_gap_threshold_secs.max(0));
+    let today = accounting_day(now, offset);
+    [
+        today.pred_opt().and_then(|day| day.pred_opt()),
+        today.pred_opt(),
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|day| next_day_start(*day, offset).is_some_and(|end| end + grace <= now))
+    .collect()
+}
+
+/// Publishes the summaries of a closed day not published yet. Drivers are
+/// walked with SSCAN and marked published page by page, so a retry after a
+/// failure only publishes the rest. The lock is renewed per page and released
+/// when done.
+async fn close_driver_session_day(
+    data: &AppState,
+    redis: &RedisConnectionPool,
+    config: &DriverSessionConfig,
+    day: NaiveDate,
+) -> Result<(), AppError> {
+    let day_key = day.to_string();
+    if is_driver_session_day_closed(redis, &day_key).await? {
+        return Ok(());
+    }
+    let lock_key = driver_session_day_close_lock_key(&day_key);
+    let is_locked = redis
+        .setnx_with_expiry(&lock_key, true, DAY_CLOSE_LOCK_SECS)
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    if !is_locked {
+        return Ok(());
+    }
+
+    let published = publish_driver_session_day(data, redis, config, day, &lock_key).await;
+    if let Err(err) = redis.delete_key(&lock_key).await {
+        error!(tag = "[Driver Session Day Close]", day = %day_key, "Failed to release lock: {:?}", err);
+    }
+    let (published, failed) = published?;
+
+    // Left open, the day is retried on the next check and only the failed
+    // summaries are published again.
+    if failed > 0 {
+        return Err(AppError::InternalError(format!(
+            "{failed} driver day summaries for {day_key} were not published"
+        )));
+    }
+    set_driver_session_day_closed(redis, &day_key, config.retention_days as i64 * 86400).await?;
+    info!(
+        tag = "[Driver Session Day Close]",
+        "Published {} driver day summaries for {}", published, day_key
+    );
+    Ok(())
+}
+
+/// Returns how many summaries were published and how many failed.
+async fn publish_driver_session_day(
+    data: &AppState,
+    redis: &RedisConnectionPool,
+    config: &DriverSessionConfig,
+    day: NaiveDate,
+    lock_key: &str,
+) -> Result<(usize, usize), AppError> {
+    let day_key = day.to_string();
+    let retention_secs = config.retention_days as i64 * 86400;
+    let (mut published, mut failed) = (0, 0);
+    let mut pages = std::pin::pin!(scan_driver_session_day_drivers(redis, &day_key));
+    while let Some(driver_ids) = pages.next().await {
+        let driver_ids = driver_ids?;
+        redis
+            .set_expiry(lock_key, DAY_CLOSE_LOCK_SECS)
+            .await
+            .map_err(|err| AppError::InternalError(err.to_string()))?;
+
+        let already_published =
+            are_driver_session_days_published(redis, &day_key, &driver_ids).await?;
+        let driver_ids: Vec<DriverId> = driver_ids
+            .into_iter()
+            .zip(already_published)
+            .filter_map(|(driver_id, already_published)| (!already_published).then_some(driver_id))
+            .collect();
+        let mut published_driver_ids = Vec::new();
+        for batch in driver_ids.chunks(DAY_CLOSE_BATCH_SIZE) {
+            let sessions = get_driver_day_sessions(redis, &day_key, batch).await?;
+            for (driver_id, fields) in batch.iter().zip(sessions) {
+                let summary = DriverDaySummary::new(
+                    driver_id.to_owned(),
+                    fields.get("merchant_id").cloned().map(MerchantId),
+                    day,
+                    &DriverDayAccounting::from_fields(&fields),
+                );
+                let outcome = match push_to_kafka(
+                    &data.producer,
+                    &data.secondary_producer,
+                    &data.kafka_sink,
+                    &config.day_close_topic,
+                    &driver_id.0,
+                    summary,
+                )
+                .await
+                {
+                    Ok(()) => {
+                        published_driver_ids.push(driver_id.to_owned());
+                        "published"
+                    }
+                    Err(err) => {
+                        error!(tag = "[Driver Session Day Close]", driver_id = %driver_id.0, error = %err);
+                        failed += 1;
+                        "failed"
+                    }
+                };
+                DRIVER_SESSION_DAY_SUMMARIES
+                    .with_label_values(&[outcome])
+                    .inc();
+            }
+        }
+        set_driver_session_days_published(redis, &day_key, &published_driver_ids, retention_secs)
+            .await?;
+        published += published_driver_ids.len();
+    }
+    Ok((published, failed))
+}
+
+/// Publishes the summaries of every closed day, checked every
+/// `day_close_check_interval_secs`.
+pub async fn start_driver_session_day_close_task(data: Data<AppState>) {
+    let Some(config) = data.driver_session.to_owned() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        config.day_close_check_interval_secs.max(1),
+    ));
+    loop {
+        interval.tick().await;
+        let redis = data.redis_migration.serving(&data.redis);
+        for day in closable_days(Utc::now(), &config) {
+            if let Err(err) = close_driver_session_day(&data, &redis, &config, day).await {
+                error!(tag = "[Driver Session Day Close]", day = %day, error = %err);
+            }
+        }
+    }
+}
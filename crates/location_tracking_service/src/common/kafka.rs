@@ -5,7 +5,7 @@
     or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
     the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use crate::tools::error::AppError;
 use log::{error, info};
@@ -14,6 +14,20 @@ use rdkafka::{
     util::Timeout,
 };
 use serde::Serialize;
+use tokio::sync::Mutex;
+
+/// A message captured by a `KafkaSink` instead of being produced.
+#[derive(Serialize, Clone, Debug)]
+pub struct KafkaSinkRecord {
+    pub topic: String,
+    pub key: String,
+    pub payload: serde_json::Value,
+}
+
+/// In-memory stand-in for the Kafka producers, used by the replay tool. When
+/// set on `AppState`, `push_to_kafka` records every message here and skips
+/// both producers.
+pub type KafkaSink = Arc<Mutex<Vec<KafkaSinkRecord>>>;
 
 /// Checks if secondary Kafka producer is enabled via environment variable.
 /// Returns true if PRODUCE_SECONDARY_KAFKA is set to "true" (case-insensitive).
@@ -33,6 +47,7 @@ fn should_produce_secondary() -> bool {
 /// # Parameters
 /// - `producer`: An optional Kafka producer to send messages to Kafka.
 /// - `secondary_producer`: An optional secondary Kafka producer for dual-write scenarios.
+/// - `kafka_sink`: If set, the message is captured in memory instead of being produced.
 /// - `topic`: The Kafka topic to which the message will be published.
 /// - `key`: A string key associated with the message for Kafka.
 /// - `message`: The message to be serialized and sent to Kafka.
@@ -46,6 +61,7 @@ fn should_produce_secondary() -> bool {
 pub async fn push_to_kafka<T>(
     producer: &Option<FutureProducer>,
     secondary_producer: &Option<FutureProducer>,
+    kafka_sink: &Option<KafkaSink>,
     topic: &str,
     key: &str,
     message: T,
@@ -53,6 +69,17 @@ pub async fn push_to_kafka<T>(
 where
     T: Serialize,
 {
+    if let Some(kafka_sink) = kafka_sink {
+        let payload = serde_json::to_value(&message)
+            .map_err(|err| AppError::SerializationError(err.to_string()))?;
+        kafka_sink.lock().await.push(KafkaSinkRecord {
+            topic: topic.to_string(),
+            key: key.to_string(),
+            payload,
+        });
+        return Ok(());
+    }
+
     let message_str = serde_json::to_string(&message)
         .map_err(|err| AppError::SerializationError(err.to_string()))?;
 
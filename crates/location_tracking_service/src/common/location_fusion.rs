@@ -0,0 +1,93 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Per-vehicle arbitration between location sources.
+//!
+//! A bus can report through the driver app, the bus-crew app and an external
+//! GPS device, all of which end up in the driver location pipeline for the same
+//! bus ride. Each ping stores its source's latest sample; the source that wins
+//! `select_location_source` is the only one allowed to publish the route
+//! location, so `track_vehicles` does not jump between devices.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use shared::redis::types::RedisConnectionPool;
+use tracing::info;
+
+use crate::common::types::*;
+use crate::environment::VehicleLocationFusionConfig;
+use crate::redis::commands::{get_vehicle_source_samples, set_vehicle_source_sample};
+use crate::tools::error::AppError;
+
+/// Source samples are kept this many sample ages so a brief gap does not forget a source.
+const SAMPLE_RETENTION_FACTOR: i64 = 10;
+
+/// Winning source among the fresh samples: any sample within `max_accuracy` beats one
+/// outside it, then `source_priority` decides, then the newer sample. `None` when no
+/// sample is fresh.
+pub fn select_location_source(
+    samples: &HashMap<LocationSource, VehicleSourceSample>,
+    config: &VehicleLocationFusionConfig,
+    now: DateTime<Utc>,
+) -> Option<LocationSource> {
+    let priority = |source: &LocationSource| {
+        config
+            .source_priority
+            .iter()
+            .position(|preferred| preferred == source)
+            .unwrap_or(config.source_priority.len())
+    };
+    samples
+        .iter()
+        .filter(|(_, sample)| {
+            (now - sample.timestamp.0).num_seconds() <= config.max_sample_age_secs
+        })
+        .min_by_key(|(source, sample)| {
+            let is_inaccurate = sample
+                .accuracy
+                .is_some_and(|Accuracy(accuracy)| accuracy > config.max_accuracy);
+            (
+                is_inaccurate,
+                priority(source),
+                std::cmp::Reverse(sample.timestamp),
+            )
+        })
+        .map(|(source, _)| *source)
+}
+
+/// Records `sample` for `source` and returns whether that source should publish the
+/// vehicle's route location. When no source is fresh, the ping publishes as before.
+pub async fn fuse_vehicle_location(
+    redis: &RedisConnectionPool,
+    config: &VehicleLocationFusionConfig,
+    vehicle_number: &str,
+    source: LocationSource,
+    sample: VehicleSourceSample,
+) -> Result<bool, AppError> {
+    set_vehicle_source_sample(
+        redis,
+        vehicle_number,
+        source,
+        &sample,
+        config.max_sample_age_secs.max(1) * SAMPLE_RETENTION_FACTOR,
+    )
+    .await?;
+    let samples = get_vehicle_source_samples(redis, vehicle_number).await?;
+    let winner = select_location_source(&samples, config, Utc::now());
+    if let Some(winner) = winner.filter(|winner| *winner != source) {
+        info!(
+            tag = "[Location Fusion]",
+            "Vehicle {}: {} ping not published, {} is the selected source",
+            vehicle_number,
+            source,
+            winner
+        );
+    }
+    Ok(winner.is_none_or(|winner| winner == source))
+}
@@ -6,11 +6,19 @@
     the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
+pub mod broadcast_token;
+pub mod broadcast_trace;
+pub mod config_override;
 pub mod detection;
+pub mod drainer_wal;
+pub mod driver_availability;
+pub mod driver_session;
 pub mod flow;
 pub mod geo_polygon;
 pub mod heap_size;
 pub mod kafka;
+pub mod location_fusion;
+pub mod ride_proximity;
 pub mod route;
 pub mod sliding_window_rate_limiter;
 pub mod stop_detection;
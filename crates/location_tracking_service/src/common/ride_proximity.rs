@@ -0,0 +1,237 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Background rider/driver proximity check for in-progress rides.
+//!
+//! Rides are registered on ride start (`set_ride_proximity_tracking`) and every
+//! tick one pod compares the rider's last location (`person_detail_key`) with the
+//! driver's (`driver_details_key`). After `sample_size` consecutive samples further
+//! apart than `max_distance`, a `RideProximityEvent` is posted to the vehicle
+//! type's callback, once per separation. An event whose callback fails is
+//! raised again on the next sample.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use shared::redis::types::RedisConnectionPool;
+use tracing::{error, info};
+
+use crate::common::{types::*, utils::distance_between_in_meters};
+use crate::domain::types::ui::location::PersonType;
+use crate::environment::RideProximityCheckConfig;
+use crate::outbound::{
+    external::trigger_ride_proximity_event,
+    types::{RideProximityEvent, RideProximityEventReq},
+};
+use crate::redis::{
+    commands::*,
+    keys::{ride_proximity_check_lock_key, ride_proximity_check_running_key},
+};
+use crate::tools::{error::AppError, prometheus::RIDE_PROXIMITY_EVENTS};
+
+/// Upper bound on a single proximity check run, in case a pod dies before
+/// releasing `ride_proximity_check_running_key`.
+const RIDE_PROXIMITY_RUN_LOCK_SECS: i64 = 300;
+
+/// Folds one rider/driver distance sample into `tracking` and returns the event
+/// to raise, if any. The caller sets `alerted` once the event is delivered.
+/// Coming back within `max_distance` resets the count and re-arms the event.
+pub fn evaluate_ride_proximity(
+    tracking: &mut RideProximityTracking,
+    distance: f64,
+    config: &RideProximityCheckConfig,
+) -> Option<RideProximityEvent> {
+    if distance <= config.max_distance as f64 {
+        tracking.separated_samples = 0;
+        tracking.has_been_together = true;
+        tracking.alerted = false;
+        return None;
+    }
+
+    tracking.separated_samples += 1;
+    if tracking.alerted || tracking.separated_samples < config.sample_size {
+        return None;
+    }
+
+    Some(if tracking.has_been_together {
+        RideProximityEvent::RiderSeparatedFromVehicle
+    } else {
+        RideProximityEvent::RiderNotInVehicleAtStart
+    })
+}
+
+async fn check_ride_proximity(
+    redis: &RedisConnectionPool,
+    configs: &HashMap<VehicleType, RideProximityCheckConfig>,
+    ride_id: &RideId,
+    mut tracking: RideProximityTracking,
+) -> Result<(), AppError> {
+    // Covers rides whose end was never reported, or a ride end racing this check.
+    let is_ride_in_progress = get_ride_details(redis, &tracking.driver_id, &tracking.merchant_id)
+        .await?
+        .is_some_and(|ride_details| {
+            ride_details.ride_id == *ride_id && ride_details.ride_status == RideStatus::INPROGRESS
+        });
+    if !is_ride_in_progress {
+        return delete_ride_proximity_tracking(redis, ride_id).await;
+    }
+
+    let Some(driver_details) = get_driver_location(redis, &tracking.driver_id).await? else {
+        return Ok(());
+    };
+    let driver_location = driver_details.driver_last_known_location;
+    let Some(config) = driver_location
+        .vehicle_type
+        .as_ref()
+        .and_then(|vehicle_type| configs.get(vehicle_type))
+    else {
+        return Ok(());
+    };
+    let Some(rider_id) =
+        get_person_by_entity(redis, PersonType::Rider, "ride", ride_id.0.as_str()).await?
+    else {
+        return Ok(());
+    };
+    let rider_id = PersonId(rider_id);
+    let Some(rider_details) = get_person_detail(redis, PersonType::Rider, &rider_id).await? else {
+        return Ok(());
+    };
+    let rider_location = rider_details.last_known_location;
+
+    let now = Utc::now();
+    let is_stale =
+        |TimeStamp(ts): TimeStamp| (now - ts).num_seconds() > config.max_location_age_secs;
+    if is_stale(rider_location.timestamp)
+        || is_stale(driver_location.timestamp)
+        || tracking
+            .last_sample_ts
+            .is_some_and(|last_sample_ts| rider_location.timestamp <= last_sample_ts)
+    {
+        return Ok(());
+    }
+
+    let distance = distance_between_in_meters(&rider_location.location, &driver_location.location);
+    tracking.last_sample_ts = Some(rider_location.timestamp);
+    let event = evaluate_ride_proximity(&mut tracking, distance, config);
+
+    let delivery = match event {
+        Some(event) => {
+            info!(
+                tag = "[Ride Proximity]",
+                "{:?} for ride {:?}, rider and driver are {:.0}m apart", event, ride_id, distance
+            );
+            RIDE_PROXIMITY_EVENTS
+                .with_label_values(&[format!("{event:?}").as_str()])
+                .inc();
+            let delivery = trigger_ride_proximity_event(
+                &config.ride_proximity_callback_url,
+                RideProximityEventReq {
+                    ride_id: ride_id.to_owned(),
+                    driver_id: tracking.driver_id.to_owned(),
+                    rider_id,
+                    merchant_id: tracking.merchant_id.to_owned(),
+                    event,
+                    rider_location: rider_location.location,
+                    driver_location: driver_location.location,
+                    distance,
+                },
+            )
+            .await
+            .map_err(|err| AppError::AlertRequestFailed(err.message()));
+            // Left unset on failure so the next sample raises the event again.
+            tracking.alerted = delivery.is_ok();
+            delivery
+        }
+        None => Ok(()),
+    };
+
+    set_ride_proximity_tracking(redis, ride_id, &tracking).await?;
+    delivery
+}
+
+/// Runs one proximity check over every tracked ride and returns how many were
+/// checked. A failure on one ride is logged and does not stop the others.
+pub async fn run_ride_proximity_checks(
+    redis: &RedisConnectionPool,
+    configs: &HashMap<VehicleType, RideProximityCheckConfig>,
+) -> Result<usize, AppError> {
+    let trackings = get_all_ride_proximity_trackings(redis).await?;
+    let count = trackings.len();
+    for (ride_id, tracking) in trackings {
+        if let Err(err) = check_ride_proximity(redis, configs, &ride_id, tracking).await {
+            error!(
+                tag = "[Ride Proximity]",
+                "Proximity check failed for ride {:?}: {}",
+                ride_id,
+                err.message()
+            );
+        }
+    }
+    Ok(count)
+}
+
+async fn take_ride_proximity_lock(redis: &RedisConnectionPool, key: &str, expiry: i64) -> bool {
+    redis
+        .setnx_with_expiry(key, true, expiry)
+        .await
+        .unwrap_or_else(|err| {
+            error!(
+                tag = "[Ride Proximity]",
+                "Failed to take proximity check lock {}: {:?}", key, err
+            );
+            false
+        })
+}
+
+pub async fn start_ride_proximity_check_task(
+    redis: Arc<RedisConnectionPool>,
+    configs: HashMap<VehicleType, RideProximityCheckConfig>,
+    interval_secs: u64,
+) {
+    if configs.is_empty() {
+        return;
+    }
+    let interval_secs = interval_secs.max(1);
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+    loop {
+        interval.tick().await;
+        // Pod tickers are not aligned, so the lock is per window rather than
+        // per tick: exactly one pod runs each window, whenever its tick lands.
+        let window = Utc::now().timestamp() / interval_secs as i64;
+        if !take_ride_proximity_lock(
+            &redis,
+            &ride_proximity_check_lock_key(window),
+            2 * interval_secs as i64,
+        )
+        .await
+            || !take_ride_proximity_lock(
+                &redis,
+                &ride_proximity_check_running_key(),
+                RIDE_PROXIMITY_RUN_LOCK_SECS,
+            )
+            .await
+        {
+            continue;
+        }
+
+        match run_ride_proximity_checks(&redis, &configs).await {
+            Ok(count) => info!(tag = "[Ride Proximity]", "Checked {} rides", count),
+            Err(err) => error!(
+                tag = "[Ride Proximity]",
+                "Failed to run proximity checks: {}",
+                err.message()
+            ),
+        }
+        if let Err(err) = redis.delete_key(&ride_proximity_check_running_key()).await {
+            error!(
+                tag = "[Ride Proximity]",
+                "Failed to release proximity check lock: {:?}", err
+            );
+        }
+    }
+}
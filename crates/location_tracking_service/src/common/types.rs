@@ -6,6 +6,7 @@
     the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 use crate::common::utils::serialize_url;
+use crate::domain::types::ui::location::PersonType;
 use crate::environment::deserialize_url;
 use chrono::{DateTime, Utc};
 use fred::types::GeoValue;
@@ -116,6 +117,9 @@ pub struct Meters(pub u32);
 
 pub type DriversLocationMap = FxHashMap<String, Vec<GeoValue>>;
 
+/// Drainer buffer split by city, so each city's buckets go to its own cluster.
+pub type CityDriversLocationMap = FxHashMap<CityName, DriversLocationMap>;
+
 #[derive(
     Debug, Clone, EnumString, EnumIter, Display, Serialize, Deserialize, Eq, Hash, PartialEq, Copy,
 )]
@@ -300,6 +304,9 @@ pub struct RideDetails {
     pub ride_status: RideStatus,
     #[serde(alias = "rideInfo")]
     pub ride_info: Option<RideInfo>,
+    /// When the ride went INPROGRESS; late points from before it are not part of the trace.
+    #[serde(default, alias = "startedAt")]
+    pub started_at: Option<TimeStamp>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
@@ -307,6 +314,21 @@ pub struct DriverDetails {
     pub driver_id: DriverId, // TODO :: Make it string from json to save deserialization cost.
 }
 
+/// Per-ride state of the rider/driver proximity check, kept from ride start to ride end.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RideProximityTracking {
+    pub merchant_id: MerchantId,
+    pub driver_id: DriverId,
+    pub started_at: TimeStamp,
+    /// Rider timestamp of the last sample, so a rider who stops pinging is not re-counted.
+    pub last_sample_ts: Option<TimeStamp>,
+    pub separated_samples: u32,
+    /// Whether rider and driver have been seen together since the ride started.
+    pub has_been_together: bool,
+    /// Set once an event is raised for the current separation.
+    pub alerted: bool,
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct DriversRideStatus {
     pub driver_id: DriverId,
@@ -319,7 +341,7 @@ pub struct Point {
     pub lon: Longitude,
 }
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Dimensions {
     pub merchant_id: MerchantId,
     pub city: CityName,
@@ -355,6 +377,7 @@ pub struct RouteDeviation {
     pub locations: VecDeque<DriverLocation>,
 }
 
+/// Everything stored about a driver, assembled from the keys below on read.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DriverAllDetails {
     pub driver_last_known_location: DriverLastKnownLocation,
@@ -367,6 +390,78 @@ pub struct DriverAllDetails {
     pub detection_state: Option<ViolationDetectionStateMap>,
     pub anti_detection_state: Option<ViolationDetectionStateMap>,
     pub group_id: Option<String>,
+    /// Read from the combined record of a driver who has not pinged since the
+    /// split, so the next ping copies the ride notification and detection state
+    /// to their own keys even if they did not change.
+    #[serde(skip)]
+    pub from_combined_record: bool,
+}
+
+impl DriverAllDetails {
+    /// The ride notification progress and detection state a ping has to write,
+    /// given what was read before it: each only when the ping changed it, a missing
+    /// detection map reading as empty. Both are written for a driver without
+    /// details of their own yet.
+    pub fn changed_ping_state<'a>(
+        previous: Option<&Self>,
+        ride_notification: &'a DriverRideNotificationState,
+        detection: &'a DriverDetectionState,
+    ) -> (
+        Option<&'a DriverRideNotificationState>,
+        Option<&'a DriverDetectionState>,
+    ) {
+        let Some(previous) = previous.filter(|previous| !previous.from_combined_record) else {
+            return (Some(ride_notification), Some(detection));
+        };
+        let ride_notification_changed = previous.ride_notification_status
+            != ride_notification.ride_notification_status
+            || previous.driver_pickup_distance != ride_notification.driver_pickup_distance;
+        let detection_changed = !same_detection_map(
+            previous.violation_trigger_flag.as_ref(),
+            detection.violation_trigger_flag.as_ref(),
+        ) || !same_detection_map(
+            previous.detection_state.as_ref(),
+            detection.detection_state.as_ref(),
+        ) || !same_detection_map(
+            previous.anti_detection_state.as_ref(),
+            detection.anti_detection_state.as_ref(),
+        );
+        (
+            ride_notification_changed.then_some(ride_notification),
+            detection_changed.then_some(detection),
+        )
+    }
+}
+
+fn same_detection_map<V: PartialEq>(
+    previous: Option<&FxHashMap<DetectionType, V>>,
+    current: Option<&FxHashMap<DetectionType, V>>,
+) -> bool {
+    previous.filter(|map| !map.is_empty()) == current.filter(|map| !map.is_empty())
+}
+
+/// Driver details rewritten on every ping.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DriverLocationState {
+    pub driver_last_known_location: DriverLastKnownLocation,
+    pub stop_detection: Option<StopDetection>,
+    pub ride_status: Option<RideStatus>,
+    pub group_id: Option<String>,
+}
+
+/// Pickup notification progress, written when it changes.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DriverRideNotificationState {
+    pub ride_notification_status: Option<RideNotificationStatus>,
+    pub driver_pickup_distance: Option<Meters>,
+}
+
+/// Violation detection history of the current ride.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct DriverDetectionState {
+    pub violation_trigger_flag: Option<ViolationDetectionTriggerMap>,
+    pub detection_state: Option<ViolationDetectionStateMap>,
+    pub anti_detection_state: Option<ViolationDetectionStateMap>,
 }
 
 #[derive(
@@ -425,6 +520,40 @@ pub struct VehicleTrackingInfo {
     pub timestamp: Option<TimeStamp>,
     pub ride_status: Option<RideStatus>,
     pub upcoming_stops: Option<Vec<UpcomingStop>>,
+    /// Source whose ping produced this location.
+    pub location_source: Option<LocationSource>,
+}
+
+/// Where a vehicle location ping came from.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Display, EnumString,
+)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
+pub enum LocationSource {
+    #[default]
+    DriverApp,
+    /// Conductor / bus-driver app pings (`handle_driver_conductor_location_update`).
+    BusCrewApp,
+    ExternalGps,
+}
+
+impl LocationSource {
+    /// Whether pings from this source are the driver's own and may update the
+    /// driver's rate limit, last known location, ping state and nearby-driver
+    /// bucket. Bus crew pings only feed the vehicle's route tracking and detection.
+    pub fn updates_driver_state(&self) -> bool {
+        !matches!(self, LocationSource::BusCrewApp)
+    }
+}
+
+/// Latest ping from one source for a vehicle, as seen by the location fusion.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VehicleSourceSample {
+    pub location: Point,
+    pub timestamp: TimeStamp,
+    pub accuracy: Option<Accuracy>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -574,9 +703,11 @@ pub enum DetectionType {
     TripNotStarted,
     SafetyCheck,
     RideStopReached,
+    SuddenJump,
+    DriverDivergence,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum ViolationDetectionState {
     StopDetection(StopDetectionState),
     RouteDeviation(RouteDeviationState),
@@ -585,48 +716,50 @@ pub enum ViolationDetectionState {
     TripNotStarted(TripNotStartedState),
     SafetyCheck(SafetyCheckState),
     RideStopReached(RideStopReachedState),
+    SuddenJump(SuddenJumpState),
+    DriverDivergence(DriverDivergenceState),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct StopDetectionState {
     pub avg_speed: Option<VecDeque<(f64, u32)>>,
     pub avg_coord_mean: VecDeque<(Point, u32)>,
     pub total_datapoints: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct RouteDeviationState {
     pub deviation_distance: f64,
     pub total_datapoints: u64,
     pub avg_deviation_record: VecDeque<(Point, u32)>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct OverspeedingState {
     pub total_datapoints: u64,
     pub avg_speed_record: VecDeque<(f64, u32)>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct OppositeDirectionState {
     pub total_datapoints: u64,
     pub expected_count: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct TripNotStartedState {
     pub total_datapoints: u64,
     pub avg_coord_mean: VecDeque<(Point, u32)>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct SafetyCheckState {
     pub avg_speed: Option<VecDeque<(f64, u32)>>,
     pub avg_coord_mean: VecDeque<(Point, u32)>,
     pub total_datapoints: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct RideStopReachedState {
     pub total_datapoints: u64,
     pub reached_stops: Vec<String>,
@@ -634,6 +767,19 @@ pub struct RideStopReachedState {
     pub avg_coord_mean: VecDeque<(Point, u32)>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SuddenJumpState {
+    pub last_location: Point,
+    pub last_timestamp: TimeStamp,
+    pub jump_distance: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DriverDivergenceState {
+    pub total_datapoints: u64,
+    pub distance: f64,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct StoppedDetectionConfig {
     pub batch_count: u32,
@@ -683,6 +829,8 @@ pub enum DetectionConfig {
     TripNotStartedDetection(TripNotStartedConfig),
     SafetyCheckDetection(SafetyCheckConfig),
     RideStopReachedDetection(RideStopReachedConfig),
+    SuddenJumpDetection(SuddenJumpConfig),
+    DriverDivergenceDetection(DriverDivergenceConfig),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -701,22 +849,39 @@ pub struct RideStopReachedConfig {
     pub min_stop_duration: u32,
 }
 
+/// A move of more than `min_jump_distance` meters between two consecutive
+/// points at an implied speed above `max_speed` (m/s).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SuddenJumpConfig {
+    pub max_speed: f64,
+    pub min_jump_distance: u32,
+}
+
+/// `sample_size` consecutive points further than `max_distance` meters from
+/// `DetectionContext::counterpart_location`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DriverDivergenceConfig {
+    pub max_distance: u32,
+    pub sample_size: u32,
+}
+
 #[derive(Debug, Clone)]
 pub struct DetectionContext<'a> {
-    pub driver_id: DriverId,
-    pub ride_id: RideId,
     pub location: Point,
     pub timestamp: TimeStamp,
     pub speed: Option<SpeedInMeterPerSecond>,
     pub ride_status: RideStatus,
     pub ride_info: Option<RideInfo>,
-    pub vehicle_type: VehicleType,
+    pub vehicle_type: Option<VehicleType>,
     pub accuracy: Accuracy,
     pub route: Option<&'a Route>,
     pub ride_stops: Option<&'a Vec<Point>>,
+    /// Latest location of the other party on the ride, e.g. the driver when
+    /// running rider safety checks.
+    pub counterpart_location: Option<Point>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum DetectionStatus {
     ContinuedViolation,
     ContinuedAntiViolation,
@@ -792,6 +957,12 @@ pub struct PersonLastKnownLocation {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PersonAllDetails {
     pub last_known_location: PersonLastKnownLocation,
+    #[serde(default)]
+    pub violation_trigger_flag: Option<ViolationDetectionTriggerMap>,
+    #[serde(default)]
+    pub detection_state: Option<ViolationDetectionStateMap>,
+    #[serde(default)]
+    pub anti_detection_state: Option<ViolationDetectionStateMap>,
 }
 
 /// Provider-specific config for an ERSS trace call.
@@ -804,11 +975,144 @@ pub struct ErssProviderConfig {
     pub trace_url_suffix: String,
 }
 
+/// Provider-specific config for a generic webhook trace call.
+///
+/// `url_template`, header values and every string inside `body_template` may use the
+/// placeholders `{{lat}}`, `{{lon}}`, `{{datetime}}`, `{{timestamp}}`, `{{signal}}`,
+/// `{{accessToken}}` and `{{externalReferenceId}}`. `{{datetime}}` and `{{timestamp}}` are
+/// the time of the location fix. A relative `url_template` is joined onto `base_url`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookProviderConfig {
+    pub url_template: String,
+    /// HTTP method, `POST` when unset.
+    pub method: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// JSON body; no body is sent when unset.
+    pub body_template: Option<serde_json::Value>,
+    #[serde(default)]
+    pub auth: WebhookAuth,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(tag = "scheme", rename_all = "camelCase")]
+pub enum WebhookAuth {
+    #[default]
+    None,
+    /// `Authorization: Bearer <access_token>`, refreshed through the usual reauth flow.
+    Bearer,
+    Basic {
+        username: String,
+        password: String,
+    },
+    /// Hex HMAC-SHA256 of the rendered body, sent in `header` (optionally prefixed, e.g. `sha256=`).
+    #[serde(rename_all = "camelCase")]
+    HmacSha256 {
+        secret: String,
+        header: String,
+        prefix: Option<String>,
+    },
+}
+
+/// Provider-specific config for publishing trace pings to an MQTT broker.
+/// `topic_template` and the strings in `payload_template` take the same placeholders as
+/// `WebhookProviderConfig`. When `username` is set without a `password`, the broadcaster
+/// access token is used as the password.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MqttProviderConfig {
+    pub broker_host: String,
+    pub broker_port: u16,
+    #[serde(default)]
+    pub use_tls: bool,
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub topic_template: String,
+    pub payload_template: serde_json::Value,
+    /// MQTT QoS level (0 or 1).
+    #[serde(default)]
+    pub qos: u8,
+}
+
 /// Add a new variant here (plus an ExternalLocationProvider impl and match arm in `make_provider`) to support a new trace provider.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "providerKind", rename_all = "camelCase")]
 pub enum TraceProvider {
     Erss(ErssProviderConfig),
+    Webhook(WebhookProviderConfig),
+    Mqtt(MqttProviderConfig),
+}
+
+/// External provider token shared by every entity whose broadcaster config has the
+/// same reauth scope (see `common::broadcast_token::token_scope`).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BroadcasterToken {
+    pub access_token: String,
+    pub token_expires_at: i64,
+}
+
+/// Consecutive reauth failures for a token scope; refreshes are skipped until `retry_after`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BroadcasterTokenBackoff {
+    pub failures: u32,
+    pub retry_after: i64,
+}
+
+/// Freshness of the location sent in a broadcast trace ping.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Display)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
+pub enum TraceSignal {
+    #[default]
+    Live,
+    /// The last known location is older than the scheduler's `stale_after_secs`.
+    Stale,
+    /// No location for `signal_lost_after_secs`; the last known one is resent.
+    SignalLost,
+}
+
+/// An active broadcaster the trace scheduler polls for, registered on `EntityStart`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BroadcastTraceTarget {
+    pub merchant_id: MerchantId,
+    pub person_type: PersonType,
+    pub person_id: PersonId,
+    pub entity_type: String,
+    pub entity_id: EntityId,
+    pub broadcaster_id: String,
+}
+
+/// What happened after a `send_ping` attempt, as far as retrying goes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum TraceRetryOutcome {
+    /// Delivered, failed without a retry path, or was itself the retry.
+    NotRetried,
+    /// Rejected with 401; retried once with a refreshed token.
+    RetriedAfterTokenRefresh,
+    /// Rejected with 401 and the token could not be refreshed.
+    TokenRefreshFailed,
+}
+
+/// One `send_ping` attempt in an entity's broadcast trace audit log.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BroadcastTraceAttempt {
+    pub ts: TimeStamp,
+    pub broadcaster_id: String,
+    pub provider: String,
+    /// 1 for the first ping, 2 for the retry after a token refresh.
+    pub attempt: u8,
+    #[serde(default)]
+    pub signal: TraceSignal,
+    pub delivered: bool,
+    pub http_status: Option<u16>,
+    pub latency_ms: u64,
+    pub retry_outcome: TraceRetryOutcome,
+    pub error: Option<String>,
 }
 
 /// Generic broadcast trace config stored in Redis within entity details.
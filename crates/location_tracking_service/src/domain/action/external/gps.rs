@@ -7,16 +7,19 @@
 */
 
 use crate::common::types::*;
+use crate::domain::action::external::gps_vendor::{
+    dt_server_timestamp_format, make_gps_vendor_adapter, DtServerGpsAdapter,
+};
 use crate::domain::action::ui::location::update_driver_location;
 use crate::domain::types::ui::location::UpdateDriverLocationRequest;
 use crate::environment::AppState;
+use crate::redis::commands::get_external_gps_imei_vehicles;
 use crate::redis::keys::driver_info_by_plate_key;
 use crate::tools::error::AppError;
 use crate::tools::prometheus::GPS_UPDATES_IGNORED_NO_ACTIVE_RIDE;
 use actix_web::{web::Data, HttpResponse};
-use chrono::{DateTime, NaiveDateTime, Utc};
 use futures::future::try_join_all;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use serde::{Deserialize, Serialize};
 use tracing::{info, warn};
 
@@ -52,13 +55,101 @@ pub async fn handle_external_gps_location(
         gps_batch.len()
     );
 
+    let pings = DtServerGpsAdapter {
+        timestamp_format: dt_server_timestamp_format(),
+    }
+    .convert(gps_batch)?;
+
     // Group by plate_number for efficient processing
-    let mut plate_groups: FxHashMap<String, Vec<ExternalGPSLocationReq>> = FxHashMap::default();
-    for gps_data in gps_batch {
+    let mut plate_groups: FxHashMap<String, Vec<UpdateDriverLocationRequest>> =
+        FxHashMap::default();
+    for ping in pings {
         plate_groups
-            .entry(gps_data.plate_number.clone())
+            .entry(ping.plate_number.unwrap_or_default())
             .or_default()
-            .push(gps_data);
+            .push(ping.location);
+    }
+
+    process_plate_groups(plate_groups, app_state).await
+}
+
+/// `/external/gps/{vendor}`: the vendor's own API key and payload schema, with
+/// vehicles resolved through its IMEI mapping before the payload's plate number.
+pub async fn handle_external_gps_vendor_location(
+    vendor: String,
+    api_key: Option<String>,
+    payload: serde_json::Value,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let vendor_config = app_state
+        .external_gps_vendors
+        .get(&vendor)
+        .ok_or_else(|| AppError::UnknownGpsVendor(vendor.to_owned()))?;
+
+    let provided_api_key = api_key.ok_or(AppError::MissingApiKey)?;
+    if provided_api_key != vendor_config.api_key {
+        return Err(AppError::InvalidApiKey);
+    }
+
+    let pings = make_gps_vendor_adapter(vendor_config).parse_batch(payload)?;
+
+    info!(
+        tag = "[GPS Batch Request]",
+        vendor = %vendor,
+        "Received {} GPS location updates",
+        pings.len()
+    );
+
+    let imeis: Vec<String> = pings
+        .iter()
+        .filter_map(|ping| ping.imei.to_owned())
+        .collect::<FxHashSet<_>>()
+        .into_iter()
+        .collect();
+    let imei_vehicles: FxHashMap<String, String> = imeis
+        .iter()
+        .cloned()
+        .zip(get_external_gps_imei_vehicles(&app_state.redis, &vendor, imeis.to_owned()).await?)
+        .filter_map(|(imei, vehicle_number)| vehicle_number.map(|vehicle| (imei, vehicle)))
+        .collect();
+
+    let mut plate_groups: FxHashMap<String, Vec<UpdateDriverLocationRequest>> =
+        FxHashMap::default();
+    let mut unresolved_imeis = Vec::new();
+    for ping in pings {
+        let vehicle_number = ping
+            .imei
+            .as_ref()
+            .and_then(|imei| imei_vehicles.get(imei).cloned())
+            .or(ping.plate_number);
+        match vehicle_number {
+            Some(vehicle_number) => plate_groups
+                .entry(vehicle_number)
+                .or_default()
+                .push(ping.location),
+            None => unresolved_imeis.push(ping.imei.unwrap_or_default()),
+        }
+    }
+
+    if !unresolved_imeis.is_empty() {
+        warn!(
+            tag = "[GPS Ignored - Unknown Vehicle]",
+            vendor = %vendor,
+            "Skipping {} GPS updates with no IMEI mapping or plate number: {:?}",
+            unresolved_imeis.len(),
+            unresolved_imeis
+        );
+    }
+
+    process_plate_groups(plate_groups, app_state).await
+}
+
+async fn process_plate_groups(
+    mut plate_groups: FxHashMap<String, Vec<UpdateDriverLocationRequest>>,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    if plate_groups.is_empty() {
+        return Ok(HttpResponse::Ok().body("SUCCESS"));
     }
 
     // Step 1: Get all unique plate numbers
@@ -131,7 +222,7 @@ pub async fn handle_external_gps_location(
 
 async fn process_vehicle_locations(
     driver_info: DriverByPlateResp,
-    locations: Vec<ExternalGPSLocationReq>,
+    location_updates: Vec<UpdateDriverLocationRequest>,
     app_state: Data<AppState>,
 ) -> Result<(), AppError> {
     let plate_number = driver_info.bus_number.clone().unwrap_or_default();
@@ -139,19 +230,16 @@ async fn process_vehicle_locations(
     info!(
         tag = "[Processing Vehicle]",
         "Processing {} GPS locations for vehicle {}",
-        locations.len(),
+        location_updates.len(),
         plate_number
     );
 
-    if locations.is_empty() {
+    if location_updates.is_empty() {
         warn!("No GPS location updates for vehicle {}", plate_number);
         return Ok(());
     }
 
-    // Step 1: Convert GPS data to LocationTrackingService format
-    let location_updates = convert_gps_to_location_updates(locations)?;
-
-    // Step 2: Call simplified location update pipeline (no token needed)
+    // Call simplified location update pipeline (no token needed)
     update_driver_location(
         DriverId(driver_info.driver_id),
         MerchantId(driver_info.merchant_id),
@@ -160,6 +248,7 @@ async fn process_vehicle_locations(
         location_updates,
         DriverMode::ONLINE,
         driver_info.group_id,
+        LocationSource::ExternalGps,
     )
     .await?;
 
@@ -170,50 +259,3 @@ async fn process_vehicle_locations(
 
     Ok(())
 }
-
-fn convert_gps_to_location_updates(
-    gps_locations: Vec<ExternalGPSLocationReq>,
-) -> Result<Vec<UpdateDriverLocationRequest>, AppError> {
-    let mut location_updates = Vec::new();
-
-    for gps in gps_locations {
-        // Validate coordinate ranges
-        if !(-90.0..=90.0).contains(&gps.lat) || !(-180.0..=180.0).contains(&gps.lng) {
-            return Err(AppError::InvalidGPSData(format!(
-                "Coordinates out of range: lat={}, lng={}",
-                gps.lat, gps.lng
-            )));
-        }
-
-        // Parse timestamp
-        let timestamp = parse_gps_timestamp(&gps.dt_server)?;
-
-        // Convert speed from km/h to m/s
-        let speed = gps.speed.map(|s| SpeedInMeterPerSecond(s as f64 / 3.6));
-
-        // Bearing is already parsed as f64
-        let bearing = gps.angle.map(Direction);
-
-        location_updates.push(UpdateDriverLocationRequest {
-            pt: Point {
-                lat: Latitude(gps.lat),
-                lon: Longitude(gps.lng),
-            },
-            ts: TimeStamp(timestamp),
-            v: speed,
-            acc: None,
-            bear: bearing,
-        });
-    }
-
-    Ok(location_updates)
-}
-
-fn parse_gps_timestamp(dt_server: &str) -> Result<DateTime<Utc>, AppError> {
-    // Parse "2025-09-16 12:51:23" format
-    let naive_dt = NaiveDateTime::parse_from_str(dt_server, "%Y-%m-%d %H:%M:%S").map_err(|_| {
-        AppError::InvalidGPSData(format!("Invalid timestamp format: {}", dt_server))
-    })?;
-
-    Ok(naive_dt.and_utc())
-}
@@ -0,0 +1,215 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::common::types::*;
+use crate::domain::action::external::gps::ExternalGPSLocationReq;
+use crate::domain::types::ui::location::UpdateDriverLocationRequest;
+use crate::environment::{ExternalGpsVendorConfig, GpsTimestampFormat, GpsVendorSchema};
+use crate::tools::error::AppError;
+
+/// Turns one vendor's push payload into location updates.
+///
+/// Adapters only know the payload layout; API keys and vehicle resolution are
+/// handled by the caller, so every vendor shares the same pipeline.
+///
+/// To add a new vendor schema:
+///   1. Add a variant to `GpsVendorSchema` in environment.rs.
+///   2. Implement this trait for a new `XyzGpsAdapter` holding the vendor's timestamp format.
+///   3. Add one match arm in `make_gps_vendor_adapter`.
+pub trait GpsVendorAdapter: Send + Sync {
+    fn parse_batch(&self, payload: serde_json::Value) -> Result<Vec<VendorGpsPing>, AppError>;
+}
+
+/// One fix from a vendor, with whatever identifies the vehicle in its payload.
+#[derive(Debug, Clone)]
+pub struct VendorGpsPing {
+    pub imei: Option<String>,
+    pub plate_number: Option<String>,
+    pub location: UpdateDriverLocationRequest,
+}
+
+pub fn make_gps_vendor_adapter(config: &ExternalGpsVendorConfig) -> Box<dyn GpsVendorAdapter> {
+    match config.schema {
+        GpsVendorSchema::DtServer => Box::new(DtServerGpsAdapter {
+            timestamp_format: config.timestamp_format.clone(),
+        }),
+        GpsVendorSchema::Generic => Box::new(GenericGpsAdapter {
+            timestamp_format: config.timestamp_format.clone(),
+        }),
+    }
+}
+
+/// Format of `dt_server` on the original `/external/gps/location` integration.
+pub fn dt_server_timestamp_format() -> GpsTimestampFormat {
+    GpsTimestampFormat::Local {
+        format: "%Y-%m-%d %H:%M:%S".to_string(),
+        utc_offset_minutes: 0,
+    }
+}
+
+pub fn parse_vendor_timestamp(
+    raw: &str,
+    timestamp_format: &GpsTimestampFormat,
+) -> Result<DateTime<Utc>, AppError> {
+    let invalid = || AppError::InvalidGPSData(format!("Invalid timestamp format: {}", raw));
+    match timestamp_format {
+        GpsTimestampFormat::Local {
+            format,
+            utc_offset_minutes,
+        } => {
+            let offset = FixedOffset::east_opt(utc_offset_minutes * 60).ok_or_else(invalid)?;
+            NaiveDateTime::parse_from_str(raw, format)
+                .map_err(|_| invalid())?
+                .and_local_timezone(offset)
+                .single()
+                .map(|datetime| datetime.with_timezone(&Utc))
+                .ok_or_else(invalid)
+        }
+        GpsTimestampFormat::EpochSeconds => raw
+            .trim()
+            .parse::<i64>()
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+            .ok_or_else(invalid),
+        GpsTimestampFormat::EpochMillis => raw
+            .trim()
+            .parse::<i64>()
+            .ok()
+            .and_then(DateTime::from_timestamp_millis)
+            .ok_or_else(invalid),
+        GpsTimestampFormat::Rfc3339 => DateTime::parse_from_rfc3339(raw)
+            .map(|datetime| datetime.with_timezone(&Utc))
+            .map_err(|_| invalid()),
+    }
+}
+
+fn to_location_update(
+    lat: f64,
+    lon: f64,
+    timestamp: DateTime<Utc>,
+    speed_kmph: Option<f64>,
+    bearing: Option<f64>,
+    accuracy: Option<f64>,
+) -> Result<UpdateDriverLocationRequest, AppError> {
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        return Err(AppError::InvalidGPSData(format!(
+            "Coordinates out of range: lat={}, lng={}",
+            lat, lon
+        )));
+    }
+
+    Ok(UpdateDriverLocationRequest {
+        pt: Point {
+            lat: Latitude(lat),
+            lon: Longitude(lon),
+        },
+        ts: TimeStamp(timestamp),
+        // Vendors report km/h
+        v: speed_kmph.map(|speed| SpeedInMeterPerSecond(speed / 3.6)),
+        acc: accuracy.map(Accuracy),
+        bear: bearing.map(Direction),
+    })
+}
+
+fn parse_payload<T: for<'de> Deserialize<'de>>(
+    payload: serde_json::Value,
+) -> Result<Vec<T>, AppError> {
+    serde_json::from_value(payload).map_err(|err| AppError::InvalidGPSData(err.to_string()))
+}
+
+pub struct DtServerGpsAdapter {
+    pub timestamp_format: GpsTimestampFormat,
+}
+
+impl DtServerGpsAdapter {
+    pub fn convert(
+        &self,
+        gps_batch: Vec<ExternalGPSLocationReq>,
+    ) -> Result<Vec<VendorGpsPing>, AppError> {
+        gps_batch
+            .into_iter()
+            .map(|gps| {
+                Ok(VendorGpsPing {
+                    location: to_location_update(
+                        gps.lat,
+                        gps.lng,
+                        parse_vendor_timestamp(&gps.dt_server, &self.timestamp_format)?,
+                        gps.speed.map(f64::from),
+                        gps.angle,
+                        None,
+                    )?,
+                    imei: Some(gps.imei),
+                    plate_number: Some(gps.plate_number),
+                })
+            })
+            .collect()
+    }
+}
+
+impl GpsVendorAdapter for DtServerGpsAdapter {
+    fn parse_batch(&self, payload: serde_json::Value) -> Result<Vec<VendorGpsPing>, AppError> {
+        self.convert(parse_payload(payload)?)
+    }
+}
+
+/// Fix timestamps arrive either as strings or as bare epoch numbers.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum VendorTimestamp {
+    Number(i64),
+    Text(String),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GenericGpsLocationReq {
+    pub imei: String,
+    pub plate_number: Option<String>,
+    pub timestamp: VendorTimestamp,
+    pub lat: f64,
+    pub lon: f64,
+    pub speed_kmph: Option<f64>,
+    pub heading: Option<f64>,
+    pub accuracy: Option<f64>,
+}
+
+pub struct GenericGpsAdapter {
+    pub timestamp_format: GpsTimestampFormat,
+}
+
+impl GpsVendorAdapter for GenericGpsAdapter {
+    fn parse_batch(&self, payload: serde_json::Value) -> Result<Vec<VendorGpsPing>, AppError> {
+        parse_payload::<GenericGpsLocationReq>(payload)?
+            .into_iter()
+            .map(|gps| {
+                let timestamp = match &gps.timestamp {
+                    VendorTimestamp::Number(number) => {
+                        parse_vendor_timestamp(&number.to_string(), &self.timestamp_format)?
+                    }
+                    VendorTimestamp::Text(text) => {
+                        parse_vendor_timestamp(text, &self.timestamp_format)?
+                    }
+                };
+                Ok(VendorGpsPing {
+                    location: to_location_update(
+                        gps.lat,
+                        gps.lon,
+                        timestamp,
+                        gps.speed_kmph,
+                        gps.heading,
+                        gps.accuracy,
+                    )?,
+                    imei: Some(gps.imei),
+                    plate_number: gps.plate_number,
+                })
+            })
+            .collect()
+    }
+}
@@ -7,3 +7,4 @@
 */
 
 pub mod gps;
+pub mod gps_vendor;
@@ -0,0 +1,333 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::Arc;
+
+use actix_web::web::Data;
+use chrono::Utc;
+use subtle::ConstantTimeEq;
+use tracing::info;
+
+use crate::common::{config_override::*, types::*};
+use crate::domain::types::internal::admin::*;
+use crate::environment::AppState;
+use crate::redis::{
+    commands::*,
+    keys::config_override_lock_key,
+    migration::{backfill_redis_migration, RedisMigrationPhase},
+};
+use crate::tools::error::AppError;
+
+enum ConfigOverrideChange {
+    Set(ConfigOverride),
+    Rollback(Option<u64>),
+}
+
+/// Admin endpoints are disabled unless `admin_api_key` is configured.
+pub fn validate_admin_api_key(
+    data: &Data<AppState>,
+    api_key: Option<String>,
+) -> Result<(), AppError> {
+    let provided_api_key = api_key.ok_or(AppError::MissingApiKey)?;
+    match data.admin_api_key.as_ref() {
+        // Constant-time, so response timing does not leak how much of the key matched.
+        Some(admin_api_key)
+            if bool::from(admin_api_key.as_bytes().ct_eq(provided_api_key.as_bytes())) =>
+        {
+            Ok(())
+        }
+        _ => Err(AppError::InvalidApiKey),
+    }
+}
+
+fn base_config(data: &AppState) -> OverridableConfig {
+    OverridableConfig {
+        pickup_notification_threshold: data.pickup_notification_threshold,
+        arriving_notification_threshold: data.arriving_notification_threshold,
+        pickup_instruction_notification_threshold: data.pickup_instruction_notification_threshold,
+        driver_location_accuracy_buffer: data.driver_location_accuracy_buffer,
+        driver_reached_destination_buffer: data.driver_reached_destination_buffer,
+        driver_source_departed_buffer: data.driver_source_departed_buffer,
+        min_location_accuracy: data.min_location_accuracy,
+        location_update_limit: data.location_update_limit,
+        blacklist_merchants: data.blacklist_merchants.to_owned(),
+        stop_detection: data.stop_detection.to_owned(),
+        detection_violation_config: data.detection_violation_config.to_owned(),
+        detection_anti_violation_config: data.detection_anti_violation_config.to_owned(),
+    }
+}
+
+fn apply_override(base: OverridableConfig, config_override: ConfigOverride) -> OverridableConfig {
+    OverridableConfig {
+        pickup_notification_threshold: config_override
+            .pickup_notification_threshold
+            .unwrap_or(base.pickup_notification_threshold),
+        arriving_notification_threshold: config_override
+            .arriving_notification_threshold
+            .unwrap_or(base.arriving_notification_threshold),
+        pickup_instruction_notification_threshold: config_override
+            .pickup_instruction_notification_threshold
+            .unwrap_or(base.pickup_instruction_notification_threshold),
+        driver_location_accuracy_buffer: config_override
+            .driver_location_accuracy_buffer
+            .unwrap_or(base.driver_location_accuracy_buffer),
+        driver_reached_destination_buffer: config_override
+            .driver_reached_destination_buffer
+            .unwrap_or(base.driver_reached_destination_buffer),
+        driver_source_departed_buffer: config_override
+            .driver_source_departed_buffer
+            .unwrap_or(base.driver_source_departed_buffer),
+        min_location_accuracy: config_override
+            .min_location_accuracy
+            .unwrap_or(base.min_location_accuracy),
+        location_update_limit: config_override
+            .location_update_limit
+            .unwrap_or(base.location_update_limit),
+        blacklist_merchants: config_override
+            .blacklist_merchants
+            .unwrap_or(base.blacklist_merchants),
+        stop_detection: config_override
+            .stop_detection
+            .map(Arc::unwrap_or_clone)
+            .unwrap_or(base.stop_detection),
+        detection_violation_config: config_override
+            .detection_violation_config
+            .map(Arc::unwrap_or_clone)
+            .unwrap_or(base.detection_violation_config),
+        detection_anti_violation_config: config_override
+            .detection_anti_violation_config
+            .map(Arc::unwrap_or_clone)
+            .unwrap_or(base.detection_anti_violation_config),
+    }
+}
+
+pub async fn get_config(
+    data: Data<AppState>,
+    query: AdminConfigQuery,
+) -> Result<AdminConfigResponse, AppError> {
+    let entries = get_all_config_overrides(&data.redis).await?;
+    let base = base_config(&data);
+
+    let resolved = resolve_from_entries(&entries, query.merchant_id.as_ref(), query.city.as_ref());
+    let effective = apply_override(base.to_owned(), resolved);
+
+    let mut overrides: Vec<ConfigOverrideEntry> = entries.into_values().collect();
+    overrides.sort_by_key(|entry| entry.scope.as_field());
+
+    Ok(AdminConfigResponse {
+        base,
+        overrides,
+        effective,
+    })
+}
+
+async fn write_config_override(
+    args: (
+        Data<AppState>,
+        ConfigOverrideScope,
+        ConfigOverrideChange,
+        Option<String>,
+        Option<String>,
+    ),
+) -> Result<ConfigOverrideEntry, AppError> {
+    let (data, scope, change, updated_by, reason) = args;
+    let field = scope.as_field();
+    let current = get_config_override(&data.redis, &field).await?;
+
+    let config = match change {
+        ConfigOverrideChange::Set(config) => config,
+        ConfigOverrideChange::Rollback(target_version) => {
+            let current_version = current.as_ref().map(|entry| entry.version).ok_or_else(|| {
+                AppError::InvalidRequest(format!("No config override set for {field}"))
+            })?;
+            let history = get_config_override_history(&data.redis, &field).await?;
+            match target_version {
+                Some(version) => history
+                    .into_iter()
+                    .find(|entry| entry.version == version)
+                    .map(|entry| entry.config)
+                    .ok_or_else(|| {
+                        AppError::InvalidRequest(format!(
+                            "Version {version} not found in override history for {field}"
+                        ))
+                    })?,
+                // History is newest-first, so the first older entry is the previous version.
+                // Rolling back the very first version clears the override.
+                None => history
+                    .into_iter()
+                    .find(|entry| entry.version < current_version)
+                    .map(|entry| entry.config)
+                    .unwrap_or_default(),
+            }
+        }
+    };
+
+    let entry = ConfigOverrideEntry {
+        scope,
+        version: current.map(|entry| entry.version + 1).unwrap_or(1),
+        config,
+        updated_at: TimeStamp(Utc::now()),
+        updated_by,
+        reason,
+    };
+    set_config_override(&data.redis, &field, &entry).await?;
+
+    // Apply on this pod immediately; other pods pick it up on their next refresh.
+    data.config_overrides
+        .write()
+        .await
+        .insert(field.to_owned(), entry.to_owned());
+
+    info!(
+        tag = "[Config Override]",
+        scope = %field,
+        version = entry.version,
+        updated_by = ?entry.updated_by,
+        "Config override updated"
+    );
+
+    Ok(entry)
+}
+
+pub async fn set_override(
+    data: Data<AppState>,
+    scope: ConfigOverrideScope,
+    request_body: ConfigOverrideRequest,
+) -> Result<ConfigOverrideEntry, AppError> {
+    request_body.config.validate()?;
+    let lock_key = config_override_lock_key(&scope.as_field());
+    with_lock_redis(
+        &data.redis,
+        lock_key,
+        10,
+        write_config_override,
+        (
+            data.clone(),
+            scope,
+            ConfigOverrideChange::Set(request_body.config),
+            request_body.updated_by,
+            request_body.reason,
+        ),
+    )
+    .await
+}
+
+pub async fn rollback_override(
+    data: Data<AppState>,
+    scope: ConfigOverrideScope,
+    request_body: ConfigRollbackRequest,
+) -> Result<ConfigOverrideEntry, AppError> {
+    let lock_key = config_override_lock_key(&scope.as_field());
+    with_lock_redis(
+        &data.redis,
+        lock_key,
+        10,
+        write_config_override,
+        (
+            data.clone(),
+            scope,
+            ConfigOverrideChange::Rollback(request_body.version),
+            request_body.updated_by,
+            request_body.reason,
+        ),
+    )
+    .await
+}
+
+pub async fn get_override_history(
+    data: Data<AppState>,
+    scope: ConfigOverrideScope,
+) -> Result<ConfigOverrideHistoryResponse, AppError> {
+    let field = scope.as_field();
+    Ok(ConfigOverrideHistoryResponse {
+        current: get_config_override(&data.redis, &field).await?,
+        history: get_config_override_history(&data.redis, &field).await?,
+    })
+}
+
+pub async fn set_external_gps_imei_mapping(
+    data: Data<AppState>,
+    vendor: String,
+    request_body: ExternalGpsImeiMappingRequest,
+) -> Result<APISuccess, AppError> {
+    if !data.external_gps_vendors.contains_key(&vendor) {
+        return Err(AppError::UnknownGpsVendor(vendor));
+    }
+
+    info!(
+        tag = "[External GPS IMEI Mapping]",
+        vendor = %vendor,
+        upserted = request_body.upsert.len(),
+        removed = request_body.remove.len(),
+    );
+    set_external_gps_imei_vehicles(
+        &data.redis,
+        &vendor,
+        request_body
+            .upsert
+            .into_iter()
+            .map(|mapping| (mapping.imei, mapping.vehicle_number))
+            .collect(),
+    )
+    .await?;
+    delete_external_gps_imei_vehicles(&data.redis, &vendor, request_body.remove).await?;
+
+    Ok(APISuccess::default())
+}
+
+pub fn redis_migration_status(data: Data<AppState>) -> RedisMigrationStatusResponse {
+    RedisMigrationStatusResponse {
+        enabled: data.redis_migration.is_enabled(),
+        phase: data.redis_migration.phase(),
+    }
+}
+
+/// Moves the migration one phase forward or back. The phase is stored in the
+/// primary Redis; other pods pick it up on their next refresh.
+/// Moving into `CutOver` first copies the long-lived keys to the secondary.
+pub async fn move_redis_migration_phase(
+    data: Data<AppState>,
+    request_body: RedisMigrationPhaseRequest,
+) -> Result<RedisMigrationStatusResponse, AppError> {
+    if !data.redis_migration.is_enabled() {
+        return Err(AppError::InvalidRequest(
+            "Redis migration is not configured".to_string(),
+        ));
+    }
+    let current = get_redis_migration_phase(data.redis_migration.source()).await?;
+    if current != request_body.phase && !current.can_move_to(request_body.phase) {
+        return Err(AppError::InvalidRequest(format!(
+            "Cannot move Redis migration from {current:?} to {:?}, phases move one step at a time",
+            request_body.phase
+        )));
+    }
+
+    // Keys not written since DualWrite started must be on the secondary before it serves.
+    if current == RedisMigrationPhase::ShadowRead
+        && request_body.phase == RedisMigrationPhase::CutOver
+    {
+        let copied = backfill_redis_migration(&data.redis_migration, &data.queue_redis()).await?;
+        info!(
+            tag = "[Redis Migration]",
+            "Backfilled {} keys before cut-over", copied
+        );
+    }
+
+    set_redis_migration_phase(data.redis_migration.source(), request_body.phase).await?;
+    data.redis_migration.set_phase(request_body.phase);
+
+    info!(
+        tag = "[Redis Migration]",
+        from = ?current,
+        to = ?request_body.phase,
+        updated_by = ?request_body.updated_by,
+        "Phase updated"
+    );
+
+    Ok(redis_migration_status(data))
+}
@@ -11,6 +11,10 @@ use std::collections::HashMap;
 use crate::tools::error::AppError;
 use crate::{
     common::{
+        driver_availability::{
+            transition_driver_availability, DriverAvailability, TransitionSource,
+        },
+        driver_session::{get_driver_day_summary, DriverDaySummary},
         types::*,
         utils::{get_bucket_from_timestamp, get_city},
     },
@@ -19,8 +23,9 @@ use crate::{
     redis::commands::*,
     tools::prometheus::{MEASURE_DURATION, NEARBY_DRIVERS_RETURNED, QUEUE_EVICTIONS},
 };
+use crate::{redis_migration_read, redis_migration_write};
 use actix_web::web::Data;
-use chrono::Utc;
+use chrono::{NaiveDate, Utc};
 use shared::measure_latency_duration;
 use shared::redis::types::RedisConnectionPool;
 use shared::tools::logger::*;
@@ -30,6 +35,7 @@ use strum::IntoEnumIterator;
 #[allow(clippy::too_many_arguments)]
 async fn search_nearby_drivers_with_vehicle(
     redis: &RedisConnectionPool,
+    geo_redis: &RedisConnectionPool,
     nearby_bucket_threshold: &u64,
     merchant_id: &MerchantId,
     city: &CityName,
@@ -41,7 +47,7 @@ async fn search_nearby_drivers_with_vehicle(
     group_id2: &Option<String>,
 ) -> Result<Vec<DriverLocationDetail>, AppError> {
     let nearby_drivers = get_drivers_within_radius(
-        redis,
+        geo_redis,
         nearby_bucket_threshold,
         merchant_id,
         city,
@@ -386,6 +392,7 @@ pub async fn get_nearby_drivers(
             for vehicle in VehicleType::iter() {
                 let nearby_drivers = search_nearby_drivers_with_vehicle(
                     &data.redis,
+                    &data.geo_redis_for_city(&city),
                     &data.nearby_bucket_threshold,
                     &merchant_id,
                     &city,
@@ -414,6 +421,7 @@ pub async fn get_nearby_drivers(
             for vehicle in vehicles {
                 let nearby_drivers = search_nearby_drivers_with_vehicle(
                     &data.redis,
+                    &data.geo_redis_for_city(&city),
                     &data.nearby_bucket_threshold,
                     &merchant_id,
                     &city,
@@ -487,31 +495,70 @@ pub async fn driver_block_till(
     data: Data<AppState>,
     request_body: DriverBlockTillRequest,
 ) -> Result<APISuccess, AppError> {
-    let driver_details = get_driver_location(&data.redis, &request_body.driver_id).await?;
+    redis_migration_write!(
+        data.redis_migration,
+        "driver_blocked_till",
+        &data.redis,
+        |redis| set_driver_blocked_till(
+            &redis,
+            &data.last_location_timstamp_expiry,
+            &data.driver_details_encoding,
+            &request_body.driver_id,
+            &request_body.block_till,
+        )
+    )?;
 
+    // A block ends the driver's ride as far as pings are concerned, as it did when
+    // the block was written into the combined record.
+    let driver_details = redis_migration_read!(
+        data.redis_migration,
+        "driver_details",
+        &data.redis,
+        |redis| get_driver_location(&redis, &request_body.driver_id)
+    )?;
     if let Some(details) = driver_details {
-        set_driver_last_location_update(
+        let location = DriverLocationState {
+            driver_last_known_location: details.driver_last_known_location,
+            stop_detection: details.stop_detection,
+            ride_status: None,
+            group_id: details.group_id,
+        };
+        let ride_notification = DriverRideNotificationState {
+            ride_notification_status: None,
+            driver_pickup_distance: details.driver_pickup_distance,
+        };
+        let detection = DriverDetectionState {
+            violation_trigger_flag: details.violation_trigger_flag,
+            detection_state: details.detection_state,
+            anti_detection_state: details.anti_detection_state,
+        };
+        redis_migration_write!(
+            data.redis_migration,
+            "driver_details",
             &data.redis,
-            &data.last_location_timstamp_expiry,
-            &request_body.driver_id,
+            |redis| set_driver_ping_state(
+                &redis,
+                &data.last_location_timstamp_expiry,
+                &data.redis_expiry,
+                &data.driver_details_encoding,
+                &request_body.driver_id,
+                &location,
+                Some(&ride_notification),
+                details.from_combined_record.then_some(&detection),
+            )
+        )?;
+    }
+
+    if request_body.block_till > TimeStamp(Utc::now()) {
+        transition_driver_availability(
+            &data,
             &request_body.merchant_id,
-            &details.driver_last_known_location.location,
-            &details.driver_last_known_location.timestamp,
-            &Some(request_body.block_till),
-            details.stop_detection,
-            &None::<RideStatus>,
-            &None,
-            &details.detection_state,
-            &details.anti_detection_state,
-            &details.violation_trigger_flag,
-            &details.driver_pickup_distance,
-            &details.driver_last_known_location.bear,
-            &details.driver_last_known_location.vehicle_type,
-            &details.driver_last_known_location.group_id,
-            &details.driver_last_known_location.group_id2,
+            &request_body.driver_id,
+            DriverAvailability::Blocked,
+            TransitionSource::Lifecycle,
         )
-        .await?;
-    };
+        .await;
+    }
     Ok(APISuccess::default())
 }
 
@@ -551,14 +598,17 @@ pub async fn driver_queue_position(
     vehicle_type: String,
     driver_id: String,
 ) -> Result<DriverQueuePositionResponse, AppError> {
-    let primary_redis = data.queue_redis();
-    let (rank, queue_size) = get_driver_queue_position_and_size(
-        &primary_redis,
-        &special_location_id,
-        &vehicle_type,
-        &driver_id,
-    )
-    .await?;
+    let (rank, queue_size) = redis_migration_read!(
+        data.redis_migration,
+        "queue_position",
+        &data.queue_redis(),
+        |redis| get_driver_queue_position_and_size(
+            &redis,
+            &special_location_id,
+            &vehicle_type,
+            &driver_id,
+        )
+    )?;
     let offset = data.queue_position_range_offset;
     let queue_position_range = rank.map(|r| {
         let pos = r + 1; // 1-indexed
@@ -577,7 +627,7 @@ pub async fn driver_queue_history(
     merchant_id: String,
     driver_id: String,
 ) -> Result<DriverQueueHistoryResponse, AppError> {
-    let primary_redis = data.queue_redis();
+    let primary_redis = data.redis_migration.serving(&data.queue_redis());
     // Tracking state and event timeline can be fetched in parallel — neither
     // depends on the other.
     let (tracking, events) = tokio::try_join!(
@@ -615,6 +665,22 @@ pub async fn driver_queue_history(
     })
 }
 
+/// Accounting of a driver for one day (`YYYY-MM-DD`), up to their last ping.
+pub async fn driver_day_session(
+    data: Data<AppState>,
+    driver_id: DriverId,
+    day: String,
+) -> Result<DriverDaySummary, AppError> {
+    if data.driver_session.is_none() {
+        return Err(AppError::InvalidRequest(
+            "driver session accounting is not enabled".to_string(),
+        ));
+    }
+    let day = NaiveDate::parse_from_str(&day, "%Y-%m-%d")
+        .map_err(|err| AppError::InvalidRequest(format!("invalid day {day}: {err}")))?;
+    get_driver_day_summary(&data.redis_migration.serving(&data.redis), &driver_id, day).await
+}
+
 pub async fn manual_queue_remove(
     data: Data<AppState>,
     special_location_id: String,
@@ -623,24 +689,29 @@ pub async fn manual_queue_remove(
     driver_id: String,
     reason: Option<String>,
 ) -> Result<APISuccess, AppError> {
-    let primary_redis = data.queue_redis();
-    remove_driver_from_queue(
-        &primary_redis,
-        &special_location_id,
-        &vehicle_type,
-        &driver_id,
-    )
-    .await?;
-    delete_driver_queue_tracking(&primary_redis, &merchant_id, &driver_id).await?;
+    let queue_redis = data.queue_redis();
+    redis_migration_write!(data.redis_migration, "queue_zset", &queue_redis, |redis| {
+        remove_driver_from_queue(&redis, &special_location_id, &vehicle_type, &driver_id)
+    })?;
+    redis_migration_write!(
+        data.redis_migration,
+        "queue_tracking",
+        &queue_redis,
+        |redis| delete_driver_queue_tracking(&redis, &merchant_id, &driver_id)
+    )?;
     // Clear last_ts so the next in-fence ping cannot resurrect the driver at
     // their original score via the drainer's ZADD-NX-with-stored-ts path.
-    delete_driver_queue_last_ts(
-        &primary_redis,
-        &special_location_id,
-        &vehicle_type,
-        &driver_id,
-    )
-    .await?;
+    redis_migration_write!(
+        data.redis_migration,
+        "queue_last_ts",
+        &queue_redis,
+        |redis| delete_driver_queue_last_ts(
+            &redis,
+            &special_location_id,
+            &vehicle_type,
+            &driver_id,
+        )
+    )?;
     // Normalize once: empty/whitespace-only reasons collapse to None so the
     // rank-history event and the prometheus label stay in sync.
     let normalized_reason = reason.as_deref().map(str::trim).filter(|r| !r.is_empty());
@@ -652,15 +723,13 @@ pub async fn manual_queue_remove(
         Some(r) => format!("exit:manual:{}", r),
         None => "exit:manual".to_string(),
     };
-    if let Err(err) = append_rank_history_event(
-        &primary_redis,
-        &merchant_id,
-        &driver_id,
-        Utc::now().timestamp() as f64,
-        &event,
-    )
-    .await
-    {
+    let event_ts = Utc::now().timestamp() as f64;
+    if let Err(err) = redis_migration_write!(
+        data.redis_migration,
+        "rank_history",
+        &queue_redis,
+        |redis| append_rank_history_event(&redis, &merchant_id, &driver_id, event_ts, &event)
+    ) {
         error!(tag = "[Manual Queue Remove Rank History]", error = %err);
     }
     QUEUE_EVICTIONS
@@ -681,30 +750,30 @@ pub async fn manual_queue_add(
     driver_id: String,
     queue_position: u64, // 1-indexed
 ) -> Result<APISuccess, AppError> {
-    let primary_redis = data.queue_redis();
+    let queue_redis = data.queue_redis();
+    // Positions are computed on the serving pool; writes are mirrored with
+    // the same score so both clusters end up with the same order.
+    let primary_redis = data.redis_migration.serving(&queue_redis);
     // First remove the driver if already in a different queue
     let existing_tracking =
         get_driver_queue_tracking(&primary_redis, &merchant_id, &driver_id).await?;
     if let Some(old) = &existing_tracking {
         if old.special_location_id != special_location_id || old.vehicle_type != vehicle_type {
-            remove_driver_from_queue(
-                &primary_redis,
-                &old.special_location_id,
-                &old.vehicle_type,
-                &driver_id,
-            )
-            .await?;
+            redis_migration_write!(data.redis_migration, "queue_zset", &queue_redis, |redis| {
+                remove_driver_from_queue(
+                    &redis,
+                    &old.special_location_id,
+                    &old.vehicle_type,
+                    &driver_id,
+                )
+            })?;
         }
     }
 
     // Also remove from the target queue if already present (to re-insert at new position)
-    remove_driver_from_queue(
-        &primary_redis,
-        &special_location_id,
-        &vehicle_type,
-        &driver_id,
-    )
-    .await?;
+    redis_migration_write!(data.redis_migration, "queue_zset", &queue_redis, |redis| {
+        remove_driver_from_queue(&redis, &special_location_id, &vehicle_type, &driver_id)
+    })?;
 
     // Recompute queue size after removal
     let queue_size_after_removal =
@@ -765,31 +834,34 @@ pub async fn manual_queue_add(
         }
     };
 
-    add_driver_to_queue_force(
-        &primary_redis,
-        &special_location_id,
-        &vehicle_type,
-        &driver_id,
-        score,
-        data.queue_expiry_seconds as i64,
-    )
-    .await?;
+    redis_migration_write!(data.redis_migration, "queue_zset", &queue_redis, |redis| {
+        add_driver_to_queue_force(
+            &redis,
+            &special_location_id,
+            &vehicle_type,
+            &driver_id,
+            score,
+            data.queue_expiry_seconds as i64,
+        )
+    })?;
 
     // Set tracking key
-    set_driver_queue_tracking(
-        &primary_redis,
-        &merchant_id,
-        &driver_id,
-        &DriverQueueTracking {
-            special_location_id,
-            vehicle_type,
-            consecutive_exit_pings: 0,
-            // Manual insert doesn't know the post-insert ZRANK; let the
-            // next drainer Enter for this driver record it fresh.
-            last_recorded_rank: None,
-        },
-    )
-    .await?;
+    let tracking = DriverQueueTracking {
+        special_location_id,
+        vehicle_type,
+        consecutive_exit_pings: 0,
+        // Manual insert doesn't know the post-insert ZRANK; let the
+        // next drainer Enter for this driver record it fresh.
+        last_recorded_rank: None,
+        last_ping_ts: None,
+        last_stream_id: None,
+    };
+    redis_migration_write!(
+        data.redis_migration,
+        "queue_tracking",
+        &queue_redis,
+        |redis| set_driver_queue_tracking(&redis, &merchant_id, &driver_id, &tracking)
+    )?;
 
     Ok(APISuccess::default())
 }
@@ -799,10 +871,12 @@ pub async fn get_queue_drivers(
     special_location_id: String,
     vehicle_type: String,
 ) -> Result<QueueDriversResponse, AppError> {
-    let primary_redis = data.queue_redis();
-    let scores =
-        get_queue_scores_at_range(&primary_redis, &special_location_id, &vehicle_type, 0, -1)
-            .await?;
+    let scores = redis_migration_read!(
+        data.redis_migration,
+        "queue_zset",
+        &data.queue_redis(),
+        |redis| get_queue_scores_at_range(&redis, &special_location_id, &vehicle_type, 0, -1)
+    )?;
     let drivers = scores
         .into_iter()
         .enumerate()
@@ -829,7 +903,7 @@ pub async fn get_special_location_drivers(
     if !data.enable_special_location_bucketing {
         return Ok(SpecialLocationDriversResponse { driver_ids: vec![] });
     }
-    let primary_redis = data.queue_redis();
+    let primary_redis = data.redis_migration.serving(&data.queue_redis());
     let current_bucket = get_bucket_from_timestamp(&data.bucket_size, TimeStamp(Utc::now()));
     let driver_ids = get_drivers_in_special_location(
         &primary_redis,
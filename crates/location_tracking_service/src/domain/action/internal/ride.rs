@@ -6,12 +6,17 @@
     the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
+use crate::common::broadcast_trace::broadcast_trace_targets;
+use crate::common::driver_availability::{
+    transition_driver_availability, DriverAvailability, TransitionSource,
+};
 use crate::domain::types::ui::location::PersonType;
 use crate::environment::AppState;
 use crate::outbound::types::LocationUpdate;
 use crate::redis::commands::*;
 use crate::tools::error::AppError;
 use crate::{common::types::*, domain::types::internal::ride::*};
+use crate::{redis_migration_read, redis_migration_write};
 use actix_web::web::Data;
 use chrono::Utc;
 use std::str::FromStr;
@@ -22,23 +27,44 @@ pub async fn ride_create(
     request_body: RideCreateRequest,
 ) -> Result<APISuccess, AppError> {
     if let Some(false) | None = request_body.is_future_ride {
-        set_ride_details_for_driver(
-            &data.redis,
-            &data.redis_expiry,
+        redis_migration_write!(data.redis_migration, "ride_details", &data.redis, |redis| {
+            set_ride_details_for_driver(
+                &redis,
+                &data.redis_expiry,
+                &request_body.merchant_id,
+                &request_body.driver_id,
+                ride_id.to_owned(),
+                RideStatus::NEW,
+                request_body.ride_info.to_owned(),
+                None,
+            )
+        })?;
+
+        transition_driver_availability(
+            &data,
             &request_body.merchant_id,
             &request_body.driver_id,
-            ride_id.to_owned(),
-            RideStatus::NEW,
-            request_body.ride_info,
+            DriverAvailability::OnPickup,
+            TransitionSource::Lifecycle,
         )
-        .await?;
+        .await;
     }
 
     let driver_details = DriverDetails {
         driver_id: request_body.driver_id,
     };
 
-    set_on_ride_driver_details(&data.redis, &data.redis_expiry, &ride_id, driver_details).await?;
+    redis_migration_write!(
+        data.redis_migration,
+        "on_ride_driver_details",
+        &data.redis,
+        |redis| set_on_ride_driver_details(
+            &redis,
+            &data.redis_expiry,
+            &ride_id,
+            driver_details.to_owned()
+        )
+    )?;
 
     Ok(APISuccess::default())
 }
@@ -48,16 +74,46 @@ pub async fn ride_start(
     data: Data<AppState>,
     request_body: RideStartRequest,
 ) -> Result<APISuccess, AppError> {
-    set_ride_details_for_driver(
-        &data.redis,
-        &data.redis_expiry,
+    // Stamped once, so every pool written during a migration agrees on it.
+    let started_at = TimeStamp(Utc::now());
+    redis_migration_write!(data.redis_migration, "ride_details", &data.redis, |redis| {
+        set_ride_details_for_driver(
+            &redis,
+            &data.redis_expiry,
+            &request_body.merchant_id,
+            &request_body.driver_id,
+            ride_id.to_owned(),
+            RideStatus::INPROGRESS,
+            request_body.ride_info.to_owned(),
+            Some(started_at),
+        )
+    })?;
+
+    transition_driver_availability(
+        &data,
         &request_body.merchant_id,
         &request_body.driver_id,
-        ride_id,
-        RideStatus::INPROGRESS,
-        request_body.ride_info,
+        DriverAvailability::OnRide,
+        TransitionSource::Lifecycle,
     )
-    .await?;
+    .await;
+
+    if !data.ride_proximity_check.is_empty() {
+        set_ride_proximity_tracking(
+            &data.redis,
+            &ride_id,
+            &RideProximityTracking {
+                merchant_id: request_body.merchant_id,
+                driver_id: request_body.driver_id,
+                started_at,
+                last_sample_ts: None,
+                separated_samples: 0,
+                has_been_together: false,
+                alerted: false,
+            },
+        )
+        .await?;
+    }
 
     Ok(APISuccess::default())
 }
@@ -67,13 +123,20 @@ pub async fn ride_end(
     data: Data<AppState>,
     request_body: RideEndRequest,
 ) -> Result<RideEndResponse, AppError> {
-    let mut on_ride_driver_locations = get_on_ride_driver_locations(
+    let mut on_ride_driver_locations = redis_migration_read!(
+        data.redis_migration,
+        "on_ride_locations",
         &data.redis,
-        &request_body.driver_id,
-        &request_body.merchant_id,
-        data.batch_size,
-    )
-    .await?;
+        |redis| get_on_ride_driver_locations(
+            &redis,
+            &request_body.driver_id,
+            &request_body.merchant_id,
+            data.batch_size,
+        )
+    )?;
+
+    // Late points are appended after newer ones, see `append_late_driver_locations`.
+    on_ride_driver_locations.sort_by_key(|location| location.ts);
 
     on_ride_driver_locations.push(LocationUpdate {
         lat: request_body.lat,
@@ -81,14 +144,28 @@ pub async fn ride_end(
         ts: Some(request_body.ts.unwrap_or_else(|| Utc::now().timestamp())),
     });
 
-    ride_cleanup(
-        &data.redis,
+    redis_migration_write!(data.redis_migration, "ride_cleanup", &data.redis, |redis| {
+        ride_cleanup(
+            &redis,
+            &request_body.merchant_id,
+            &request_body.driver_id,
+            &ride_id,
+            &request_body.ride_info,
+        )
+    })?;
+
+    if !data.ride_proximity_check.is_empty() {
+        delete_ride_proximity_tracking(&data.redis, &ride_id).await?;
+    }
+
+    transition_driver_availability(
+        &data,
         &request_body.merchant_id,
         &request_body.driver_id,
-        &ride_id,
-        &request_body.ride_info,
+        DriverAvailability::OnlineIdle,
+        TransitionSource::Lifecycle,
     )
-    .await?;
+    .await;
 
     if let Some(next_ride_id) = request_body.next_ride_id {
         let ride_details_request = RideDetailsRequest {
@@ -116,15 +193,24 @@ pub async fn get_driver_locations(
     data: Data<AppState>,
     request_body: DriverLocationRequest,
 ) -> Result<DriverLocationResponse, AppError> {
-    let on_ride_driver_locations = get_on_ride_driver_locations(
+    let on_ride_driver_locations = redis_migration_read!(
+        data.redis_migration,
+        "on_ride_locations",
         &data.redis,
-        &request_body.driver_id,
-        &request_body.merchant_id,
-        data.batch_size,
-    )
-    .await?;
+        |redis| get_on_ride_driver_locations(
+            &redis,
+            &request_body.driver_id,
+            &request_body.merchant_id,
+            data.batch_size,
+        )
+    )?;
 
-    let driver_location_details = get_driver_location(&data.redis, &request_body.driver_id).await?;
+    let driver_location_details = redis_migration_read!(
+        data.redis_migration,
+        "driver_details",
+        &data.redis,
+        |redis| get_driver_location(&redis, &request_body.driver_id)
+    )?;
 
     Ok(DriverLocationResponse {
         loc: on_ride_driver_locations,
@@ -141,86 +227,119 @@ pub async fn ride_details(
     let driver_id = request_body.driver_id.clone();
 
     if let RideStatus::CANCELLED = request_body.ride_status {
-        ride_cleanup(
-            &data.redis,
-            &request_body.merchant_id,
-            &driver_id,
-            &request_body.ride_id,
-            &request_body.ride_info,
-        )
-        .await?;
+        redis_migration_write!(data.redis_migration, "ride_cleanup", &data.redis, |redis| {
+            ride_cleanup(
+                &redis,
+                &request_body.merchant_id,
+                &driver_id,
+                &request_body.ride_id,
+                &request_body.ride_info,
+            )
+        })?;
 
-        if let Some(driver_location) = get_driver_location(&data.redis, &driver_id).await? {
-            set_driver_last_location_update(
-                &data.redis,
+        let ride_notification = DriverRideNotificationState {
+            ride_notification_status: None,
+            driver_pickup_distance: None,
+        };
+        redis_migration_write!(
+            data.redis_migration,
+            "driver_ride_state",
+            &data.redis,
+            |redis| set_driver_ride_state(
+                &redis,
+                &data.last_location_timstamp_expiry,
                 &data.redis_expiry,
+                &data.driver_details_encoding,
                 &driver_id,
-                &request_body.merchant_id,
-                &driver_location.driver_last_known_location.location,
-                &driver_location.driver_last_known_location.timestamp,
-                &driver_location.blocked_till,
-                driver_location.stop_detection,
-                &driver_location.ride_status,
-                &None,
-                &driver_location.detection_state,
-                &driver_location.anti_detection_state,
-                &driver_location.violation_trigger_flag,
-                &driver_location.driver_pickup_distance,
-                &driver_location.driver_last_known_location.bear,
-                &driver_location.driver_last_known_location.vehicle_type,
-                &driver_location.driver_last_known_location.group_id,
-                &driver_location.driver_last_known_location.group_id2,
+                &ride_notification,
+                None,
             )
-            .await?;
-        }
+        )?;
+
+        transition_driver_availability(
+            &data,
+            &request_body.merchant_id,
+            &driver_id,
+            DriverAvailability::OnlineIdle,
+            TransitionSource::Lifecycle,
+        )
+        .await;
     } else {
         let driver_details = DriverDetails {
             driver_id: driver_id.clone(),
         };
 
-        set_on_ride_driver_details(
+        redis_migration_write!(
+            data.redis_migration,
+            "on_ride_driver_details",
             &data.redis,
-            &data.redis_expiry,
-            &request_body.ride_id,
-            driver_details,
-        )
-        .await?;
-
-        if let Some(false) | None = request_body.is_future_ride {
-            set_ride_details_for_driver(
-                &data.redis,
+            |redis| set_on_ride_driver_details(
+                &redis,
                 &data.redis_expiry,
-                &request_body.merchant_id,
-                &driver_id,
-                request_body.ride_id.to_owned(),
-                request_body.ride_status,
-                request_body.ride_info,
+                &request_body.ride_id,
+                driver_details.to_owned(),
             )
-            .await?;
+        )?;
 
-            if let Some(driver_location) = get_driver_location(&data.redis, &driver_id).await? {
-                set_driver_last_location_update(
+        if let Some(false) | None = request_body.is_future_ride {
+            // Re-sends of an in-progress ride keep the start `ride_start` recorded.
+            let started_at = match request_body.ride_status {
+                RideStatus::INPROGRESS => redis_migration_read!(
+                    data.redis_migration,
+                    "ride_details",
                     &data.redis,
+                    |redis| get_ride_details(&redis, &driver_id, &request_body.merchant_id)
+                )?
+                .filter(|ride_details| ride_details.ride_id == request_body.ride_id)
+                .and_then(|ride_details| ride_details.started_at),
+                _ => None,
+            };
+            redis_migration_write!(data.redis_migration, "ride_details", &data.redis, |redis| {
+                set_ride_details_for_driver(
+                    &redis,
                     &data.redis_expiry,
-                    &driver_id,
                     &request_body.merchant_id,
-                    &driver_location.driver_last_known_location.location,
-                    &driver_location.driver_last_known_location.timestamp,
-                    &driver_location.blocked_till,
-                    driver_location.stop_detection,
-                    &driver_location.ride_status,
-                    &Some(RideNotificationStatus::Idle),
-                    &None,
-                    &None,
-                    &None,
-                    &None,
-                    &driver_location.driver_last_known_location.bear,
-                    &driver_location.driver_last_known_location.vehicle_type,
-                    &driver_location.driver_last_known_location.group_id,
-                    &driver_location.driver_last_known_location.group_id2,
+                    &driver_id,
+                    request_body.ride_id.to_owned(),
+                    request_body.ride_status.to_owned(),
+                    request_body.ride_info.to_owned(),
+                    started_at,
                 )
-                .await?;
-            }
+            })?;
+
+            // A new ride starts with fresh pickup notifications and detection state.
+            let ride_notification = DriverRideNotificationState {
+                ride_notification_status: Some(RideNotificationStatus::Idle),
+                driver_pickup_distance: None,
+            };
+            let detection = DriverDetectionState::default();
+            redis_migration_write!(
+                data.redis_migration,
+                "driver_ride_state",
+                &data.redis,
+                |redis| set_driver_ride_state(
+                    &redis,
+                    &data.last_location_timstamp_expiry,
+                    &data.redis_expiry,
+                    &data.driver_details_encoding,
+                    &driver_id,
+                    &ride_notification,
+                    Some(&detection),
+                )
+            )?;
+
+            let availability = match request_body.ride_status {
+                RideStatus::INPROGRESS => DriverAvailability::OnRide,
+                _ => DriverAvailability::OnPickup,
+            };
+            transition_driver_availability(
+                &data,
+                &request_body.merchant_id,
+                &driver_id,
+                availability,
+                TransitionSource::Lifecycle,
+            )
+            .await;
         }
     }
 
@@ -249,7 +368,18 @@ fn build_broadcaster_config(
     }
 }
 
-/// Generic entity upsert: create (ride only), start, or end. Uses generic Redis keys; rider-only for now.
+fn parse_entity_id(entity_type: &str, entity_id_str: &str) -> Result<EntityId, AppError> {
+    match entity_type.to_lowercase().as_str() {
+        "ride" => Ok(EntityId::Ride(RideId(entity_id_str.to_string()))),
+        "sos" => Ok(EntityId::Sos(SosId(entity_id_str.to_string()))),
+        _ => Err(AppError::InvalidRequest(format!(
+            "Unknown entity_type: {}",
+            entity_type
+        ))),
+    }
+}
+
+/// Generic entity upsert: create (ride only), start, or end. Uses generic Redis keys, for any person type.
 pub async fn entity_upsert(
     person_type: &str,
     entity_type: &str,
@@ -257,25 +387,9 @@ pub async fn entity_upsert(
     data: Data<AppState>,
     request_body: EntityUpsertRequest,
 ) -> Result<EntityUpsertResponse, AppError> {
-    let entity_id = match entity_type.to_lowercase().as_str() {
-        "ride" => EntityId::Ride(RideId(entity_id_str.to_string())),
-        "sos" => EntityId::Sos(SosId(entity_id_str.to_string())),
-        _ => {
-            return Err(AppError::InvalidRequest(format!(
-                "Unknown entity_type: {}",
-                entity_type
-            )))
-        }
-    };
+    let entity_id = parse_entity_id(entity_type, entity_id_str)?;
     let person_type = PersonType::from_str(person_type)
         .map_err(|_| AppError::InvalidRequest(format!("Invalid person_type: {}", person_type)))?;
-    if person_type.is_bus_crew() {
-        return Err(AppError::InvalidRequest(
-            "bus crew (bus_conductor / bus_driver) does not support entity upsert; \
-             they forward directly to Kafka"
-                .to_string(),
-        ));
-    }
     let person_id = PersonId(request_body.person_id.clone());
     let merchant_id = &request_body.merchant_id;
 
@@ -291,24 +405,33 @@ pub async fn entity_upsert(
                 broadcaster_ids: Vec::new(),
                 broadcaster_configs: Vec::new(),
             };
-            add_entity_for_person(
+            redis_migration_write!(
+                data.redis_migration,
+                "entity_details",
                 &data.redis,
-                merchant_id,
-                person_type,
-                &person_id,
-                entity_type,
-                entry,
-                &data.redis_expiry,
-            )
-            .await?;
-            set_person_by_entity(
+                |redis| add_entity_for_person(
+                    &redis,
+                    merchant_id,
+                    person_type,
+                    &person_id,
+                    entity_type,
+                    entry.clone(),
+                    &data.redis_expiry,
+                )
+            )?;
+            redis_migration_write!(
+                data.redis_migration,
+                "entity_details",
                 &data.redis,
-                entity_type,
-                entity_id_str,
-                &person_id,
-                &data.redis_expiry,
-            )
-            .await?;
+                |redis| set_person_by_entity(
+                    &redis,
+                    person_type,
+                    entity_type,
+                    entity_id_str,
+                    &person_id,
+                    &data.redis_expiry,
+                )
+            )?;
             Ok(EntityUpsertResponse::APISuccess(APISuccess::default()))
         }
         EntityInfo::EntityStart => {
@@ -328,59 +451,149 @@ pub async fn entity_upsert(
                 broadcaster_ids,
                 broadcaster_configs,
             };
-            add_entity_for_person(
+            // Broadcasters with a config are polled by the trace scheduler as well, so the
+            // trace keeps going if the person's app stops sending locations.
+            let trace_targets =
+                broadcast_trace_targets(merchant_id, person_type, &person_id, entity_type, &entry);
+            redis_migration_write!(
+                data.redis_migration,
+                "entity_details",
                 &data.redis,
-                merchant_id,
-                person_type,
-                &person_id,
-                entity_type,
-                entry,
-                &data.redis_expiry,
-            )
-            .await?;
-            set_person_by_entity(
+                |redis| add_entity_for_person(
+                    &redis,
+                    merchant_id,
+                    person_type,
+                    &person_id,
+                    entity_type,
+                    entry.clone(),
+                    &data.redis_expiry,
+                )
+            )?;
+            redis_migration_write!(
+                data.redis_migration,
+                "entity_details",
                 &data.redis,
-                entity_type,
-                entity_id_str,
-                &person_id,
-                &data.redis_expiry,
-            )
-            .await?;
+                |redis| set_person_by_entity(
+                    &redis,
+                    person_type,
+                    entity_type,
+                    entity_id_str,
+                    &person_id,
+                    &data.redis_expiry,
+                )
+            )?;
+            for target in &trace_targets {
+                redis_migration_write!(
+                    data.redis_migration,
+                    "broadcast_trace",
+                    &data.redis,
+                    |redis| set_broadcast_trace_target(&redis, target)
+                )?;
+            }
             Ok(EntityUpsertResponse::APISuccess(APISuccess::default()))
         }
         EntityInfo::EntityEnd { lat, lon } => {
             let batch_size = data.batch_size;
-            let mut loc = get_entity_locations(
+            let mut loc = redis_migration_read!(
+                data.redis_migration,
+                "entity_locations",
                 &data.redis,
-                merchant_id,
-                person_type,
-                &person_id,
-                batch_size,
-            )
-            .await?;
+                |redis| get_entity_locations(
+                    &redis,
+                    merchant_id,
+                    person_type,
+                    &person_id,
+                    batch_size,
+                )
+            )?;
             loc.push(Point {
                 lat: *lat,
                 lon: *lon,
             });
+            if let Some(entry) = redis_migration_read!(
+                data.redis_migration,
+                "entity_details",
+                &data.redis,
+                |redis| get_entity_details_for_person(&redis, merchant_id, person_type, &person_id)
+            )?
+            .and_then(|mut map| map.entities.remove(entity_type))
+            {
+                for broadcaster_id in &entry.broadcaster_ids {
+                    redis_migration_write!(
+                        data.redis_migration,
+                        "broadcast_trace",
+                        &data.redis,
+                        |redis| delete_broadcast_trace_target(
+                            &redis,
+                            &entry.entity_id,
+                            broadcaster_id
+                        )
+                    )?;
+                }
+            }
             // Remove only this entity type from the map, then clean up related keys.
-            remove_entity_for_person(
+            redis_migration_write!(
+                data.redis_migration,
+                "entity_details",
                 &data.redis,
-                merchant_id,
-                person_type,
-                &person_id,
-                entity_type,
-            )
-            .await?;
-            entity_cleanup_generic(
+                |redis| remove_entity_for_person(
+                    &redis,
+                    merchant_id,
+                    person_type,
+                    &person_id,
+                    entity_type,
+                )
+            )?;
+            redis_migration_write!(
+                data.redis_migration,
+                "entity_details",
                 &data.redis,
-                merchant_id,
-                person_type,
-                &person_id,
-                entity_type,
-                entity_id_str,
-            )
-            .await?;
+                |redis| entity_cleanup_generic(
+                    &redis,
+                    merchant_id,
+                    person_type,
+                    &person_id,
+                    entity_type,
+                    entity_id_str,
+                )
+            )?;
             Ok(EntityUpsertResponse::EntityEnd { loc })
         }
     }
 }
+
+/// Delivery status from a newest-first audit log: the latest attempt decides whether
+/// traces are getting through, along with the length of the current failure streak.
+pub fn summarize_broadcast_trace_delivery(
+    attempts: &[BroadcastTraceAttempt],
+) -> (BroadcastTraceDeliveryStatus, u32) {
+    let consecutive_failures = attempts
+        .iter()
+        .take_while(|attempt| !attempt.delivered)
+        .count() as u32;
+    let status = match attempts.first() {
+        None => BroadcastTraceDeliveryStatus::NoAttempts,
+        Some(latest) if latest.delivered => BroadcastTraceDeliveryStatus::Delivering,
+        Some(_) => BroadcastTraceDeliveryStatus::Failing,
+    };
+    (status, consecutive_failures)
+}
+
+pub async fn broadcast_trace_status(
+    entity_type: &str,
+    entity_id_str: &str,
+    data: Data<AppState>,
+) -> Result<BroadcastTraceStatusResponse, AppError> {
+    let entity_id = parse_entity_id(entity_type, entity_id_str)?;
+    let attempts = get_broadcast_trace_attempts(&data.redis, &entity_id).await?;
+    let last_success = get_broadcast_trace_last_success(&data.redis, &entity_id).await?;
+    let (delivery_status, consecutive_failures) = summarize_broadcast_trace_delivery(&attempts);
+    Ok(BroadcastTraceStatusResponse {
+        entity: entity_id,
+        delivery_status,
+        consecutive_failures,
+        last_success_at: last_success.as_ref().map(|attempt| attempt.ts),
+        last_success,
+        attempts,
+    })
+}
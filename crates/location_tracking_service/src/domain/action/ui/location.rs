@@ -6,7 +6,15 @@
     the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 #![allow(clippy::all)]
+use crate::common::broadcast_trace::{try_send_broadcast_trace, BroadcastLocation};
+use crate::common::config_override::{resolve_config_field, resolve_config_override};
 use crate::common::detection::*;
+use crate::common::drainer_wal::DrainerEntry;
+use crate::common::driver_availability::{
+    transition_driver_availability, DriverAvailability, TransitionSource,
+};
+use crate::common::driver_session::account_driver_session;
+use crate::common::location_fusion::fuse_vehicle_location;
 use crate::common::stop_detection::*;
 use crate::common::utils::is_within_polygon;
 use crate::common::utils::{
@@ -18,19 +26,25 @@ use crate::domain::types::ui::location::{
     DriverLocationResponse, PersonLocationResponse, PersonType, UpdateDriverLocationRequest,
     UpdatePersonLocationRequest,
 };
-use crate::environment::AppState;
+use crate::environment::{AppState, DrainerFullChannelPolicy};
 use crate::kafka::producers::kafka_stream_updates;
 use crate::outbound::external::driver_source_departed;
 use crate::outbound::external::get_distance_matrix;
-use crate::outbound::external::trigger_detection_alert;
 use crate::outbound::external::{
     authenticate_bap, authenticate_dobpp, bulk_location_update_dobpp, driver_reached_destination,
     trigger_fcm_bap, trigger_fcm_dobpp, trigger_stop_detection_event,
 };
-use crate::outbound::types::{LocationUpdate, ViolationDetectionReq};
-use crate::redis::{commands::*, keys::*};
+use crate::outbound::external::{trigger_detection_alert, trigger_rider_safety_alert};
+use crate::outbound::types::{
+    DetectionOutcome, LocationUpdate, RiderSafetyAlertReq, ViolationDetectionReq,
+};
+use crate::redis::{commands::*, keys::*, migration::RedisMigration};
 use crate::tools::error::AppError;
-use crate::tools::prometheus::{MEASURE_DURATION, QUEUE_EVICTIONS};
+use crate::tools::prometheus::{
+    DRAINER_BACKPRESSURE, DRAINER_CHANNEL_OCCUPANCY, DRAINER_STREAM_ENTRIES, LATE_LOCATION_UPDATES,
+    MEASURE_DURATION, QUEUE_EVICTIONS,
+};
+use crate::{redis_migration_read, redis_migration_write};
 use actix::Arbiter;
 use actix_web::{web::Data, HttpResponse};
 use chrono::Utc;
@@ -42,8 +56,10 @@ use shared::redis::types::RedisConnectionPool;
 use std::collections::HashMap;
 use std::env::var;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::time::Duration;
 use strum::IntoEnumIterator;
+use tokio::sync::mpsc::error::TrySendError;
 use tracing::{debug, error, info, warn};
 
 #[macros::measure_duration]
@@ -146,8 +162,9 @@ struct BusGpsUpdate {
 }
 
 /// Forward bus-crew pings (`bus_conductor` / `bus_driver`) straight to the
-/// per-fleet Kafka topic keyed by `gtfs_id`, bypassing the driver location
-/// pipeline. `person_type`, `gtfs_id` and `vehicle_no` are passed as headers.
+/// per-fleet Kafka topic keyed by `gtfs_id`. With `enable_bus_crew_tracking_pipeline`
+/// they are also run through the driver location pipeline of the bus ride on
+/// `vehicle_no`. `person_type`, `gtfs_id` and `vehicle_no` are passed as headers.
 pub async fn handle_driver_conductor_location_update(
     token: Token,
     data: Data<AppState>,
@@ -198,15 +215,21 @@ pub async fn handle_driver_conductor_location_update(
         return Ok(());
     };
     let city = get_city(&last_entry.pt.lat, &last_entry.pt.lon, &data.polygon)?;
+    let location_update_limit =
+        resolve_config_field(&data.config_overrides, &merchant_id, &city, |config| {
+            config.location_update_limit
+        })
+        .await
+        .unwrap_or(data.location_update_limit);
     sliding_window_limiter(
         &data.redis,
         &person_rate_limit_key(&merchant_id, &bus_person_id, &city),
-        data.location_update_limit,
+        location_update_limit,
         data.location_update_interval as u32,
     )
     .await?;
 
-    for entry in request_body.into_iter() {
+    for entry in request_body.iter() {
         let payload = BusGpsUpdate {
             device_id: format!("{gtfs_id}:{vehicle_no}"),
             vehicle_no: vehicle_no.clone(),
@@ -222,6 +245,7 @@ pub async fn handle_driver_conductor_location_update(
         if let Err(e) = crate::common::kafka::push_to_kafka(
             &data.producer,
             &data.secondary_producer,
+            &data.kafka_sink,
             &topic,
             &vehicle_no,
             payload,
@@ -235,9 +259,61 @@ pub async fn handle_driver_conductor_location_update(
         }
     }
 
+    if data.enable_bus_crew_tracking_pipeline {
+        track_bus_crew_locations(data, &vehicle_no, request_body).await;
+    }
+
     Ok(())
 }
 
+/// Runs bus-crew pings through `update_driver_location` for the driver whose bus
+/// ride registered `vehicle_no` on ride start, so route projection,
+/// `set_route_location` and detection see them and the bus shows up in
+/// `track_vehicles`. The crew is not the driver: `LocationSource::BusCrewApp`
+/// leaves the driver's rate limit, last known location and GEO bucket alone.
+/// Failures are only logged: the pings have already been forwarded to Kafka.
+async fn track_bus_crew_locations(
+    data: Data<AppState>,
+    vehicle_no: &str,
+    locations: Vec<UpdateDriverLocationRequest>,
+) {
+    let driver_info = match get_driver_info_by_plate(&data.redis, vehicle_no).await {
+        Ok(Some(driver_info)) => driver_info,
+        Ok(None) => {
+            info!(
+                tag = "[Bus Crew Tracking]",
+                "No active bus ride on vehicle {}, skipping route tracking", vehicle_no
+            );
+            return;
+        }
+        Err(e) => {
+            error!(
+                tag = "[Bus Crew Tracking]",
+                "Failed to look up bus ride for vehicle {}: {:?}", vehicle_no, e
+            );
+            return;
+        }
+    };
+
+    if let Err(e) = update_driver_location(
+        DriverId(driver_info.driver_id),
+        MerchantId(driver_info.merchant_id),
+        driver_info.vehicle_service_tier_type,
+        data,
+        locations,
+        DriverMode::ONLINE,
+        driver_info.group_id,
+        LocationSource::BusCrewApp,
+    )
+    .await
+    {
+        warn!(
+            tag = "[Bus Crew Tracking]",
+            "Route tracking failed for vehicle {}: {:?}", vehicle_no, e
+        );
+    }
+}
+
 #[macros::measure_duration]
 fn get_filtered_driver_locations(
     last_known_location: Option<&DriverLastKnownLocation>,
@@ -274,12 +350,210 @@ fn get_filtered_driver_locations(
     )
 }
 
+/// Sorts a batch by `ts`, keeps one point per timestamp and splits it at the
+/// driver's last known timestamp. Points at or after it are live and go
+/// through `process_driver_locations`; earlier ones arrived late (offline
+/// backfill, retried batch) and are returned separately.
+fn split_late_driver_locations(
+    mut locations: Vec<UpdateDriverLocationRequest>,
+    driver_location_details: Option<&DriverAllDetails>,
+) -> (
+    Vec<UpdateDriverLocationRequest>,
+    Vec<UpdateDriverLocationRequest>,
+) {
+    locations.sort_by_key(|location| location.ts);
+    locations.dedup_by_key(|location| location.ts);
+
+    let last_known_ts = driver_location_details.map(|driver_location_details| {
+        driver_location_details.driver_last_known_location.timestamp
+    });
+    locations.into_iter().partition(|location| {
+        last_known_ts
+            .map(|last_known_ts| location.ts >= last_known_ts)
+            .unwrap_or(true)
+    })
+}
+
+/// Appends points to the on-ride trace, flushing the trace to the bulk
+/// location callback once it would exceed `batch_size`. Flushed points are
+/// sent in `ts` order, so late points land where they belong for distance
+/// calculation, and the latest `ts` sent is recorded for
+/// `append_late_driver_locations`.
+async fn append_on_ride_driver_locations(
+    data: &Data<AppState>,
+    ride_id: &RideId,
+    driver_id: &DriverId,
+    merchant_id: &MerchantId,
+    geo_entries: Vec<LocationUpdate>,
+) -> Result<(), AppError> {
+    let on_ride_driver_locations_count = redis_migration_read!(
+        data.redis_migration,
+        "on_ride_locations_count",
+        &data.redis,
+        |redis| get_on_ride_driver_locations_count(&redis, driver_id, merchant_id)
+    )?;
+
+    if on_ride_driver_locations_count + geo_entries.len() as i64 > data.batch_size {
+        let mut on_ride_driver_locations = redis_migration_write!(
+            data.redis_migration,
+            "on_ride_locations",
+            &data.redis,
+            |redis| get_on_ride_driver_locations_and_delete(
+                &redis,
+                driver_id,
+                merchant_id,
+                on_ride_driver_locations_count,
+            )
+        )?;
+        on_ride_driver_locations.extend(geo_entries);
+        on_ride_driver_locations.sort_by_key(|location| location.ts);
+        let flushed_till = on_ride_driver_locations
+            .iter()
+            .filter_map(|location| location.ts)
+            .max();
+
+        bulk_location_update_dobpp(
+            &data.bulk_location_callback_url,
+            ride_id.to_owned(),
+            driver_id.to_owned(),
+            on_ride_driver_locations,
+            false,
+        )
+        .await
+        .map_err(|err| AppError::DriverBulkLocationUpdateFailed(err.message()))?;
+
+        if let Some(flushed_till) = flushed_till {
+            redis_migration_write!(
+                data.redis_migration,
+                "on_ride_trace_flushed_till",
+                &data.redis,
+                |redis| set_on_ride_trace_flushed_till(
+                    &redis,
+                    &data.redis_expiry,
+                    ride_id,
+                    flushed_till
+                )
+            )?;
+        }
+    } else {
+        redis_migration_write!(
+            data.redis_migration,
+            "on_ride_locations",
+            &data.redis,
+            |redis| push_on_ride_driver_locations(
+                &redis,
+                driver_id,
+                merchant_id,
+                geo_entries.to_owned(),
+                &data.redis_expiry,
+            )
+        )?;
+    }
+    Ok(())
+}
+
+/// Points of a late batch that belong on a ride's trace: accurate enough and,
+/// when the ride start is known, not older than it.
+pub fn late_ride_trace_points(
+    late_locations: Vec<UpdateDriverLocationRequest>,
+    ride_started_at: Option<TimeStamp>,
+    min_location_accuracy: Accuracy,
+) -> Vec<LocationUpdate> {
+    late_locations
+        .into_iter()
+        .filter(|location| location.acc.unwrap_or(Accuracy(0.0)) <= min_location_accuracy)
+        .filter(|location| ride_started_at.map_or(true, |started_at| location.ts >= started_at))
+        .map(|location| LocationUpdate {
+            lat: location.pt.lat,
+            lon: location.pt.lon,
+            ts: Some(location.ts.0.timestamp()),
+        })
+        .collect()
+}
+
+/// Late points only extend the trace of an in-progress ride, so they count
+/// towards ride distance. They never move the live location or feed
+/// notifications, stop detection or violation detection. Called under the
+/// driver's processing lock, so it cannot interleave with a live batch
+/// flushing the same trace.
+async fn append_late_driver_locations(
+    data: &Data<AppState>,
+    driver_ride_details: Option<&RideDetails>,
+    driver_id: &DriverId,
+    merchant_id: &MerchantId,
+    city: &CityName,
+    late_locations: Vec<UpdateDriverLocationRequest>,
+) -> Result<(), AppError> {
+    let (ride_id, ride_started_at) = match driver_ride_details {
+        Some(RideDetails {
+            ride_id,
+            ride_status: RideStatus::INPROGRESS,
+            started_at,
+            ..
+        }) => (ride_id, *started_at),
+        _ => return Ok(()),
+    };
+
+    let min_location_accuracy =
+        resolve_config_field(&data.config_overrides, merchant_id, city, |config| {
+            config.min_location_accuracy
+        })
+        .await
+        .unwrap_or(data.min_location_accuracy);
+
+    let geo_entries =
+        late_ride_trace_points(late_locations, ride_started_at, min_location_accuracy);
+
+    if geo_entries.is_empty() {
+        return Ok(());
+    }
+
+    // Points older than a batch already sent can no longer be sorted into it;
+    // they are sent on their own, flagged late.
+    let flushed_till = redis_migration_read!(
+        data.redis_migration,
+        "on_ride_trace_flushed_till",
+        &data.redis,
+        |redis| get_on_ride_trace_flushed_till(&redis, ride_id)
+    )?;
+    let (before_flushed, geo_entries): (Vec<LocationUpdate>, Vec<LocationUpdate>) =
+        geo_entries.into_iter().partition(|location| {
+            flushed_till.is_some_and(|flushed_till| location.ts.is_some_and(|ts| ts < flushed_till))
+        });
+    if !before_flushed.is_empty() {
+        LATE_LOCATION_UPDATES.inc_by(before_flushed.len() as u64);
+        bulk_location_update_dobpp(
+            &data.bulk_location_callback_url,
+            ride_id.to_owned(),
+            driver_id.to_owned(),
+            before_flushed,
+            true,
+        )
+        .await
+        .map_err(|err| AppError::DriverBulkLocationUpdateFailed(err.message()))?;
+    }
+    if geo_entries.is_empty() {
+        return Ok(());
+    }
+
+    info!(
+        tag = "[Late Location Updates]",
+        "Appending {} late points to the trace of ride {:?} for driver {:?}",
+        geo_entries.len(),
+        ride_id,
+        driver_id
+    );
+    LATE_LOCATION_UPDATES.inc_by(geo_entries.len() as u64);
+
+    append_on_ride_driver_locations(data, ride_id, driver_id, merchant_id, geo_entries).await
+}
+
 #[macros::measure_duration]
 pub async fn update_driver_location_by_token(
     token: Token,
     vehicle_type: VehicleType,
     data: Data<AppState>,
-    mut locations: Vec<UpdateDriverLocationRequest>,
+    locations: Vec<UpdateDriverLocationRequest>,
     driver_mode: DriverMode,
     group_id: Option<String>,
     group_id2: Option<String>,
@@ -311,13 +585,12 @@ pub async fn update_driver_location_by_token(
         );
     }
 
-    locations.sort_by(|a, b| {
-        let TimeStamp(a_ts) = a.ts;
-        let TimeStamp(b_ts) = b.ts;
-        (a_ts).cmp(&b_ts)
-    });
-
-    let driver_location_details = get_driver_location(&data.redis, &driver_id).await?;
+    let driver_location_details = redis_migration_read!(
+        data.redis_migration,
+        "driver_details",
+        &data.redis,
+        |redis| get_driver_location(&redis, &driver_id)
+    )?;
 
     if let Some(driver_location_details) = driver_location_details.as_ref() {
         let curr_time = TimeStamp(Utc::now());
@@ -327,20 +600,13 @@ pub async fn update_driver_location_by_token(
         }
     }
 
-    let locations: Vec<UpdateDriverLocationRequest> = locations
-        .into_iter()
-        .filter(|location| {
-            driver_location_details
-                .as_ref()
-                .map(|driver_location_details| {
-                    location.ts >= driver_location_details.driver_last_known_location.timestamp
-                })
-                .unwrap_or(true)
-        })
-        .collect();
+    let (locations, late_locations) =
+        split_late_driver_locations(locations, driver_location_details.as_ref());
 
-    let latest_driver_location = if let Some(location) = locations.last() {
-        location.to_owned()
+    // Late points are appended under the same rate limit and lock as live
+    // ones, so a batch of only late points still resolves a city.
+    let city_location = if let Some(location) = locations.last().or(late_locations.last()) {
+        location.pt.to_owned()
     } else {
         return Ok(HttpResponse::Ok().finish());
     };
@@ -350,16 +616,19 @@ pub async fn update_driver_location_by_token(
         "Got location updates for Driver Id : {:?} : {:?}", &driver_id, &locations
     );
 
-    let city = get_city(
-        &latest_driver_location.pt.lat,
-        &latest_driver_location.pt.lon,
-        &data.polygon,
-    )?;
+    let city = get_city(&city_location.lat, &city_location.lon, &data.polygon)?;
+
+    let location_update_limit =
+        resolve_config_field(&data.config_overrides, &merchant_id, &city, |config| {
+            config.location_update_limit
+        })
+        .await
+        .unwrap_or(data.location_update_limit);
 
     sliding_window_limiter(
         &data.redis,
         &sliding_rate_limiter_key(&driver_id, &city, &merchant_id),
-        data.location_update_limit,
+        location_update_limit,
         data.location_update_interval as u32,
     )
     .await?;
@@ -372,7 +641,7 @@ pub async fn update_driver_location_by_token(
         (
             data.clone(),
             locations,
-            latest_driver_location,
+            late_locations,
             TimeStamp(current_ts),
             driver_location_details,
             driver_id,
@@ -383,23 +652,90 @@ pub async fn update_driver_location_by_token(
             driver_mode,
             group_id,
             group_id2,
+            LocationSource::DriverApp,
         ),
     )
     .await?;
     Ok(HttpResponse::Ok().finish())
 }
 
+/// Hands a location to the drainer: the partitioned drainer stream when one is
+/// configured, the in-process drainer otherwise.
+async fn send_to_drainer(data: &AppState, entry: DrainerEntry) -> Result<(), AppError> {
+    match data.drainer_stream.as_ref() {
+        Some(drainer_stream) => {
+            let result = publish_drainer_entry(&data.redis, drainer_stream, &entry).await;
+            DRAINER_STREAM_ENTRIES
+                .with_label_values(&[if result.is_ok() {
+                    "published"
+                } else {
+                    "publish_failed"
+                }])
+                .inc();
+            result
+        }
+        None => {
+            let result = send_to_local_drainer(data, entry).await;
+            DRAINER_CHANNEL_OCCUPANCY
+                .set((data.sender.max_capacity() - data.sender.capacity()) as i64);
+            result
+        }
+    }
+}
+
+/// Sends to the in-process drainer, applying the configured full-channel policy
+/// instead of always waiting for room.
+async fn send_to_local_drainer(data: &AppState, entry: DrainerEntry) -> Result<(), AppError> {
+    let policy = data.drainer_backpressure.full_channel_policy;
+    let entry = if policy == DrainerFullChannelPolicy::Coalesce {
+        match data.drainer_overflow.push_if_active(entry) {
+            Some(entry) => entry,
+            None => {
+                DRAINER_BACKPRESSURE.with_label_values(&["coalesced"]).inc();
+                return Ok(());
+            }
+        }
+    } else {
+        entry
+    };
+
+    match data.sender.try_send(entry) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(entry)) => match policy {
+            DrainerFullChannelPolicy::Wait => {
+                DRAINER_BACKPRESSURE.with_label_values(&["waited"]).inc();
+                data.sender
+                    .send(entry)
+                    .await
+                    .map_err(|err| AppError::DrainerPushFailed(err.to_string()))
+            }
+            DrainerFullChannelPolicy::Coalesce => {
+                DRAINER_BACKPRESSURE.with_label_values(&["coalesced"]).inc();
+                data.drainer_overflow.push(entry);
+                Ok(())
+            }
+            DrainerFullChannelPolicy::Drop => {
+                DRAINER_BACKPRESSURE.with_label_values(&["dropped"]).inc();
+                Ok(())
+            }
+        },
+        Err(err @ TrySendError::Closed(_)) => Err(AppError::DrainerPushFailed(err.to_string())),
+    }
+}
+
 /// Simplified version for external GPS providers that already have driver_id
 /// No token authentication needed
 #[macros::measure_duration]
+#[allow(clippy::too_many_arguments)]
 pub async fn update_driver_location(
     driver_id: DriverId,
     merchant_id: MerchantId,
     vehicle_type: VehicleType,
     data: Data<AppState>,
-    mut locations: Vec<UpdateDriverLocationRequest>,
+    locations: Vec<UpdateDriverLocationRequest>,
     driver_mode: DriverMode,
     group_id: Option<String>,
+    location_source: LocationSource,
 ) -> Result<HttpResponse, AppError> {
     let current_ts = Utc::now();
 
@@ -414,13 +750,12 @@ pub async fn update_driver_location(
         );
     }
 
-    locations.sort_by(|a, b| {
-        let TimeStamp(a_ts) = a.ts;
-        let TimeStamp(b_ts) = b.ts;
-        (a_ts).cmp(&b_ts)
-    });
-
-    let driver_location_details = get_driver_location(&data.redis, &driver_id).await?;
+    let driver_location_details = redis_migration_read!(
+        data.redis_migration,
+        "driver_details",
+        &data.redis,
+        |redis| get_driver_location(&redis, &driver_id)
+    )?;
 
     if let Some(driver_location_details) = driver_location_details.as_ref() {
         let curr_time = TimeStamp(Utc::now());
@@ -430,20 +765,13 @@ pub async fn update_driver_location(
         }
     }
 
-    let locations: Vec<UpdateDriverLocationRequest> = locations
-        .into_iter()
-        .filter(|location| {
-            driver_location_details
-                .as_ref()
-                .map(|driver_location_details| {
-                    location.ts >= driver_location_details.driver_last_known_location.timestamp
-                })
-                .unwrap_or(true)
-        })
-        .collect();
+    let (locations, late_locations) =
+        split_late_driver_locations(locations, driver_location_details.as_ref());
 
-    let latest_driver_location = if let Some(location) = locations.last() {
-        location.to_owned()
+    // Late points are appended under the same rate limit and lock as live
+    // ones, so a batch of only late points still resolves a city.
+    let city_location = if let Some(location) = locations.last().or(late_locations.last()) {
+        location.pt.to_owned()
     } else {
         return Ok(HttpResponse::Ok().finish());
     };
@@ -453,19 +781,25 @@ pub async fn update_driver_location(
         "Got location updates for Driver Id : {:?} : {:?}", &driver_id, &locations
     );
 
-    let city = get_city(
-        &latest_driver_location.pt.lat,
-        &latest_driver_location.pt.lon,
-        &data.polygon,
-    )?;
+    let city = get_city(&city_location.lat, &city_location.lon, &data.polygon)?;
 
-    sliding_window_limiter(
-        &data.redis,
-        &sliding_rate_limiter_key(&driver_id, &city, &merchant_id),
-        data.location_update_limit,
-        data.location_update_interval as u32,
-    )
-    .await?;
+    // Bus crew pings are rate limited per crew member before they get here.
+    if location_source.updates_driver_state() {
+        let location_update_limit =
+            resolve_config_field(&data.config_overrides, &merchant_id, &city, |config| {
+                config.location_update_limit
+            })
+            .await
+            .unwrap_or(data.location_update_limit);
+
+        sliding_window_limiter(
+            &data.redis,
+            &sliding_rate_limiter_key(&driver_id, &city, &merchant_id),
+            location_update_limit,
+            data.location_update_interval as u32,
+        )
+        .await?;
+    }
 
     with_lock_redis(
         &data.redis,
@@ -475,7 +809,7 @@ pub async fn update_driver_location(
         (
             data.clone(),
             locations,
-            latest_driver_location,
+            late_locations,
             TimeStamp(current_ts),
             driver_location_details,
             driver_id,
@@ -486,6 +820,7 @@ pub async fn update_driver_location(
             driver_mode,
             group_id,
             None, // group_id2
+            location_source,
         ),
     )
     .await?;
@@ -498,12 +833,22 @@ pub async fn update_driver_location(
 /// its TTL gives them a recovery window where coming back online preserves
 /// their original rank via the drainer's stored-ts ZADD path. All steps are
 /// best-effort: failures are logged but don't propagate.
-async fn handle_driver_offline_queue_cleanup(
-    redis: &RedisConnectionPool,
+///
+/// Queue state lives on `queue_redis`; while migrating, the tracking is read
+/// from the serving pool and every write is mirrored.
+pub async fn handle_driver_offline_queue_cleanup(
+    redis_migration: &RedisMigration,
+    queue_redis: &Arc<RedisConnectionPool>,
     merchant_id: &str,
     driver_id: &str,
 ) {
-    let tracking = match get_driver_queue_tracking(redis, merchant_id, driver_id).await {
+    let tracking = match get_driver_queue_tracking(
+        &redis_migration.serving(queue_redis),
+        merchant_id,
+        driver_id,
+    )
+    .await
+    {
         Ok(t) => t,
         Err(err) => {
             error!(tag = "[Offline Queue Cleanup]", driver_id = %driver_id, error = %err);
@@ -516,27 +861,28 @@ async fn handle_driver_offline_queue_cleanup(
     let Some(tracking) = tracking else {
         return;
     };
-    if let Err(err) = remove_driver_from_queue(
-        redis,
-        &tracking.special_location_id,
-        &tracking.vehicle_type,
-        driver_id,
-    )
-    .await
-    {
+    if let Err(err) = redis_migration_write!(redis_migration, "queue_zset", queue_redis, |redis| {
+        remove_driver_from_queue(
+            &redis,
+            &tracking.special_location_id,
+            &tracking.vehicle_type,
+            driver_id,
+        )
+    }) {
         error!(tag = "[Offline Queue Cleanup ZREM]", driver_id = %driver_id, error = %err);
     }
-    if let Err(err) = delete_driver_queue_tracking(redis, merchant_id, driver_id).await {
+    if let Err(err) =
+        redis_migration_write!(redis_migration, "queue_tracking", queue_redis, |redis| {
+            delete_driver_queue_tracking(&redis, merchant_id, driver_id)
+        })
+    {
         error!(tag = "[Offline Queue Cleanup DEL]", driver_id = %driver_id, error = %err);
     }
-    if let Err(err) = append_rank_history_event(
-        redis,
-        merchant_id,
-        driver_id,
-        Utc::now().timestamp() as f64,
-        "exit:offline",
-    )
-    .await
+    let timestamp = Utc::now().timestamp() as f64;
+    if let Err(err) =
+        redis_migration_write!(redis_migration, "rank_history", queue_redis, |redis| {
+            append_rank_history_event(&redis, merchant_id, driver_id, timestamp, "exit:offline")
+        })
     {
         error!(tag = "[Offline Queue Cleanup History]", driver_id = %driver_id, error = %err);
     }
@@ -551,7 +897,7 @@ async fn process_driver_locations(
     args: (
         Data<AppState>,
         Vec<UpdateDriverLocationRequest>,
-        UpdateDriverLocationRequest,
+        Vec<UpdateDriverLocationRequest>,
         TimeStamp,
         Option<DriverAllDetails>,
         DriverId,
@@ -562,12 +908,13 @@ async fn process_driver_locations(
         DriverMode,
         Option<String>,
         Option<String>,
+        LocationSource,
     ),
 ) -> Result<(), AppError> {
     let (
         data,
         locations,
-        latest_driver_location,
+        late_locations,
         current_ts,
         driver_location_details,
         driver_id,
@@ -578,8 +925,53 @@ async fn process_driver_locations(
         driver_mode,
         group_id,
         group_id2,
+        location_source,
     ) = args;
 
+    let driver_ride_details =
+        redis_migration_read!(data.redis_migration, "ride_details", &data.redis, |redis| {
+            get_ride_details(&redis, &driver_id, &merchant_id)
+        })?;
+
+    // Pings that are not the driver's own only track the bus ride's vehicle.
+    let updates_driver_state = location_source.updates_driver_state();
+    if !updates_driver_state
+        && !matches!(
+            driver_ride_details
+                .as_ref()
+                .and_then(|ride_details| ride_details.ride_info.as_ref()),
+            Some(RideInfo::Bus { .. })
+        )
+    {
+        return Ok(());
+    }
+
+    if updates_driver_state && !late_locations.is_empty() {
+        if let Err(err) = append_late_driver_locations(
+            &data,
+            driver_ride_details.as_ref(),
+            &driver_id,
+            &merchant_id,
+            &city,
+            late_locations,
+        )
+        .await
+        {
+            error!(
+                tag = "[Late Location Updates]",
+                "Failed to append late points for driver {:?}: {}",
+                driver_id,
+                err.message()
+            );
+        }
+    }
+
+    let latest_driver_location = if let Some(location) = locations.last() {
+        location.to_owned()
+    } else {
+        return Ok(());
+    };
+
     // OFFLINE pings are a state-change signal: evict the driver from any
     // queue they were sitting in (last_ts retained for grace re-entry). Done
     // before the drainer push so we don't race a same-tick Enter that would
@@ -587,11 +979,15 @@ async fn process_driver_locations(
     // OFFLINE driver shouldn't appear in nearby-driver buckets either.
     let is_offline = matches!(driver_mode, DriverMode::OFFLINE);
     if is_offline {
-        handle_driver_offline_queue_cleanup(&data.redis, &merchant_id.0, &driver_id.0).await;
+        handle_driver_offline_queue_cleanup(
+            &data.redis_migration,
+            &data.queue_redis(),
+            &merchant_id.0,
+            &driver_id.0,
+        )
+        .await;
     }
 
-    let driver_ride_details = get_ride_details(&data.redis, &driver_id, &merchant_id).await?;
-
     let driver_ride_id = driver_ride_details
         .as_ref()
         .map(|ride_details| ride_details.ride_id.to_owned());
@@ -638,6 +1034,46 @@ async fn process_driver_locations(
 
     let base_vehicle_type = get_base_vehicle_type(&vehicle_type);
 
+    let config_override =
+        resolve_config_override(&data.config_overrides, &merchant_id, &city).await;
+    let pickup_notification_threshold = config_override
+        .pickup_notification_threshold
+        .unwrap_or(data.pickup_notification_threshold);
+    let arriving_notification_threshold = config_override
+        .arriving_notification_threshold
+        .unwrap_or(data.arriving_notification_threshold);
+    let pickup_instruction_notification_threshold = config_override
+        .pickup_instruction_notification_threshold
+        .unwrap_or(data.pickup_instruction_notification_threshold);
+    let driver_location_accuracy_buffer = config_override
+        .driver_location_accuracy_buffer
+        .unwrap_or(data.driver_location_accuracy_buffer);
+    let driver_reached_destination_buffer = config_override
+        .driver_reached_destination_buffer
+        .unwrap_or(data.driver_reached_destination_buffer);
+    let driver_source_departed_buffer = config_override
+        .driver_source_departed_buffer
+        .unwrap_or(data.driver_source_departed_buffer);
+    let min_location_accuracy = config_override
+        .min_location_accuracy
+        .unwrap_or(data.min_location_accuracy);
+    let blacklist_merchants = config_override
+        .blacklist_merchants
+        .as_ref()
+        .unwrap_or(&data.blacklist_merchants);
+    let detection_violation_config_map = config_override
+        .detection_violation_config
+        .as_deref()
+        .unwrap_or(&data.detection_violation_config);
+    let detection_anti_violation_config_map = config_override
+        .detection_anti_violation_config
+        .as_deref()
+        .unwrap_or(&data.detection_anti_violation_config);
+    let stop_detection_config_map = config_override
+        .stop_detection
+        .as_deref()
+        .unwrap_or(&data.stop_detection);
+
     let route = if let Some(RideInfo::Bus { route_code, .. }) = driver_ride_info.as_ref() {
         data.routes.read().await.get(route_code).cloned()
     } else {
@@ -702,8 +1138,6 @@ async fn process_driver_locations(
                 ),
                  detection_type| {
                     let context = DetectionContext {
-                        ride_id: ride_id.clone(),
-                        driver_id: driver_id.clone(),
                         location: latest_driver_location.pt.clone(),
                         timestamp: latest_driver_location_ts.clone(),
                         speed: latest_driver_location
@@ -711,27 +1145,28 @@ async fn process_driver_locations(
                             .map(|v| SpeedInMeterPerSecond(v.inner())),
                         ride_status: ride_status.to_owned(),
                         ride_info: driver_ride_info.clone(),
-                        vehicle_type: vehicle_type.to_owned(),
+                        vehicle_type: Some(vehicle_type.to_owned()),
                         accuracy: latest_driver_location.acc.unwrap_or(Accuracy(0.0)),
                         route: route.as_ref(),
                         ride_stops: ride_stops,
+                        counterpart_location: None,
                     };
 
                     if let (
                         Some(detection_violation_config),
                         Some(detection_anti_violation_config),
                     ) = (
-                        data.detection_violation_config
+                        detection_violation_config_map
                             .get(&base_vehicle_type)
                             .and_then(|ride_status_map| ride_status_map.get(&ride_status))
                             .and_then(|inner_map| inner_map.get(&detection_type)),
-                        data.detection_anti_violation_config
+                        detection_anti_violation_config_map
                             .get(&base_vehicle_type)
                             .and_then(|ride_status_map| ride_status_map.get(&ride_status))
                             .and_then(|inner_map| inner_map.get(&detection_type)),
                     ) {
                         // Skip detection if accuracy is poor
-                        if context.accuracy > data.min_location_accuracy {
+                        if context.accuracy > min_location_accuracy {
                             return (
                                 detection_violation_state_map,
                                 detection_anti_violation_state_map,
@@ -768,12 +1203,21 @@ async fn process_driver_locations(
                             if detection_violation_config.enabled
                                 && detection_anti_violation_config.enabled
                             {
-                                if let Some(violation_detection_request) = violation_detection_req {
+                                if let Some(DetectionOutcome {
+                                    is_violated,
+                                    detection_data,
+                                }) = violation_detection_req
+                                {
                                     violation_detection_requests.push((
                                         detection_violation_config
                                             .detection_callback_url
                                             .to_owned(),
-                                        violation_detection_request,
+                                        ViolationDetectionReq {
+                                            ride_id: ride_id.clone(),
+                                            driver_id: driver_id.clone(),
+                                            is_violated,
+                                            detection_data,
+                                        },
                                     ))
                                 }
                             }
@@ -798,7 +1242,7 @@ async fn process_driver_locations(
     };
 
     let (stop_detected, stop_detection) =
-        if let Some(stop_detection_config) = data.stop_detection.get(&base_vehicle_type) {
+        if let Some(stop_detection_config) = stop_detection_config_map.get(&base_vehicle_type) {
             let stop_detection_config = match driver_ride_status {
                 Some(RideStatus::NEW) => stop_detection_config.get(&RideStatus::NEW),
                 Some(RideStatus::INPROGRESS) => stop_detection_config.get(&RideStatus::INPROGRESS),
@@ -851,16 +1295,16 @@ async fn process_driver_locations(
                     if std::cmp::max(
                         std::cmp::max(
                             driver_pickup_distance.inner().saturating_sub(50),
-                            data.pickup_notification_threshold as u32,
+                            pickup_notification_threshold as u32,
                         ),
                         std::cmp::max(
-                            data.arriving_notification_threshold as u32,
-                            data.pickup_instruction_notification_threshold as u32,
+                            arriving_notification_threshold as u32,
+                            pickup_instruction_notification_threshold as u32,
                         ),
                     ) as f64
                         > pickup_distance
                     {
-                        if pickup_distance <= data.pickup_notification_threshold
+                        if pickup_distance <= pickup_notification_threshold
                             && RideNotificationStatus::DriverReached > ride_notification_status
                             && get_distance_matrix(
                                 &[pickup_location.to_owned()],
@@ -873,7 +1317,7 @@ async fn process_driver_locations(
                                     .get(0)
                                     .and_then(|distances| {
                                         distances.get(0).map(|distance| {
-                                            distance <= &data.pickup_notification_threshold
+                                            distance <= &pickup_notification_threshold
                                         })
                                     })
                                     .unwrap_or(true)
@@ -885,7 +1329,7 @@ async fn process_driver_locations(
                                 true,
                                 Some(driver_pickup_distance),
                             );
-                        } else if pickup_distance <= data.arriving_notification_threshold
+                        } else if pickup_distance <= arriving_notification_threshold
                             && RideNotificationStatus::DriverReaching > ride_notification_status
                         {
                             return (
@@ -893,7 +1337,7 @@ async fn process_driver_locations(
                                 true,
                                 Some(driver_pickup_distance),
                             );
-                        } else if pickup_distance <= data.pickup_instruction_notification_threshold
+                        } else if pickup_distance <= pickup_instruction_notification_threshold
                             && RideNotificationStatus::DriverPickupInstruction
                                 > ride_notification_status
                         {
@@ -936,6 +1380,11 @@ async fn process_driver_locations(
     }
     .await;
 
+    let driver_ride_notification_state = DriverRideNotificationState {
+        ride_notification_status: driver_ride_notification_status,
+        driver_pickup_distance,
+    };
+
     let (locations, upcoming_stops_with_eta) = match driver_ride_info.as_ref() {
         Some(RideInfo::Bus {
             route_code,
@@ -946,7 +1395,7 @@ async fn process_driver_locations(
                 Vec::new();
 
             let is_blacklist_for_bus_depot = latest_driver_location.acc.is_some_and(|acc| {
-                acc.inner() < data.driver_location_accuracy_buffer
+                acc.inner() < driver_location_accuracy_buffer
                     && is_within_polygon(
                         &latest_driver_location.pt.lat,
                         &latest_driver_location.pt.lon,
@@ -954,6 +1403,26 @@ async fn process_driver_locations(
                     )
             });
 
+            // With fusion configured, only the selected source for this bus publishes
+            // its route location; the others keep what that source last published.
+            let publishes_route_location = match data.vehicle_location_fusion.as_ref() {
+                Some(fusion_config) => {
+                    fuse_vehicle_location(
+                        &data.redis,
+                        fusion_config,
+                        &bus_number,
+                        location_source,
+                        VehicleSourceSample {
+                            location: latest_driver_location.pt.to_owned(),
+                            timestamp: latest_driver_location_ts,
+                            accuracy: latest_driver_location.acc,
+                        },
+                    )
+                    .await?
+                }
+                None => true,
+            };
+
             let vehicle_route_location =
                 get_route_location_by_vehicle_number(&data.redis, &route_code, &bus_number).await?;
 
@@ -962,7 +1431,7 @@ async fn process_driver_locations(
                 .map(|vehicle_route_location| vehicle_route_location.upcoming_stops.to_owned())
                 .flatten();
 
-            let upcoming_stops = if !is_blacklist_for_bus_depot {
+            let upcoming_stops = if publishes_route_location && !is_blacklist_for_bus_depot {
                 route.as_ref().and_then(|route| {
                     get_upcoming_stops_by_route_code(
                         prev_upcoming_stops_with_eta.to_owned(),
@@ -975,7 +1444,9 @@ async fn process_driver_locations(
                 None
             };
 
-            let upcoming_stops_with_eta = if !is_blacklist_for_bus_depot {
+            let upcoming_stops_with_eta = if !publishes_route_location {
+                prev_upcoming_stops_with_eta
+            } else if !is_blacklist_for_bus_depot {
                 let upcoming_stops_with_eta = if let Some(upcoming_stops) = upcoming_stops.as_ref()
                 {
                     estimated_upcoming_stops_eta(prev_upcoming_stops_with_eta, upcoming_stops)
@@ -993,6 +1464,7 @@ async fn process_driver_locations(
                         &latest_driver_location_ts,
                         driver_ride_status.to_owned(),
                         upcoming_stops_with_eta,
+                        location_source,
                     )
                     .await?;
                     Ok(())
@@ -1012,37 +1484,58 @@ async fn process_driver_locations(
                 None
             };
 
-            let set_driver_last_location_update = async {
-                set_driver_last_location_update(
+            let driver_location_state = DriverLocationState {
+                driver_last_known_location: DriverLastKnownLocation {
+                    location: latest_driver_location.pt.to_owned(),
+                    timestamp: latest_driver_location_ts,
+                    merchant_id: merchant_id.to_owned(),
+                    bear: None,
+                    vehicle_type: Some(vehicle_type),
+                    group_id: group_id.to_owned(),
+                    group_id2: group_id2.to_owned(),
+                },
+                stop_detection,
+                ride_status: driver_ride_status.to_owned(),
+                group_id: group_id.to_owned(),
+            };
+            let driver_detection_state = DriverDetectionState {
+                violation_trigger_flag: Some(violation_trigger_flag),
+                detection_state: Some(detection_state),
+                anti_detection_state: Some(anti_detection_state),
+            };
+            let (changed_ride_notification_state, changed_detection_state) =
+                DriverAllDetails::changed_ping_state(
+                    driver_location_details.as_ref(),
+                    &driver_ride_notification_state,
+                    &driver_detection_state,
+                );
+            let set_driver_ping_state = async {
+                redis_migration_write!(
+                    data.redis_migration,
+                    "driver_details",
                     &data.redis,
-                    &data.last_location_timstamp_expiry,
-                    &driver_id,
-                    &merchant_id,
-                    &latest_driver_location.pt,
-                    &latest_driver_location_ts,
-                    &None,
-                    stop_detection,
-                    &driver_ride_status,
-                    &driver_ride_notification_status,
-                    &Some(detection_state),
-                    &Some(anti_detection_state),
-                    &Some(violation_trigger_flag),
-                    &driver_pickup_distance,
-                    &None,
-                    &Some(vehicle_type.clone()),
-                    &group_id,
-                    &group_id2,
-                )
-                .await?;
+                    |redis| set_driver_ping_state(
+                        &redis,
+                        &data.last_location_timstamp_expiry,
+                        &data.redis_expiry,
+                        &data.driver_details_encoding,
+                        &driver_id,
+                        &driver_location_state,
+                        changed_ride_notification_state,
+                        changed_detection_state,
+                    )
+                )?;
                 Ok(())
             };
-            all_tasks.push(Box::pin(set_driver_last_location_update));
+            if updates_driver_state {
+                all_tasks.push(Box::pin(set_driver_ping_state));
+            }
 
-            if !is_offline {
+            if updates_driver_state && !is_offline {
                 let send_driver_location_to_drainer = async {
-                    let _ = &data
-                        .sender
-                        .send((
+                    send_to_drainer(
+                        &data,
+                        (
                             Dimensions {
                                 merchant_id: merchant_id.to_owned(),
                                 city: city.to_owned(),
@@ -1055,10 +1548,9 @@ async fn process_driver_locations(
                             current_ts,
                             latest_driver_location_ts.to_owned(),
                             driver_id.to_owned(),
-                        ))
-                        .await
-                        .map_err(|err| AppError::DrainerPushFailed(err.to_string()))?;
-                    Ok(())
+                        ),
+                    )
+                    .await
                 };
                 all_tasks.push(Box::pin(send_driver_location_to_drainer));
             }
@@ -1081,7 +1573,7 @@ async fn process_driver_locations(
                 Vec::new();
 
             // TODO: When the new special location API is released, remove this old blacklist logic and use the new implementation (see SPECIAL_LOCATION_DRIVERS_PLAN.md).
-            let is_blacklist_for_special_zone = data.blacklist_merchants.contains(&merchant_id)
+            let is_blacklist_for_special_zone = blacklist_merchants.contains(&merchant_id)
                 && is_within_polygon(
                     &latest_driver_location.pt.lat,
                     &latest_driver_location.pt.lon,
@@ -1090,9 +1582,9 @@ async fn process_driver_locations(
 
             if !is_blacklist_for_special_zone && !is_offline {
                 let send_driver_location_to_drainer = async {
-                    let _ = &data
-                        .sender
-                        .send((
+                    send_to_drainer(
+                        &data,
+                        (
                             Dimensions {
                                 merchant_id: merchant_id.to_owned(),
                                 city: city.to_owned(),
@@ -1105,10 +1597,9 @@ async fn process_driver_locations(
                             current_ts,
                             latest_driver_location_ts.to_owned(),
                             driver_id.to_owned(),
-                        ))
-                        .await
-                        .map_err(|err| AppError::DrainerPushFailed(err.to_string()))?;
-                    Ok(())
+                        ),
+                    )
+                    .await
                 };
                 all_tasks.push(Box::pin(send_driver_location_to_drainer));
             }
@@ -1119,8 +1610,8 @@ async fn process_driver_locations(
                     ..
                 }) => min_distance_between_two_points
                     .map(|x| x as f64)
-                    .unwrap_or(data.driver_location_accuracy_buffer),
-                _ => data.driver_location_accuracy_buffer,
+                    .unwrap_or(driver_location_accuracy_buffer),
+                _ => driver_location_accuracy_buffer,
             };
 
             let (locations, any_location_unfiltered) =
@@ -1128,7 +1619,7 @@ async fn process_driver_locations(
                     let (locations, any_location_unfiltered) = get_filtered_driver_locations(
                         driver_last_known_location.as_ref(),
                         locations,
-                        data.min_location_accuracy,
+                        min_location_accuracy,
                         driver_location_accuracy_buffer_to_use,
                     );
                     if !locations.is_empty() {
@@ -1172,31 +1663,50 @@ async fn process_driver_locations(
                 }
             };
 
-            let set_driver_last_location_update = async {
-                set_driver_last_location_update(
+            let driver_location_state = DriverLocationState {
+                driver_last_known_location: DriverLastKnownLocation {
+                    location: driver_location.to_owned(),
+                    timestamp: *driver_location_timestamp,
+                    merchant_id: merchant_id.to_owned(),
+                    bear: latest_driver_location.bear,
+                    vehicle_type: Some(vehicle_type),
+                    group_id: group_id.to_owned(),
+                    group_id2: group_id2.to_owned(),
+                },
+                stop_detection,
+                ride_status: driver_ride_status.to_owned(),
+                group_id: group_id.to_owned(),
+            };
+            let driver_detection_state = DriverDetectionState {
+                violation_trigger_flag: Some(violation_trigger_flag),
+                detection_state: Some(detection_state),
+                anti_detection_state: Some(anti_detection_state),
+            };
+            let (changed_ride_notification_state, changed_detection_state) =
+                DriverAllDetails::changed_ping_state(
+                    driver_location_details.as_ref(),
+                    &driver_ride_notification_state,
+                    &driver_detection_state,
+                );
+            let set_driver_ping_state = async {
+                redis_migration_write!(
+                    data.redis_migration,
+                    "driver_details",
                     &data.redis,
-                    &data.last_location_timstamp_expiry,
-                    &driver_id,
-                    &merchant_id,
-                    driver_location,
-                    driver_location_timestamp,
-                    &None::<TimeStamp>,
-                    stop_detection,
-                    &driver_ride_status,
-                    &driver_ride_notification_status,
-                    &Some(detection_state),
-                    &Some(anti_detection_state),
-                    &Some(violation_trigger_flag),
-                    &driver_pickup_distance,
-                    &latest_driver_location.bear,
-                    &Some(vehicle_type.clone()),
-                    &group_id,
-                    &group_id2,
-                )
-                .await?;
+                    |redis| set_driver_ping_state(
+                        &redis,
+                        &data.last_location_timstamp_expiry,
+                        &data.redis_expiry,
+                        &data.driver_details_encoding,
+                        &driver_id,
+                        &driver_location_state,
+                        changed_ride_notification_state,
+                        changed_detection_state,
+                    )
+                )?;
                 Ok(())
             };
-            all_tasks.push(Box::pin(set_driver_last_location_update));
+            all_tasks.push(Box::pin(set_driver_ping_state));
 
             if any_location_unfiltered {
                 if let (Some(RideStatus::INPROGRESS), Some(ride_id)) =
@@ -1214,58 +1724,13 @@ async fn process_driver_locations(
                         })
                         .collect::<Vec<LocationUpdate>>();
 
-                    let on_ride_driver_locations_count = get_on_ride_driver_locations_count(
-                        &data.redis,
-                        &driver_id.clone(),
+                    all_tasks.push(Box::pin(append_on_ride_driver_locations(
+                        &data,
+                        ride_id,
+                        &driver_id,
                         &merchant_id,
-                    )
-                    .await?;
-
-                    if on_ride_driver_locations_count + geo_entries.len() as i64 > data.batch_size {
-                        let mut on_ride_driver_locations = get_on_ride_driver_locations_and_delete(
-                            &data.redis,
-                            &driver_id,
-                            &merchant_id,
-                            on_ride_driver_locations_count,
-                        )
-                        .await?;
-                        on_ride_driver_locations.extend(geo_entries);
-
-                        let bulk_location_update_dobpp = async {
-                            bulk_location_update_dobpp(
-                                &data.bulk_location_callback_url,
-                                ride_id.to_owned(),
-                                driver_id.to_owned(),
-                                on_ride_driver_locations
-                                    .into_iter()
-                                    .map(|dl| LocationUpdate {
-                                        lat: dl.lat,
-                                        lon: dl.lon,
-                                        ts: dl.ts,
-                                    })
-                                    .collect(),
-                            )
-                            .await
-                            .map_err(|err| {
-                                AppError::DriverBulkLocationUpdateFailed(err.message())
-                            })?;
-                            Ok(())
-                        };
-                        all_tasks.push(Box::pin(bulk_location_update_dobpp));
-                    } else {
-                        let push_on_ride_driver_locations = async {
-                            push_on_ride_driver_locations(
-                                &data.redis,
-                                &driver_id,
-                                &merchant_id,
-                                geo_entries,
-                                &data.redis_expiry,
-                            )
-                            .await?;
-                            Ok(())
-                        };
-                        all_tasks.push(Box::pin(push_on_ride_driver_locations));
-                    }
+                        geo_entries,
+                    )));
                 }
             }
 
@@ -1279,6 +1744,10 @@ async fn process_driver_locations(
     };
 
     Arbiter::current().spawn(async move {
+        let stop_detection_config_map = config_override
+            .stop_detection
+            .as_deref()
+            .unwrap_or(&data.stop_detection);
         match driver_ride_info {
             Some(RideInfo::Pilot { .. }) => {}
             Some(RideInfo::Bus {
@@ -1295,16 +1764,16 @@ async fn process_driver_locations(
                         ) {
                             if let Some(upcoming_stop_with_eta) = upcoming_stops_with_eta.first() {
                                 if latest_driver_location.acc.is_some_and(|Accuracy(acc)| {
-                                    acc < data.driver_location_accuracy_buffer
+                                    acc < driver_location_accuracy_buffer
                                 }) && distance_between_in_meters(
                                     &source,
                                     &latest_driver_location.pt,
-                                ) > data.driver_source_departed_buffer
+                                ) > driver_source_departed_buffer
                                     && upcoming_stop_with_eta
                                         .stop
                                         .distance_from_previous_intermediate_stop
                                         .inner() as f64
-                                        > data.driver_source_departed_buffer
+                                        > driver_source_departed_buffer
                                 {
                                     let _ = driver_source_departed(
                                         &data.driver_source_departed_callback_url,
@@ -1323,7 +1792,7 @@ async fn process_driver_locations(
                             (stop_detected.as_ref(), driver_ride_id.as_ref())
                         {
                             if distance_between_in_meters(&destination, &location)
-                                < data.driver_reached_destination_buffer
+                                < driver_reached_destination_buffer
                             {
                                 let _ = driver_reached_destination(
                                     &data.driver_reached_destination_callback_url,
@@ -1356,7 +1825,8 @@ async fn process_driver_locations(
                 if let (Some(location), Some(ride_id)) =
                     (stop_detected.as_ref(), driver_ride_id.as_ref())
                 {
-                    if let Some(stop_detection_config) = data.stop_detection.get(&base_vehicle_type)
+                    if let Some(stop_detection_config) =
+                        stop_detection_config_map.get(&base_vehicle_type)
                     {
                         let stop_detection_config = match driver_ride_status {
                             Some(RideStatus::NEW) => stop_detection_config.get(&RideStatus::NEW),
@@ -1405,7 +1875,8 @@ async fn process_driver_locations(
                 if let (Some(location), Some(ride_id)) =
                     (stop_detected.as_ref(), driver_ride_id.as_ref())
                 {
-                    if let Some(stop_detection_config) = data.stop_detection.get(&base_vehicle_type)
+                    if let Some(stop_detection_config) =
+                        stop_detection_config_map.get(&base_vehicle_type)
                     {
                         let stop_detection_config = match driver_ride_status {
                             Some(RideStatus::NEW) => stop_detection_config.get(&RideStatus::NEW),
@@ -1440,9 +1911,53 @@ async fn process_driver_locations(
                 })
         });
 
+        let availability = DriverAvailability::from_ping(&driver_mode, driver_ride_status.as_ref());
+
+        if data.driver_session.is_some() {
+            let pings: Vec<(Point, TimeStamp, bool)> = locations
+                .iter()
+                .map(|(location, location_type)| {
+                    (
+                        location.pt.to_owned(),
+                        location.ts.min(current_ts),
+                        *location_type == LocationType::UNFILTERED,
+                    )
+                })
+                .collect();
+            account_driver_session(&data, &merchant_id, &driver_id, availability, &pings).await;
+        }
+
+        // Queue membership is set by the drainer after the ping is pushed, so a
+        // driver entering or leaving a queue is picked up on their next ping.
+        if data.driver_availability_topic.is_some() {
+            let availability = match availability {
+                DriverAvailability::OnlineIdle
+                    if get_driver_queue_tracking(
+                        &data.redis_migration.serving(&data.queue_redis()),
+                        &merchant_id.0,
+                        &driver_id.0,
+                    )
+                    .await
+                    .is_ok_and(|tracking| tracking.is_some()) =>
+                {
+                    DriverAvailability::Queued
+                }
+                availability => availability,
+            };
+            transition_driver_availability(
+                &data,
+                &merchant_id,
+                &driver_id,
+                availability,
+                TransitionSource::Ping,
+            )
+            .await;
+        }
+
         kafka_stream_updates(
             &data.producer,
             &data.secondary_producer,
+            &data.kafka_sink,
             &data.driver_location_update_topic,
             locations,
             current_ts,
@@ -1478,9 +1993,13 @@ pub async fn track_driver_location(
                 "COMPLETED".to_string(),
             ))?;
 
-    let driver_location_details = get_driver_location(&data.redis, &driver_details.driver_id)
-        .await?
-        .ok_or(AppError::DriverLastKnownLocationNotFound)?;
+    let driver_location_details = redis_migration_read!(
+        data.redis_migration,
+        "driver_details",
+        &data.redis,
+        |redis| get_driver_location(&redis, &driver_details.driver_id)
+    )?
+    .ok_or(AppError::DriverLastKnownLocationNotFound)?;
 
     let delay_time = match driver_location_details.ride_status {
         Some(RideStatus::NEW) => {
@@ -1516,20 +2035,21 @@ pub async fn track_person_entity_location(
     entity_type: &str,
     entity_id: &str,
 ) -> Result<PersonLocationResponse, AppError> {
-    if person_type.is_bus_crew() {
-        return Err(AppError::InvalidRequest(
-            "bus crew (bus_conductor / bus_driver) is not tracked via entity location; \
-             they forward directly to Kafka"
-                .to_string(),
-        ));
-    }
-    let person_id_str = get_person_by_entity(&data.redis, entity_type, entity_id)
-        .await?
-        .ok_or(AppError::RiderLocationNotFound)?;
+    let person_id_str = redis_migration_read!(
+        data.redis_migration,
+        "entity_details",
+        &data.redis,
+        |redis| get_person_by_entity(&redis, person_type, entity_type, entity_id)
+    )?
+    .ok_or(AppError::RiderLocationNotFound)?;
     let person_id = PersonId(person_id_str);
-    let details = get_person_detail(&data.redis, person_type, &person_id)
-        .await?
-        .ok_or(AppError::RiderLocationNotFound)?;
+    let details = redis_migration_read!(
+        data.redis_migration,
+        "person_detail",
+        &data.redis,
+        |redis| get_person_detail(&redis, person_type, &person_id)
+    )?
+    .ok_or(AppError::RiderLocationNotFound)?;
     Ok(PersonLocationResponse {
         curr_point: details.last_known_location.location,
         last_update: details.last_known_location.timestamp,
@@ -1537,7 +2057,40 @@ pub async fn track_person_entity_location(
     })
 }
 
-/// Generic: update person location (batch). Rider-only for now; uses generic keys and entity_loc vector.
+/// Resolves the caller of the generic person APIs. Riders go through the BAP auth;
+/// drivers and bus crew share the driver auth, and their driver id is the person id.
+async fn get_person_id_from_authentication(
+    data: &AppState,
+    person_type: PersonType,
+    token: &Token,
+) -> Result<(PersonId, MerchantId, MerchantOperatingCityId), AppError> {
+    match person_type {
+        PersonType::Rider => {
+            get_rider_id_from_authentication(
+                &data.redis,
+                &data.rider_auth_url,
+                &data.rider_auth_api_key,
+                &data.rider_auth_token_expiry,
+                token,
+            )
+            .await
+        }
+        PersonType::Driver | PersonType::BusConductor | PersonType::BusDriver => {
+            let (DriverId(driver_id), merchant_id, merchant_operating_city_id) =
+                get_driver_id_from_authentication(
+                    &data.redis,
+                    &data.auth_url,
+                    &data.auth_api_key,
+                    &data.auth_token_expiry,
+                    token,
+                )
+                .await?;
+            Ok((PersonId(driver_id), merchant_id, merchant_operating_city_id))
+        }
+    }
+}
+
+/// Generic: update person location (batch) for any person type; uses generic keys and entity_loc vector.
 pub async fn update_person_location(
     person_type: PersonType,
     token: Token,
@@ -1547,85 +2100,82 @@ pub async fn update_person_location(
     if locations.is_empty() {
         return Ok(HttpResponse::Ok().finish());
     }
-    match person_type {
-        PersonType::Rider => {
-            let (person_id, merchant_id, _merchant_operating_city_id) =
-                get_rider_id_from_authentication(
-                    &data.redis,
-                    &data.rider_auth_url,
-                    &data.rider_auth_api_key,
-                    &data.rider_auth_token_expiry,
-                    &token,
-                )
-                .await?;
-            let entity_details =
-                get_entity_details_for_person(&data.redis, &merchant_id, person_type, &person_id)
-                    .await?
-                    .ok_or(AppError::InvalidRequest(
-                        "No active entity for rider; call entity upsert start first".to_string(),
-                    ))?;
-            let current_ts = Utc::now();
-            let mut locations = locations;
-            locations.sort_by(|a, b| {
-                let TimeStamp(a_ts) = a.ts;
-                let TimeStamp(b_ts) = b.ts;
-                a_ts.cmp(&b_ts)
-            });
-            let person_detail = get_person_detail(&data.redis, person_type, &person_id).await?;
-            let locations: Vec<UpdatePersonLocationRequest> = locations
-                .into_iter()
-                .filter(|loc| {
-                    person_detail
-                        .as_ref()
-                        .map(|d| loc.ts >= d.last_known_location.timestamp)
-                        .unwrap_or(true)
-                })
-                .collect();
-            let latest = match locations.last() {
-                Some(l) => l.clone(),
-                None => return Ok(HttpResponse::Ok().finish()),
-            };
-            info!(
-                tag = "[Person Location Updates]",
-                "Got location updates for Rider Id : {:?} (entity: {:?})",
-                person_id.inner(),
-                entity_details.entities
-            );
-            let city = get_city(&latest.pt.lat, &latest.pt.lon, &data.polygon)?;
-            sliding_window_limiter(
-                &data.redis,
-                &person_rate_limit_key(&merchant_id, &person_id, &city),
-                data.location_update_limit,
-                data.location_update_interval as u32,
-            )
-            .await?;
-            with_lock_redis(
-                &data.redis,
-                person_processing_lock_key(&merchant_id, person_type, &person_id, &city),
-                60,
-                process_person_locations,
-                (
-                    data.clone(),
-                    locations,
-                    TimeStamp(current_ts),
-                    merchant_id,
-                    person_type,
-                    person_id.inner().clone(),
-                ),
-            )
-            .await?;
-            Ok(HttpResponse::Ok().finish())
-        }
-        PersonType::Driver => Err(AppError::InvalidRequest(
-            "Generic update_person_location does not support driver; use existing driver API"
-                .to_string(),
-        )),
-        PersonType::BusConductor | PersonType::BusDriver => Err(AppError::InvalidRequest(
-            "Generic update_person_location does not support bus crew; \
-             use /ui/driver/location with person_type=bus_conductor or bus_driver"
-                .to_string(),
-        )),
-    }
+    let (person_id, merchant_id, _merchant_operating_city_id) =
+        get_person_id_from_authentication(&data, person_type, &token).await?;
+    let entity_details = redis_migration_read!(
+        data.redis_migration,
+        "entity_details",
+        &data.redis,
+        |redis| get_entity_details_for_person(&redis, &merchant_id, person_type, &person_id)
+    )?
+    .ok_or(AppError::InvalidRequest(format!(
+        "No active entity for {}; call entity upsert start first",
+        person_type.as_str()
+    )))?;
+    let current_ts = Utc::now();
+    let mut locations = locations;
+    locations.sort_by(|a, b| {
+        let TimeStamp(a_ts) = a.ts;
+        let TimeStamp(b_ts) = b.ts;
+        a_ts.cmp(&b_ts)
+    });
+    let person_detail = redis_migration_read!(
+        data.redis_migration,
+        "person_detail",
+        &data.redis,
+        |redis| get_person_detail(&redis, person_type, &person_id)
+    )?;
+    let locations: Vec<UpdatePersonLocationRequest> = locations
+        .into_iter()
+        .filter(|loc| {
+            person_detail
+                .as_ref()
+                .map(|d| loc.ts >= d.last_known_location.timestamp)
+                .unwrap_or(true)
+        })
+        .collect();
+    let latest = match locations.last() {
+        Some(l) => l.clone(),
+        None => return Ok(HttpResponse::Ok().finish()),
+    };
+    info!(
+        tag = "[Person Location Updates]",
+        "Got location updates for {} Id : {:?} (entity: {:?})",
+        person_type.as_str(),
+        person_id.inner(),
+        entity_details.entities
+    );
+    let city = get_city(&latest.pt.lat, &latest.pt.lon, &data.polygon)?;
+    let location_update_limit =
+        resolve_config_field(&data.config_overrides, &merchant_id, &city, |config| {
+            config.location_update_limit
+        })
+        .await
+        .unwrap_or(data.location_update_limit);
+    sliding_window_limiter(
+        &data.redis,
+        &person_rate_limit_key(&merchant_id, &person_id, &city),
+        location_update_limit,
+        data.location_update_interval as u32,
+    )
+    .await?;
+    with_lock_redis(
+        &data.redis,
+        person_processing_lock_key(&merchant_id, person_type, &person_id, &city),
+        60,
+        process_person_locations,
+        (
+            data.clone(),
+            locations,
+            TimeStamp(current_ts),
+            merchant_id,
+            person_type,
+            person_id.inner().clone(),
+            city,
+        ),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().finish())
 }
 
 async fn process_person_locations(
@@ -1636,14 +2186,52 @@ async fn process_person_locations(
         MerchantId,
         PersonType,
         String,
+        CityName,
     ),
 ) -> Result<(), AppError> {
-    let (data, locations, _current_ts, merchant_id, person_type, person_id) = args;
+    let (data, locations, _current_ts, merchant_id, person_type, person_id, city) = args;
     let person_id = PersonId(person_id);
     let last = match locations.last() {
         Some(l) => l,
         None => return Ok(()),
     };
+    let entity_map = redis_migration_read!(
+        data.redis_migration,
+        "entity_details",
+        &data.redis,
+        |redis| get_entity_details_for_person(&redis, &merchant_id, person_type, &person_id)
+    )?;
+
+    let (detection_state, anti_detection_state, violation_trigger_flag, rider_safety_alerts) =
+        match (person_type, entity_map.as_ref()) {
+            (PersonType::Rider, Some(entity_map)) => {
+                let previous_detail = redis_migration_read!(
+                    data.redis_migration,
+                    "person_detail",
+                    &data.redis,
+                    |redis| get_person_detail(&redis, person_type, &person_id)
+                )?;
+                let (detection_state, anti_detection_state, violation_trigger_flag, alerts) =
+                    detect_rider_safety_violations(
+                        &data,
+                        &person_id,
+                        &merchant_id,
+                        &city,
+                        entity_map,
+                        previous_detail,
+                        last,
+                    )
+                    .await;
+                (
+                    Some(detection_state),
+                    Some(anti_detection_state),
+                    Some(violation_trigger_flag),
+                    alerts,
+                )
+            }
+            _ => (None, None, None, Vec::new()),
+        };
+
     let person_detail = PersonAllDetails {
         last_known_location: PersonLastKnownLocation {
             location: last.pt.clone(),
@@ -1652,19 +2240,37 @@ async fn process_person_locations(
             bear: last.bear,
             accuracy: last.acc,
         },
+        violation_trigger_flag,
+        detection_state,
+        anti_detection_state,
     };
-    set_person_detail(
+    redis_migration_write!(
+        data.redis_migration,
+        "person_detail",
         &data.redis,
-        person_type,
-        &person_id,
-        &person_detail,
-        &data.redis_expiry,
-    )
-    .await?;
+        |redis| set_person_detail(
+            &redis,
+            person_type,
+            &person_id,
+            &person_detail,
+            &data.redis_expiry,
+        )
+    )?;
+
+    if !rider_safety_alerts.is_empty() {
+        Arbiter::current().spawn(async move {
+            for (callback_url, alert) in rider_safety_alerts {
+                if let Err(err) = trigger_rider_safety_alert(&callback_url, alert)
+                    .await
+                    .map_err(|err| AppError::AlertRequestFailed(err.message()))
+                {
+                    warn!("Rider Safety Alert could not be sent. {} ", err);
+                }
+            }
+        });
+    }
 
     // For each active entity, check if it has broadcasters that need location pings.
-    let entity_map =
-        get_entity_details_for_person(&data.redis, &merchant_id, person_type, &person_id).await?;
     if let Some(map) = entity_map {
         for (entity_type, entry) in &map.entities {
             for broadcaster_id in &entry.broadcaster_ids {
@@ -1674,26 +2280,29 @@ async fn process_person_locations(
                     .find(|c| c.broadcaster_id == *broadcaster_id)
                 {
                     let redis_clone = data.redis.clone();
-                    let broadcaster_id_owned = broadcaster_id.clone();
+                    let redis_migration = data.redis_migration.clone();
+                    let target = BroadcastTraceTarget {
+                        merchant_id: merchant_id.clone(),
+                        person_type,
+                        person_id: person_id.clone(),
+                        entity_type: entity_type.clone(),
+                        entity_id: entry.entity_id.clone(),
+                        broadcaster_id: broadcaster_id.clone(),
+                    };
                     let cfg_owned = cfg.clone();
-                    let lat = last.pt.lat.inner();
-                    let lon = last.pt.lon.inner();
-                    let redis_expiry = data.redis_expiry;
-                    let merchant_id_clone = merchant_id.clone();
-                    let person_id_clone = person_id.clone();
-                    let entity_type_clone = entity_type.clone();
+                    let location = BroadcastLocation {
+                        lat: last.pt.lat.inner(),
+                        lon: last.pt.lon.inner(),
+                        timestamp: chrono::Utc::now().timestamp(),
+                        signal: TraceSignal::Live,
+                    };
                     Arbiter::current().spawn(async move {
                         try_send_broadcast_trace(
                             redis_clone,
-                            broadcaster_id_owned,
+                            redis_migration,
+                            target,
                             cfg_owned,
-                            lat,
-                            lon,
-                            redis_expiry,
-                            merchant_id_clone,
-                            person_type,
-                            person_id_clone,
-                            entity_type_clone,
+                            location,
                         )
                         .await;
                     });
@@ -1705,207 +2314,184 @@ async fn process_person_locations(
     Ok(())
 }
 
-/// Fire-and-forget: rate-limits via a Redis lock, refreshes the bearer token reactively on 401,
-/// and POSTs a location trace via the appropriate ExternalLocationProvider.
-async fn try_send_broadcast_trace(
-    redis: std::sync::Arc<RedisConnectionPool>,
-    broadcaster_id: String,
-    mut cfg: BroadcastTraceConfig,
-    lat: f64,
-    lon: f64,
-    redis_expiry: u32,
-    merchant_id: MerchantId,
-    person_type: PersonType,
-    person_id: PersonId,
-    entity_type: String,
+/// Runs the rider detections (`rider_detection_*_config`) on the latest rider point
+/// while an SOS or ride entity is active. SOS takes precedence as the alerted entity;
+/// driver divergence is measured against the last known location of the ride's driver.
+async fn detect_rider_safety_violations(
+    data: &AppState,
+    person_id: &PersonId,
+    merchant_id: &MerchantId,
+    city: &CityName,
+    entity_map: &PersonEntityDetailsMap,
+    previous_detail: Option<PersonAllDetails>,
+    latest: &UpdatePersonLocationRequest,
+) -> (
+    ViolationDetectionStateMap,
+    ViolationDetectionStateMap,
+    ViolationDetectionTriggerMap,
+    Vec<(Url, RiderSafetyAlertReq)>,
 ) {
-    let now_secs = chrono::Utc::now().timestamp();
-
-    // Rate-limit: skip if we traced within the last polling_interval_secs.
-    let should_trace = match cfg.last_trace_ts {
-        None => true,
-        Some(last_ts) => now_secs - last_ts >= cfg.polling_interval_secs as i64,
-    };
-    if !should_trace {
-        return;
-    }
-
-    // time_diff_secs shifts the UTC epoch into city-local wall-clock time so the provider
-    // receives a human-readable local datetime string in YYYY-MM-DD HH:MM:SS format.
-    let local_ts = chrono::DateTime::from_timestamp(now_secs + cfg.time_diff_secs, 0)
-        .unwrap_or_else(chrono::Utc::now);
-    let datetime_str = local_ts.format("%Y-%m-%d %H:%M:%S").to_string();
-
-    // Proactive token refresh: renew 60 seconds before expiry.
-    // if cfg.token_expires_at <= now_secs + 60 {
-    //     tracing::info!(
-    //         "[broadcast trace] broadcaster_id={} | step: token expiring soon (expires_at={}, now={}), proactive refresh",
-    //         broadcaster_id, cfg.token_expires_at, now_secs
-    //     );
-    //     match refresh_token(&redis, &broadcaster_id, &mut cfg, redis_expiry, now_secs).await {
-    //         Ok(()) => {
-    //             tracing::info!("[broadcast trace] broadcaster_id={} | step: proactive token refresh succeeded", broadcaster_id);
-    //         }
-    //         Err(()) => return,
-    //     }
-    // }
-
-    let mut provider = match crate::outbound::provider::make_provider(&cfg) {
-        Ok(p) => p,
-        Err(e) => {
-            tracing::error!(
-                "Failed to build provider for broadcaster {}: {}",
-                broadcaster_id,
-                e
-            );
-            return;
+    let (detection_state, anti_detection_state, violation_trigger_flag) = previous_detail
+        .map(|detail| {
+            (
+                detail.detection_state.unwrap_or_default(),
+                detail.anti_detection_state.unwrap_or_default(),
+                detail.violation_trigger_flag.unwrap_or_default(),
+            )
+        })
+        .unwrap_or_default();
+
+    let sos_id = entity_map
+        .entities
+        .values()
+        .find_map(|entry| match &entry.entity_id {
+            EntityId::Sos(sos_id) => Some(sos_id.to_owned()),
+            EntityId::Ride(_) => None,
+        });
+    let ride_id = entity_map
+        .entities
+        .values()
+        .find_map(|entry| match &entry.entity_id {
+            EntityId::Ride(ride_id) => Some(ride_id.to_owned()),
+            EntityId::Sos(_) => None,
+        });
+    let entity = match (sos_id.as_ref(), ride_id.as_ref()) {
+        (Some(sos_id), _) => EntityId::Sos(sos_id.to_owned()),
+        (None, Some(ride_id)) => EntityId::Ride(ride_id.to_owned()),
+        (None, None) => {
+            return (
+                detection_state,
+                anti_detection_state,
+                violation_trigger_flag,
+                Vec::new(),
+            )
         }
     };
 
-    let trace_result = provider.send_ping(lat, lon, &datetime_str).await;
-
-    let trace_result = match trace_result {
-        Err(AppError::TraceTokenExpired) => {
-            // Reactive token refresh: provider returned 401 — refresh and retry once.
-            match refresh_token(
-                &redis,
-                &broadcaster_id,
-                &mut cfg,
-                redis_expiry,
-                now_secs,
-                &merchant_id,
-                person_type,
-                &person_id,
-                &entity_type,
-            )
+    // Lookup failures only pause divergence detection, they should not fail the rider update.
+    let driver_id = match ride_id.as_ref() {
+        Some(ride_id) => get_driver_details(&data.redis, ride_id)
             .await
-            {
-                Ok(()) => {
-                    provider.update_token(cfg.access_token.clone(), cfg.token_expires_at);
-                    provider.send_ping(lat, lon, &datetime_str).await
-                }
-                Err(()) => return,
-            }
-        }
-        other => other,
+            .ok()
+            .flatten()
+            .map(|details| details.driver_id),
+        None => None,
+    };
+    let driver_location = match driver_id.as_ref() {
+        Some(driver_id) => redis_migration_read!(
+            data.redis_migration,
+            "driver_details",
+            &data.redis,
+            |redis| get_driver_location(&redis, driver_id)
+        )
+        .ok()
+        .flatten()
+        .map(|details| details.driver_last_known_location.location),
+        None => None,
     };
 
-    match trace_result {
-        Ok(()) => {
-            cfg.last_trace_ts = Some(now_secs);
-            if let Err(e) = persist_broadcaster_config(
-                &redis,
-                &merchant_id,
-                person_type,
-                &person_id,
-                &entity_type,
-                &broadcaster_id,
-                &cfg,
-                &redis_expiry,
-            )
-            .await
-            {
-                tracing::error!(
-                    "Failed to update broadcast_trace_config after trace for {}: {}",
-                    broadcaster_id,
-                    e
-                );
-            }
-        }
-        Err(e) => {
-            tracing::error!(
-                "Broadcast trace failed for broadcaster {}: {}",
-                broadcaster_id,
-                e
-            );
-        }
-    }
-}
+    let min_location_accuracy =
+        resolve_config_field(&data.config_overrides, merchant_id, city, |config| {
+            config.min_location_accuracy
+        })
+        .await
+        .unwrap_or(data.min_location_accuracy);
 
-/// Persist updated BroadcastTraceConfig back into the entity_details map.
-async fn persist_broadcaster_config(
-    redis: &RedisConnectionPool,
-    merchant_id: &MerchantId,
-    person_type: PersonType,
-    person_id: &PersonId,
-    entity_type: &str,
-    broadcaster_id: &str,
-    cfg: &BroadcastTraceConfig,
-    expiry: &u32,
-) -> Result<(), AppError> {
-    let mut map = get_entity_details_for_person(redis, merchant_id, person_type, person_id)
-        .await?
-        .unwrap_or_else(|| PersonEntityDetailsMap {
-            entities: std::collections::HashMap::new(),
-            merchant_id: merchant_id.clone(),
-        });
-    if let Some(entry) = map.entities.get_mut(entity_type) {
-        if let Some(existing) = entry
-            .broadcaster_configs
-            .iter_mut()
-            .find(|c| c.broadcaster_id == broadcaster_id)
-        {
-            *existing = cfg.clone();
-        } else {
-            let mut new_cfg = cfg.clone();
-            new_cfg.broadcaster_id = broadcaster_id.to_string();
-            entry.broadcaster_configs.push(new_cfg);
-        }
-    }
-    set_entity_details_for_person(redis, merchant_id, person_type, person_id, &map, expiry).await
-}
+    DetectionType::iter().fold(
+        (
+            detection_state,
+            anti_detection_state,
+            violation_trigger_flag,
+            Vec::new(),
+        ),
+        |(
+            mut detection_violation_state_map,
+            mut detection_anti_violation_state_map,
+            mut violation_trigger_flag_map,
+            mut rider_safety_alerts,
+        ),
+         detection_type| {
+            if let (Some(detection_violation_config), Some(detection_anti_violation_config)) = (
+                data.rider_detection_violation_config.get(&detection_type),
+                data.rider_detection_anti_violation_config
+                    .get(&detection_type),
+            ) {
+                let context = DetectionContext {
+                    location: latest.pt.to_owned(),
+                    timestamp: latest.ts,
+                    speed: latest.v.to_owned(),
+                    ride_status: RideStatus::INPROGRESS,
+                    ride_info: None,
+                    vehicle_type: None,
+                    accuracy: latest.acc.unwrap_or(Accuracy(0.0)),
+                    route: None,
+                    ride_stops: None,
+                    counterpart_location: driver_location.to_owned(),
+                };
 
-/// Refresh the external bearer token via reauth and persist the updated config.
-/// Returns `Ok(())` on success, `Err(())` if the refresh failed (already logged).
-async fn refresh_token(
-    redis: &RedisConnectionPool,
-    broadcaster_id: &str,
-    cfg: &mut BroadcastTraceConfig,
-    redis_expiry: u32,
-    _now_secs: i64,
-    merchant_id: &MerchantId,
-    person_type: PersonType,
-    person_id: &PersonId,
-    entity_type: &str,
-) -> Result<(), ()> {
-    match crate::outbound::external::refresh_external_trace_token(
-        &cfg.ny_reauth_url,
-        &cfg.ny_api_key,
-        &cfg.merchant_operating_city_id,
-    )
-    .await
-    {
-        Ok(resp) => {
-            cfg.access_token = resp.access_token;
-            cfg.token_expires_at = resp.expires_at;
-            // Persist refreshed token so sibling workers pick it up.
-            if let Err(e) = persist_broadcaster_config(
-                redis,
-                merchant_id,
-                person_type,
-                person_id,
-                entity_type,
-                broadcaster_id,
-                cfg,
-                &redis_expiry,
-            )
-            .await
-            {
-                tracing::error!(
-                    "Failed to persist refreshed external token for {}: {}",
-                    broadcaster_id,
-                    e
-                );
+                // Skip detection if accuracy is poor
+                if context.accuracy > min_location_accuracy {
+                    return (
+                        detection_violation_state_map,
+                        detection_anti_violation_state_map,
+                        violation_trigger_flag_map,
+                        rider_safety_alerts,
+                    );
+                }
+
+                if let (
+                    Some(detection_violation_state),
+                    Some(detection_anti_violation_state),
+                    violation_trigger_flag,
+                    violation_detection_req,
+                ) = check(
+                    detection_violation_config,
+                    detection_anti_violation_config,
+                    context,
+                    detection_violation_state_map.get(&detection_type).cloned(),
+                    detection_anti_violation_state_map
+                        .get(&detection_type)
+                        .cloned(),
+                    violation_trigger_flag_map
+                        .get(&detection_type)
+                        .cloned()
+                        .flatten(),
+                ) {
+                    detection_violation_state_map
+                        .insert(detection_type.clone(), detection_violation_state);
+                    detection_anti_violation_state_map
+                        .insert(detection_type.clone(), detection_anti_violation_state);
+                    violation_trigger_flag_map
+                        .insert(detection_type.clone(), violation_trigger_flag);
+
+                    if detection_violation_config.enabled && detection_anti_violation_config.enabled
+                    {
+                        if let Some(DetectionOutcome {
+                            is_violated,
+                            detection_data,
+                        }) = violation_detection_req
+                        {
+                            rider_safety_alerts.push((
+                                detection_violation_config.detection_callback_url.to_owned(),
+                                RiderSafetyAlertReq {
+                                    entity: entity.to_owned(),
+                                    rider_id: person_id.to_owned(),
+                                    merchant_id: merchant_id.to_owned(),
+                                    driver_id: driver_id.to_owned(),
+                                    is_violated,
+                                    detection_data,
+                                },
+                            ))
+                        }
+                    }
+                }
             }
-            Ok(())
-        }
-        Err(e) => {
-            tracing::error!(
-                "External token refresh failed for broadcaster {}: {}",
-                broadcaster_id,
-                e
-            );
-            Err(())
-        }
-    }
+            (
+                detection_violation_state_map,
+                detection_anti_violation_state_map,
+                violation_trigger_flag_map,
+                rider_safety_alerts,
+            )
+        },
+    )
 }
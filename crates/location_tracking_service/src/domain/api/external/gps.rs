@@ -6,7 +6,9 @@
     the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::domain::action::external::gps::{handle_external_gps_location, ExternalGPSLocationReq};
+use crate::domain::action::external::gps::{
+    handle_external_gps_location, handle_external_gps_vendor_location, ExternalGPSLocationReq,
+};
 use crate::environment::AppState;
 use actix_web::{web, HttpRequest, HttpResponse, ResponseError, Result};
 
@@ -29,3 +31,25 @@ pub async fn external_gps_location(
         Err(error) => Ok(error.error_response()),
     }
 }
+
+/// Registered after `/external/gps/location`, which keeps the original schema.
+#[actix_web::post("/external/gps/{vendor}")]
+pub async fn external_gps_vendor_location(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    json: web::Json<serde_json::Value>,
+) -> Result<HttpResponse> {
+    let api_key = req
+        .headers()
+        .get("X-API-Key")
+        .and_then(|header_value| header_value.to_str().ok())
+        .map(|api_key_str| api_key_str.to_string());
+
+    match handle_external_gps_vendor_location(path.into_inner(), api_key, json.into_inner(), data)
+        .await
+    {
+        Ok(response) => Ok(response),
+        Err(error) => Ok(error.error_response()),
+    }
+}
@@ -0,0 +1,123 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use actix_web::{
+    get, post,
+    web::{Data, Json, Path, Query},
+    HttpRequest,
+};
+
+use crate::tools::error::AppError;
+use crate::{
+    common::{
+        config_override::{ConfigOverrideEntry, ConfigOverrideScope},
+        types::APISuccess,
+    },
+    domain::{action::internal::*, types::internal::admin::*},
+    environment::AppState,
+};
+
+fn api_key_from_header(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get("X-API-Key")
+        .and_then(|header_value| header_value.to_str().ok())
+        .map(|api_key_str| api_key_str.to_string())
+}
+
+#[get("/internal/admin/config")]
+async fn get_config(
+    data: Data<AppState>,
+    req: HttpRequest,
+    query: Query<AdminConfigQuery>,
+) -> Result<Json<AdminConfigResponse>, AppError> {
+    admin::validate_admin_api_key(&data, api_key_from_header(&req))?;
+
+    Ok(Json(admin::get_config(data, query.into_inner()).await?))
+}
+
+#[post("/internal/admin/config/override")]
+async fn set_config_override(
+    data: Data<AppState>,
+    req: HttpRequest,
+    query: Query<AdminConfigQuery>,
+    param_obj: Json<ConfigOverrideRequest>,
+) -> Result<Json<ConfigOverrideEntry>, AppError> {
+    admin::validate_admin_api_key(&data, api_key_from_header(&req))?;
+    let AdminConfigQuery { merchant_id, city } = query.into_inner();
+    let scope = ConfigOverrideScope::new(merchant_id, city);
+
+    Ok(Json(
+        admin::set_override(data, scope, param_obj.into_inner()).await?,
+    ))
+}
+
+#[post("/internal/admin/config/rollback")]
+async fn rollback_config_override(
+    data: Data<AppState>,
+    req: HttpRequest,
+    query: Query<AdminConfigQuery>,
+    param_obj: Json<ConfigRollbackRequest>,
+) -> Result<Json<ConfigOverrideEntry>, AppError> {
+    admin::validate_admin_api_key(&data, api_key_from_header(&req))?;
+    let AdminConfigQuery { merchant_id, city } = query.into_inner();
+    let scope = ConfigOverrideScope::new(merchant_id, city);
+
+    Ok(Json(
+        admin::rollback_override(data, scope, param_obj.into_inner()).await?,
+    ))
+}
+
+#[get("/internal/admin/config/history")]
+async fn config_override_history(
+    data: Data<AppState>,
+    req: HttpRequest,
+    query: Query<AdminConfigQuery>,
+) -> Result<Json<ConfigOverrideHistoryResponse>, AppError> {
+    admin::validate_admin_api_key(&data, api_key_from_header(&req))?;
+    let AdminConfigQuery { merchant_id, city } = query.into_inner();
+    let scope = ConfigOverrideScope::new(merchant_id, city);
+
+    Ok(Json(admin::get_override_history(data, scope).await?))
+}
+
+#[post("/internal/admin/externalGps/{vendor}/imeiMapping")]
+async fn set_external_gps_imei_mapping(
+    data: Data<AppState>,
+    req: HttpRequest,
+    path: Path<String>,
+    param_obj: Json<ExternalGpsImeiMappingRequest>,
+) -> Result<Json<APISuccess>, AppError> {
+    admin::validate_admin_api_key(&data, api_key_from_header(&req))?;
+
+    Ok(Json(
+        admin::set_external_gps_imei_mapping(data, path.into_inner(), param_obj.into_inner())
+            .await?,
+    ))
+}
+
+#[get("/internal/admin/redisMigration")]
+async fn redis_migration_status(
+    data: Data<AppState>,
+    req: HttpRequest,
+) -> Result<Json<RedisMigrationStatusResponse>, AppError> {
+    admin::validate_admin_api_key(&data, api_key_from_header(&req))?;
+
+    Ok(Json(admin::redis_migration_status(data)))
+}
+
+#[post("/internal/admin/redisMigration/phase")]
+async fn set_redis_migration_phase(
+    data: Data<AppState>,
+    req: HttpRequest,
+    param_obj: Json<RedisMigrationPhaseRequest>,
+) -> Result<Json<RedisMigrationStatusResponse>, AppError> {
+    admin::validate_admin_api_key(&data, api_key_from_header(&req))?;
+
+    Ok(Json(
+        admin::move_redis_migration_phase(data, param_obj.into_inner()).await?,
+    ))
+}
@@ -13,7 +13,7 @@ use actix_web::{
 
 use crate::tools::error::AppError;
 use crate::{
-    common::types::*,
+    common::{driver_session::DriverDaySummary, types::*},
     domain::{action::internal::*, types::internal::location::*},
     environment::AppState,
 };
@@ -148,6 +148,17 @@ async fn driver_queue_history(
     ))
 }
 
+#[get("/internal/drivers/{driver_id}/sessions/{day}")]
+async fn driver_day_session(
+    data: Data<AppState>,
+    path: Path<(String, String)>,
+) -> Result<Json<DriverDaySummary>, AppError> {
+    let (driver_id, day) = path.into_inner();
+    Ok(Json(
+        location::driver_day_session(data, DriverId(driver_id), day).await?,
+    ))
+}
+
 #[delete("/internal/special-locations/{special_location_id}/queue/{vehicle_type}/drivers/{merchant_id}/{driver_id}")]
 async fn manual_queue_remove(
     data: Data<AppState>,
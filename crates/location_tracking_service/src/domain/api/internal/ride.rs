@@ -89,3 +89,14 @@ async fn entity_upsert(
         ride::entity_upsert(&person_type, &entity_type, &entity_id, data, request_body).await?,
     ))
 }
+
+#[get("/internal/entity/{entity_type}/{entity_id}/broadcastTrace/status")]
+async fn broadcast_trace_status(
+    data: Data<AppState>,
+    path: Path<(String, String)>,
+) -> Result<Json<BroadcastTraceStatusResponse>, AppError> {
+    let (entity_type, entity_id) = path.into_inner();
+    Ok(Json(
+        ride::broadcast_trace_status(&entity_type, &entity_id, data).await?,
+    ))
+}
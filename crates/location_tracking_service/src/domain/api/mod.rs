@@ -32,8 +32,18 @@ pub fn handler(config: &mut ServiceConfig) {
         .service(internal::location::manual_queue_remove)
         .service(internal::location::manual_queue_add)
         .service(internal::location::driver_queue_history)
+        .service(internal::location::driver_day_session)
         .service(external::gps::external_gps_location)
+        .service(external::gps::external_gps_vendor_location)
         .service(ui::location::track_person_entity_location)
         .service(ui::location::update_person_location)
-        .service(internal::ride::entity_upsert);
+        .service(internal::ride::entity_upsert)
+        .service(internal::ride::broadcast_trace_status)
+        .service(internal::admin::get_config)
+        .service(internal::admin::set_config_override)
+        .service(internal::admin::rollback_config_override)
+        .service(internal::admin::config_override_history)
+        .service(internal::admin::set_external_gps_imei_mapping)
+        .service(internal::admin::redis_migration_status)
+        .service(internal::admin::set_redis_migration_phase);
 }
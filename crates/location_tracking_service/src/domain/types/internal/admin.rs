@@ -0,0 +1,111 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use serde::{Deserialize, Serialize};
+
+use crate::common::{config_override::*, types::*};
+use crate::redis::migration::RedisMigrationPhase;
+
+/// Scope query shared by the /internal/admin/config endpoints. For GET it
+/// selects which merchant / city the effective config is resolved for; for
+/// writes it names the scope being overridden (neither set = global).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminConfigQuery {
+    pub merchant_id: Option<MerchantId>,
+    pub city: Option<CityName>,
+}
+
+/// Static values loaded from dhall for the overridable fields.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OverridableConfig {
+    pub pickup_notification_threshold: f64,
+    pub arriving_notification_threshold: f64,
+    pub pickup_instruction_notification_threshold: f64,
+    pub driver_location_accuracy_buffer: f64,
+    pub driver_reached_destination_buffer: f64,
+    pub driver_source_departed_buffer: f64,
+    pub min_location_accuracy: Accuracy,
+    pub location_update_limit: usize,
+    pub blacklist_merchants: Vec<MerchantId>,
+    pub stop_detection: StopDetectionConfigMap,
+    pub detection_violation_config: DetectionConfigMap,
+    pub detection_anti_violation_config: DetectionConfigMap,
+}
+
+/// Response for GET /internal/admin/config
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminConfigResponse {
+    pub base: OverridableConfig,
+    pub overrides: Vec<ConfigOverrideEntry>,
+    pub effective: OverridableConfig,
+}
+
+/// Request body for POST /internal/admin/config/override.
+/// Replaces the scope's override as a whole; omitted fields inherit.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigOverrideRequest {
+    pub config: ConfigOverride,
+    pub updated_by: Option<String>,
+    pub reason: Option<String>,
+}
+
+/// Request body for POST /internal/admin/config/rollback.
+/// `version` defaults to the one before the current override; rolling back
+/// past the first version clears the override.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigRollbackRequest {
+    pub version: Option<u64>,
+    pub updated_by: Option<String>,
+    pub reason: Option<String>,
+}
+
+/// Response for GET /internal/admin/config/history, newest-first.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigOverrideHistoryResponse {
+    pub current: Option<ConfigOverrideEntry>,
+    pub history: Vec<ConfigOverrideEntry>,
+}
+
+/// Request body for POST /internal/admin/externalGps/{vendor}/imeiMapping.
+/// `upsert` is applied before `remove`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalGpsImeiMappingRequest {
+    #[serde(default)]
+    pub upsert: Vec<ImeiVehicleMapping>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ImeiVehicleMapping {
+    pub imei: String,
+    pub vehicle_number: String,
+}
+
+/// Request body for POST /internal/admin/redisMigration/phase.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RedisMigrationPhaseRequest {
+    pub phase: RedisMigrationPhase,
+    pub updated_by: Option<String>,
+}
+
+/// Response for the /internal/admin/redisMigration endpoints.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RedisMigrationStatusResponse {
+    pub enabled: bool,
+    pub phase: RedisMigrationPhase,
+}
@@ -6,5 +6,6 @@
     the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
+pub mod admin;
 pub mod location;
 pub mod ride;
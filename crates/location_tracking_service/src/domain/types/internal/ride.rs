@@ -113,6 +113,28 @@ pub enum EntityUpsertResponse {
     EntityEnd { loc: Vec<Point> },
 }
 
+/// Whether broadcast traces for an entity are getting through, judged by the latest attempt.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum BroadcastTraceDeliveryStatus {
+    NoAttempts,
+    Delivering,
+    Failing,
+}
+
+/// Response for the broadcast trace status endpoint. `attempts` is the capped audit log,
+/// newest first; `last_success` survives even after it has been trimmed from the log.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BroadcastTraceStatusResponse {
+    pub entity: EntityId,
+    pub delivery_status: BroadcastTraceDeliveryStatus,
+    pub consecutive_failures: u32,
+    pub last_success_at: Option<TimeStamp>,
+    pub last_success: Option<BroadcastTraceAttempt>,
+    pub attempts: Vec<BroadcastTraceAttempt>,
+}
+
 /// Request body for registering a broadcaster config. Sent as part of `EntityStart`.
 /// The `provider` field carries provider-kind discriminant + provider-specific fields.
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -5,19 +5,30 @@
     or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
     the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
+use crate::environment::DrainerStreamConfig;
 use crate::queue_drainer_latency;
+use crate::redis::{migration::RedisMigration, routing::CityRedisRouter};
+use crate::redis_migration_write;
 use crate::special_location::{lookup_special_location, SpecialLocationCache};
-use crate::tools::prometheus::{QUEUE_DRAINER_LATENCY, QUEUE_EVICTIONS, TOTAL_LOCATION_UPDATES};
+use crate::tools::error::AppError;
+use crate::tools::prometheus::{
+    DRAINER_BACKPRESSURE, DRAINER_CHANNEL_OCCUPANCY, DRAINER_COALESCED_ENTRIES,
+    DRAINER_STREAM_ENTRIES, QUEUE_DRAINER_LATENCY, QUEUE_EVICTIONS, TOTAL_LOCATION_UPDATES,
+};
 use crate::{
     common::{
+        drainer_wal::{DrainerEntry, DrainerWal, WalAppender, DRAINER_WAL_MAX_BYTES},
         types::*,
         utils::{abs_diff_utc_as_sec, get_bucket_from_timestamp},
     },
     redis::{
         commands::{
-            add_driver_to_special_location_zset, batch_get_driver_queue_last_ts,
-            batch_get_driver_queue_trackings, push_drainer_driver_location, rank_history_payload,
-            DriverQueueTracking, RANK_HISTORY_TTL_SECS,
+            ack_drainer_stream_entries, add_driver_to_special_location_zset,
+            batch_get_driver_queue_last_ts, batch_get_driver_queue_trackings,
+            claim_idle_drainer_stream_entries, drainer_stream_id_order,
+            ensure_drainer_stream_group, push_drainer_driver_location, rank_history_payload,
+            read_drainer_stream, trim_acked_drainer_stream, DriverQueueTracking,
+            RANK_HISTORY_TTL_SECS,
         },
         keys::{
             driver_loc_bucket_key, driver_queue_last_ts_key, driver_queue_rank_history_key,
@@ -33,24 +44,40 @@ use shared::redis::types::RedisConnectionPool;
 use shared::termination;
 use shared::tools::prometheus::TERMINATION;
 use std::cmp::max;
+use std::collections::{hash_map::Entry, VecDeque};
 use std::{
     cmp::min,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
 };
 use std::{sync::Arc, time::Duration};
 use tokio::sync::mpsc;
-use tokio::time::interval;
 use tokio::time::Instant;
+use tokio::time::{interval, sleep};
 use tracing::{error, info};
 
+/// Wait before retrying a failed drain, doubled on every further failure.
+const DRAIN_RETRY_BASE_BACKOFF: Duration = Duration::from_secs(1);
+const DRAIN_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
 /// Queue action collected during the drainer loop.
-enum QueueAction {
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueueAction {
     Enter {
         merchant_id: String,
         driver_id: String,
         special_location_id: String,
         vehicle_type: String,
+        /// Earliest server timestamp of the coalesced pings, which the rank
+        /// is scored by.
         timestamp: f64,
+        /// Latest server timestamp of the coalesced pings, recorded as the
+        /// tracking's `last_ping_ts`.
+        last_ping_ts: f64,
+        /// Drainer stream entry the ping came from, `None` for the in-process drainer.
+        stream_id: Option<String>,
     },
     PossibleExit {
         merchant_id: String,
@@ -60,9 +87,216 @@ enum QueueAction {
         // the driver — same source as Enter timestamps so the two event
         // types interleave on a single timeline.
         timestamp: f64,
+        stream_id: Option<String>,
     },
 }
 
+impl QueueAction {
+    fn stream_id(&self) -> Option<&str> {
+        match self {
+            QueueAction::Enter { stream_id, .. } | QueueAction::PossibleExit { stream_id, .. } => {
+                stream_id.as_deref()
+            }
+        }
+    }
+
+    /// Whether `tracking` already reflects this action. Only a batch reclaimed
+    /// from the drainer stream can repeat actions, so only its actions are
+    /// checked, against the id of the last stream entry applied. Pings of the
+    /// same second, or from another pod, are never skipped.
+    pub fn is_applied_to(&self, tracking: Option<&DriverQueueTracking>, reclaimed: bool) -> bool {
+        reclaimed
+            && self
+                .stream_id()
+                .zip(tracking)
+                .is_some_and(|(stream_id, tracking)| tracking.has_applied_stream_entry(stream_id))
+    }
+
+    /// The later of this action's stream entry and the last one `tracking` applied.
+    fn last_stream_id(&self, tracking: Option<&DriverQueueTracking>) -> Option<String> {
+        let applied = tracking.and_then(|tracking| tracking.last_stream_id.as_deref());
+        match (self.stream_id(), applied) {
+            (Some(stream_id), Some(applied))
+                if drainer_stream_id_order(stream_id) < drainer_stream_id_order(applied) =>
+            {
+                Some(applied.to_string())
+            }
+            (Some(stream_id), _) => Some(stream_id.to_string()),
+            (None, applied) => applied.map(str::to_string),
+        }
+    }
+}
+
+/// Entries buffered for the next drain, coalesced as they are added so repeated
+/// pings of a driver within the drain window do not grow the buffers.
+#[derive(Default)]
+pub struct DrainBuffer {
+    driver_locations: CityDriversLocationMap,
+    /// Position of each (bucket key, driver) in its bucket's locations.
+    location_index: FxHashMap<(String, String), usize>,
+    special_location_entries: FxHashMap<String, Vec<(DriverId, u64, f64)>>,
+    /// Position of each (special location, driver, bucket) in its entries.
+    special_location_index: FxHashMap<(String, DriverId, u64), usize>,
+    queue_actions: Vec<QueueAction>,
+    /// Position of each driver's Enter that no later action of theirs follows.
+    pending_enters: FxHashMap<(String, String), usize>,
+    /// Entries reclaimed from the drainer stream, which may have been applied already.
+    reclaimed: bool,
+}
+
+impl DrainBuffer {
+    pub fn driver_locations(&self) -> &CityDriversLocationMap {
+        &self.driver_locations
+    }
+
+    pub fn special_location_entries(&self) -> &FxHashMap<String, Vec<(DriverId, u64, f64)>> {
+        &self.special_location_entries
+    }
+
+    pub fn queue_actions(&self) -> &[QueueAction] {
+        &self.queue_actions
+    }
+
+    /// Marks the buffer as holding entries reclaimed from the drainer stream.
+    pub fn set_reclaimed(&mut self) {
+        self.reclaimed = true;
+    }
+
+    /// Keeps the locations of a failed drain for the next attempt, with later
+    /// entries coalesced on top. Queue actions are dispatched on their own
+    /// whatever the outcome of the drain, so they are dropped.
+    pub fn retain_for_retry(&mut self) {
+        self.queue_actions.clear();
+        self.pending_enters.clear();
+    }
+
+    /// Keeps only the latest position of each driver per bucket key; GEOADD would
+    /// overwrite the earlier ones anyway.
+    pub fn add_driver_location(
+        &mut self,
+        city: CityName,
+        bucket_key: String,
+        driver_id: String,
+        Latitude(latitude): Latitude,
+        Longitude(longitude): Longitude,
+    ) {
+        let coordinates = GeoPosition {
+            latitude,
+            longitude,
+        };
+        let locations = self
+            .driver_locations
+            .entry(city)
+            .or_default()
+            .entry(bucket_key.clone())
+            .or_default();
+        match self.location_index.entry((bucket_key, driver_id)) {
+            Entry::Occupied(index) => {
+                locations[*index.get()].coordinates = coordinates;
+                DRAINER_COALESCED_ENTRIES
+                    .with_label_values(&["location"])
+                    .inc();
+            }
+            Entry::Vacant(index) => {
+                locations.push(GeoValue {
+                    coordinates,
+                    member: index.key().1.clone().into(),
+                });
+                index.insert(locations.len() - 1);
+            }
+        }
+    }
+
+    /// Keeps only the latest entry of each driver per special location bucket.
+    pub fn add_special_location_entry(
+        &mut self,
+        special_location_id: String,
+        driver_id: DriverId,
+        bucket: u64,
+        score: f64,
+    ) {
+        let entries = self
+            .special_location_entries
+            .entry(special_location_id.clone())
+            .or_default();
+        match self
+            .special_location_index
+            .entry((special_location_id, driver_id, bucket))
+        {
+            Entry::Occupied(index) => {
+                entries[*index.get()].2 = score;
+                DRAINER_COALESCED_ENTRIES
+                    .with_label_values(&["special_location"])
+                    .inc();
+            }
+            Entry::Vacant(index) => {
+                entries.push((index.key().1.clone(), bucket, score));
+                index.insert(entries.len() - 1);
+            }
+        }
+    }
+
+    /// Folds a driver's Enter into their previous Enter into the same queue,
+    /// keeping the earliest timestamp so the rank still reflects the earliest
+    /// arrival, and the latest one as the last ping applied.
+    ///
+    /// Every action of a batch is applied against the tracking read before the batch,
+    /// so a PossibleExit or an Enter into another queue in between keeps the Enters
+    /// apart to preserve the outcome.
+    pub fn add_queue_action(&mut self, action: QueueAction) {
+        match action {
+            QueueAction::Enter {
+                ref merchant_id,
+                ref driver_id,
+                ref special_location_id,
+                ref vehicle_type,
+                timestamp,
+                last_ping_ts,
+                ref stream_id,
+            } => {
+                let driver_key = (merchant_id.clone(), driver_id.clone());
+                if let Some(&index) = self.pending_enters.get(&driver_key) {
+                    if let QueueAction::Enter {
+                        special_location_id: pending_special_location_id,
+                        vehicle_type: pending_vehicle_type,
+                        timestamp: pending_timestamp,
+                        last_ping_ts: pending_last_ping_ts,
+                        stream_id: pending_stream_id,
+                        ..
+                    } = &mut self.queue_actions[index]
+                    {
+                        if pending_special_location_id == special_location_id
+                            && pending_vehicle_type == vehicle_type
+                        {
+                            *pending_timestamp = pending_timestamp.min(timestamp);
+                            *pending_last_ping_ts = pending_last_ping_ts.max(last_ping_ts);
+                            // Entries are buffered in stream order.
+                            if stream_id.is_some() {
+                                pending_stream_id.clone_from(stream_id);
+                            }
+                            DRAINER_COALESCED_ENTRIES
+                                .with_label_values(&["queue_enter"])
+                                .inc();
+                            return;
+                        }
+                    }
+                }
+                self.pending_enters
+                    .insert(driver_key, self.queue_actions.len());
+            }
+            QueueAction::PossibleExit {
+                ref merchant_id,
+                ref driver_id,
+                ..
+            } => {
+                self.pending_enters
+                    .remove(&(merchant_id.clone(), driver_id.clone()));
+            }
+        }
+        self.queue_actions.push(action);
+    }
+}
+
 /// Process queue actions (enter/exit) in the background using batched Redis operations.
 /// Uses MGET to fetch all tracking keys in one round trip, then a pipeline for all writes.
 /// Errors are logged but never block the caller.
@@ -86,6 +320,8 @@ struct EnteredForRankHistory {
     special_location_id: String,
     vehicle_type: String,
     timestamp: f64,
+    last_ping_ts: f64,
+    last_stream_id: Option<String>,
     /// Last rank we recorded for this driver in the rank-history hash, as
     /// of the start of this batch. Used to skip the HSET (and the tracking
     /// refresh) when the post-write ZRANK is the same — stationary drivers
@@ -95,6 +331,7 @@ struct EnteredForRankHistory {
 
 async fn drain_queue_actions(
     actions: Vec<QueueAction>,
+    reclaimed: bool,
     redis: &RedisConnectionPool,
     queue_expiry: u64,
     queue_exit_hysteresis_threshold: u32,
@@ -176,6 +413,18 @@ async fn drain_queue_actions(
         .zip(trackings.iter())
         .zip(last_ts_results.iter())
     {
+        // A stream batch reclaimed after its drain failed half way carries
+        // pings this tracking may already reflect; applying them again would
+        // count exits twice or undo a later queue switch.
+        if action.is_applied_to(old_tracking.as_ref(), reclaimed) {
+            info!(
+                tag = "[Queue Action Already Applied]",
+                stream_id = ?action.stream_id(),
+                "Skipping queue action from a reclaimed batch"
+            );
+            continue;
+        }
+        let last_stream_id = action.last_stream_id(old_tracking.as_ref());
         match action {
             QueueAction::Enter {
                 merchant_id,
@@ -183,6 +432,8 @@ async fn drain_queue_actions(
                 special_location_id,
                 vehicle_type,
                 timestamp,
+                last_ping_ts,
+                ..
             } => {
                 // True only if prior tracking exists AND points at this same
                 // queue. Drives both (1) carry-forward of last_recorded_rank
@@ -210,6 +461,8 @@ async fn drain_queue_actions(
                     // in-progress hysteresis countdown.
                     consecutive_exit_pings: 0,
                     last_recorded_rank: prev_recorded_rank,
+                    last_ping_ts: Some(*last_ping_ts),
+                    last_stream_id: last_stream_id.clone(),
                 };
 
                 // If driver was in a different queue, evict from the old one:
@@ -362,6 +615,8 @@ async fn drain_queue_actions(
                     special_location_id: special_location_id.clone(),
                     vehicle_type: vehicle_type.clone(),
                     timestamp: *timestamp,
+                    last_ping_ts: *last_ping_ts,
+                    last_stream_id,
                     prev_recorded_rank,
                 });
             }
@@ -369,6 +624,7 @@ async fn drain_queue_actions(
                 merchant_id,
                 driver_id,
                 timestamp,
+                ..
             } => {
                 if let Some(ref tracking) = old_tracking {
                     // Hysteresis: require N consecutive exit pings before actually
@@ -462,6 +718,8 @@ async fn drain_queue_actions(
                             // Hysteresis-pending evict only bumps the counter;
                             // rank tracking is unaffected.
                             last_recorded_rank: tracking.last_recorded_rank,
+                            last_ping_ts: Some(*timestamp),
+                            last_stream_id,
                         };
                         if let Ok(value) = serde_json::to_string(&updated) {
                             let _ = pipeline
@@ -566,6 +824,8 @@ async fn record_rank_history(redis: &RedisConnectionPool, entered: &[EnteredForR
             vehicle_type: e.vehicle_type.clone(),
             consecutive_exit_pings: 0,
             last_recorded_rank: Some(rank),
+            last_ping_ts: Some(e.last_ping_ts),
+            last_stream_id: e.last_stream_id.clone(),
         };
         if let Ok(value) = serde_json::to_string(&updated_tracking) {
             let _ = lpush_pipeline
@@ -591,9 +851,9 @@ async fn record_rank_history(redis: &RedisConnectionPool, entered: &[EnteredForR
 ///
 /// # Arguments
 ///
-/// * `driver_locations` - A reference to a hashmap containing driver locations.
+/// * `buffer` - The driver locations, by city, special location entries and queue actions to drain.
 /// * `bucket_expiry` - The expiration time for a bucket.
-/// * `redis` - A reference to the Redis connection pool.
+/// * `geo_redis` - The clusters serving the geo buckets of each city.
 ///
 /// # Example
 ///
@@ -602,29 +862,64 @@ async fn record_rank_history(redis: &RedisConnectionPool, entered: &[EnteredForR
 /// ```ignore
 /// async fn run_drainer(...) {
 ///     ...
-///     drain_driver_locations(&driver_locations, bucket_expiry, &redis).await;
+///     drain_driver_locations(&buffer, bucket_expiry, &geo_redis).await;
 ///     ...
 /// }
 /// ```
+///
+/// The buffer was coalesced as it filled, so only the latest location, special
+/// location entry and Enter of each driver are sent.
+///
+/// Returns whether the locations and special location entries were all written.
+/// Queue actions are applied in the background and do not count.
 #[allow(clippy::too_many_arguments)]
 async fn drain_driver_locations(
-    driver_locations: &DriversLocationMap,
-    special_location_entries: &FxHashMap<String, Vec<(DriverId, u64, f64)>>,
-    queue_actions: Vec<QueueAction>,
+    buffer: &DrainBuffer,
     bucket_expiry: i64,
     queue_expiry: u64,
     queue_exit_hysteresis_threshold: u32,
     entry_ts_ttl: u32,
-    redis: &Arc<RedisConnectionPool>,
+    geo_redis: &CityRedisRouter,
     queue_redis: &Arc<RedisConnectionPool>,
-) {
+    redis_migration: &RedisMigration,
+) -> bool {
+    // Cities served by the same cluster share one GEOADD batch.
+    let mut cluster_driver_locations: Vec<DriversLocationMap> =
+        vec![FxHashMap::default(); geo_redis.pools().len()];
+    for (city, locations) in buffer.driver_locations() {
+        cluster_driver_locations[geo_redis.pool_index(city)].extend(locations.clone());
+    }
+    let special_location_entries = buffer.special_location_entries();
+    let queue_actions = buffer.queue_actions();
+
     info!(
         tag = "[Queued Entries For Draining]",
-        "Queue: {:?}\nPushing to redis server", driver_locations
+        "Queue: {:?}\nPushing to redis server", cluster_driver_locations
     );
 
-    if let Err(err) = push_drainer_driver_location(driver_locations, &bucket_expiry, redis).await {
-        error!(tag = "[Error Pushing To Redis]", error = %err);
+    let mut drained = true;
+
+    for (index, (redis, locations)) in geo_redis
+        .pools()
+        .iter()
+        .zip(&cluster_driver_locations)
+        .enumerate()
+    {
+        if locations.is_empty() {
+            continue;
+        }
+        // Only the primary cluster takes part in the Redis migration.
+        let pushed = if index == CityRedisRouter::PRIMARY {
+            redis_migration_write!(redis_migration, "geo_bucket", redis, |pool| {
+                push_drainer_driver_location(locations, &bucket_expiry, &pool)
+            })
+        } else {
+            push_drainer_driver_location(locations, &bucket_expiry, redis).await
+        };
+        if let Err(err) = pushed {
+            error!(tag = "[Error Pushing To Redis]", error = %err);
+            drained = false;
+        }
     }
 
     // Bucketed presence ZSET: only membership is consumed (by
@@ -635,34 +930,347 @@ async fn drain_driver_locations(
             let ts = chrono::TimeZone::timestamp_opt(&Utc, *score as i64, 0)
                 .single()
                 .unwrap_or_else(Utc::now);
-            if let Err(err) = add_driver_to_special_location_zset(
+            if let Err(err) = redis_migration_write!(
+                redis_migration,
+                "special_location_zset",
                 queue_redis,
-                key,
-                *bucket,
-                driver_id,
-                &TimeStamp(ts),
-                bucket_expiry,
-            )
-            .await
-            {
+                |redis| add_driver_to_special_location_zset(
+                    &redis,
+                    key,
+                    *bucket,
+                    driver_id,
+                    &TimeStamp(ts),
+                    bucket_expiry,
+                )
+            ) {
                 error!(tag = "[Error Adding To Special Location ZSET]", key = %key, error = %err);
+                drained = false;
             }
         }
     }
 
     // Fire-and-forget queue actions in a spawned task so they don't block the main drain.
+    // While migrating, each cluster applies them against its own queue state.
     if !queue_actions.is_empty() {
-        let queue_redis = Arc::clone(queue_redis);
-        tokio::spawn(async move {
-            drain_queue_actions(
-                queue_actions,
-                &queue_redis,
-                queue_expiry,
-                queue_exit_hysteresis_threshold,
-                entry_ts_ttl,
-            )
-            .await;
-        });
+        let reclaimed = buffer.reclaimed;
+        for queue_redis in redis_migration.write_pools(queue_redis) {
+            let queue_actions = queue_actions.to_vec();
+            tokio::spawn(async move {
+                drain_queue_actions(
+                    queue_actions,
+                    reclaimed,
+                    &queue_redis,
+                    queue_expiry,
+                    queue_exit_hysteresis_threshold,
+                    entry_ts_ttl,
+                )
+                .await;
+            });
+        }
+    }
+
+    drained
+}
+
+/// Latest entry per driver, set aside while the drainer channel is full under the
+/// `Coalesce` policy. Only the latest position matters for the GEO buckets.
+///
+/// Once active, every ping goes here (not to the channel) so a driver's entries
+/// stay in order; the drainer takes the entries back once it has emptied the
+/// channel, or on its next tick. With a WAL attached, entries are appended to it
+/// as they are set aside.
+#[derive(Default)]
+pub struct DrainerOverflow {
+    active: AtomicBool,
+    entries: Mutex<FxHashMap<DriverId, DrainerEntry>>,
+    wal: OnceLock<WalAppender>,
+}
+
+impl DrainerOverflow {
+    fn lock_entries(&self) -> std::sync::MutexGuard<'_, FxHashMap<DriverId, DrainerEntry>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Sets `entry` aside if the overflow is already in use, otherwise hands it back.
+    pub fn push_if_active(&self, entry: DrainerEntry) -> Option<DrainerEntry> {
+        if !self.active.load(Ordering::Acquire) {
+            return Some(entry);
+        }
+        let mut entries = self.lock_entries();
+        if !self.active.load(Ordering::Acquire) {
+            return Some(entry);
+        }
+        self.log(&entry);
+        entries.insert(entry.5.clone(), entry);
+        None
+    }
+
+    /// Sets `entry` aside, replacing an older one for the same driver.
+    pub fn push(&self, entry: DrainerEntry) {
+        let mut entries = self.lock_entries();
+        self.active.store(true, Ordering::Release);
+        self.log(&entry);
+        entries.insert(entry.5.clone(), entry);
+    }
+
+    /// Appends entries set aside from now on to the drainer's WAL.
+    pub fn attach_wal(&self, appender: WalAppender) {
+        let _ = self.wal.set(appender);
+    }
+
+    /// Called with the entries lock held, so it is ordered against `acknowledge_wal`.
+    fn log(&self, entry: &DrainerEntry) {
+        if let Some(wal) = self.wal.get() {
+            if let Err(err) = wal.append(entry) {
+                error!(tag = "[Drainer WAL]", error = %err);
+            }
+        }
+    }
+
+    /// Acknowledges the WAL after a drain, then appends again the entries it still
+    /// has to cover: those taken back but not yet buffered, and those still set
+    /// aside. Senders are held off meanwhile so none of theirs is truncated away.
+    pub fn acknowledge_wal(
+        &self,
+        wal: &mut DrainerWal,
+        pending: &VecDeque<DrainerEntry>,
+    ) -> Result<(), AppError> {
+        let entries = self.lock_entries();
+        wal.acknowledge()?;
+        for entry in pending.iter().chain(entries.values()) {
+            wal.append(entry)?;
+        }
+        Ok(())
+    }
+
+    /// Takes every entry set aside; senders go back to the channel.
+    pub fn take(&self) -> Vec<DrainerEntry> {
+        if !self.active.load(Ordering::Acquire) {
+            return Vec::new();
+        }
+        let mut entries = self.lock_entries();
+        self.active.store(false, Ordering::Release);
+        entries.drain().map(|(_, entry)| entry).collect()
+    }
+}
+
+/// Entries left in the WAL by the previous run are buffered first, then entries
+/// taken back from the overflow, then live entries. Live entries are appended to
+/// the WAL as they are received; overflow entries were appended when set aside.
+/// `rx.recv()` is cancel safe and nothing after it awaits, so an entry is never
+/// lost when the timer branch wins.
+async fn next_drainer_entry(
+    replayed_entries: &mut VecDeque<DrainerEntry>,
+    coalesced_entries: &mut VecDeque<DrainerEntry>,
+    rx: &mut mpsc::Receiver<DrainerEntry>,
+    overflow: &DrainerOverflow,
+    wal: Option<&mut DrainerWal>,
+) -> Option<DrainerEntry> {
+    if let Some(entry) = replayed_entries.pop_front() {
+        return Some(entry);
+    }
+    if let Some(entry) = coalesced_entries.pop_front() {
+        return Some(entry);
+    }
+    let entry = rx.recv().await?;
+    if let Some(wal) = wal {
+        if let Err(err) = wal.append(&entry) {
+            error!(tag = "[Drainer WAL]", error = %err);
+        }
+    }
+    // Overflow entries are newer than anything still in the channel.
+    if rx.is_empty() {
+        coalesced_entries.extend(overflow.take());
+    }
+    Some(entry)
+}
+
+/// Truncates the WAL after a successful drain and logs the overflow entries not yet
+/// drained again. A failed drain leaves the WAL alone; its batch stays buffered and
+/// is covered by the next successful drain. Replayed entries not yet buffered are
+/// only in the WAL, so it is kept until the replay is done.
+fn acknowledge_drain(
+    wal: Option<&mut DrainerWal>,
+    replayed_entries: &VecDeque<DrainerEntry>,
+    coalesced_entries: &VecDeque<DrainerEntry>,
+    overflow: &DrainerOverflow,
+    drained: bool,
+) {
+    let Some(wal) = wal else {
+        return;
+    };
+    if drained && replayed_entries.is_empty() {
+        if let Err(err) = overflow.acknowledge_wal(wal, coalesced_entries) {
+            error!(tag = "[Drainer WAL]", error = %err);
+        }
+    }
+}
+
+/// Settings `buffer_drainer_entry` needs, shared by the in-process and stream drainers.
+struct DrainerEntrySettings {
+    bucket_size: u64,
+    special_location_cache: Option<SpecialLocationCache>,
+    enable_special_location_bucketing: bool,
+    enable_queue_cache_empty_guard: bool,
+}
+
+/// Adds one entry to the pending drain: its bucketed location (unless the special
+/// location suppresses it), special location ZSET entry and queue action.
+///
+/// With `shed_bucketing` the special location ZSET entry is skipped; queue actions
+/// and GEO buckets are always kept.
+#[allow(clippy::too_many_arguments)]
+async fn buffer_drainer_entry(
+    (
+        Dimensions {
+            merchant_id,
+            city,
+            vehicle_type,
+            created_at,
+            merchant_operating_city_id,
+        },
+        Latitude(latitude),
+        Longitude(longitude),
+        TimeStamp(server_timestamp),
+        TimeStamp(timestamp),
+        DriverId(driver_id),
+    ): DrainerEntry,
+    stream_id: Option<&str>,
+    settings: &DrainerEntrySettings,
+    shed_bucketing: bool,
+    buffer: &mut DrainBuffer,
+    drainer_queue_min_max_timestamp_range: &mut Option<(DateTime<Utc>, DateTime<Utc>)>,
+) {
+    let bucket = get_bucket_from_timestamp(&settings.bucket_size, TimeStamp(timestamp));
+
+    let skip_normal_drain = if let Some(ref cache) = settings.special_location_cache {
+        let guard = cache.read().await;
+        let city_entry_count = guard
+            .get(&merchant_operating_city_id)
+            .map(|v| v.len())
+            .unwrap_or(0);
+        info!(tag = "[Special Location Lookup]", driver_id = %driver_id, city_id = %merchant_operating_city_id.0, lat = %latitude, lon = %longitude, "Cache has {} locations for this city", city_entry_count);
+        if let Some(entry) = lookup_special_location(
+            &guard,
+            &merchant_operating_city_id,
+            &Latitude(latitude),
+            &Longitude(longitude),
+        ) {
+            info!(tag = "[Special Location Match]", driver_id = %driver_id, special_location_id = %entry.id.0, queue_enabled = %entry.is_queue_enabled, open_market = %entry.is_open_market_enabled);
+            if settings.enable_special_location_bucketing && shed_bucketing {
+                DRAINER_BACKPRESSURE
+                    .with_label_values(&["bucketing_shed"])
+                    .inc();
+            } else if settings.enable_special_location_bucketing {
+                buffer.add_special_location_entry(
+                    entry.id.0.clone(),
+                    DriverId(driver_id.clone()),
+                    bucket,
+                    server_timestamp.timestamp() as f64,
+                );
+            }
+            // Queue entry: if this special location is queue-enabled, enqueue driver
+            if entry.is_queue_enabled {
+                info!(tag = "[Queue Action]", driver_id = %driver_id, special_location_id = %entry.id.0, vehicle_type = %vehicle_type, "Pushing Enter action");
+                buffer.add_queue_action(QueueAction::Enter {
+                    merchant_id: merchant_id.0.clone(),
+                    driver_id: driver_id.clone(),
+                    special_location_id: entry.id.0.clone(),
+                    vehicle_type: vehicle_type.to_string(),
+                    timestamp: server_timestamp.timestamp() as f64,
+                    last_ping_ts: server_timestamp.timestamp() as f64,
+                    stream_id: stream_id.map(str::to_string),
+                });
+            }
+            !entry.is_open_market_enabled
+        } else if settings.enable_queue_cache_empty_guard && city_entry_count == 0 {
+            // Cache has no polygons for this city (likely mid-reload or
+            // missing data). Treat as "unknown" instead of "outside" so we
+            // don't wipe the queue. Skip both PossibleExit and normal drain
+            // suppression.
+            info!(tag = "[Special Location Cache Empty Guard]", driver_id = %driver_id, city_id = %merchant_operating_city_id.0, "Skipping PossibleExit; cache has 0 entries for city");
+            false
+        } else {
+            // No special location match → possible exit from queue
+            info!(tag = "[Special Location No Match]", driver_id = %driver_id, city_id = %merchant_operating_city_id.0, lat = %latitude, lon = %longitude, "No geofence match, pushing PossibleExit");
+            buffer.add_queue_action(QueueAction::PossibleExit {
+                merchant_id: merchant_id.0.clone(),
+                driver_id: driver_id.clone(),
+                timestamp: server_timestamp.timestamp() as f64,
+                stream_id: stream_id.map(str::to_string),
+            });
+            false
+        }
+    } else {
+        info!(tag = "[Special Location Cache]", driver_id = %driver_id, "Cache is None (special_location_list_base_url not set)");
+        false
+    };
+
+    if !skip_normal_drain {
+        let bucket_key = driver_loc_bucket_key(&merchant_id, &city, &vehicle_type, &bucket);
+        buffer.add_driver_location(
+            city,
+            bucket_key,
+            driver_id,
+            Latitude(latitude),
+            Longitude(longitude),
+        );
+    }
+    *drainer_queue_min_max_timestamp_range = drainer_queue_min_max_timestamp_range.map_or(
+        Some((created_at, created_at)),
+        |(min_duration, max_duration)| {
+            Some((min(created_at, min_duration), max(created_at, max_duration)))
+        },
+    );
+}
+
+/// Backoff between attempts at draining a buffer whose last drain failed.
+#[derive(Default)]
+struct DrainRetry {
+    failures: u32,
+    next_attempt: Option<Instant>,
+}
+
+impl DrainRetry {
+    fn is_due(&self) -> bool {
+        !matches!(self.next_attempt, Some(next_attempt) if Instant::now() < next_attempt)
+    }
+
+    fn record(&mut self, drained: bool) {
+        if drained {
+            *self = DrainRetry::default();
+            return;
+        }
+        let backoff = DRAIN_RETRY_BASE_BACKOFF
+            .saturating_mul(1 << self.failures.min(6))
+            .min(DRAIN_RETRY_MAX_BACKOFF);
+        self.failures += 1;
+        self.next_attempt = Some(Instant::now() + backoff);
+        error!(
+            tag = "[Drain Failed]",
+            failures = self.failures,
+            "Retrying the buffered entries in {:?}",
+            backoff
+        );
+    }
+}
+
+/// Clears the drainer after a successful drain. After a failed one the buffer is
+/// kept for the retry, which `retry` holds off for a growing backoff.
+fn settle_drain(
+    drained: bool,
+    retry: &mut DrainRetry,
+    drainer_size: &mut usize,
+    buffer: &mut DrainBuffer,
+    drainer_queue_min_max_timestamp_range: &mut Option<(DateTime<Utc>, DateTime<Utc>)>,
+) {
+    retry.record(drained);
+    if drained {
+        cleanup_drainer(drainer_size, buffer, drainer_queue_min_max_timestamp_range);
+    } else {
+        buffer.retain_for_retry();
     }
 }
 
@@ -674,21 +1282,19 @@ async fn drain_driver_locations(
 /// # Arguments
 ///
 /// * `drainer_size` - A mutable reference to the current size of the drainer.
-/// * `driver_locations` - A mutable reference to the map storing driver locations.
+/// * `buffer` - A mutable reference to the entries buffered for the drain.
 /// * `drainer_queue_min_max_timestamp_range` - A mutable reference to the minimum and maximum durations for draining the current data batch.
 ///
 fn cleanup_drainer(
     drainer_size: &mut usize,
-    driver_locations: &mut DriversLocationMap,
-    special_location_zset_entries: &mut FxHashMap<String, Vec<(DriverId, u64, f64)>>,
+    buffer: &mut DrainBuffer,
     drainer_queue_min_max_timestamp_range: &mut Option<(DateTime<Utc>, DateTime<Utc>)>,
 ) {
     if let Some((min_drainer_ts, max_drainer_ts)) = drainer_queue_min_max_timestamp_range {
         queue_drainer_latency!(*min_drainer_ts, *max_drainer_ts);
     };
     *drainer_size = 0;
-    *driver_locations = FxHashMap::default();
-    *special_location_zset_entries = FxHashMap::default();
+    *buffer = DrainBuffer::default();
     *drainer_queue_min_max_timestamp_range = None;
 }
 
@@ -705,38 +1311,65 @@ fn cleanup_drainer(
 /// * `drainer_delay` - Time interval for periodic draining.
 /// * `bucket_size` - The size of each time bucket.
 /// * `near_by_bucket_threshold` - A threshold for nearby buckets.
-/// * `redis` - Redis connection pool for non-persistent storage.
+/// * `geo_redis` - The clusters serving the geo buckets of each city.
+/// * `redis_migration` - Mirrors special location and queue writes while migrating Redis.
+/// * `drainer_wal_path` - Write-ahead log for the buffered entries; replayed on startup.
+/// * `overflow` - Entries coalesced by the senders while the channel was full.
+/// * `shed_bucketing_ratio` - Channel fill ratio from which special location bucketing is skipped.
 ///
 #[allow(clippy::too_many_arguments)]
 pub async fn run_drainer(
-    mut rx: mpsc::Receiver<(
-        Dimensions,
-        Latitude,
-        Longitude,
-        TimeStamp,
-        TimeStamp,
-        DriverId,
-    )>,
+    mut rx: mpsc::Receiver<DrainerEntry>,
     graceful_termination_requested: Arc<AtomicBool>,
     drainer_capacity: usize,
     drainer_delay: u64,
     bucket_size: u64,
     near_by_bucket_threshold: u64,
-    redis: Arc<RedisConnectionPool>,
+    geo_redis: CityRedisRouter,
     queue_redis: Arc<RedisConnectionPool>,
+    redis_migration: Arc<RedisMigration>,
     special_location_cache: Option<SpecialLocationCache>,
     enable_special_location_bucketing: bool,
     queue_expiry_seconds: u64,
     queue_exit_hysteresis_threshold: u32,
     enable_queue_cache_empty_guard: bool,
     special_location_entry_ts_ttl_sec: u64,
+    drainer_wal_path: Option<String>,
+    overflow: Arc<DrainerOverflow>,
+    shed_bucketing_ratio: Option<f64>,
 ) {
-    let mut driver_locations: DriversLocationMap = FxHashMap::default();
-    let mut special_location_zset_entries: FxHashMap<String, Vec<(DriverId, u64, f64)>> =
-        FxHashMap::default();
-    let mut queue_actions: Vec<QueueAction> = Vec::new();
+    let (mut wal, mut replayed_entries) = match drainer_wal_path
+        .map(|drainer_wal_path| DrainerWal::open(drainer_wal_path, DRAINER_WAL_MAX_BYTES))
+    {
+        Some(Ok((wal, pending))) => {
+            info!(
+                tag = "[Drainer WAL]",
+                "Replaying {} undrained entries",
+                pending.len()
+            );
+            overflow.attach_wal(wal.appender());
+            (Some(wal), VecDeque::from(pending))
+        }
+        Some(Err(err)) => {
+            error!(tag = "[Drainer WAL]", error = %err, "Running without a write-ahead log");
+            (None, VecDeque::new())
+        }
+        None => (None, VecDeque::new()),
+    };
+
+    let entry_settings = DrainerEntrySettings {
+        bucket_size,
+        special_location_cache,
+        enable_special_location_bucketing,
+        enable_queue_cache_empty_guard,
+    };
+    let mut buffer = DrainBuffer::default();
     let mut timer = interval(Duration::from_secs(drainer_delay));
     let mut drainer_queue_min_max_timestamp_range = None;
+    let mut coalesced_entries: VecDeque<DrainerEntry> = VecDeque::new();
+    let mut drain_retry = DrainRetry::default();
+    let shed_bucketing_threshold =
+        shed_bucketing_ratio.map(|ratio| (ratio * rx.max_capacity() as f64).ceil() as usize);
 
     let mut drainer_size = 0;
 
@@ -748,125 +1381,72 @@ pub async fn run_drainer(
             info!(tag = "[Graceful Shutting Down]", length = %drainer_size);
             if drainer_size > 0 {
                 info!(tag = "[Force Draining Queue]", length = %drainer_size);
-                let actions = std::mem::take(&mut queue_actions);
-                drain_driver_locations(
-                    &driver_locations,
-                    &special_location_zset_entries,
-                    actions,
+                let drained = drain_driver_locations(
+                    &buffer,
                     bucket_expiry,
                     queue_expiry_seconds,
                     queue_exit_hysteresis_threshold,
                     special_location_entry_ts_ttl_sec as u32,
-                    &redis,
+                    &geo_redis,
                     &queue_redis,
+                    &redis_migration,
                 )
                 .await;
-                cleanup_drainer(
+                acknowledge_drain(
+                    wal.as_mut(),
+                    &replayed_entries,
+                    &coalesced_entries,
+                    &overflow,
+                    drained,
+                );
+                // Left in the WAL if it failed, and replayed on the next start.
+                settle_drain(
+                    drained,
+                    &mut drain_retry,
                     &mut drainer_size,
-                    &mut driver_locations,
-                    &mut special_location_zset_entries,
+                    &mut buffer,
                     &mut drainer_queue_min_max_timestamp_range,
                 );
             }
             break;
         }
-        // TODO :: When drainer is ticked that time all locations coming to reciever could get dropped and vice versa.
         tokio::select! {
-            item = rx.recv() => {
+            item = next_drainer_entry(&mut replayed_entries, &mut coalesced_entries, &mut rx, &overflow, wal.as_mut()) => {
                 info!(tag = "[Recieved Entries For Queuing]");
                 match item {
-                    Some((Dimensions { merchant_id, city, vehicle_type, created_at, merchant_operating_city_id }, Latitude(latitude), Longitude(longitude), TimeStamp(server_timestamp), TimeStamp(timestamp), DriverId(driver_id))) => {
-                        let bucket = get_bucket_from_timestamp(&bucket_size, TimeStamp(timestamp));
-
-                        let skip_normal_drain = if let Some(ref cache) = special_location_cache {
-                            let guard = cache.read().await;
-                            let city_entry_count = guard.get(&merchant_operating_city_id).map(|v| v.len()).unwrap_or(0);
-                            info!(tag = "[Special Location Lookup]", driver_id = %driver_id, city_id = %merchant_operating_city_id.0, lat = %latitude, lon = %longitude, "Cache has {} locations for this city", city_entry_count);
-                            if let Some(entry) = lookup_special_location(
-                                &guard,
-                                &merchant_operating_city_id,
-                                &Latitude(latitude),
-                                &Longitude(longitude),
-                            ) {
-                                info!(tag = "[Special Location Match]", driver_id = %driver_id, special_location_id = %entry.id.0, queue_enabled = %entry.is_queue_enabled, open_market = %entry.is_open_market_enabled);
-                                if enable_special_location_bucketing {
-                                    special_location_zset_entries
-                                        .entry(entry.id.0.clone())
-                                        .or_default()
-                                        .push((
-                                            DriverId(driver_id.clone()),
-                                            bucket,
-                                            server_timestamp.timestamp() as f64,
-                                        ));
-                                }
-                                // Queue entry: if this special location is queue-enabled, enqueue driver
-                                if entry.is_queue_enabled {
-                                    info!(tag = "[Queue Action]", driver_id = %driver_id, special_location_id = %entry.id.0, vehicle_type = %vehicle_type, "Pushing Enter action");
-                                    queue_actions.push(QueueAction::Enter {
-                                        merchant_id: merchant_id.0.clone(),
-                                        driver_id: driver_id.clone(),
-                                        special_location_id: entry.id.0.clone(),
-                                        vehicle_type: vehicle_type.to_string(),
-                                        timestamp: server_timestamp.timestamp() as f64,
-                                    });
-                                }
-                                !entry.is_open_market_enabled
-                            } else if enable_queue_cache_empty_guard && city_entry_count == 0 {
-                                // Cache has no polygons for this city (likely mid-reload or
-                                // missing data). Treat as "unknown" instead of "outside" so we
-                                // don't wipe the queue. Skip both PossibleExit and normal drain
-                                // suppression.
-                                info!(tag = "[Special Location Cache Empty Guard]", driver_id = %driver_id, city_id = %merchant_operating_city_id.0, "Skipping PossibleExit; cache has 0 entries for city");
-                                false
-                            } else {
-                                // No special location match → possible exit from queue
-                                info!(tag = "[Special Location No Match]", driver_id = %driver_id, city_id = %merchant_operating_city_id.0, lat = %latitude, lon = %longitude, "No geofence match, pushing PossibleExit");
-                                queue_actions.push(QueueAction::PossibleExit {
-                                    merchant_id: merchant_id.0.clone(),
-                                    driver_id: driver_id.clone(),
-                                    timestamp: server_timestamp.timestamp() as f64,
-                                });
-                                false
-                            }
-                        } else {
-                            info!(tag = "[Special Location Cache]", driver_id = %driver_id, "Cache is None (special_location_list_base_url not set)");
-                            false
-                        };
-
-                        if !skip_normal_drain {
-                            driver_locations
-                                .entry(driver_loc_bucket_key(&merchant_id, &city, &vehicle_type, &bucket))
-                                .or_default()
-                                .push(GeoValue {
-                                    coordinates: GeoPosition {
-                                        latitude,
-                                        longitude,
-                                    },
-                                    member: driver_id.into(),
-                                });
-                        }
-                        drainer_queue_min_max_timestamp_range = drainer_queue_min_max_timestamp_range.map_or(Some((created_at, created_at)), |(min_duration, max_duration)| Some((min(created_at, min_duration), max(created_at, max_duration))));
+                    Some(entry) => {
+                        let shed_bucketing = shed_bucketing_threshold
+                            .is_some_and(|threshold| rx.len() >= threshold);
+                        buffer_drainer_entry(
+                            entry,
+                            None,
+                            &entry_settings,
+                            shed_bucketing,
+                            &mut buffer,
+                            &mut drainer_queue_min_max_timestamp_range,
+                        )
+                        .await;
                         drainer_size += 1;
 
-                        if drainer_size >= drainer_capacity {
+                        if drainer_size >= drainer_capacity && drain_retry.is_due() {
                             info!(tag = "[Force Draining Queue]", length = %drainer_size);
-                            let actions = std::mem::take(&mut queue_actions);
-                            drain_driver_locations(
-                                &driver_locations,
-                                &special_location_zset_entries,
-                                actions,
+                            let drained = drain_driver_locations(
+                                &buffer,
                                 bucket_expiry,
                                 queue_expiry_seconds,
                                 queue_exit_hysteresis_threshold,
                                 special_location_entry_ts_ttl_sec as u32,
-                                &redis,
+                                &geo_redis,
                                 &queue_redis,
+                                &redis_migration,
                             )
                             .await;
-                            cleanup_drainer(
+                            acknowledge_drain(wal.as_mut(), &replayed_entries, &coalesced_entries, &overflow, drained);
+                            settle_drain(
+                                drained,
+                                &mut drain_retry,
                                 &mut drainer_size,
-                                &mut driver_locations,
-                                &mut special_location_zset_entries,
+                                &mut buffer,
                                 &mut drainer_queue_min_max_timestamp_range
                             );
                         }
@@ -880,25 +1460,30 @@ pub async fn run_drainer(
                 }
             },
             _ = timer.tick() => {
-                if drainer_size > 0 {
+                // Senders only sample occupancy when they send; an idle or stuck
+                // channel is sampled here.
+                DRAINER_CHANNEL_OCCUPANCY.set(rx.len() as i64);
+                // Picks up entries coalesced after the channel was last emptied.
+                coalesced_entries.extend(overflow.take());
+                if drainer_size > 0 && drain_retry.is_due() {
                     info!(tag = "[Draining Queue]", length = %drainer_size);
-                    let actions = std::mem::take(&mut queue_actions);
-                    drain_driver_locations(
-                        &driver_locations,
-                        &special_location_zset_entries,
-                        actions,
+                    let drained = drain_driver_locations(
+                        &buffer,
                         bucket_expiry,
                         queue_expiry_seconds,
                         queue_exit_hysteresis_threshold,
                         special_location_entry_ts_ttl_sec as u32,
-                        &redis,
+                        &geo_redis,
                         &queue_redis,
+                        &redis_migration,
                     )
                     .await;
-                    cleanup_drainer(
+                    acknowledge_drain(wal.as_mut(), &replayed_entries, &coalesced_entries, &overflow, drained);
+                    settle_drain(
+                        drained,
+                        &mut drain_retry,
                         &mut drainer_size,
-                        &mut driver_locations,
-                        &mut special_location_zset_entries,
+                        &mut buffer,
                         &mut drainer_queue_min_max_timestamp_range
                     );
                 }
@@ -906,3 +1491,160 @@ pub async fn run_drainer(
         }
     }
 }
+
+/// Drains one partition of the drainer stream as a worker of `config.consumer_group`.
+///
+/// Each read is buffered and drained as one batch, acknowledged, and then trimmed
+/// from the partition up to the oldest entry still pending. A failed drain leaves
+/// its entries pending; they are reclaimed after `claim_idle_ms` by whichever worker
+/// reads the partition next, as are the entries of a worker that died mid-batch.
+/// Queue actions of a reclaimed batch that were already applied are skipped.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_stream_drainer(
+    partition: u32,
+    consumer: String,
+    config: DrainerStreamConfig,
+    graceful_termination_requested: Arc<AtomicBool>,
+    bucket_size: u64,
+    near_by_bucket_threshold: u64,
+    redis: Arc<RedisConnectionPool>,
+    geo_redis: CityRedisRouter,
+    queue_redis: Arc<RedisConnectionPool>,
+    redis_migration: Arc<RedisMigration>,
+    special_location_cache: Option<SpecialLocationCache>,
+    enable_special_location_bucketing: bool,
+    queue_expiry_seconds: u64,
+    queue_exit_hysteresis_threshold: u32,
+    enable_queue_cache_empty_guard: bool,
+    special_location_entry_ts_ttl_sec: u64,
+) {
+    let entry_settings = DrainerEntrySettings {
+        bucket_size,
+        special_location_cache,
+        enable_special_location_bucketing,
+        enable_queue_cache_empty_guard,
+    };
+    let bucket_expiry = (bucket_size * near_by_bucket_threshold) as i64;
+    let poll_interval = Duration::from_millis(config.poll_interval_ms);
+    let mut group_ready = false;
+
+    while !graceful_termination_requested.load(Ordering::Relaxed) {
+        if !group_ready {
+            match ensure_drainer_stream_group(&redis, partition, &config.consumer_group).await {
+                Ok(()) => group_ready = true,
+                Err(err) => {
+                    error!(tag = "[Drainer Stream]", partition = %partition, error = %err);
+                    sleep(poll_interval).await;
+                    continue;
+                }
+            }
+        }
+
+        let mut reclaimed = false;
+        let batch = match claim_idle_drainer_stream_entries(
+            &redis,
+            partition,
+            &config.consumer_group,
+            &consumer,
+            config.claim_idle_ms,
+            config.read_count,
+        )
+        .await
+        {
+            Ok(claimed) if !claimed.is_empty() => {
+                reclaimed = true;
+                Ok(claimed)
+            }
+            Ok(_) => {
+                read_drainer_stream(
+                    &redis,
+                    partition,
+                    &config.consumer_group,
+                    &consumer,
+                    config.read_count,
+                )
+                .await
+            }
+            Err(err) => Err(err),
+        };
+        let batch = match batch {
+            Ok(batch) if !batch.is_empty() => batch,
+            Ok(_) => {
+                sleep(poll_interval).await;
+                continue;
+            }
+            Err(err) => {
+                error!(tag = "[Drainer Stream]", partition = %partition, error = %err);
+                sleep(poll_interval).await;
+                continue;
+            }
+        };
+
+        let mut buffer = DrainBuffer::default();
+        if reclaimed {
+            buffer.set_reclaimed();
+        }
+        let mut drainer_queue_min_max_timestamp_range = None;
+        let mut ids = Vec::with_capacity(batch.len());
+        for (id, entry) in batch {
+            if let Some(entry) = entry {
+                buffer_drainer_entry(
+                    entry,
+                    Some(&id),
+                    &entry_settings,
+                    false,
+                    &mut buffer,
+                    &mut drainer_queue_min_max_timestamp_range,
+                )
+                .await;
+                TOTAL_LOCATION_UPDATES.inc();
+            }
+            ids.push(id);
+        }
+
+        info!(tag = "[Draining Stream]", partition = %partition, length = %ids.len());
+        let drained = drain_driver_locations(
+            &buffer,
+            bucket_expiry,
+            queue_expiry_seconds,
+            queue_exit_hysteresis_threshold,
+            special_location_entry_ts_ttl_sec as u32,
+            &geo_redis,
+            &queue_redis,
+            &redis_migration,
+        )
+        .await;
+        if let Some((min_drainer_ts, max_drainer_ts)) = drainer_queue_min_max_timestamp_range {
+            queue_drainer_latency!(min_drainer_ts, max_drainer_ts);
+        }
+
+        if !drained {
+            DRAINER_STREAM_ENTRIES
+                .with_label_values(&["drain_failed"])
+                .inc_by(ids.len() as u64);
+            continue;
+        }
+        DRAINER_STREAM_ENTRIES
+            .with_label_values(&["drained"])
+            .inc_by(ids.len() as u64);
+        if let Err(err) =
+            ack_drainer_stream_entries(&redis, partition, &config.consumer_group, &ids).await
+        {
+            error!(tag = "[Drainer Stream]", partition = %partition, error = %err, "Acknowledgement failed; the batch will be drained again");
+            continue;
+        }
+        if let Some(acked_id) = ids.last() {
+            if let Err(err) =
+                trim_acked_drainer_stream(&redis, partition, &config.consumer_group, acked_id).await
+            {
+                error!(tag = "[Drainer Stream]", partition = %partition, error = %err, "Trim failed; retried after the next batch");
+            }
+        }
+    }
+
+    info!(
+        tag = "[Drainer Stream]",
+        partition = %partition,
+        "Stopped; unacknowledged entries are reclaimed by the remaining workers"
+    );
+}
@@ -13,13 +13,18 @@ use chrono::NaiveTime;
 use rdkafka::{error::KafkaError, producer::FutureProducer, ClientConfig};
 use reqwest::Url;
 use rustc_hash::FxHashMap;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use shared::redis::types::{RedisConnectionPool, RedisSettings};
 use std::collections::HashMap;
 use tokio::sync::{mpsc::Sender, RwLock};
 use tracing::{error, info};
 
-use crate::common::{geo_polygon::read_geo_polygon, route::read_route_data, types::*};
+use crate::common::{
+    config_override::ConfigOverrideCache, geo_polygon::read_geo_polygon, kafka::KafkaSink,
+    route::read_route_data, types::*, utils::serialize_url,
+};
+use crate::drainer::DrainerOverflow;
+use crate::redis::{migration::RedisMigration, routing::CityRedisRouter};
 use crate::special_location::SpecialLocationCache;
 
 use shared::tools::logger::LoggerConfig;
@@ -32,9 +37,27 @@ pub struct AppConfig {
     pub replica_redis_cfg: Option<RedisConfig>,
     pub secondary_redis_cfg: Option<RedisConfig>,
     pub zone_to_redis_replica_mapping: Option<HashMap<String, String>>,
+    /// Dedicated clusters for the geo buckets of some cities; other cities stay
+    /// on `redis_cfg`. Only geo buckets move: driver details and queues of
+    /// these cities stay on `redis_cfg` / `queue_redis()` (see `CityRedisRouter`).
+    #[serde(default)]
+    pub city_geo_redis_clusters: Vec<CityGeoRedisClusterConfig>,
+    /// Enables migrating LTS state to `secondary_redis_cfg` through the admin API.
+    #[serde(default)]
+    pub redis_migration: Option<RedisMigrationConfig>,
     pub workers: usize,
     pub drainer_delay: u64,
     pub drainer_size: usize,
+    /// Write-ahead log of the drainer buffer, replayed on startup. Should live on
+    /// storage that outlives the container (e.g. an emptyDir). Unset = memory only.
+    #[serde(default)]
+    pub drainer_wal_path: Option<String>,
+    /// Publish drainer entries to partitioned Redis streams, drained by
+    /// `--role drainer` workers, instead of the in-process drainer.
+    #[serde(default)]
+    pub drainer_stream: Option<DrainerStreamConfig>,
+    #[serde(default)]
+    pub drainer_backpressure: DrainerBackpressureConfig,
     pub auth_url: String,
     pub auth_api_key: String,
     pub bulk_location_callback_url: String,
@@ -42,14 +65,28 @@ pub struct AppConfig {
     pub redis_expiry: u32,
     pub min_location_accuracy: f64,
     pub last_location_timstamp_expiry: u32,
+    /// How driver details are written; reads accept either encoding.
+    #[serde(default)]
+    pub driver_details_encoding: DriverDetailsEncoding,
     pub location_update_limit: usize,
     pub location_update_interval: u64,
     pub stop_detection: HashMap<VehicleType, HashMap<RideStatus, StopDetectionConfig>>,
     pub kafka_cfg: KafkaConfig,
     pub secondary_kafka_cfg: Option<KafkaConfig>,
     pub driver_location_update_topic: String,
+    /// Topic for driver availability transitions; without it availability
+    /// is not tracked.
+    #[serde(default)]
+    pub driver_availability_topic: Option<String>,
+    /// An online driver not seen for this long is moved to `Offline`.
+    #[serde(default = "default_driver_availability_ping_timeout")]
+    pub driver_availability_ping_timeout_secs: u64,
     #[serde(default)]
     pub gtfs_id_to_topic: HashMap<String, String>,
+    /// Also run bus-crew pings through the driver location pipeline (route
+    /// projection, route location, detection) of the bus ride on `vehicle_no`.
+    #[serde(default)]
+    pub enable_bus_crew_tracking_pipeline: bool,
     pub batch_size: i64,
     pub bucket_size: u64,
     pub nearby_bucket_threshold: u64,
@@ -76,6 +113,12 @@ pub struct AppConfig {
         HashMap<VehicleType, HashMap<RideStatus, HashMap<DetectionType, ViolationDetectionConfig>>>,
     pub detection_anti_violation_config:
         HashMap<VehicleType, HashMap<RideStatus, HashMap<DetectionType, ViolationDetectionConfig>>>,
+    /// Detections run on rider locations while the rider has an active SOS or
+    /// ride entity. Alerts go to each entry's `detection_callback_url`.
+    #[serde(default)]
+    pub rider_detection_violation_config: HashMap<DetectionType, ViolationDetectionConfig>,
+    #[serde(default)]
+    pub rider_detection_anti_violation_config: HashMap<DetectionType, ViolationDetectionConfig>,
     #[serde(deserialize_with = "deserialize_url")]
     pub google_compute_route_url: Url,
     pub google_api_key: String,
@@ -111,6 +154,34 @@ pub struct AppConfig {
     /// tail. Defaults to 1800 (30 minutes).
     #[serde(default = "default_special_location_entry_ts_ttl")]
     pub special_location_entry_ts_ttl_sec: u64,
+    /// API key required (as the `X-API-Key` header) on `/internal/admin/*`
+    /// endpoints. When unset, the admin API rejects every request.
+    #[serde(default)]
+    pub admin_api_key: Option<String>,
+    /// How often each pod reloads runtime config overrides from Redis.
+    #[serde(default = "default_config_override_refresh_interval")]
+    pub config_override_refresh_interval_secs: u64,
+    /// Per-vehicle thresholds for the in-progress ride rider/driver proximity
+    /// check. Vehicle types without an entry are not checked.
+    #[serde(default)]
+    pub ride_proximity_check: HashMap<VehicleType, RideProximityCheckConfig>,
+    #[serde(default = "default_ride_proximity_check_interval")]
+    pub ride_proximity_check_interval_secs: u64,
+    /// Background broadcast trace polling, so that traces keep flowing when the
+    /// person's app stops sending locations.
+    #[serde(default)]
+    pub broadcast_trace_scheduler: BroadcastTraceSchedulerConfig,
+    /// Arbitration between the sources reporting for the same bus. Without it every
+    /// source publishes the route location as its pings arrive.
+    #[serde(default)]
+    pub vehicle_location_fusion: Option<VehicleLocationFusionConfig>,
+    /// Telematics vendors pushing to `/external/gps/{vendor}`, keyed by vendor name.
+    #[serde(default)]
+    pub external_gps_vendors: HashMap<String, ExternalGpsVendorConfig>,
+    /// Daily online-hours accounting per driver. Without it pings are not
+    /// accounted.
+    #[serde(default)]
+    pub driver_session: Option<DriverSessionConfig>,
 }
 
 fn default_queue_expiry() -> u64 {
@@ -133,6 +204,18 @@ fn default_special_location_entry_ts_ttl() -> u64 {
     1800 // 30 minutes
 }
 
+fn default_config_override_refresh_interval() -> u64 {
+    30
+}
+
+fn default_ride_proximity_check_interval() -> u64 {
+    15
+}
+
+fn default_driver_availability_ping_timeout() -> u64 {
+    600
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct KafkaConfig {
     pub kafka_key: String,
@@ -159,9 +242,60 @@ pub struct RedisConfig {
     pub broadcast_channel_capacity: usize,
 }
 
+impl RedisConfig {
+    fn settings(self) -> RedisSettings {
+        RedisSettings::new(
+            self.redis_host,
+            self.redis_port,
+            self.redis_pool_size,
+            self.redis_partition,
+            self.reconnect_max_attempts,
+            self.reconnect_delay,
+            self.default_ttl,
+            self.default_hash_ttl,
+            self.stream_read_count,
+            self.broadcast_channel_capacity,
+        )
+    }
+}
+
+/// Replica settings, pointed at the pod zone's replica host when one is mapped.
+fn replica_redis_settings(
+    replica_redis_cfg: Option<RedisConfig>,
+    zone_to_redis_replica_mapping: Option<&HashMap<String, String>>,
+    pod_zone: Option<&String>,
+) -> Option<RedisSettings> {
+    let zone_replica_host =
+        pod_zone.and_then(|zone| zone_to_redis_replica_mapping?.get(zone).cloned());
+    replica_redis_cfg.map(|mut replica_redis_cfg| {
+        if let Some(host) = zone_replica_host {
+            replica_redis_cfg.redis_host = host;
+        }
+        replica_redis_cfg.settings()
+    })
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RedisMigrationConfig {
+    /// One shadow-read mismatch out of this many is logged.
+    pub mismatch_log_every: u64,
+    /// How often each pod picks up the phase set through the admin API.
+    pub phase_refresh_interval_secs: u64,
+}
+
+/// A Redis cluster holding the geo buckets, and nothing else, of `cities`,
+/// read through its own zone replicas.
 #[derive(Debug, Deserialize, Clone)]
+pub struct CityGeoRedisClusterConfig {
+    pub cities: Vec<CityName>,
+    pub redis_cfg: RedisConfig,
+    pub replica_redis_cfg: Option<RedisConfig>,
+    pub zone_to_redis_replica_mapping: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct StopDetectionConfig {
-    #[serde(deserialize_with = "deserialize_url")]
+    #[serde(deserialize_with = "deserialize_url", serialize_with = "serialize_url")]
     pub stop_detection_update_callback_url: Url,
     pub max_eligible_stop_speed_threshold: Option<f64>,
     pub radius_threshold_meters: u64,
@@ -169,6 +303,154 @@ pub struct StopDetectionConfig {
     pub enable_onride_stop_detection: bool,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RideProximityCheckConfig {
+    #[serde(deserialize_with = "deserialize_url", serialize_with = "serialize_url")]
+    pub ride_proximity_callback_url: Url,
+    /// Rider and driver further apart than this are counted as separated.
+    pub max_distance: u32,
+    /// Consecutive separated samples needed before an event is raised.
+    pub sample_size: u32,
+    /// Samples where either location is older than this are ignored.
+    pub max_location_age_secs: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct BroadcastTraceSchedulerConfig {
+    pub interval_secs: u64,
+    /// Last known locations older than this are sent flagged `Stale`.
+    pub stale_after_secs: i64,
+    /// Last known locations older than this are sent flagged `SignalLost`.
+    pub signal_lost_after_secs: i64,
+}
+
+impl Default for BroadcastTraceSchedulerConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 5,
+            stale_after_secs: 30,
+            signal_lost_after_secs: 120,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VehicleLocationFusionConfig {
+    /// Most preferred first. Sources not listed rank after every listed one.
+    pub source_priority: Vec<LocationSource>,
+    /// Samples older than this no longer compete.
+    pub max_sample_age_secs: i64,
+    /// A fresh sample less accurate than this loses to any fresh sample within it.
+    pub max_accuracy: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DriverSessionConfig {
+    /// A gap between pings longer than this ends the session; the gap itself
+    /// is not counted.
+    pub ping_gap_threshold_secs: i64,
+    /// Topic the day summaries are published to once the day has closed.
+    pub day_close_topic: String,
+    /// Offset from UTC of the accounting day, e.g. 330 for IST.
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+    /// How long day summaries stay queryable.
+    #[serde(default = "default_driver_session_retention_days")]
+    pub retention_days: u32,
+    #[serde(default = "default_driver_session_day_close_check_interval")]
+    pub day_close_check_interval_secs: u64,
+}
+
+fn default_driver_session_retention_days() -> u32 {
+    7
+}
+
+fn default_driver_session_day_close_check_interval() -> u64 {
+    300
+}
+
+/// Storage encoding of the driver details key, see `redis::codec`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DriverDetailsEncoding {
+    #[default]
+    Json,
+    /// Versioned MessagePack. Only enable once every reader decodes it.
+    Binary,
+}
+
+/// What a ping does when the in-process drainer channel is full.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DrainerFullChannelPolicy {
+    /// Wait for room, holding up the request.
+    #[default]
+    Wait,
+    /// Keep only the latest entry per driver aside until the drainer takes them.
+    Coalesce,
+    /// Drop the entry; the driver's next ping refreshes the GEO bucket.
+    Drop,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DrainerBackpressureConfig {
+    #[serde(default)]
+    pub full_channel_policy: DrainerFullChannelPolicy,
+    /// Channel fill ratio (0..=1) from which the drainer skips special-location
+    /// bucketing. Queue actions and GEO buckets are never shed.
+    #[serde(default)]
+    pub shed_bucketing_ratio: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DrainerStreamConfig {
+    /// A driver's entries always go to the same partition.
+    pub partitions: u32,
+    /// Drainer replicas, run as a StatefulSet: the pod whose `HOSTNAME` ends in
+    /// ordinal N drains the partitions p with p % pods == N, so every partition has
+    /// a single reader and is drained in order. Unset = every pod drains every
+    /// partition.
+    #[serde(default)]
+    pub pods: Option<u32>,
+    pub consumer_group: String,
+    /// Entries per read, i.e. the largest drain batch.
+    pub read_count: u64,
+    /// Pause after reading nothing from a partition.
+    pub poll_interval_ms: u64,
+    /// Pending entries idle this long (worker died, or the drain failed) are
+    /// reclaimed by whichever worker reads the partition next.
+    pub claim_idle_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExternalGpsVendorConfig {
+    /// Expected in the `X-API-Key` header of this vendor's pushes.
+    pub api_key: String,
+    pub schema: GpsVendorSchema,
+    pub timestamp_format: GpsTimestampFormat,
+}
+
+/// Payload layouts understood by the external GPS vendor adapters.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum GpsVendorSchema {
+    /// `ExternalGPSLocationReq`: imei, `dt_server`, lat/lng, plate_number.
+    DtServer,
+    /// `GenericGpsLocationReq`: imei, optional plate_number, timestamp, lat/lon.
+    Generic,
+}
+
+/// How a vendor encodes the time of a fix.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum GpsTimestampFormat {
+    /// chrono `format` in a fixed offset from UTC, e.g. "%Y-%m-%d %H:%M:%S" at 330.
+    Local {
+        format: String,
+        utc_offset_minutes: i32,
+    },
+    EpochSeconds,
+    EpochMillis,
+    Rfc3339,
+}
+
 pub fn deserialize_url<'de, D>(deserializer: D) -> Result<Url, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -182,6 +464,9 @@ pub struct AppState {
     pub redis: Arc<RedisConnectionPool>,
     pub secondary_redis: Option<Arc<RedisConnectionPool>>,
     pub use_secondary_lts_redis: bool,
+    pub geo_redis: CityRedisRouter,
+    pub redis_migration: Arc<RedisMigration>,
+    pub redis_migration_cfg: Option<RedisMigrationConfig>,
     pub sender: Sender<(
         Dimensions,
         Latitude,
@@ -192,6 +477,10 @@ pub struct AppState {
     )>,
     pub drainer_delay: u64,
     pub drainer_size: usize,
+    pub drainer_wal_path: Option<String>,
+    pub drainer_stream: Option<DrainerStreamConfig>,
+    pub drainer_backpressure: DrainerBackpressureConfig,
+    pub drainer_overflow: Arc<DrainerOverflow>,
     pub polygon: Vec<MultiPolygonBody>,
     pub blacklist_polygon: Vec<MultiPolygonBody>,
     pub bus_depot_polygon: Vec<MultiPolygonBody>,
@@ -203,12 +492,17 @@ pub struct AppState {
     pub min_location_accuracy: Accuracy,
     pub stop_detection: HashMap<VehicleType, HashMap<RideStatus, StopDetectionConfig>>,
     pub last_location_timstamp_expiry: u32,
+    pub driver_details_encoding: DriverDetailsEncoding,
     pub location_update_limit: usize,
     pub location_update_interval: u64,
     pub producer: Option<FutureProducer>,
     pub secondary_producer: Option<FutureProducer>,
+    pub kafka_sink: Option<KafkaSink>,
     pub driver_location_update_topic: String,
+    pub driver_availability_topic: Option<String>,
+    pub driver_availability_ping_timeout_secs: u64,
     pub gtfs_id_to_topic: HashMap<String, String>,
+    pub enable_bus_crew_tracking_pipeline: bool,
     pub batch_size: i64,
     pub bucket_size: u64,
     pub nearby_bucket_threshold: u64,
@@ -233,6 +527,8 @@ pub struct AppState {
         HashMap<VehicleType, HashMap<RideStatus, HashMap<DetectionType, ViolationDetectionConfig>>>,
     pub detection_anti_violation_config:
         HashMap<VehicleType, HashMap<RideStatus, HashMap<DetectionType, ViolationDetectionConfig>>>,
+    pub rider_detection_violation_config: HashMap<DetectionType, ViolationDetectionConfig>,
+    pub rider_detection_anti_violation_config: HashMap<DetectionType, ViolationDetectionConfig>,
     pub routes: Arc<RwLock<FxHashMap<String, Route>>>,
     pub google_compute_route_url: Url,
     pub google_api_key: String,
@@ -251,6 +547,15 @@ pub struct AppState {
     pub queue_exit_hysteresis_threshold: u32,
     pub enable_queue_cache_empty_guard: bool,
     pub special_location_entry_ts_ttl_sec: u64,
+    pub admin_api_key: Option<String>,
+    pub config_overrides: ConfigOverrideCache,
+    pub config_override_refresh_interval_secs: u64,
+    pub ride_proximity_check: HashMap<VehicleType, RideProximityCheckConfig>,
+    pub ride_proximity_check_interval_secs: u64,
+    pub broadcast_trace_scheduler: BroadcastTraceSchedulerConfig,
+    pub vehicle_location_fusion: Option<VehicleLocationFusionConfig>,
+    pub external_gps_vendors: HashMap<String, ExternalGpsVendorConfig>,
+    pub driver_session: Option<DriverSessionConfig>,
 }
 
 impl AppState {
@@ -270,67 +575,40 @@ impl AppState {
             .unwrap_or(false); // default: false — use primary Redis for queue operations
 
         let pod_zone = var("POD_ZONE").ok();
-        let new_replica_host = pod_zone
-            .as_ref()
-            .and_then(|zone| app_config.zone_to_redis_replica_mapping.as_ref()?.get(zone))
-            .cloned();
 
         let redis = Arc::new(
             RedisConnectionPool::new(
-                RedisSettings::new(
-                    app_config.redis_cfg.redis_host,
-                    app_config.redis_cfg.redis_port,
-                    app_config.redis_cfg.redis_pool_size,
-                    app_config.redis_cfg.redis_partition,
-                    app_config.redis_cfg.reconnect_max_attempts,
-                    app_config.redis_cfg.reconnect_delay,
-                    app_config.redis_cfg.default_ttl,
-                    app_config.redis_cfg.default_hash_ttl,
-                    app_config.redis_cfg.stream_read_count,
-                    app_config.redis_cfg.broadcast_channel_capacity,
+                app_config.redis_cfg.settings(),
+                replica_redis_settings(
+                    app_config.replica_redis_cfg,
+                    app_config.zone_to_redis_replica_mapping.as_ref(),
+                    pod_zone.as_ref(),
                 ),
-                app_config.replica_redis_cfg.map(|mut replica_redis_cfg| {
-                    if let Some(host) = new_replica_host {
-                        replica_redis_cfg.redis_host = host;
-                    }
-                    RedisSettings::new(
-                        replica_redis_cfg.redis_host,
-                        replica_redis_cfg.redis_port,
-                        replica_redis_cfg.redis_pool_size,
-                        replica_redis_cfg.redis_partition,
-                        replica_redis_cfg.reconnect_max_attempts,
-                        replica_redis_cfg.reconnect_delay,
-                        replica_redis_cfg.default_ttl,
-                        replica_redis_cfg.default_hash_ttl,
-                        replica_redis_cfg.stream_read_count,
-                        replica_redis_cfg.broadcast_channel_capacity,
-                    )
-                }),
             )
             .await
             .expect("Failed to create Generic Redis connection pool"),
         );
 
+        let mut geo_redis = CityRedisRouter::new(redis.clone());
+        for cluster in app_config.city_geo_redis_clusters {
+            let pool = RedisConnectionPool::new(
+                cluster.redis_cfg.settings(),
+                replica_redis_settings(
+                    cluster.replica_redis_cfg,
+                    cluster.zone_to_redis_replica_mapping.as_ref(),
+                    pod_zone.as_ref(),
+                ),
+            )
+            .await
+            .expect("Failed to create city Redis connection pool");
+            info!(tag = "[Redis Connection]", cities = ?cluster.cities, "City Redis connected successfully");
+            geo_redis.add_cluster(cluster.cities, Arc::new(pool));
+        }
+
         let secondary_redis: Option<Arc<RedisConnectionPool>> = match app_config.secondary_redis_cfg
         {
             Some(secondary_cfg) => {
-                match RedisConnectionPool::new(
-                    RedisSettings::new(
-                        secondary_cfg.redis_host,
-                        secondary_cfg.redis_port,
-                        secondary_cfg.redis_pool_size,
-                        secondary_cfg.redis_partition,
-                        secondary_cfg.reconnect_max_attempts,
-                        secondary_cfg.reconnect_delay,
-                        secondary_cfg.default_ttl,
-                        secondary_cfg.default_hash_ttl,
-                        secondary_cfg.stream_read_count,
-                        secondary_cfg.broadcast_channel_capacity,
-                    ),
-                    None,
-                )
-                .await
-                {
+                match RedisConnectionPool::new(secondary_cfg.settings(), None).await {
                     Ok(pool) => {
                         info!(
                             tag = "[Redis Connection]",
@@ -350,6 +628,18 @@ impl AppState {
             None => None,
         };
 
+        let redis_migration = Arc::new(RedisMigration::new(
+            redis.clone(),
+            app_config
+                .redis_migration
+                .as_ref()
+                .and(secondary_redis.clone()),
+            app_config
+                .redis_migration
+                .as_ref()
+                .map_or(1, |cfg| cfg.mismatch_log_every),
+        ));
+
         let geo_config_path = var("GEO_CONFIG").unwrap_or_else(|_| "./geo_config".to_string());
         let polygons = read_geo_polygon(&geo_config_path).expect("Failed to read geoJSON");
 
@@ -442,8 +732,15 @@ impl AppState {
             redis,
             secondary_redis,
             use_secondary_lts_redis,
+            geo_redis,
+            redis_migration,
+            redis_migration_cfg: app_config.redis_migration,
             drainer_delay: app_config.drainer_delay,
             drainer_size: app_config.drainer_size,
+            drainer_wal_path: app_config.drainer_wal_path,
+            drainer_stream: app_config.drainer_stream,
+            drainer_backpressure: app_config.drainer_backpressure,
+            drainer_overflow: Arc::new(DrainerOverflow::default()),
             sender,
             polygon: polygons,
             blacklist_polygon: blacklist_polygons,
@@ -456,12 +753,17 @@ impl AppState {
             min_location_accuracy: Accuracy(app_config.min_location_accuracy),
             redis_expiry: app_config.redis_expiry,
             last_location_timstamp_expiry: app_config.last_location_timstamp_expiry,
+            driver_details_encoding: app_config.driver_details_encoding,
             location_update_limit: app_config.location_update_limit,
             location_update_interval: app_config.location_update_interval,
             producer,
             secondary_producer,
+            kafka_sink: None,
             driver_location_update_topic: app_config.driver_location_update_topic,
+            driver_availability_topic: app_config.driver_availability_topic,
+            driver_availability_ping_timeout_secs: app_config.driver_availability_ping_timeout_secs,
             gtfs_id_to_topic: app_config.gtfs_id_to_topic,
+            enable_bus_crew_tracking_pipeline: app_config.enable_bus_crew_tracking_pipeline,
             batch_size: app_config.batch_size,
             bucket_size: app_config.bucket_size,
             nearby_bucket_threshold: app_config.nearby_bucket_threshold,
@@ -492,6 +794,8 @@ impl AppState {
             arriving_notification_threshold: app_config.arriving_notification_threshold,
             detection_violation_config,
             detection_anti_violation_config,
+            rider_detection_violation_config: app_config.rider_detection_violation_config,
+            rider_detection_anti_violation_config: app_config.rider_detection_anti_violation_config,
             routes: Arc::new(RwLock::new(routes)),
             google_compute_route_url: app_config.google_compute_route_url,
             google_api_key: app_config.google_api_key,
@@ -514,6 +818,15 @@ impl AppState {
             queue_exit_hysteresis_threshold: app_config.queue_exit_hysteresis_threshold,
             enable_queue_cache_empty_guard: app_config.enable_queue_cache_empty_guard,
             special_location_entry_ts_ttl_sec: app_config.special_location_entry_ts_ttl_sec,
+            admin_api_key: app_config.admin_api_key,
+            config_overrides: Arc::new(RwLock::new(FxHashMap::default())),
+            config_override_refresh_interval_secs: app_config.config_override_refresh_interval_secs,
+            ride_proximity_check: app_config.ride_proximity_check,
+            ride_proximity_check_interval_secs: app_config.ride_proximity_check_interval_secs,
+            broadcast_trace_scheduler: app_config.broadcast_trace_scheduler,
+            vehicle_location_fusion: app_config.vehicle_location_fusion,
+            external_gps_vendors: app_config.external_gps_vendors,
+            driver_session: app_config.driver_session,
         }
     }
 
@@ -528,4 +841,14 @@ impl AppState {
         }
         self.redis.clone()
     }
+
+    /// Returns the Redis pool serving `city`'s geo buckets. Cities on the primary
+    /// cluster follow the Redis migration; dedicated clusters are not migrated.
+    pub fn geo_redis_for_city(&self, city: &CityName) -> Arc<RedisConnectionPool> {
+        if self.geo_redis.pool_index(city) == CityRedisRouter::PRIMARY {
+            self.redis_migration.serving(&self.redis)
+        } else {
+            self.geo_redis.for_city(city).clone()
+        }
+    }
 }
@@ -7,7 +7,10 @@
 */
 use super::types::*;
 use crate::{
-    common::{kafka::push_to_kafka, types::*},
+    common::{
+        kafka::{push_to_kafka, KafkaSink},
+        types::*,
+    },
     domain::types::ui::location::UpdateDriverLocationRequest,
 };
 use log::*;
@@ -22,6 +25,7 @@ use rdkafka::producer::FutureProducer;
 /// # Parameters
 /// - `producer`: An optional Kafka producer to send messages to Kafka.
 /// - `secondary_producer`: An optional secondary Kafka producer for dual-write scenarios.
+/// - `kafka_sink`: An optional in-memory sink that replaces both producers when set.
 /// - `topic`: The Kafka topic to which the location updates will be published.
 /// - `locations`: A list of location updates for a driver.
 /// - `merchant_id`: The unique identifier for the merchant.
@@ -37,6 +41,7 @@ use rdkafka::producer::FutureProducer;
 pub async fn kafka_stream_updates(
     producer: &Option<FutureProducer>,
     secondary_producer: &Option<FutureProducer>,
+    kafka_sink: &Option<KafkaSink>,
     topic: &str,
     locations: Vec<(UpdateDriverLocationRequest, LocationType)>,
     server_timestamp: TimeStamp,
@@ -92,8 +97,15 @@ pub async fn kafka_stream_updates(
             location_type,
             next_upcoming_stop_eta, // travelled_distance: travelled_distance.to_owned(),
         };
-        if let Err(err) =
-            push_to_kafka(producer, secondary_producer, topic, key.as_str(), message).await
+        if let Err(err) = push_to_kafka(
+            producer,
+            secondary_producer,
+            kafka_sink,
+            topic,
+            key.as_str(),
+            message,
+        )
+        .await
         {
             error!("Error occured in push_to_kafka => {}", err.message())
         }
@@ -8,15 +8,31 @@
 
 use actix_web::{web, App, HttpServer};
 use location_tracking_service::{
-    common::{route::start_route_refresh_task, types::*, utils::read_dhall_config},
+    common::{
+        broadcast_trace::start_broadcast_trace_scheduler,
+        config_override::start_config_override_refresh_task,
+        driver_availability::start_driver_availability_sweep_task,
+        driver_session::start_driver_session_day_close_task,
+        ride_proximity::start_ride_proximity_check_task, route::start_route_refresh_task, types::*,
+        utils::read_dhall_config,
+    },
     domain::api,
-    drainer::run_drainer,
-    environment::AppState,
+    drainer::{run_drainer, run_stream_drainer},
+    environment::{AppConfig, AppState},
     middleware::*,
     outbound::external::get_special_locations_list,
-    special_location::build_special_location_cache,
-    tools::{error::AppError, prometheus::prometheus_metrics},
+    redis::{
+        commands::{drainer_stream_pod_partitions, pod_ordinal},
+        migration::start_redis_migration_phase_refresh_task,
+    },
+    special_location::{build_special_location_cache, SpecialLocationCache},
+    tools::{
+        config_validation::{validate_config, validate_config_with_url_checks},
+        error::AppError,
+        prometheus::prometheus_metrics,
+    },
 };
+use reqwest::Url;
 use shared::{middleware::incoming_request::IncomingRequestMetrics, tools::logger::setup_tracing};
 use shared::{termination, tools::prometheus::TERMINATION};
 use std::{
@@ -34,17 +50,16 @@ use tracing::error;
 use tracing::info;
 use tracing_actix_web::TracingLogger;
 
-#[actix_web::main]
-async fn start_server() -> std::io::Result<()> {
+fn read_app_config() -> AppConfig {
     let dhall_config_path = var("DHALL_CONFIG")
         .unwrap_or_else(|_| "./dhall-configs/dev/location_tracking_service.dhall".to_string());
-    let app_config = read_dhall_config(&dhall_config_path).unwrap_or_else(|err| {
+    read_dhall_config(&dhall_config_path).unwrap_or_else(|err| {
         println!("Dhall Config Reading Error : {}", err);
         std::process::exit(1);
-    });
-
-    let _guard = setup_tracing(app_config.logger_cfg);
+    })
+}
 
+fn set_panic_hook() {
     std::panic::set_hook(Box::new(|panic_info| {
         termination!("panic", Instant::now());
         let payload = if let Some(s) = panic_info.payload().downcast_ref::<&str>() {
@@ -56,11 +71,44 @@ async fn start_server() -> std::io::Result<()> {
         };
         error!("Panic Occured : {} - {:?}", payload, panic_info);
     }));
+}
 
-    let port = var("SERVICE_PORT")
+/// Set once SIGTERM or SIGINT is received.
+fn listen_for_termination() -> Arc<AtomicBool> {
+    let graceful_termination_requested = Arc::new(AtomicBool::new(false));
+    let graceful_termination_requested_sigterm = graceful_termination_requested.to_owned();
+    let graceful_termination_requested_sigint = graceful_termination_requested.to_owned();
+    // Listen for SIGTERM signal.
+    tokio::spawn(async move {
+        let mut sigterm = signal(SignalKind::terminate()).unwrap();
+        sigterm.recv().await;
+        graceful_termination_requested_sigterm.store(true, Ordering::Relaxed);
+    });
+    // Listen for SIGINT (Ctrl+C) signal.
+    tokio::spawn(async move {
+        let mut ctrl_c = signal(SignalKind::interrupt()).unwrap();
+        ctrl_c.recv().await;
+        graceful_termination_requested_sigint.store(true, Ordering::Relaxed);
+    });
+    graceful_termination_requested
+}
+
+fn service_port(app_config: &AppConfig) -> u16 {
+    var("SERVICE_PORT")
         .ok()
         .and_then(|p| p.parse::<u16>().ok())
-        .unwrap_or(app_config.port);
+        .unwrap_or(app_config.port)
+}
+
+#[actix_web::main]
+async fn start_server() -> std::io::Result<()> {
+    let app_config = read_app_config();
+
+    let _guard = setup_tracing(app_config.logger_cfg);
+
+    set_panic_hook();
+
+    let port = service_port(&app_config);
     let workers = app_config.workers;
     let max_allowed_req_size = app_config.max_allowed_req_size;
 
@@ -88,21 +136,7 @@ async fn start_server() -> std::io::Result<()> {
 
     let data = web::Data::new(app_state);
 
-    let graceful_termination_requested = Arc::new(AtomicBool::new(false));
-    let graceful_termination_requested_sigterm = graceful_termination_requested.to_owned();
-    let graceful_termination_requested_sigint = graceful_termination_requested.to_owned();
-    // Listen for SIGTERM signal.
-    tokio::spawn(async move {
-        let mut sigterm = signal(SignalKind::terminate()).unwrap();
-        sigterm.recv().await;
-        graceful_termination_requested_sigterm.store(true, Ordering::Relaxed);
-    });
-    // Listen for SIGINT (Ctrl+C) signal.
-    tokio::spawn(async move {
-        let mut ctrl_c = signal(SignalKind::interrupt()).unwrap();
-        ctrl_c.recv().await;
-        graceful_termination_requested_sigint.store(true, Ordering::Relaxed);
-    });
+    let graceful_termination_requested = listen_for_termination();
 
     let (
         redis,
@@ -132,12 +166,12 @@ async fn start_server() -> std::io::Result<()> {
         .await;
     });
 
-    let (drainer_size, drainer_delay, bucket_size, nearby_bucket_threshold, redis, queue_redis) = (
+    let (drainer_size, drainer_delay, bucket_size, nearby_bucket_threshold, geo_redis, queue_redis) = (
         data.drainer_size,
         data.drainer_delay,
         data.bucket_size,
         data.nearby_bucket_threshold,
-        data.redis.clone(),
+        data.geo_redis.clone(),
         data.queue_redis(),
     );
     let special_location_cache = data
@@ -149,101 +183,73 @@ async fn start_server() -> std::io::Result<()> {
     let queue_exit_hysteresis_threshold = data.queue_exit_hysteresis_threshold;
     let enable_queue_cache_empty_guard = data.enable_queue_cache_empty_guard;
     let special_location_entry_ts_ttl_sec = data.special_location_entry_ts_ttl_sec;
-    let channel_thread = tokio::spawn(async move {
-        run_drainer(
-            receiver,
-            graceful_termination_requested,
-            drainer_size,
-            drainer_delay,
-            bucket_size,
-            nearby_bucket_threshold,
-            redis,
-            queue_redis,
-            special_location_cache,
-            enable_special_location_bucketing,
-            queue_expiry_seconds,
-            queue_exit_hysteresis_threshold,
-            enable_queue_cache_empty_guard,
-            special_location_entry_ts_ttl_sec,
+    let drainer_wal_path = data.drainer_wal_path.clone();
+    let redis_migration = data.redis_migration.clone();
+    let drainer_overflow = data.drainer_overflow.clone();
+    let shed_bucketing_ratio = data.drainer_backpressure.shed_bucketing_ratio;
+    // With the drainer stream, draining is left to the `--role drainer` workers.
+    let channel_thread = data.drainer_stream.is_none().then(|| {
+        tokio::spawn(async move {
+            run_drainer(
+                receiver,
+                graceful_termination_requested,
+                drainer_size,
+                drainer_delay,
+                bucket_size,
+                nearby_bucket_threshold,
+                geo_redis,
+                queue_redis,
+                redis_migration,
+                special_location_cache,
+                enable_special_location_bucketing,
+                queue_expiry_seconds,
+                queue_exit_hysteresis_threshold,
+                enable_queue_cache_empty_guard,
+                special_location_entry_ts_ttl_sec,
+                drainer_wal_path,
+                drainer_overflow,
+                shed_bucketing_ratio,
+            )
+            .await;
+        })
+    });
+
+    if let Some(ref base_url) = data.special_location_list_base_url {
+        spawn_special_location_cache_refresh(base_url.clone(), data.special_location_cache.clone());
+    }
+
+    spawn_redis_migration_phase_refresh(&data);
+
+    let (config_override_redis, config_overrides, config_override_refresh_interval_secs) = (
+        data.redis.clone(),
+        data.config_overrides.clone(),
+        data.config_override_refresh_interval_secs,
+    );
+    tokio::spawn(async move {
+        start_config_override_refresh_task(
+            config_override_redis,
+            config_overrides,
+            config_override_refresh_interval_secs,
         )
         .await;
     });
 
-    if let Some(ref base_url) = data.special_location_list_base_url {
-        let cache = data.special_location_cache.clone();
-        let base_url = base_url.clone();
-        tokio::spawn(async move {
-            match get_special_locations_list(&base_url).await {
-                Ok(list) => {
-                    info!(
-                        tag = "[Special Location Cache]",
-                        "Fetched {} special locations from API",
-                        list.len()
-                    );
-                    let new_map = build_special_location_cache(list);
-                    let total_entries: usize = new_map.values().map(|v| v.len()).sum();
-                    info!(
-                        tag = "[Special Location Cache]",
-                        "Built cache with {} cities, {} locations (after filtering)",
-                        new_map.len(),
-                        total_entries
-                    );
-                    for (city_id, entries) in &new_map {
-                        for entry in entries {
-                            info!(
-                                tag = "[Special Location Cache]",
-                                "  city={} id={} queue_enabled={} open_market={}",
-                                city_id.0,
-                                entry.id.0,
-                                entry.is_queue_enabled,
-                                entry.is_open_market_enabled
-                            );
-                        }
-                    }
-                    let mut guard = cache.write().await;
-                    *guard = new_map;
-                }
-                Err(e) => {
-                    error!(
-                        tag = "[Special Location Cache]",
-                        "Failed to fetch special locations: {} - {}",
-                        e,
-                        e.message()
-                    );
-                }
-            }
-            let mut interval = tokio::time::interval(Duration::from_secs(300));
-            loop {
-                interval.tick().await;
-                match get_special_locations_list(&base_url).await {
-                    Ok(list) => {
-                        info!(
-                            tag = "[Special Location Cache Refresh]",
-                            "Fetched {} special locations",
-                            list.len()
-                        );
-                        let new_map = build_special_location_cache(list);
-                        let total_entries: usize = new_map.values().map(|v| v.len()).sum();
-                        info!(
-                            tag = "[Special Location Cache Refresh]",
-                            "Rebuilt cache with {} cities, {} locations",
-                            new_map.len(),
-                            total_entries
-                        );
-                        let mut guard = cache.write().await;
-                        *guard = new_map;
-                    }
-                    Err(e) => {
-                        error!(
-                            tag = "[Special Location Cache Refresh]",
-                            "Failed to refresh: {} - {}",
-                            e,
-                            e.message()
-                        );
-                    }
-                }
-            }
-        });
+    let (ride_proximity_redis, ride_proximity_check, ride_proximity_check_interval_secs) = (
+        data.redis.clone(),
+        data.ride_proximity_check.to_owned(),
+        data.ride_proximity_check_interval_secs,
+    );
+    tokio::spawn(async move {
+        start_ride_proximity_check_task(
+            ride_proximity_redis,
+            ride_proximity_check,
+            ride_proximity_check_interval_secs,
+        )
+        .await;
+    });
+
+    if var("RUN_BACKGROUND_TASKS").is_ok_and(|run| run == "true") {
+        spawn_background_tasks(&data);
     }
 
     let prometheus = prometheus_metrics();
@@ -270,15 +276,279 @@ async fn start_server() -> std::io::Result<()> {
     .run()
     .await?;
 
-    tokio::select! {
-        res = channel_thread => {
-            error!("[CHANNEL_THREAD_ENDED] : {:?}", res);
+    if let Some(channel_thread) = channel_thread {
+        tokio::select! {
+            res = channel_thread => {
+                error!("[CHANNEL_THREAD_ENDED] : {:?}", res);
+            }
         }
     }
 
     Err(std::io::Error::other("[MAIN_THREAD_ENDED]"))
 }
 
+/// Keyspace-wide schedulers: the broadcast trace scheduler (with its startup
+/// backfill SCAN), the driver availability sweep and the driver session day
+/// close. They take a lock per window, so running them on every API pod only
+/// adds SCANs and lock contention. They run on `--role drainer` pods, and on an
+/// API pod only when it sets `RUN_BACKGROUND_TASKS=true` (e.g. deployments
+/// without a drainer stream).
+fn spawn_background_tasks(data: &web::Data<AppState>) {
+    let (broadcast_trace_redis, broadcast_trace_redis_migration, broadcast_trace_scheduler) = (
+        data.redis.clone(),
+        data.redis_migration.clone(),
+        data.broadcast_trace_scheduler.to_owned(),
+    );
+    tokio::spawn(async move {
+        start_broadcast_trace_scheduler(
+            broadcast_trace_redis,
+            broadcast_trace_redis_migration,
+            broadcast_trace_scheduler,
+        )
+        .await;
+    });
+
+    tokio::spawn(start_driver_session_day_close_task(data.clone()));
+    tokio::spawn(start_driver_availability_sweep_task(data.clone()));
+}
+
+/// Follows the Redis migration phase set through the admin API, when enabled.
+fn spawn_redis_migration_phase_refresh(data: &AppState) {
+    if let (true, Some(cfg)) = (
+        data.redis_migration.is_enabled(),
+        data.redis_migration_cfg.as_ref(),
+    ) {
+        tokio::spawn(start_redis_migration_phase_refresh_task(
+            data.redis_migration.clone(),
+            cfg.phase_refresh_interval_secs,
+        ));
+    }
+}
+
+/// Loads the special location cache, then refreshes it every 5 minutes.
+fn spawn_special_location_cache_refresh(base_url: Url, cache: SpecialLocationCache) {
+    tokio::spawn(async move {
+        match get_special_locations_list(&base_url).await {
+            Ok(list) => {
+                info!(
+                    tag = "[Special Location Cache]",
+                    "Fetched {} special locations from API",
+                    list.len()
+                );
+                let new_map = build_special_location_cache(list);
+                let total_entries: usize = new_map.values().map(|v| v.len()).sum();
+                info!(
+                    tag = "[Special Location Cache]",
+                    "Built cache with {} cities, {} locations (after filtering)",
+                    new_map.len(),
+                    total_entries
+                );
+                for (city_id, entries) in &new_map {
+                    for entry in entries {
+                        info!(
+                            tag = "[Special Location Cache]",
+                            "  city={} id={} queue_enabled={} open_market={}",
+                            city_id.0,
+                            entry.id.0,
+                            entry.is_queue_enabled,
+                            entry.is_open_market_enabled
+                        );
+                    }
+                }
+                let mut guard = cache.write().await;
+                *guard = new_map;
+            }
+            Err(e) => {
+                error!(
+                    tag = "[Special Location Cache]",
+                    "Failed to fetch special locations: {} - {}",
+                    e,
+                    e.message()
+                );
+            }
+        }
+        let mut interval = tokio::time::interval(Duration::from_secs(300));
+        loop {
+            interval.tick().await;
+            match get_special_locations_list(&base_url).await {
+                Ok(list) => {
+                    info!(
+                        tag = "[Special Location Cache Refresh]",
+                        "Fetched {} special locations",
+                        list.len()
+                    );
+                    let new_map = build_special_location_cache(list);
+                    let total_entries: usize = new_map.values().map(|v| v.len()).sum();
+                    info!(
+                        tag = "[Special Location Cache Refresh]",
+                        "Rebuilt cache with {} cities, {} locations",
+                        new_map.len(),
+                        total_entries
+                    );
+                    let mut guard = cache.write().await;
+                    *guard = new_map;
+                }
+                Err(e) => {
+                    error!(
+                        tag = "[Special Location Cache Refresh]",
+                        "Failed to refresh: {} - {}",
+                        e,
+                        e.message()
+                    );
+                }
+            }
+        }
+    });
+}
+
+/// `location-tracking-service validate-config [path] [--check-urls]`
+///
+/// Parses the dhall config and runs the semantic checks in
+/// `tools::config_validation`, exiting non-zero if any check fails.
+fn validate_config_command(args: Vec<String>) -> bool {
+    let check_urls = args.iter().any(|arg| arg == "--check-urls");
+    let dhall_config_path = args
+        .iter()
+        .find(|arg| !arg.starts_with("--"))
+        .cloned()
+        .or_else(|| var("DHALL_CONFIG").ok())
+        .unwrap_or_else(|| "./dhall-configs/dev/location_tracking_service.dhall".to_string());
+
+    println!("Validating {}", dhall_config_path);
+    let app_config = match read_dhall_config(&dhall_config_path) {
+        Ok(app_config) => app_config,
+        Err(err) => {
+            println!("Dhall Config Reading Error : {}", err);
+            return false;
+        }
+    };
+
+    let report = if check_urls {
+        actix_web::rt::System::new().block_on(validate_config_with_url_checks(
+            &app_config,
+            Duration::from_secs(5),
+        ))
+    } else {
+        validate_config(&app_config)
+    };
+    println!("{}", report);
+
+    !report.has_errors()
+}
+
+/// `location-tracking-service --role drainer`
+///
+/// Runs one drainer stream worker per partition this pod owns instead of the HTTP
+/// API; only `/metrics` is served. With `drainer_stream.pods` set, the pod owns the
+/// partitions matching the ordinal at the end of its `HOSTNAME`, otherwise all of
+/// them. Consumers are named after `HOSTNAME`, so a restarted pod picks up where
+/// it left off. Drainer pods also run the background schedulers
+/// (see `spawn_background_tasks`).
+#[actix_web::main]
+async fn start_drainer() -> std::io::Result<()> {
+    let app_config = read_app_config();
+
+    let _guard = setup_tracing(app_config.logger_cfg);
+
+    set_panic_hook();
+
+    let port = service_port(&app_config);
+    let Some(drainer_stream) = app_config.drainer_stream.to_owned() else {
+        return Err(std::io::Error::other(
+            "--role drainer requires drainer_stream in the config",
+        ));
+    };
+
+    // Drainer workers never send to the in-process drainer.
+    let (sender, _) = mpsc::channel(1);
+    let data = web::Data::new(AppState::new(app_config, sender).await);
+
+    let graceful_termination_requested = listen_for_termination();
+
+    if let Some(ref base_url) = data.special_location_list_base_url {
+        spawn_special_location_cache_refresh(base_url.clone(), data.special_location_cache.clone());
+    }
+
+    spawn_redis_migration_phase_refresh(&data);
+
+    spawn_background_tasks(&data);
+
+    let consumer_prefix = var("HOSTNAME").unwrap_or_else(|_| "drainer".to_string());
+    let partitions = match drainer_stream.pods {
+        Some(pods) => {
+            let Some(ordinal) = pod_ordinal(&consumer_prefix) else {
+                return Err(std::io::Error::other(
+                    "drainer_stream.pods requires a HOSTNAME ending in the pod ordinal",
+                ));
+            };
+            drainer_stream_pod_partitions(drainer_stream.partitions, pods, ordinal)
+        }
+        None => (0..drainer_stream.partitions).collect(),
+    };
+    info!(
+        tag = "[Drainer Stream]",
+        "Draining partitions {:?} as {}", partitions, consumer_prefix
+    );
+    let workers: Vec<_> = partitions
+        .into_iter()
+        .map(|partition| {
+            tokio::spawn(run_stream_drainer(
+                partition,
+                format!("{consumer_prefix}:{partition}"),
+                drainer_stream.to_owned(),
+                graceful_termination_requested.to_owned(),
+                data.bucket_size,
+                data.nearby_bucket_threshold,
+                data.redis.clone(),
+                data.geo_redis.clone(),
+                data.queue_redis(),
+                data.redis_migration.clone(),
+                data.special_location_list_base_url
+                    .as_ref()
+                    .map(|_| data.special_location_cache.clone()),
+                data.enable_special_location_bucketing,
+                data.queue_expiry_seconds,
+                data.queue_exit_hysteresis_threshold,
+                data.enable_queue_cache_empty_guard,
+                data.special_location_entry_ts_ttl_sec,
+            ))
+        })
+        .collect();
+
+    let prometheus = prometheus_metrics();
+    let server = HttpServer::new(move || App::new().wrap(prometheus.clone()))
+        .workers(1)
+        .bind((Ipv4Addr::UNSPECIFIED, port))?
+        .run();
+    let server_handle = server.handle();
+    tokio::spawn(server);
+
+    for worker in workers {
+        if let Err(err) = worker.await {
+            error!("[DRAINER_WORKER_ENDED] : {:?}", err);
+        }
+    }
+    server_handle.stop(true).await;
+
+    Ok(())
+}
+
 fn main() {
-    start_server().expect("Failed to start the server");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("validate-config") {
+        let valid = validate_config_command(args.into_iter().skip(1).collect());
+        std::process::exit(if valid { 0 } else { 1 });
+    }
+    let role = args
+        .iter()
+        .position(|arg| arg == "--role")
+        .and_then(|index| args.get(index + 1));
+    match role.map(String::as_str) {
+        None | Some("api") => start_server().expect("Failed to start the server"),
+        Some("drainer") => start_drainer().expect("Failed to start the drainer"),
+        Some(role) => {
+            println!("Unknown role : {role} (expected api or drainer)");
+            std::process::exit(1);
+        }
+    }
 }
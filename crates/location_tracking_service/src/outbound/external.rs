@@ -142,6 +142,7 @@ pub async fn bulk_location_update_dobpp(
     ride_id: RideId,
     driver_id: DriverId,
     on_ride_driver_locations: Vec<LocationUpdate>,
+    late: bool,
 ) -> Result<APISuccess, AppError> {
     call_api::<APISuccess, BulkDataReq>(
         Protocol::Http1,
@@ -152,6 +153,7 @@ pub async fn bulk_location_update_dobpp(
             ride_id,
             driver_id,
             loc: on_ride_driver_locations.clone(),
+            late: late.then_some(true),
         }),
         None,
     )
@@ -355,6 +357,38 @@ pub async fn trigger_detection_alert(
     .map_err(|e| e.into())
 }
 
+pub async fn trigger_ride_proximity_event(
+    ride_proximity_callback_url: &Url,
+    ride_proximity_event_req: RideProximityEventReq,
+) -> Result<APISuccess, AppError> {
+    call_api::<APISuccess, RideProximityEventReq>(
+        Protocol::Http1,
+        Method::POST,
+        ride_proximity_callback_url,
+        vec![("content-type", "application/json")],
+        Some(ride_proximity_event_req),
+        None,
+    )
+    .await
+    .map_err(|e| e.into())
+}
+
+pub async fn trigger_rider_safety_alert(
+    alert_url: &Url,
+    rider_safety_alert_req: RiderSafetyAlertReq,
+) -> Result<APISuccess, AppError> {
+    call_api::<APISuccess, RiderSafetyAlertReq>(
+        Protocol::Http1,
+        Method::POST,
+        alert_url,
+        vec![("content-type", "application/json")],
+        Some(rider_safety_alert_req),
+        None,
+    )
+    .await
+    .map_err(|e| e.into())
+}
+
 /// Computes routes between two points using the Google Routes API.
 ///
 /// This function communicates with the Google Routes API to calculate
@@ -7,14 +7,26 @@
 */
 
 use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
 use reqwest::{Client, Method, StatusCode, Url};
+use rumqttc::{
+    AsyncClient, ConnectReturnCode, ConnectionError, Event, MqttOptions, Outgoing, Packet, QoS,
+    Transport,
+};
 use serde::Serialize;
-use shared::tools::callapi::call_api_with_client;
+use sha2::Sha256;
+use std::collections::HashMap;
 use std::fmt::Debug;
-use std::sync::LazyLock;
-use std::time::Duration;
+use std::sync::{Arc, LazyLock, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
 
-use crate::common::types::{BroadcastTraceConfig, ErssProviderConfig, TraceProvider};
+use crate::common::types::{
+    BroadcastTraceConfig, ErssProviderConfig, MqttProviderConfig, TraceProvider, TraceSignal,
+    WebhookAuth, WebhookProviderConfig,
+};
 use crate::tools::error::AppError;
 
 /// Trait for external location providers that receive broadcast trace pings.
@@ -31,7 +43,9 @@ use crate::tools::error::AppError;
 #[async_trait]
 pub trait ExternalLocationProvider: Send + Sync {
     /// Send a location ping. All connection context (URL, token) is already held by the provider.
-    async fn send_ping(&self, lat: f64, lon: f64, datetime_str: &str) -> Result<(), AppError>;
+    /// Returns the HTTP status the ping was accepted with, when the transport exposes one.
+    /// Rejections carry theirs in `TraceTokenExpired` (401) / `TraceDeliveryFailed`.
+    async fn send_ping(&self, ping: &TracePing<'_>) -> Result<Option<u16>, AppError>;
 
     /// Swap in refreshed credentials without rebuilding the provider.
     fn update_token(&mut self, access_token: String, token_expires_at: i64);
@@ -40,6 +54,15 @@ pub trait ExternalLocationProvider: Send + Sync {
     fn token_expires_at(&self) -> i64;
 }
 
+/// Stable provider label for logs, metrics and the delivery audit log.
+pub fn provider_name(provider: &TraceProvider) -> &'static str {
+    match provider {
+        TraceProvider::Erss(_) => "erss",
+        TraceProvider::Webhook(_) => "webhook",
+        TraceProvider::Mqtt(_) => "mqtt",
+    }
+}
+
 /// Factory: build the right provider from a `BroadcastTraceConfig`. All common fields
 /// (base_url, access_token, token_expires_at) are read from `cfg`; provider-specific
 /// fields come from `cfg.provider`.
@@ -55,6 +78,96 @@ pub fn make_provider(
                 .config(erss_cfg.clone())
                 .build()?,
         )),
+        TraceProvider::Webhook(webhook_cfg) => Ok(Box::new(
+            WebhookLocationProvider::builder()
+                .base_url(cfg.base_url.clone())
+                .access_token(cfg.access_token.clone())
+                .token_expires_at(cfg.token_expires_at)
+                .external_reference_id(cfg.external_reference_id.clone())
+                .config(webhook_cfg.clone())
+                .build()?,
+        )),
+        TraceProvider::Mqtt(mqtt_cfg) => Ok(Box::new(
+            MqttLocationProvider::builder()
+                .access_token(cfg.access_token.clone())
+                .token_expires_at(cfg.token_expires_at)
+                .external_reference_id(cfg.external_reference_id.clone())
+                .config(mqtt_cfg.clone())
+                .build()?,
+        )),
+    }
+}
+
+/// One location ping handed to a provider.
+pub struct TracePing<'a> {
+    pub lat: f64,
+    pub lon: f64,
+    /// City-local `YYYY-MM-DD HH:MM:SS` of the location fix.
+    pub datetime: &'a str,
+    /// Epoch seconds of the location fix.
+    pub timestamp: i64,
+    pub signal: TraceSignal,
+}
+
+// --------------- Templating ---------------
+
+/// Per-ping values substituted into webhook and MQTT templates.
+pub struct TraceTemplateValues<'a> {
+    pub lat: f64,
+    pub lon: f64,
+    pub datetime: &'a str,
+    pub timestamp: i64,
+    pub signal: TraceSignal,
+    pub access_token: &'a str,
+    pub external_reference_id: &'a str,
+}
+
+impl TraceTemplateValues<'_> {
+    fn numeric(&self, placeholder: &str) -> Option<serde_json::Value> {
+        match placeholder {
+            "{{lat}}" => serde_json::Number::from_f64(self.lat).map(serde_json::Value::Number),
+            "{{lon}}" => serde_json::Number::from_f64(self.lon).map(serde_json::Value::Number),
+            "{{timestamp}}" => Some(serde_json::Value::from(self.timestamp)),
+            _ => None,
+        }
+    }
+}
+
+/// Replace every known `{{placeholder}}` in `template`.
+pub fn render_template(template: &str, values: &TraceTemplateValues) -> String {
+    template
+        .replace("{{lat}}", &values.lat.to_string())
+        .replace("{{lon}}", &values.lon.to_string())
+        .replace("{{datetime}}", values.datetime)
+        .replace("{{timestamp}}", &values.timestamp.to_string())
+        .replace("{{signal}}", &values.signal.to_string())
+        .replace("{{accessToken}}", values.access_token)
+        .replace("{{externalReferenceId}}", values.external_reference_id)
+}
+
+/// Render every string in a JSON template. A string that is exactly `{{lat}}`, `{{lon}}`
+/// or `{{timestamp}}` becomes a JSON number rather than a string.
+pub fn render_json_template(
+    template: &serde_json::Value,
+    values: &TraceTemplateValues,
+) -> serde_json::Value {
+    match template {
+        serde_json::Value::String(s) => values
+            .numeric(s)
+            .unwrap_or_else(|| serde_json::Value::String(render_template(s, values))),
+        serde_json::Value::Array(items) => serde_json::Value::Array(
+            items
+                .iter()
+                .map(|item| render_json_template(item, values))
+                .collect(),
+        ),
+        serde_json::Value::Object(fields) => serde_json::Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key.to_owned(), render_json_template(value, values)))
+                .collect(),
+        ),
+        other => other.to_owned(),
     }
 }
 
@@ -139,13 +252,15 @@ impl ErssLocationProviderBuilder {
 
 #[async_trait]
 impl ExternalLocationProvider for ErssLocationProvider {
-    async fn send_ping(&self, lat: f64, lon: f64, datetime_str: &str) -> Result<(), AppError> {
+    async fn send_ping(&self, ping: &TracePing<'_>) -> Result<Option<u16>, AppError> {
         // Packet format: dateTime,latitude,longitude,mobileNo,authId,authCode,SOS#
+        // The packet has no status field; a stale or lost signal shows only through the
+        // fix time in dateTime.
         let packet = format!(
             "{},{},{},{},{},{},SOS#",
-            datetime_str,
-            lat,
-            lon,
+            ping.datetime,
+            ping.lat,
+            ping.lon,
             self.config.mobile_no,
             self.config.auth_id,
             self.config.auth_code
@@ -165,32 +280,514 @@ impl ExternalLocationProvider for ErssLocationProvider {
         let url = Url::parse(&url_str)
             .map_err(|e| AppError::InvalidRequest(format!("Invalid trace URL: {}", e)))?;
 
-        let bearer = format!("Bearer {}", self.access_token);
-        call_api_with_client::<serde_json::Value, TraceReq, AppError>(
-            &self.client,
-            Method::POST,
-            &url,
-            vec![
-                ("content-type", "application/json"),
-                ("Authorization", &bearer),
-            ],
-            Some(TraceReq { packet: &packet }),
-            None,
-            Box::new(|resp| {
-                Box::pin(async move {
-                    if resp.status() == StatusCode::UNAUTHORIZED {
-                        AppError::TraceTokenExpired
-                    } else {
-                        AppError::ExternalAPICallError(format!(
-                            "Broadcast trace HTTP {}",
-                            resp.status()
+        let resp = self
+            .client
+            .post(url)
+            .bearer_auth(&self.access_token)
+            .json(&TraceReq { packet: &packet })
+            .send()
+            .await
+            .map_err(|e| AppError::ExternalAPICallError(format!("Broadcast trace: {}", e)))?;
+        match resp.status() {
+            status if status.is_success() => Ok(Some(status.as_u16())),
+            StatusCode::UNAUTHORIZED => Err(AppError::TraceTokenExpired),
+            status => Err(AppError::TraceDeliveryFailed(status.as_u16())),
+        }
+    }
+
+    fn update_token(&mut self, access_token: String, token_expires_at: i64) {
+        self.access_token = access_token;
+        self.token_expires_at = token_expires_at;
+    }
+
+    fn access_token(&self) -> &str {
+        &self.access_token
+    }
+
+    fn token_expires_at(&self) -> i64 {
+        self.token_expires_at
+    }
+}
+
+// --------------- Webhook Provider ---------------
+
+static WEBHOOK_CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    Client::builder()
+        .pool_max_idle_per_host(10)
+        .pool_idle_timeout(Duration::from_secs(30))
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap_or_default()
+});
+
+pub struct WebhookLocationProvider {
+    base_url: String,
+    access_token: String,
+    token_expires_at: i64,
+    external_reference_id: String,
+    method: Method,
+    config: WebhookProviderConfig,
+    client: Client,
+}
+
+// --- Builder ---
+
+#[derive(Default)]
+pub struct WebhookLocationProviderBuilder {
+    base_url: Option<String>,
+    access_token: Option<String>,
+    token_expires_at: Option<i64>,
+    external_reference_id: Option<String>,
+    config: Option<WebhookProviderConfig>,
+}
+
+impl WebhookLocationProvider {
+    pub fn builder() -> WebhookLocationProviderBuilder {
+        WebhookLocationProviderBuilder::default()
+    }
+}
+
+impl WebhookLocationProviderBuilder {
+    pub fn base_url(mut self, v: impl Into<String>) -> Self {
+        self.base_url = Some(v.into());
+        self
+    }
+
+    pub fn access_token(mut self, v: impl Into<String>) -> Self {
+        self.access_token = Some(v.into());
+        self
+    }
+
+    pub fn token_expires_at(mut self, v: i64) -> Self {
+        self.token_expires_at = Some(v);
+        self
+    }
+
+    pub fn external_reference_id(mut self, v: impl Into<String>) -> Self {
+        self.external_reference_id = Some(v.into());
+        self
+    }
+
+    pub fn config(mut self, c: WebhookProviderConfig) -> Self {
+        self.config = Some(c);
+        self
+    }
+
+    pub fn build(self) -> Result<WebhookLocationProvider, AppError> {
+        let config = self.config.ok_or_else(|| {
+            AppError::InvalidRequest("WebhookLocationProvider: config is required".into())
+        })?;
+        let method = match config.method.as_deref() {
+            Some(method) => Method::from_bytes(method.to_uppercase().as_bytes()).map_err(|_| {
+                AppError::InvalidRequest(format!(
+                    "WebhookLocationProvider: invalid method {}",
+                    method
+                ))
+            })?,
+            None => Method::POST,
+        };
+        Ok(WebhookLocationProvider {
+            base_url: self.base_url.ok_or_else(|| {
+                AppError::InvalidRequest("WebhookLocationProvider: base_url is required".into())
+            })?,
+            access_token: self.access_token.ok_or_else(|| {
+                AppError::InvalidRequest("WebhookLocationProvider: access_token is required".into())
+            })?,
+            token_expires_at: self.token_expires_at.ok_or_else(|| {
+                AppError::InvalidRequest(
+                    "WebhookLocationProvider: token_expires_at is required".into(),
+                )
+            })?,
+            external_reference_id: self.external_reference_id.unwrap_or_default(),
+            method,
+            config,
+            client: WEBHOOK_CLIENT.clone(),
+        })
+    }
+}
+
+// --- Trait impl ---
+
+#[async_trait]
+impl ExternalLocationProvider for WebhookLocationProvider {
+    async fn send_ping(&self, ping: &TracePing<'_>) -> Result<Option<u16>, AppError> {
+        let values = TraceTemplateValues {
+            lat: ping.lat,
+            lon: ping.lon,
+            datetime: ping.datetime,
+            timestamp: ping.timestamp,
+            signal: ping.signal,
+            access_token: &self.access_token,
+            external_reference_id: &self.external_reference_id,
+        };
+
+        let url_str = render_template(&self.config.url_template, &values);
+        let url = if url_str.starts_with("http://") || url_str.starts_with("https://") {
+            Url::parse(&url_str)
+        } else {
+            Url::parse(&format!(
+                "{}/{}",
+                self.base_url.trim_end_matches('/'),
+                url_str.trim_start_matches('/')
+            ))
+        }
+        .map_err(|e| AppError::InvalidRequest(format!("Invalid trace URL: {}", e)))?;
+
+        // The body is rendered to bytes up front so an HMAC signature covers exactly what is sent.
+        let body = self
+            .config
+            .body_template
+            .as_ref()
+            .map(|template| render_json_template(template, &values).to_string());
+
+        let mut request = self.client.request(self.method.clone(), url);
+        if body.is_some() {
+            request = request.header("content-type", "application/json");
+        }
+        for (name, value) in &self.config.headers {
+            request = request.header(name.as_str(), render_template(value, &values));
+        }
+        request = match &self.config.auth {
+            WebhookAuth::None => request,
+            WebhookAuth::Bearer => request.bearer_auth(&self.access_token),
+            WebhookAuth::Basic { username, password } => request.header(
+                "Authorization",
+                format!("Basic {}", BASE64.encode(format!("{username}:{password}"))),
+            ),
+            WebhookAuth::HmacSha256 {
+                secret,
+                header,
+                prefix,
+            } => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).map_err(|e| {
+                    AppError::InvalidRequest(format!("Invalid webhook HMAC secret: {}", e))
+                })?;
+                mac.update(body.as_deref().unwrap_or_default().as_bytes());
+                let signature = hex::encode(mac.finalize().into_bytes());
+                request.header(
+                    header.as_str(),
+                    format!("{}{}", prefix.as_deref().unwrap_or_default(), signature),
+                )
+            }
+        };
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+
+        let resp = request
+            .send()
+            .await
+            .map_err(|e| AppError::ExternalAPICallError(format!("Broadcast trace: {}", e)))?;
+        match resp.status() {
+            status if status.is_success() => Ok(Some(status.as_u16())),
+            StatusCode::UNAUTHORIZED => Err(AppError::TraceTokenExpired),
+            status => Err(AppError::TraceDeliveryFailed(status.as_u16())),
+        }
+    }
+
+    fn update_token(&mut self, access_token: String, token_expires_at: i64) {
+        self.access_token = access_token;
+        self.token_expires_at = token_expires_at;
+    }
+
+    fn access_token(&self) -> &str {
+        &self.access_token
+    }
+
+    fn token_expires_at(&self) -> i64 {
+        self.token_expires_at
+    }
+}
+
+// --------------- MQTT Provider ---------------
+
+const MQTT_PUBLISH_TIMEOUT: Duration = Duration::from_secs(5);
+const MQTT_KEEP_ALIVE: Duration = Duration::from_secs(30);
+const MQTT_RECONNECT_BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MQTT_RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Connections no ping has used for this long are closed, so broadcasters that
+/// were removed do not keep a broker connection open.
+const MQTT_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Broker connections shared by every ping of a broadcaster. Providers are
+/// rebuilt per ping, so the connections live here rather than on the provider.
+static MQTT_CONNECTIONS: LazyLock<
+    StdMutex<HashMap<MqttConnectionKey, (Arc<MqttConnection>, Instant)>>,
+> = LazyLock::new(|| StdMutex::new(HashMap::new()));
+
+/// What identifies a broker session. Credentials are not part of the key: a
+/// refreshed token replaces the broadcaster's connection instead of opening a
+/// second one.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct MqttConnectionKey {
+    broker_host: String,
+    broker_port: u16,
+    use_tls: bool,
+    client_id: Option<String>,
+    username: Option<String>,
+}
+
+/// Event loop progress forwarded to the ping waiting on it.
+#[derive(Clone, Debug)]
+enum MqttEvent {
+    Published(u16),
+    Acked(u16),
+    Refused,
+    Failed(String),
+}
+
+/// One client whose event loop is polled by a background task for as long as
+/// the client lives. rumqttc reconnects on the next poll after an error, so the
+/// task only backs off between attempts; it stops when the broker refuses the
+/// credentials or the client is dropped.
+struct MqttConnection {
+    client: AsyncClient,
+    password: Option<String>,
+    events: broadcast::Sender<MqttEvent>,
+    /// One publish in flight at a time, so the next `Published` event is ours.
+    publishing: tokio::sync::Mutex<()>,
+    poller: JoinHandle<()>,
+}
+
+impl MqttConnection {
+    fn connect(key: &MqttConnectionKey, password: Option<String>) -> Self {
+        let client_id = key
+            .client_id
+            .to_owned()
+            .unwrap_or_else(|| format!("lts-{}", uuid::Uuid::new_v4()));
+        let mut options = MqttOptions::new(client_id, key.broker_host.as_str(), key.broker_port);
+        options.set_keep_alive(MQTT_KEEP_ALIVE);
+        if key.use_tls {
+            options.set_transport(Transport::tls_with_default_config());
+        }
+        if let (Some(username), Some(password)) = (key.username.as_ref(), password.as_ref()) {
+            options.set_credentials(username, password);
+        }
+
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        let (events, _) = broadcast::channel(64);
+        let sender = events.clone();
+        let poller = tokio::spawn(async move {
+            let mut backoff = MQTT_RECONNECT_BASE_BACKOFF;
+            loop {
+                let event = match eventloop.poll().await {
+                    Ok(Event::Outgoing(Outgoing::Publish(pkid))) => MqttEvent::Published(pkid),
+                    Ok(Event::Incoming(Packet::PubAck(ack))) => MqttEvent::Acked(ack.pkid),
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                    Ok(_) => {
+                        backoff = MQTT_RECONNECT_BASE_BACKOFF;
+                        continue;
+                    }
+                    Err(ConnectionError::RequestsDone) => break,
+                    Err(ConnectionError::ConnectionRefused(
+                        ConnectReturnCode::NotAuthorized | ConnectReturnCode::BadUserNamePassword,
+                    )) => {
+                        let _ = sender.send(MqttEvent::Refused);
+                        break;
+                    }
+                    Err(e) => {
+                        let _ = sender.send(MqttEvent::Failed(e.to_string()));
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MQTT_RECONNECT_MAX_BACKOFF);
+                        continue;
+                    }
+                };
+                let _ = sender.send(event);
+            }
+        });
+
+        MqttConnection {
+            client,
+            password,
+            events,
+            publishing: tokio::sync::Mutex::new(()),
+            poller,
+        }
+    }
+
+    /// The connection for `key`, reusing the open one unless its credentials
+    /// changed or its event loop stopped.
+    fn shared(key: MqttConnectionKey, password: Option<String>) -> Arc<MqttConnection> {
+        let mut connections = MQTT_CONNECTIONS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        connections.retain(|_, (connection, last_used)| {
+            last_used.elapsed() < MQTT_IDLE_TIMEOUT && !connection.poller.is_finished()
+        });
+        match connections.get_mut(&key) {
+            Some((connection, last_used)) if connection.password == password => {
+                *last_used = Instant::now();
+                connection.clone()
+            }
+            _ => {
+                let connection = Arc::new(MqttConnection::connect(&key, password));
+                connections.insert(key, (connection.clone(), Instant::now()));
+                connection
+            }
+        }
+    }
+}
+
+impl Drop for MqttConnection {
+    fn drop(&mut self) {
+        // Queues a DISCONNECT; the poller sends it and then stops.
+        let _ = self.client.try_disconnect();
+    }
+}
+
+/// Publishes pings over a broker connection shared by every ping of the
+/// broadcaster (see `MqttConnection`), instead of connecting per ping.
+pub struct MqttLocationProvider {
+    access_token: String,
+    token_expires_at: i64,
+    external_reference_id: String,
+    qos: QoS,
+    config: MqttProviderConfig,
+}
+
+// --- Builder ---
+
+#[derive(Default)]
+pub struct MqttLocationProviderBuilder {
+    access_token: Option<String>,
+    token_expires_at: Option<i64>,
+    external_reference_id: Option<String>,
+    config: Option<MqttProviderConfig>,
+}
+
+impl MqttLocationProvider {
+    pub fn builder() -> MqttLocationProviderBuilder {
+        MqttLocationProviderBuilder::default()
+    }
+}
+
+impl MqttLocationProviderBuilder {
+    pub fn access_token(mut self, v: impl Into<String>) -> Self {
+        self.access_token = Some(v.into());
+        self
+    }
+
+    pub fn token_expires_at(mut self, v: i64) -> Self {
+        self.token_expires_at = Some(v);
+        self
+    }
+
+    pub fn external_reference_id(mut self, v: impl Into<String>) -> Self {
+        self.external_reference_id = Some(v.into());
+        self
+    }
+
+    pub fn config(mut self, c: MqttProviderConfig) -> Self {
+        self.config = Some(c);
+        self
+    }
+
+    pub fn build(self) -> Result<MqttLocationProvider, AppError> {
+        let config = self.config.ok_or_else(|| {
+            AppError::InvalidRequest("MqttLocationProvider: config is required".into())
+        })?;
+        let qos = match config.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            qos => {
+                return Err(AppError::InvalidRequest(format!(
+                    "MqttLocationProvider: unsupported qos {}",
+                    qos
+                )))
+            }
+        };
+        Ok(MqttLocationProvider {
+            access_token: self.access_token.ok_or_else(|| {
+                AppError::InvalidRequest("MqttLocationProvider: access_token is required".into())
+            })?,
+            token_expires_at: self.token_expires_at.ok_or_else(|| {
+                AppError::InvalidRequest(
+                    "MqttLocationProvider: token_expires_at is required".into(),
+                )
+            })?,
+            external_reference_id: self.external_reference_id.unwrap_or_default(),
+            qos,
+            config,
+        })
+    }
+}
+
+// --- Trait impl ---
+
+#[async_trait]
+impl ExternalLocationProvider for MqttLocationProvider {
+    async fn send_ping(&self, ping: &TracePing<'_>) -> Result<Option<u16>, AppError> {
+        let values = TraceTemplateValues {
+            lat: ping.lat,
+            lon: ping.lon,
+            datetime: ping.datetime,
+            timestamp: ping.timestamp,
+            signal: ping.signal,
+            access_token: &self.access_token,
+            external_reference_id: &self.external_reference_id,
+        };
+        let topic = render_template(&self.config.topic_template, &values);
+        let payload = render_json_template(&self.config.payload_template, &values).to_string();
+
+        let key = MqttConnectionKey {
+            broker_host: self.config.broker_host.to_owned(),
+            broker_port: self.config.broker_port,
+            use_tls: self.config.use_tls,
+            client_id: self
+                .config
+                .client_id
+                .as_ref()
+                .map(|client_id| render_template(client_id, &values)),
+            username: self.config.username.to_owned(),
+        };
+        let password = self.config.username.as_ref().map(|_| {
+            self.config
+                .password
+                .to_owned()
+                .unwrap_or_else(|| self.access_token.to_owned())
+        });
+        let connection = MqttConnection::shared(key, password);
+
+        let _publishing = connection.publishing.lock().await;
+        let mut events = connection.events.subscribe();
+        connection
+            .client
+            .publish(topic, self.qos, false, payload)
+            .await
+            .map_err(|e| AppError::ExternalAPICallError(format!("Broadcast trace MQTT: {}", e)))?;
+
+        let qos = self.qos;
+        tokio::time::timeout(MQTT_PUBLISH_TIMEOUT, async move {
+            let mut published = None;
+            loop {
+                match events.recv().await {
+                    Ok(MqttEvent::Published(pkid)) if published.is_none() => {
+                        if qos == QoS::AtMostOnce {
+                            return Ok(None);
+                        }
+                        published = Some(pkid);
+                    }
+                    Ok(MqttEvent::Acked(pkid)) if published == Some(pkid) => return Ok(None),
+                    Ok(MqttEvent::Refused) => return Err(AppError::TraceTokenExpired),
+                    Ok(MqttEvent::Failed(e)) => {
+                        return Err(AppError::ExternalAPICallError(format!(
+                            "Broadcast trace MQTT: {}",
+                            e
+                        )))
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(AppError::ExternalAPICallError(
+                            "Broadcast trace MQTT: connection closed".to_string(),
                         ))
                     }
-                })
-            }),
-        )
+                }
+            }
+        })
         .await
-        .map(|_| ())
+        .map_err(|_| {
+            AppError::ExternalAPICallError("Broadcast trace MQTT: publish timed out".to_string())
+        })?
     }
 
     fn update_token(&mut self, access_token: String, token_expires_at: i64) {
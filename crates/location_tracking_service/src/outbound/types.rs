@@ -70,6 +70,10 @@ pub struct BulkDataReq {
     pub ride_id: RideId,
     pub loc: Vec<LocationUpdate>,
     pub driver_id: DriverId,
+    /// Set on late points older than a batch already sent for the ride, which
+    /// belong before it on the trace.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub late: Option<bool>,
 }
 
 // Trigger FCM to start location pings if not sent for a while
@@ -181,6 +185,17 @@ pub enum DetectionData {
     OppositeDirectionDetection(OppositeDirectionDetectionData),
     SafetyCheckDetection(SafetyCheckDetectionData),
     RideStopReachedDetection(RideStopReachedDetectionData),
+    SuddenJumpDetection(SuddenJumpDetectionData),
+    DriverDivergenceDetection(DriverDivergenceDetectionData),
+}
+
+/// Result of a detection `check`, not tied to who it ran for. Drivers report it
+/// as a `ViolationDetectionReq` against their ride, riders as a `RiderSafetyAlertReq`
+/// against their SOS or ride entity.
+#[derive(Debug)]
+pub struct DetectionOutcome {
+    pub is_violated: bool,
+    pub detection_data: DetectionData,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -192,6 +207,52 @@ pub struct ViolationDetectionReq {
     pub detection_data: DetectionData,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SuddenJumpDetectionData {
+    pub location: Point,
+    pub distance: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DriverDivergenceDetectionData {
+    pub location: Point,
+    pub distance: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RideProximityEvent {
+    RiderSeparatedFromVehicle,
+    RiderNotInVehicleAtStart,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RideProximityEventReq {
+    pub ride_id: RideId,
+    pub driver_id: DriverId,
+    pub rider_id: PersonId,
+    pub merchant_id: MerchantId,
+    pub event: RideProximityEvent,
+    pub rider_location: Point,
+    pub driver_location: Point,
+    pub distance: f64,
+}
+
+/// Safety alert for a rider with an active SOS or ride entity.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RiderSafetyAlertReq {
+    pub entity: EntityId,
+    pub rider_id: PersonId,
+    pub merchant_id: MerchantId,
+    pub driver_id: Option<DriverId>,
+    pub is_violated: bool,
+    pub detection_data: DetectionData,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct GoogleRoutesRequest {
@@ -0,0 +1,63 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Storage encoding of the driver details keys, read and written on every ping.
+//!
+//! A value is either the original JSON, or MessagePack behind a two byte
+//! header: `DRIVER_DETAILS_BINARY_MARKER` then the format version. The marker
+//! is a byte MessagePack never uses and JSON never starts with, so reads can
+//! tell the two apart and accept both. Writes follow `driver_details_encoding`;
+//! after switching it to `Binary`, JSON values are replaced as their keys are
+//! next written and the rest expire.
+//!
+//! Structs are encoded with field names, so fields can be added or skipped the
+//! same way as with JSON.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::environment::DriverDetailsEncoding;
+use crate::tools::error::AppError;
+use crate::tools::prometheus::DRIVER_DETAILS_DECODED;
+
+pub const DRIVER_DETAILS_BINARY_MARKER: u8 = 0xc1;
+pub const DRIVER_DETAILS_BINARY_VERSION: u8 = 1;
+
+pub fn encode_driver_details<T: Serialize>(
+    details: &T,
+    encoding: DriverDetailsEncoding,
+) -> Result<Vec<u8>, AppError> {
+    match encoding {
+        DriverDetailsEncoding::Json => {
+            serde_json::to_vec(details).map_err(|err| AppError::SerializationError(err.to_string()))
+        }
+        DriverDetailsEncoding::Binary => {
+            let mut payload = vec![DRIVER_DETAILS_BINARY_MARKER, DRIVER_DETAILS_BINARY_VERSION];
+            rmp_serde::encode::write_named(&mut payload, details)
+                .map_err(|err| AppError::SerializationError(err.to_string()))?;
+            Ok(payload)
+        }
+    }
+}
+
+pub fn decode_driver_details<T: DeserializeOwned>(payload: &[u8]) -> Result<T, AppError> {
+    match payload {
+        [DRIVER_DETAILS_BINARY_MARKER, DRIVER_DETAILS_BINARY_VERSION, body @ ..] => {
+            DRIVER_DETAILS_DECODED.with_label_values(&["binary"]).inc();
+            rmp_serde::from_slice(body)
+                .map_err(|err| AppError::DeserializationError(err.to_string()))
+        }
+        [DRIVER_DETAILS_BINARY_MARKER, version, ..] => Err(AppError::DeserializationError(
+            format!("Unsupported driver details encoding version {version}"),
+        )),
+        _ => {
+            DRIVER_DETAILS_DECODED.with_label_values(&["json"]).inc();
+            serde_json::from_slice(payload)
+                .map_err(|err| AppError::DeserializationError(err.to_string()))
+        }
+    }
+}
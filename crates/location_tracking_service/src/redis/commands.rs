@@ -5,18 +5,34 @@
     or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
     the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
+use crate::common::config_override::ConfigOverrideEntry;
+use crate::common::drainer_wal::DrainerEntry;
+use crate::common::driver_availability::DriverAvailabilityRecord;
+use crate::common::driver_session::{DriverDayAccounting, DriverSessionCursor};
 use crate::common::types::*;
 use crate::domain::types::ui::location::PersonType;
+use crate::environment::{DrainerStreamConfig, DriverDetailsEncoding};
 use crate::outbound::types::LocationUpdate;
+use crate::redis::codec::{decode_driver_details, encode_driver_details};
 use crate::redis::keys::*;
+use crate::redis::migration::RedisMigrationPhase;
 use crate::tools::error::AppError;
-use fred::prelude::{KeysInterface, ListInterface, SortedSetsInterface};
-use fred::types::{GeoPosition, GeoUnit, RedisValue, SetOptions, SortOrder};
-use futures::Future;
-use rustc_hash::FxHashSet;
-use serde::{Deserialize, Serialize};
+use chrono::Utc;
+use fred::clients::{Pipeline, RedisClient};
+use fred::prelude::{
+    HashesInterface, KeysInterface, ListInterface, LuaInterface, SetsInterface,
+    SortedSetsInterface, StreamsInterface,
+};
+use fred::types::{
+    Expiration, GeoPosition, GeoUnit, RedisValue, Scanner, SetOptions, SortOrder, XReadResponse,
+    XReadValue, XID,
+};
+use futures::{Future, Stream, StreamExt};
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use shared::redis::types::{RedisConnectionPool, Ttl};
 use std::collections::HashMap;
+use std::str::FromStr;
 use tracing::{error, info};
 
 /// Sets the ride details (i.e, rideId and rideStatus) to the Redis store.
@@ -32,6 +48,8 @@ use tracing::{error, info};
 /// * `ride_id` - The ID of the ride.
 /// * `ride_status` - The current status of the ride.
 /// * `ride_info` - Ride related details based on vehicle category.
+/// * `started_at` - When the ride went INPROGRESS. Stamped once by `ride_start`;
+///   other writes carry the stored value forward.
 ///
 /// # Returns
 /// * A Result indicating the success or failure of the operation.
@@ -44,11 +62,13 @@ pub async fn set_ride_details_for_driver(
     ride_id: RideId,
     ride_status: RideStatus,
     ride_info: Option<RideInfo>,
+    started_at: Option<TimeStamp>,
 ) -> Result<(), AppError> {
     let ride_details = RideDetails {
         ride_id,
         ride_status,
         ride_info: ride_info.clone(),
+        started_at,
     };
     redis
         .set_key(
@@ -87,6 +107,17 @@ pub async fn set_ride_details_for_driver(
     Ok(())
 }
 
+/// Driver whose bus ride is running on `plate_number`, cached on ride start.
+pub async fn get_driver_info_by_plate(
+    redis: &RedisConnectionPool,
+    plate_number: &str,
+) -> Result<Option<DriverByPlateResp>, AppError> {
+    redis
+        .get_key::<DriverByPlateResp>(&driver_info_by_plate_key(plate_number))
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
 /// Fetches the details of a specific ride from the Redis database.
 ///
 /// This function looks up the ride details for a given merchant and driver
@@ -308,10 +339,11 @@ pub async fn get_drivers_within_radius(
     Ok(resp)
 }
 
-/// Fetches the last known location of a driver.
+/// Fetches everything stored about a driver.
 ///
-/// Queries the Redis datastore using the driver's ID and retrieves their last known location.
-/// If the driver's details aren't found or the location isn't available, returns `None`.
+/// Reads the split driver details keys with one MGET. A driver without a location
+/// key has not pinged since the details were split, so the combined record is read
+/// instead, with any split key already written taking precedence.
 ///
 /// # Arguments
 ///
@@ -320,93 +352,386 @@ pub async fn get_drivers_within_radius(
 ///
 /// # Returns
 ///
-/// A `Result` wrapping the driver's last known location as `Option<DriverLastKnownLocation>`,
-/// or an `AppError` in case of deserialization failure.
+/// A `Result` wrapping the driver's details, `None` if no location is stored for them,
+/// or an `AppError` in case of deserialization failure. Either storage encoding is accepted.
 pub async fn get_driver_location(
     redis: &RedisConnectionPool,
     driver_id: &DriverId,
 ) -> Result<Option<DriverAllDetails>, AppError> {
-    redis
-        .get_key::<DriverAllDetails>(&driver_details_key(driver_id))
+    let values: Vec<RedisValue> = redis
+        .reader_pool
+        .mget(vec![
+            driver_location_state_key(driver_id),
+            driver_ride_notification_state_key(driver_id),
+            driver_detection_state_key(driver_id),
+            driver_blocked_till_key(driver_id),
+        ])
         .await
-        .map_err(|err| AppError::InternalError(err.to_string()))
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    let [location, ride_notification, detection, blocked_till] = values.as_slice() else {
+        return Err(AppError::InternalError(format!(
+            "Expected 4 driver details values, got {}",
+            values.len()
+        )));
+    };
+    let ride_notification = decode_driver_details_value(ride_notification)?;
+    let detection = decode_driver_details_value(detection)?;
+    let blocked_till = decode_driver_details_value(blocked_till)?;
+
+    let (location, ride_notification, detection, blocked_till, from_combined_record) =
+        match decode_driver_details_value::<DriverLocationState>(location)? {
+            Some(location) => (location, ride_notification, detection, blocked_till, false),
+            None => match get_combined_driver_details(redis, driver_id).await? {
+                Some(details) => {
+                    let (
+                        combined_location,
+                        combined_ride_notification,
+                        combined_detection,
+                        combined_blocked_till,
+                    ) = split_driver_details(details);
+                    (
+                        combined_location,
+                        ride_notification.or(Some(combined_ride_notification)),
+                        detection.or(Some(combined_detection)),
+                        blocked_till.or(combined_blocked_till),
+                        true,
+                    )
+                }
+                None => return Ok(None),
+            },
+        };
+
+    let ride_notification = ride_notification.unwrap_or(DriverRideNotificationState {
+        ride_notification_status: None,
+        driver_pickup_distance: None,
+    });
+    let detection = detection.unwrap_or_default();
+    Ok(Some(DriverAllDetails {
+        driver_last_known_location: location.driver_last_known_location,
+        blocked_till,
+        stop_detection: location.stop_detection,
+        ride_status: location.ride_status,
+        ride_notification_status: ride_notification.ride_notification_status,
+        driver_pickup_distance: ride_notification.driver_pickup_distance,
+        violation_trigger_flag: detection.violation_trigger_flag,
+        detection_state: detection.detection_state,
+        anti_detection_state: detection.anti_detection_state,
+        group_id: location.group_id,
+        from_combined_record,
+    }))
+}
+
+/// Reads the combined record written before the driver details were split.
+async fn get_combined_driver_details(
+    redis: &RedisConnectionPool,
+    driver_id: &DriverId,
+) -> Result<Option<DriverAllDetails>, AppError> {
+    let value: RedisValue = redis
+        .reader_pool
+        .get(driver_details_key(driver_id))
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    decode_driver_details_value(&value)
+}
+
+fn split_driver_details(
+    details: DriverAllDetails,
+) -> (
+    DriverLocationState,
+    DriverRideNotificationState,
+    DriverDetectionState,
+    Option<TimeStamp>,
+) {
+    (
+        DriverLocationState {
+            driver_last_known_location: details.driver_last_known_location,
+            stop_detection: details.stop_detection,
+            ride_status: details.ride_status,
+            group_id: details.group_id,
+        },
+        DriverRideNotificationState {
+            ride_notification_status: details.ride_notification_status,
+            driver_pickup_distance: details.driver_pickup_distance,
+        },
+        DriverDetectionState {
+            violation_trigger_flag: details.violation_trigger_flag,
+            detection_state: details.detection_state,
+            anti_detection_state: details.anti_detection_state,
+        },
+        details.blocked_till,
+    )
 }
 
-/// Updates and stores the last known location of a driver.
-///
-/// This function creates a record of the driver's last known location, serializes it,
-/// and then stores the serialized data in a Redis datastore.
+fn decode_driver_details_value<T: DeserializeOwned>(
+    value: &RedisValue,
+) -> Result<Option<T>, AppError> {
+    value.as_bytes().map(decode_driver_details).transpose()
+}
+
+/// SETs driver details keys (key, payload, expiry in seconds) in one pipeline.
+async fn set_driver_details_keys(
+    redis: &RedisConnectionPool,
+    entries: Vec<(String, Vec<u8>, u32)>,
+) -> Result<(), AppError> {
+    let pipeline = redis.writer_pool.next().pipeline();
+    for (key, payload, expiry) in entries {
+        let _ = pipeline
+            .set::<(), _, _>(
+                key,
+                payload,
+                Some(Expiration::EX(expiry as i64)),
+                None,
+                false,
+            )
+            .await;
+    }
+    pipeline
+        .all::<Vec<RedisValue>>()
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(())
+}
+
+/// Writes what a ping updates in one pipeline: the location always, and the ride
+/// notification progress and detection state only when given, i.e. when the ping
+/// changed them. The block key is left alone.
 ///
 /// # Arguments
 ///
 /// * `redis` - A connection pool to the Redis datastore.
-/// * `last_location_timstamp_expiry` - Expiry duration (in seconds) for the last known location record in Redis.
+/// * `last_location_timstamp_expiry` - Expiry (in seconds) of the location and detection keys.
+/// * `redis_expiry` - Expiry (in seconds) of the ride notification key, as for the ride details.
+/// * `driver_details_encoding` - Encoding the keys are written in.
 /// * `driver_id` - Unique identifier of the driver whose location is being updated.
-/// * `merchant_id` - Identifier for the merchant associated with the driver.
-/// * `last_location_pt` - Geographical point representing the driver's last known location.
-/// * `last_location_ts` - Timestamp of when the driver was last at the specified location.
-///
-/// # Returns
-///
-/// A `Result` wrapping the driver's updated last known location (`DriverLastKnownLocation`),
-/// or an `AppError` in case of serialization failure.
 #[allow(clippy::too_many_arguments)]
-pub async fn set_driver_last_location_update(
+pub async fn set_driver_ping_state(
     redis: &RedisConnectionPool,
     last_location_timstamp_expiry: &u32,
+    redis_expiry: &u32,
+    driver_details_encoding: &DriverDetailsEncoding,
     driver_id: &DriverId,
-    merchant_id: &MerchantId,
-    last_location_pt: &Point,
-    last_location_ts: &TimeStamp,
-    blocked_till: &Option<TimeStamp>,
-    stop_detection: Option<StopDetection>,
-    ride_status: &Option<RideStatus>,
-    ride_notification_status: &Option<RideNotificationStatus>,
-    detection_state: &Option<ViolationDetectionStateMap>,
-    anti_detection_state: &Option<ViolationDetectionStateMap>,
-    violation_trigger_flag: &Option<ViolationDetectionTriggerMap>,
-    driver_pickup_distance: &Option<Meters>,
-    bear: &Option<Direction>,
-    vehicle_type: &Option<VehicleType>,
-    group_id: &Option<String>,
-    group_id2: &Option<String>,
-) -> Result<DriverLastKnownLocation, AppError> {
-    let last_known_location = DriverLastKnownLocation {
-        location: Point {
-            lat: last_location_pt.lat,
-            lon: last_location_pt.lon,
-        },
-        timestamp: *last_location_ts,
-        merchant_id: merchant_id.to_owned(),
-        bear: *bear,
-        vehicle_type: *vehicle_type,
-        group_id: group_id.clone(),
-        group_id2: group_id2.clone(),
-    };
+    location: &DriverLocationState,
+    ride_notification: Option<&DriverRideNotificationState>,
+    detection: Option<&DriverDetectionState>,
+) -> Result<(), AppError> {
+    let mut entries = vec![(
+        driver_location_state_key(driver_id),
+        encode_driver_details(location, *driver_details_encoding)?,
+        *last_location_timstamp_expiry,
+    )];
+    if let Some(ride_notification) = ride_notification {
+        entries.push((
+            driver_ride_notification_state_key(driver_id),
+            encode_driver_details(ride_notification, *driver_details_encoding)?,
+            *redis_expiry,
+        ));
+    }
+    if let Some(detection) = detection {
+        entries.push((
+            driver_detection_state_key(driver_id),
+            encode_driver_details(detection, *driver_details_encoding)?,
+            *last_location_timstamp_expiry,
+        ));
+    }
+    set_driver_details_keys(redis, entries).await
+}
 
-    let value = DriverAllDetails {
-        driver_last_known_location: last_known_location.to_owned(),
-        blocked_till: blocked_till.to_owned(),
-        stop_detection,
-        ride_status: ride_status.to_owned(),
-        ride_notification_status: *ride_notification_status,
-        driver_pickup_distance: *driver_pickup_distance,
-        detection_state: detection_state.to_owned(),
-        violation_trigger_flag: violation_trigger_flag.clone(),
-        anti_detection_state: anti_detection_state.clone(),
-        group_id: group_id.clone(),
-    };
+/// Resets the ride notification progress and, when given, the detection state,
+/// as on ride creation and cancellation.
+pub async fn set_driver_ride_state(
+    redis: &RedisConnectionPool,
+    last_location_timstamp_expiry: &u32,
+    redis_expiry: &u32,
+    driver_details_encoding: &DriverDetailsEncoding,
+    driver_id: &DriverId,
+    ride_notification: &DriverRideNotificationState,
+    detection: Option<&DriverDetectionState>,
+) -> Result<(), AppError> {
+    let mut entries = vec![(
+        driver_ride_notification_state_key(driver_id),
+        encode_driver_details(ride_notification, *driver_details_encoding)?,
+        *redis_expiry,
+    )];
+    if let Some(detection) = detection {
+        entries.push((
+            driver_detection_state_key(driver_id),
+            encode_driver_details(detection, *driver_details_encoding)?,
+            *last_location_timstamp_expiry,
+        ));
+    }
+    set_driver_details_keys(redis, entries).await
+}
 
+/// Blocks the driver's pings until `blocked_till`. The key outlives the block by
+/// at least `last_location_timstamp_expiry`, so a lifted block is never shadowed
+/// by the combined record.
+pub async fn set_driver_blocked_till(
+    redis: &RedisConnectionPool,
+    last_location_timstamp_expiry: &u32,
+    driver_details_encoding: &DriverDetailsEncoding,
+    driver_id: &DriverId,
+    blocked_till: &TimeStamp,
+) -> Result<(), AppError> {
+    let remaining_secs = (blocked_till.inner() - Utc::now()).num_seconds();
+    let payload = encode_driver_details(blocked_till, *driver_details_encoding)?;
     redis
-        .set_key(
-            &driver_details_key(driver_id),
-            value,
-            *last_location_timstamp_expiry,
+        .writer_pool
+        .next()
+        .set::<(), _, _>(
+            driver_blocked_till_key(driver_id),
+            payload,
+            Some(Expiration::EX(
+                remaining_secs.max(*last_location_timstamp_expiry as i64),
+            )),
+            None,
+            false,
+        )
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
+/// Sets `key` to `value` only if it still holds `expected`, `None` meaning the
+/// key is absent. Returns whether the value was set.
+pub async fn compare_and_set_key(
+    redis: &RedisConnectionPool,
+    key: &str,
+    expected: Option<&str>,
+    value: &str,
+    expiry: i64,
+) -> Result<bool, AppError> {
+    const COMPARE_AND_SET: &str = r#"
+        if (redis.call('GET', KEYS[1]) or '') == ARGV[1] then
+            redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+            return 1
+        end
+        return 0
+    "#;
+    let set: i64 = redis
+        .writer_pool
+        .next()
+        .eval(
+            COMPARE_AND_SET,
+            vec![key],
+            vec![
+                expected.unwrap_or_default().to_string(),
+                value.to_string(),
+                expiry.to_string(),
+            ],
         )
         .await
         .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(set == 1)
+}
+
+/// Reads the driver's availability together with the stored payload, which
+/// `set_driver_availability_if_unchanged` compares against.
+pub async fn get_driver_availability(
+    redis: &RedisConnectionPool,
+    driver_id: &DriverId,
+) -> Result<Option<(DriverAvailabilityRecord, String)>, AppError> {
+    let stored = redis
+        .get_key_as_str(&driver_availability_key(driver_id))
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    stored
+        .map(|stored| {
+            serde_json::from_str(&stored)
+                .map(|record| (record, stored))
+                .map_err(|err| AppError::DeserializationError(err.to_string()))
+        })
+        .transpose()
+}
+
+/// Stores the driver's availability unless it changed since it was read as
+/// `expected`. Returns whether it was stored.
+pub async fn set_driver_availability_if_unchanged(
+    redis: &RedisConnectionPool,
+    redis_expiry: &u32,
+    driver_id: &DriverId,
+    expected: Option<&str>,
+    record: &DriverAvailabilityRecord,
+) -> Result<bool, AppError> {
+    let value = serde_json::to_string(record)
+        .map_err(|err| AppError::SerializationError(err.to_string()))?;
+    compare_and_set_key(
+        redis,
+        &driver_availability_key(driver_id),
+        expected,
+        &value,
+        *redis_expiry as i64,
+    )
+    .await
+}
+
+/// Stores the driver's availability as is, for the pool writes are mirrored to.
+pub async fn set_driver_availability(
+    redis: &RedisConnectionPool,
+    redis_expiry: &u32,
+    driver_id: &DriverId,
+    record: &DriverAvailabilityRecord,
+) -> Result<(), AppError> {
+    let value = serde_json::to_string(record)
+        .map_err(|err| AppError::SerializationError(err.to_string()))?;
+    redis
+        .set_key_as_str(&driver_availability_key(driver_id), &value, *redis_expiry)
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
+/// Records when an online driver was last seen, for the ping timeout sweep.
+/// Offline and blocked drivers are dropped from it.
+pub async fn set_driver_availability_last_seen(
+    redis: &RedisConnectionPool,
+    driver_id: &DriverId,
+    last_seen: Option<&TimeStamp>,
+) -> Result<(), AppError> {
+    let key = driver_availability_last_seen_key();
+    match last_seen {
+        Some(last_seen) => {
+            let _: RedisValue = redis
+                .writer_pool
+                .next()
+                .zadd(
+                    &key,
+                    None,
+                    None,
+                    false,
+                    false,
+                    (last_seen.inner().timestamp() as f64, driver_id.0.as_str()),
+                )
+                .await
+                .map_err(|err| AppError::InternalError(err.to_string()))?;
+        }
+        None => {
+            let _: RedisValue = redis
+                .writer_pool
+                .next()
+                .zrem(&key, driver_id.0.as_str())
+                .await
+                .map_err(|err| AppError::InternalError(err.to_string()))?;
+        }
+    }
+    Ok(())
+}
 
-    Ok(last_known_location)
+/// Up to `count` drivers last seen online at or before `cutoff`, oldest first.
+pub async fn get_drivers_last_seen_before(
+    redis: &RedisConnectionPool,
+    cutoff: &TimeStamp,
+    count: i64,
+) -> Result<Vec<DriverId>, AppError> {
+    let driver_ids: Vec<String> = redis
+        .reader_pool
+        .zrangebyscore(
+            driver_availability_last_seen_key(),
+            "-inf",
+            cutoff.inner().timestamp() as f64,
+            false,
+            Some((0, count)),
+        )
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(driver_ids.into_iter().map(DriverId).collect())
 }
 
 /// Pushes a list of geographical locations to the end of a Redis list for a specific driver on a ride.
@@ -458,6 +783,28 @@ pub async fn push_on_ride_driver_locations(
 ///
 /// A `Result` wrapping the length of the Redis list representing the count of geographical locations (`i64`),
 /// or an `AppError` in case of failures.
+pub async fn get_on_ride_trace_flushed_till(
+    redis: &RedisConnectionPool,
+    ride_id: &RideId,
+) -> Result<Option<i64>, AppError> {
+    redis
+        .get_key::<i64>(&on_ride_trace_flushed_till_key(ride_id))
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
+pub async fn set_on_ride_trace_flushed_till(
+    redis: &RedisConnectionPool,
+    redis_expiry: &u32,
+    ride_id: &RideId,
+    ts: i64,
+) -> Result<(), AppError> {
+    redis
+        .set_key(&on_ride_trace_flushed_till_key(ride_id), ts, *redis_expiry)
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
 pub async fn get_on_ride_driver_locations_count(
     redis: &RedisConnectionPool,
     driver_id: &DriverId,
@@ -695,11 +1042,16 @@ pub async fn remove_entity_for_person(
 /// Gets person ID by entity (entity_type: "ride" | "sos", entity_id: id string).
 pub async fn get_person_by_entity(
     redis: &RedisConnectionPool,
+    person_type: PersonType,
     entity_type: &str,
     entity_id: &str,
 ) -> Result<Option<String>, AppError> {
     redis
-        .get_key::<String>(&person_detail_by_entity_key(entity_type, entity_id))
+        .get_key::<String>(&person_detail_by_entity_key(
+            person_type,
+            entity_type,
+            entity_id,
+        ))
         .await
         .map_err(|err| AppError::InternalError(err.to_string()))
 }
@@ -707,6 +1059,7 @@ pub async fn get_person_by_entity(
 /// Sets person ↔ entity mapping (person_id string stored by entity).
 pub async fn set_person_by_entity(
     redis: &RedisConnectionPool,
+    person_type: PersonType,
     entity_type: &str,
     entity_id: &str,
     person_id: &PersonId,
@@ -714,7 +1067,7 @@ pub async fn set_person_by_entity(
 ) -> Result<(), AppError> {
     redis
         .set_key(
-            &person_detail_by_entity_key(entity_type, entity_id),
+            &person_detail_by_entity_key(person_type, entity_type, entity_id),
             person_id.inner().clone(),
             *expiry,
         )
@@ -822,7 +1175,7 @@ pub async fn entity_cleanup_generic(
 ) -> Result<(), AppError> {
     let keys = [
         entity_details_key(merchant_id, person_type, person_id),
-        person_detail_by_entity_key(entity_type, entity_id),
+        person_detail_by_entity_key(person_type, entity_type, entity_id),
         entity_loc_key(merchant_id, person_type, person_id),
         person_detail_key(person_type, person_id),
     ];
@@ -923,20 +1276,54 @@ pub async fn get_all_driver_last_locations(
     redis: &RedisConnectionPool,
     driver_ids: &[DriverId],
 ) -> Result<Vec<Option<DriverLastKnownLocation>>, AppError> {
-    let driver_last_location_updates_keys = driver_ids
+    if driver_ids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let values: Vec<RedisValue> = redis
+        .reader_pool
+        .mget(
+            driver_ids
+                .iter()
+                .map(driver_location_state_key)
+                .collect::<Vec<String>>(),
+        )
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    let mut driver_last_known_locations = values
         .iter()
-        .map(driver_details_key)
-        .collect::<Vec<String>>();
+        .map(|value| {
+            decode_driver_details_value::<DriverLocationState>(value)
+                .map(|state| state.map(|state| state.driver_last_known_location))
+        })
+        .collect::<Result<Vec<Option<DriverLastKnownLocation>>, AppError>>()?;
 
-    let driver_last_known_location = redis
-        .mget_keys::<DriverAllDetails>(driver_last_location_updates_keys)
+    // Drivers that have not pinged since the details were split.
+    let missing = driver_last_known_locations
+        .iter()
+        .enumerate()
+        .filter_map(|(index, location)| location.is_none().then_some(index))
+        .collect::<Vec<usize>>();
+    if missing.is_empty() {
+        return Ok(driver_last_known_locations);
+    }
+    let combined: Vec<RedisValue> = redis
+        .reader_pool
+        .mget(
+            missing
+                .iter()
+                .map(|index| driver_details_key(&driver_ids[*index]))
+                .collect::<Vec<String>>(),
+        )
         .await
-        .map_err(|err| AppError::InternalError(err.to_string()))?
-        .into_iter()
-        .map(|opt_detail| opt_detail.map(|detail| detail.driver_last_known_location))
-        .collect::<Vec<Option<DriverLastKnownLocation>>>();
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    for (index, value) in missing.into_iter().zip(combined.iter()) {
+        driver_last_known_locations[index] =
+            decode_driver_details_value::<DriverAllDetails>(value)?
+                .map(|details| details.driver_last_known_location);
+    }
 
-    Ok(driver_last_known_location)
+    Ok(driver_last_known_locations)
 }
 
 /// Push the driver location data to a non-persistent Redis store with specified expiration.
@@ -994,6 +1381,7 @@ pub async fn set_route_location(
     timestamp: &TimeStamp,
     ride_status: Option<RideStatus>,
     upcoming_stops: Option<Vec<UpcomingStop>>,
+    location_source: LocationSource,
 ) -> Result<(), AppError> {
     let vehicle_tracking_info = VehicleTrackingInfo {
         schedule_relationship: None,
@@ -1005,6 +1393,7 @@ pub async fn set_route_location(
         timestamp: Some(*timestamp),
         ride_status,
         upcoming_stops,
+        location_source: Some(location_source),
     };
     redis
         .set_hash_fields_with_hashmap_expiry(
@@ -1253,6 +1642,30 @@ pub struct DriverQueueTracking {
     /// rank doesn't change skip the HSET entirely.
     #[serde(default)]
     pub last_recorded_rank: Option<u64>,
+    /// Server timestamp of the latest ping applied to this tracking.
+    #[serde(default)]
+    pub last_ping_ts: Option<f64>,
+    /// Id of the latest drainer stream entry applied to this tracking. A batch
+    /// reclaimed from the stream after it was applied is skipped up to here
+    /// instead of counting exits twice.
+    #[serde(default)]
+    pub last_stream_id: Option<String>,
+}
+
+impl DriverQueueTracking {
+    /// Whether the drainer stream entry `stream_id` was already applied. A
+    /// driver's entries all go to one partition, so its ids only grow.
+    pub fn has_applied_stream_entry(&self, stream_id: &str) -> bool {
+        match (
+            self.last_stream_id
+                .as_deref()
+                .and_then(drainer_stream_id_order),
+            drainer_stream_id_order(stream_id),
+        ) {
+            (Some(applied), Some(stream_id)) => stream_id <= applied,
+            _ => false,
+        }
+    }
 }
 
 /// TTL applied to the per-driver rank-history list on every event write.
@@ -1614,3 +2027,1049 @@ pub async fn get_drivers_in_special_location(
     }
     Ok(driver_ids.into_iter().collect())
 }
+
+/// Number of override versions retained per scope for rollback.
+pub const CONFIG_OVERRIDE_HISTORY_LEN: i64 = 50;
+
+/// Read every current config override from the `lts:config_overrides` hash.
+/// Read from the writer pool so a just-written override is never hidden by
+/// replica lag. Entries that fail JSON deserialization are dropped.
+pub async fn get_all_config_overrides(
+    redis: &RedisConnectionPool,
+) -> Result<FxHashMap<String, ConfigOverrideEntry>, AppError> {
+    let raw: HashMap<String, String> = redis
+        .writer_pool
+        .next()
+        .hgetall(config_overrides_key())
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(raw
+        .into_iter()
+        .filter_map(|(scope, value)| {
+            serde_json::from_str::<ConfigOverrideEntry>(&value)
+                .map_err(|err| error!(tag = "[Config Override]", scope = %scope, error = %err))
+                .ok()
+                .map(|entry| (scope, entry))
+        })
+        .collect())
+}
+
+/// Get the current override for a single scope, if any.
+pub async fn get_config_override(
+    redis: &RedisConnectionPool,
+    scope: &str,
+) -> Result<Option<ConfigOverrideEntry>, AppError> {
+    let raw: Option<String> = redis
+        .writer_pool
+        .next()
+        .hget(config_overrides_key(), scope)
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    raw.map(|value| {
+        serde_json::from_str::<ConfigOverrideEntry>(&value)
+            .map_err(|err| AppError::DeserializationError(err.to_string()))
+    })
+    .transpose()
+}
+
+/// Store `entry` as the current override for `scope` and prepend it to the
+/// scope's history list (capped at `CONFIG_OVERRIDE_HISTORY_LEN`), all in one
+/// pipeline. Callers are expected to hold `config_override_lock_key(scope)`.
+pub async fn set_config_override(
+    redis: &RedisConnectionPool,
+    scope: &str,
+    entry: &ConfigOverrideEntry,
+) -> Result<(), AppError> {
+    let payload = serde_json::to_string(entry)
+        .map_err(|err| AppError::SerializationError(err.to_string()))?;
+    let history_key = config_override_history_key(scope);
+    let pipeline = redis.writer_pool.next().pipeline();
+    let _ = pipeline
+        .hset::<RedisValue, _, _>(config_overrides_key(), (scope, payload.as_str()))
+        .await;
+    let _ = pipeline
+        .lpush::<RedisValue, _, _>(&history_key, payload.as_str())
+        .await;
+    let _ = pipeline
+        .ltrim::<(), _>(&history_key, 0, CONFIG_OVERRIDE_HISTORY_LEN - 1)
+        .await;
+    pipeline
+        .all::<Vec<RedisValue>>()
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(())
+}
+
+/// Read the migration phase from the writer pool, so a just-advanced phase is
+/// never hidden by replica lag. Unset means `Off`.
+pub async fn get_redis_migration_phase(
+    redis: &RedisConnectionPool,
+) -> Result<RedisMigrationPhase, AppError> {
+    let raw: Option<String> = redis
+        .writer_pool
+        .next()
+        .get(redis_migration_phase_key())
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    raw.map(|value| {
+        serde_json::from_str::<RedisMigrationPhase>(&value)
+            .map_err(|err| AppError::DeserializationError(err.to_string()))
+    })
+    .transpose()
+    .map(Option::unwrap_or_default)
+}
+
+pub async fn set_redis_migration_phase(
+    redis: &RedisConnectionPool,
+    phase: RedisMigrationPhase,
+) -> Result<(), AppError> {
+    let payload = serde_json::to_string(&phase)
+        .map_err(|err| AppError::SerializationError(err.to_string()))?;
+    redis
+        .writer_pool
+        .next()
+        .set::<(), _, _>(redis_migration_phase_key(), payload, None, None, false)
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
+/// Copies `keys` from `source` to `target` with DUMP and RESTORE, keeping their TTL
+/// and replacing the target's value. Keys gone from the source are skipped.
+/// Returns how many keys were copied.
+pub async fn copy_keys(
+    source: &RedisConnectionPool,
+    target: &RedisConnectionPool,
+    keys: &[String],
+) -> Result<usize, AppError> {
+    let mut copied = 0;
+    for key in keys {
+        let pipeline = source.writer_pool.next().pipeline();
+        let _ = pipeline.dump::<RedisValue, _>(key).await;
+        let _ = pipeline.pttl::<RedisValue, _>(key).await;
+        let results = pipeline
+            .all::<Vec<RedisValue>>()
+            .await
+            .map_err(|err| AppError::InternalError(err.to_string()))?;
+        let (dump, pttl) = match results.as_slice() {
+            [dump, pttl] if !dump.is_null() => (dump.to_owned(), pttl.as_i64().unwrap_or(-1)),
+            _ => continue,
+        };
+        // -1: no expiry, which RESTORE takes as 0. -2: expired since the DUMP.
+        if pttl == -2 {
+            continue;
+        }
+        target
+            .writer_pool
+            .next()
+            .restore::<(), _>(key, pttl.max(0), dump, true, false, None, None)
+            .await
+            .map_err(|err| AppError::InternalError(err.to_string()))?;
+        copied += 1;
+    }
+    Ok(copied)
+}
+
+/// Read the override history for a scope, newest-first.
+pub async fn get_config_override_history(
+    redis: &RedisConnectionPool,
+    scope: &str,
+) -> Result<Vec<ConfigOverrideEntry>, AppError> {
+    let raw: Vec<String> = redis
+        .writer_pool
+        .next()
+        .lrange(config_override_history_key(scope), 0, -1)
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(raw
+        .into_iter()
+        .filter_map(|s| serde_json::from_str::<ConfigOverrideEntry>(&s).ok())
+        .collect())
+}
+
+/// Start (or reset) the proximity tracking entry for a ride.
+pub async fn set_ride_proximity_tracking(
+    redis: &RedisConnectionPool,
+    ride_id: &RideId,
+    tracking: &RideProximityTracking,
+) -> Result<(), AppError> {
+    let payload = serde_json::to_string(tracking)
+        .map_err(|err| AppError::SerializationError(err.to_string()))?;
+    redis
+        .writer_pool
+        .next()
+        .hset::<RedisValue, _, _>(
+            ride_proximity_tracking_key(),
+            (ride_id.0.as_str(), payload.as_str()),
+        )
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(())
+}
+
+/// Read every ride under the proximity check. Entries that fail JSON
+/// deserialization are dropped.
+pub async fn get_all_ride_proximity_trackings(
+    redis: &RedisConnectionPool,
+) -> Result<FxHashMap<RideId, RideProximityTracking>, AppError> {
+    let raw: HashMap<String, String> = redis
+        .writer_pool
+        .next()
+        .hgetall(ride_proximity_tracking_key())
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(raw
+        .into_iter()
+        .filter_map(|(ride_id, value)| {
+            serde_json::from_str::<RideProximityTracking>(&value)
+                .map_err(|err| error!(tag = "[Ride Proximity]", ride_id = %ride_id, error = %err))
+                .ok()
+                .map(|tracking| (RideId(ride_id), tracking))
+        })
+        .collect())
+}
+
+pub async fn delete_ride_proximity_tracking(
+    redis: &RedisConnectionPool,
+    ride_id: &RideId,
+) -> Result<(), AppError> {
+    redis
+        .writer_pool
+        .next()
+        .hdel::<RedisValue, _, _>(ride_proximity_tracking_key(), ride_id.0.as_str())
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(())
+}
+
+pub async fn get_broadcaster_token(
+    redis: &RedisConnectionPool,
+    scope: &str,
+) -> Result<Option<BroadcasterToken>, AppError> {
+    redis
+        .get_key::<BroadcasterToken>(&broadcaster_token_key(scope))
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
+pub async fn set_broadcaster_token(
+    redis: &RedisConnectionPool,
+    scope: &str,
+    token: &BroadcasterToken,
+    expiry: u32,
+) -> Result<(), AppError> {
+    redis
+        .set_key(&broadcaster_token_key(scope), token, expiry)
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
+pub async fn get_broadcaster_token_backoff(
+    redis: &RedisConnectionPool,
+    scope: &str,
+) -> Result<Option<BroadcasterTokenBackoff>, AppError> {
+    redis
+        .get_key::<BroadcasterTokenBackoff>(&broadcaster_token_backoff_key(scope))
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
+pub async fn set_broadcaster_token_backoff(
+    redis: &RedisConnectionPool,
+    scope: &str,
+    backoff: &BroadcasterTokenBackoff,
+    expiry: u32,
+) -> Result<(), AppError> {
+    redis
+        .set_key(&broadcaster_token_backoff_key(scope), backoff, expiry)
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
+pub async fn delete_broadcaster_token_backoff(
+    redis: &RedisConnectionPool,
+    scope: &str,
+) -> Result<(), AppError> {
+    redis
+        .delete_key(&broadcaster_token_backoff_key(scope))
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
+/// Entries kept per entity in the broadcast trace audit list.
+pub const BROADCAST_TRACE_AUDIT_MAX_ENTRIES: i64 = 500;
+/// TTL of the broadcast trace audit keys, refreshed on every attempt. A week leaves room
+/// for post-incident review after the SOS or ride has ended.
+pub const BROADCAST_TRACE_AUDIT_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// LPUSH an attempt onto the entity's audit list, trim it to
+/// `BROADCAST_TRACE_AUDIT_MAX_ENTRIES` and refresh the TTL in one round trip.
+/// Delivered attempts also overwrite the entity's last-success record.
+pub async fn append_broadcast_trace_attempt(
+    redis: &RedisConnectionPool,
+    entity_id: &EntityId,
+    attempt: &BroadcastTraceAttempt,
+) -> Result<(), AppError> {
+    let key = broadcast_trace_audit_key(entity_id);
+    let payload =
+        serde_json::to_string(attempt).map_err(|e| AppError::SerializationError(e.to_string()))?;
+    let pipeline = redis.writer_pool.next().pipeline();
+    let _ = pipeline.lpush::<RedisValue, _, _>(&key, payload).await;
+    let _ = pipeline
+        .ltrim::<(), _>(&key, 0, BROADCAST_TRACE_AUDIT_MAX_ENTRIES - 1)
+        .await;
+    let _ = pipeline
+        .expire::<(), _>(&key, BROADCAST_TRACE_AUDIT_TTL_SECS)
+        .await;
+    pipeline
+        .all::<Vec<RedisValue>>()
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+
+    if attempt.delivered {
+        redis
+            .set_key(
+                &broadcast_trace_last_success_key(entity_id),
+                attempt,
+                BROADCAST_TRACE_AUDIT_TTL_SECS as u32,
+            )
+            .await
+            .map_err(|err| AppError::InternalError(err.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Read the entity's broadcast trace audit list, newest first. Entries that fail to
+/// deserialize are dropped.
+pub async fn get_broadcast_trace_attempts(
+    redis: &RedisConnectionPool,
+    entity_id: &EntityId,
+) -> Result<Vec<BroadcastTraceAttempt>, AppError> {
+    let raw: Vec<String> = redis
+        .writer_pool
+        .next()
+        .lrange(&broadcast_trace_audit_key(entity_id), 0, -1)
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(raw
+        .into_iter()
+        .filter_map(|s| serde_json::from_str::<BroadcastTraceAttempt>(&s).ok())
+        .collect())
+}
+
+pub async fn get_broadcast_trace_last_success(
+    redis: &RedisConnectionPool,
+    entity_id: &EntityId,
+) -> Result<Option<BroadcastTraceAttempt>, AppError> {
+    redis
+        .get_key::<BroadcastTraceAttempt>(&broadcast_trace_last_success_key(entity_id))
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
+pub async fn set_broadcast_trace_target(
+    redis: &RedisConnectionPool,
+    target: &BroadcastTraceTarget,
+) -> Result<(), AppError> {
+    let payload = serde_json::to_string(target)
+        .map_err(|err| AppError::SerializationError(err.to_string()))?;
+    let field = broadcast_trace_target_field(&target.entity_id, &target.broadcaster_id);
+    redis
+        .writer_pool
+        .next()
+        .hset::<RedisValue, _, _>(
+            broadcast_trace_targets_key(),
+            (field.as_str(), payload.as_str()),
+        )
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(())
+}
+
+/// Read every broadcaster under the trace scheduler. Entries that fail JSON
+/// deserialization are dropped.
+pub async fn get_all_broadcast_trace_targets(
+    redis: &RedisConnectionPool,
+) -> Result<Vec<BroadcastTraceTarget>, AppError> {
+    let raw: HashMap<String, String> = redis
+        .writer_pool
+        .next()
+        .hgetall(broadcast_trace_targets_key())
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(raw
+        .into_iter()
+        .filter_map(|(field, value)| {
+            serde_json::from_str::<BroadcastTraceTarget>(&value)
+                .map_err(|err| error!(tag = "[Broadcast Trace]", field = %field, error = %err))
+                .ok()
+        })
+        .collect())
+}
+
+/// Drop a broadcaster from the trace scheduler along with its last-sent timestamp.
+pub async fn delete_broadcast_trace_target(
+    redis: &RedisConnectionPool,
+    entity_id: &EntityId,
+    broadcaster_id: &str,
+) -> Result<(), AppError> {
+    let field = broadcast_trace_target_field(entity_id, broadcaster_id);
+    let pipeline = redis.writer_pool.next().pipeline();
+    let _ = pipeline
+        .hdel::<RedisValue, _, _>(broadcast_trace_targets_key(), field.as_str())
+        .await;
+    let _ = pipeline
+        .hdel::<RedisValue, _, _>(broadcast_trace_last_sent_key(), field.as_str())
+        .await;
+    pipeline
+        .all::<Vec<RedisValue>>()
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(())
+}
+
+pub async fn set_broadcast_trace_last_sent(
+    redis: &RedisConnectionPool,
+    entity_id: &EntityId,
+    broadcaster_id: &str,
+    sent_at: i64,
+) -> Result<(), AppError> {
+    redis
+        .writer_pool
+        .next()
+        .hset::<RedisValue, _, _>(
+            broadcast_trace_last_sent_key(),
+            (
+                broadcast_trace_target_field(entity_id, broadcaster_id),
+                sent_at,
+            ),
+        )
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(())
+}
+
+pub async fn get_broadcast_trace_last_sent(
+    redis: &RedisConnectionPool,
+    entity_id: &EntityId,
+    broadcaster_id: &str,
+) -> Result<Option<i64>, AppError> {
+    redis
+        .writer_pool
+        .next()
+        .hget::<Option<i64>, _, _>(
+            broadcast_trace_last_sent_key(),
+            broadcast_trace_target_field(entity_id, broadcaster_id),
+        )
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
+const SCAN_PAGE_SIZE: u32 = 500;
+
+/// SCAN every primary for keys matching `pattern`. Used by one-off backfills only;
+/// never call this on a request path.
+pub async fn scan_keys(
+    redis: &RedisConnectionPool,
+    pattern: &str,
+) -> Result<Vec<String>, AppError> {
+    let mut pages = redis
+        .writer_pool
+        .next()
+        .scan_cluster(pattern, Some(SCAN_PAGE_SIZE), None);
+    let mut keys = Vec::new();
+    while let Some(page) = pages.next().await {
+        let mut page = page.map_err(|err| AppError::InternalError(err.to_string()))?;
+        if let Some(results) = page.take_results() {
+            keys.extend(results.into_iter().filter_map(|key| key.into_string()));
+        }
+        page.next()
+            .map_err(|err| AppError::InternalError(err.to_string()))?;
+    }
+    Ok(keys)
+}
+
+/// Store a source's latest ping for a vehicle and refresh the hash TTL in one round trip.
+pub async fn set_vehicle_source_sample(
+    redis: &RedisConnectionPool,
+    vehicle_number: &str,
+    source: LocationSource,
+    sample: &VehicleSourceSample,
+    expiry: i64,
+) -> Result<(), AppError> {
+    let key = vehicle_location_sources_key(vehicle_number);
+    let payload = serde_json::to_string(sample)
+        .map_err(|err| AppError::SerializationError(err.to_string()))?;
+    let pipeline = redis.writer_pool.next().pipeline();
+    let _ = pipeline
+        .hset::<RedisValue, _, _>(&key, (source.to_string(), payload))
+        .await;
+    let _ = pipeline.expire::<(), _>(&key, expiry).await;
+    pipeline
+        .all::<Vec<RedisValue>>()
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(())
+}
+
+/// Latest ping per source for a vehicle. Unknown sources and entries that fail JSON
+/// deserialization are dropped.
+pub async fn get_vehicle_source_samples(
+    redis: &RedisConnectionPool,
+    vehicle_number: &str,
+) -> Result<HashMap<LocationSource, VehicleSourceSample>, AppError> {
+    let raw: HashMap<String, String> = redis
+        .writer_pool
+        .next()
+        .hgetall(vehicle_location_sources_key(vehicle_number))
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(raw
+        .into_iter()
+        .filter_map(|(source, value)| {
+            Some((
+                LocationSource::from_str(&source).ok()?,
+                serde_json::from_str::<VehicleSourceSample>(&value).ok()?,
+            ))
+        })
+        .collect())
+}
+
+/// Vehicle numbers mapped to `imeis` for a vendor, in the same order. Unmapped
+/// IMEIs are `None`.
+pub async fn get_external_gps_imei_vehicles(
+    redis: &RedisConnectionPool,
+    vendor: &str,
+    imeis: Vec<String>,
+) -> Result<Vec<Option<String>>, AppError> {
+    if imeis.is_empty() {
+        return Ok(Vec::new());
+    }
+    redis
+        .writer_pool
+        .next()
+        .hmget(external_gps_imei_vehicle_key(vendor), imeis)
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
+pub async fn set_external_gps_imei_vehicles(
+    redis: &RedisConnectionPool,
+    vendor: &str,
+    imei_vehicles: Vec<(String, String)>,
+) -> Result<(), AppError> {
+    if imei_vehicles.is_empty() {
+        return Ok(());
+    }
+    redis
+        .writer_pool
+        .next()
+        .hset::<RedisValue, _, _>(external_gps_imei_vehicle_key(vendor), imei_vehicles)
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(())
+}
+
+pub async fn delete_external_gps_imei_vehicles(
+    redis: &RedisConnectionPool,
+    vendor: &str,
+    imeis: Vec<String>,
+) -> Result<(), AppError> {
+    if imeis.is_empty() {
+        return Ok(());
+    }
+    redis
+        .writer_pool
+        .next()
+        .hdel::<RedisValue, _, _>(external_gps_imei_vehicle_key(vendor), imeis)
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(())
+}
+
+const DRAINER_STREAM_ENTRY_FIELD: &str = "entry";
+
+/// FNV-1a of the driver id, so every pod sends a driver's entries to the same
+/// partition and they are drained in order.
+pub fn drainer_stream_partition(driver_id: &DriverId, partitions: u32) -> u32 {
+    let hash = driver_id.0.bytes().fold(0x811c9dc5_u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x01000193)
+    });
+    hash % partitions.max(1)
+}
+
+/// Orders drainer stream entry ids (`<ms>-<seq>`); `None` if malformed.
+pub fn drainer_stream_id_order(stream_id: &str) -> Option<(u64, u64)> {
+    let (ms, seq) = stream_id.split_once('-')?;
+    Some((ms.parse().ok()?, seq.parse().ok()?))
+}
+
+/// The pod ordinal at the end of a StatefulSet `HOSTNAME` (`lts-drainer-3` → 3).
+pub fn pod_ordinal(hostname: &str) -> Option<u32> {
+    hostname.rsplit_once('-')?.1.parse().ok()
+}
+
+/// Partitions drained by the pod with `ordinal` out of `pods`.
+pub fn drainer_stream_pod_partitions(partitions: u32, pods: u32, ordinal: u32) -> Vec<u32> {
+    (0..partitions)
+        .filter(|partition| partition % pods.max(1) == ordinal)
+        .collect()
+}
+
+pub async fn publish_drainer_entry(
+    redis: &RedisConnectionPool,
+    config: &DrainerStreamConfig,
+    entry: &DrainerEntry,
+) -> Result<(), AppError> {
+    let payload = serde_json::to_string(entry)
+        .map_err(|err| AppError::SerializationError(err.to_string()))?;
+    let (.., driver_id) = entry;
+    redis
+        .writer_pool
+        .next()
+        .xadd::<RedisValue, _, _, _, _>(
+            drainer_stream_key(drainer_stream_partition(driver_id, config.partitions)),
+            false,
+            None,
+            "*",
+            (DRAINER_STREAM_ENTRY_FIELD, payload),
+        )
+        .await
+        .map_err(|err| AppError::DrainerPushFailed(err.to_string()))?;
+    Ok(())
+}
+
+/// Creates the consumer group (and the stream) unless it already exists.
+pub async fn ensure_drainer_stream_group(
+    redis: &RedisConnectionPool,
+    partition: u32,
+    group: &str,
+) -> Result<(), AppError> {
+    match redis
+        .writer_pool
+        .next()
+        .xgroup_create::<RedisValue, _, _, _>(drainer_stream_key(partition), group, "0", true)
+        .await
+    {
+        Err(err) if !err.to_string().contains("BUSYGROUP") => {
+            Err(AppError::InternalError(err.to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Stream ids with their decoded entries; `None` for entries that failed to
+/// decode, which should still be acknowledged.
+pub type DrainerStreamBatch = Vec<(String, Option<DrainerEntry>)>;
+
+fn decode_drainer_stream_entries(
+    entries: Vec<XReadValue<String, String, String>>,
+) -> DrainerStreamBatch {
+    entries
+        .into_iter()
+        .map(|(id, fields)| {
+            let entry = fields.get(DRAINER_STREAM_ENTRY_FIELD).and_then(|payload| {
+                serde_json::from_str::<DrainerEntry>(payload)
+                    .map_err(|err| error!(tag = "[Drainer Stream]", id = %id, error = %err))
+                    .ok()
+            });
+            (id, entry)
+        })
+        .collect()
+}
+
+/// Entries of the partition never delivered to any worker in `group`.
+pub async fn read_drainer_stream(
+    redis: &RedisConnectionPool,
+    partition: u32,
+    group: &str,
+    consumer: &str,
+    count: u64,
+) -> Result<DrainerStreamBatch, AppError> {
+    let response: XReadResponse<String, String, String, String> = redis
+        .writer_pool
+        .next()
+        .xreadgroup_map(
+            group,
+            consumer,
+            Some(count),
+            None,
+            false,
+            drainer_stream_key(partition),
+            ">",
+        )
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(decode_drainer_stream_entries(
+        response.into_values().flatten().collect(),
+    ))
+}
+
+/// Takes over entries delivered but left unacknowledged for `min_idle_ms`.
+pub async fn claim_idle_drainer_stream_entries(
+    redis: &RedisConnectionPool,
+    partition: u32,
+    group: &str,
+    consumer: &str,
+    min_idle_ms: u64,
+    count: u64,
+) -> Result<DrainerStreamBatch, AppError> {
+    let (_, claimed): (String, Vec<XReadValue<String, String, String>>) = redis
+        .writer_pool
+        .next()
+        .xautoclaim_values(
+            drainer_stream_key(partition),
+            group,
+            consumer,
+            min_idle_ms,
+            "0-0",
+            Some(count),
+            false,
+        )
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(decode_drainer_stream_entries(claimed))
+}
+
+pub async fn ack_drainer_stream_entries(
+    redis: &RedisConnectionPool,
+    partition: u32,
+    group: &str,
+    ids: &[String],
+) -> Result<(), AppError> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    redis
+        .writer_pool
+        .next()
+        .xack::<RedisValue, _, _, _>(
+            drainer_stream_key(partition),
+            group,
+            ids.iter()
+                .map(|id| XID::from(id.as_str()))
+                .collect::<Vec<_>>(),
+        )
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(())
+}
+
+/// Trims the partition up to the oldest entry still pending in `group`, or up to
+/// `acked_id` when nothing is pending, so only acknowledged entries are dropped.
+pub async fn trim_acked_drainer_stream(
+    redis: &RedisConnectionPool,
+    partition: u32,
+    group: &str,
+    acked_id: &str,
+) -> Result<(), AppError> {
+    let key = drainer_stream_key(partition);
+    let summary: RedisValue = redis
+        .writer_pool
+        .next()
+        .xpending(&key, group, ())
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    // Summary form: [count, oldest pending id, newest pending id, consumers].
+    let oldest_pending = match summary {
+        RedisValue::Array(values) => values.get(1).and_then(RedisValue::as_string),
+        _ => None,
+    };
+    let min_id = oldest_pending.unwrap_or_else(|| acked_id.to_string());
+    redis
+        .writer_pool
+        .next()
+        .xtrim::<RedisValue, _, _>(&key, ("MINID", "~", min_id.as_str()))
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(())
+}
+
+/// Reads the driver's session cursor together with the stored payload, which
+/// `set_driver_session_cursor_if_unchanged` compares against.
+pub async fn get_driver_session_cursor(
+    redis: &RedisConnectionPool,
+    driver_id: &DriverId,
+) -> Result<Option<(DriverSessionCursor, String)>, AppError> {
+    let stored = redis
+        .get_key_as_str(&driver_session_cursor_key(driver_id))
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    stored
+        .map(|stored| {
+            serde_json::from_str(&stored)
+                .map(|cursor| (cursor, stored))
+                .map_err(|err| AppError::DeserializationError(err.to_string()))
+        })
+        .transpose()
+}
+
+/// Moves the session cursor unless another batch moved it since it was read as
+/// `expected`. Returns whether it was moved. The cursor lives as long as a
+/// session may pause.
+pub async fn set_driver_session_cursor_if_unchanged(
+    redis: &RedisConnectionPool,
+    ping_gap_threshold_secs: i64,
+    driver_id: &DriverId,
+    expected: Option<&str>,
+    cursor: &DriverSessionCursor,
+) -> Result<bool, AppError> {
+    let cursor = serde_json::to_string(cursor)
+        .map_err(|err| AppError::SerializationError(err.to_string()))?;
+    compare_and_set_key(
+        redis,
+        &driver_session_cursor_key(driver_id),
+        expected,
+        &cursor,
+        ping_gap_threshold_secs.max(1),
+    )
+    .await
+}
+
+/// Moves the session cursor as is and adds a batch of pings to the driver's day
+/// entries, in one pipeline, for the pool writes are mirrored to.
+#[allow(clippy::too_many_arguments)]
+pub async fn record_driver_session(
+    redis: &RedisConnectionPool,
+    ping_gap_threshold_secs: i64,
+    retention_secs: i64,
+    merchant_id: &MerchantId,
+    driver_id: &DriverId,
+    cursor: &DriverSessionCursor,
+    days: &[(String, DriverDayAccounting)],
+) -> Result<(), AppError> {
+    let cursor = serde_json::to_string(cursor)
+        .map_err(|err| AppError::SerializationError(err.to_string()))?;
+    let pipeline = redis.writer_pool.next().pipeline();
+    let _ = pipeline
+        .set::<RedisValue, _, _>(
+            driver_session_cursor_key(driver_id),
+            cursor,
+            Some(Expiration::EX(ping_gap_threshold_secs.max(1))),
+            None,
+            false,
+        )
+        .await;
+    add_driver_session_days_to_pipeline(&pipeline, retention_secs, merchant_id, driver_id, days)
+        .await;
+    pipeline
+        .all::<Vec<RedisValue>>()
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(())
+}
+
+/// Adds a batch of pings to the driver's day entries, in one pipeline, once
+/// the cursor has been moved past them.
+pub async fn add_driver_session_days(
+    redis: &RedisConnectionPool,
+    retention_secs: i64,
+    merchant_id: &MerchantId,
+    driver_id: &DriverId,
+    days: &[(String, DriverDayAccounting)],
+) -> Result<(), AppError> {
+    if days.is_empty() {
+        return Ok(());
+    }
+    let pipeline = redis.writer_pool.next().pipeline();
+    add_driver_session_days_to_pipeline(&pipeline, retention_secs, merchant_id, driver_id, days)
+        .await;
+    pipeline
+        .all::<Vec<RedisValue>>()
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(())
+}
+
+async fn add_driver_session_days_to_pipeline(
+    pipeline: &Pipeline<RedisClient>,
+    retention_secs: i64,
+    merchant_id: &MerchantId,
+    driver_id: &DriverId,
+    days: &[(String, DriverDayAccounting)],
+) {
+    for (day, accounting) in days {
+        let key = driver_day_session_key(day, driver_id);
+        for (field, value) in [
+            ("idle_ms", accounting.idle_ms),
+            ("silent_ms", accounting.silent_ms),
+            ("on_pickup_ms", accounting.on_pickup_ms),
+            ("on_ride_ms", accounting.on_ride_ms),
+            ("sessions", accounting.sessions),
+        ] {
+            if value != 0 {
+                let _ = pipeline
+                    .hincrby::<RedisValue, _, _>(&key, field, value)
+                    .await;
+            }
+        }
+        if accounting.distance_meters > 0.0 {
+            let _ = pipeline
+                .hincrbyfloat::<RedisValue, _, _>(
+                    &key,
+                    "distance_meters",
+                    accounting.distance_meters,
+                )
+                .await;
+        }
+        let _ = pipeline
+            .hset::<RedisValue, _, _>(&key, ("merchant_id", merchant_id.0.as_str()))
+            .await;
+        let _ = pipeline.expire::<(), _>(&key, retention_secs).await;
+        let drivers_key = driver_session_day_drivers_key(day);
+        let _ = pipeline
+            .sadd::<RedisValue, _, _>(&drivers_key, driver_id.0.as_str())
+            .await;
+        let _ = pipeline.expire::<(), _>(&drivers_key, retention_secs).await;
+    }
+}
+
+pub async fn get_driver_day_session(
+    redis: &RedisConnectionPool,
+    day: &str,
+    driver_id: &DriverId,
+) -> Result<HashMap<String, String>, AppError> {
+    redis
+        .reader_pool
+        .hgetall(driver_day_session_key(day, driver_id))
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
+/// Day entries of several drivers in one pipeline, in the order of `driver_ids`.
+pub async fn get_driver_day_sessions(
+    redis: &RedisConnectionPool,
+    day: &str,
+    driver_ids: &[DriverId],
+) -> Result<Vec<HashMap<String, String>>, AppError> {
+    let pipeline = redis.writer_pool.next().pipeline();
+    for driver_id in driver_ids {
+        let _ = pipeline
+            .hgetall::<RedisValue, _>(driver_day_session_key(day, driver_id))
+            .await;
+    }
+    let results: Vec<RedisValue> = pipeline
+        .all()
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    results
+        .into_iter()
+        .map(|value| {
+            value
+                .convert::<HashMap<String, String>>()
+                .map_err(|err| AppError::DeserializationError(err.to_string()))
+        })
+        .collect()
+}
+
+/// Walks the drivers with an accounting entry for the day with SSCAN, a page at
+/// a time. A driver may come up more than once.
+pub fn scan_driver_session_day_drivers<'a>(
+    redis: &'a RedisConnectionPool,
+    day: &str,
+) -> impl Stream<Item = Result<Vec<DriverId>, AppError>> + 'a {
+    redis
+        .writer_pool
+        .next()
+        .sscan(
+            driver_session_day_drivers_key(day),
+            "*",
+            Some(SCAN_PAGE_SIZE),
+        )
+        .map(|page| {
+            let mut page = page.map_err(|err| AppError::InternalError(err.to_string()))?;
+            let driver_ids = page
+                .take_results()
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|driver_id| driver_id.into_string())
+                .map(DriverId)
+                .collect();
+            page.next()
+                .map_err(|err| AppError::InternalError(err.to_string()))?;
+            Ok(driver_ids)
+        })
+}
+
+/// Whether each driver's summary for the day was already published, in the
+/// order of `driver_ids`.
+pub async fn are_driver_session_days_published(
+    redis: &RedisConnectionPool,
+    day: &str,
+    driver_ids: &[DriverId],
+) -> Result<Vec<bool>, AppError> {
+    if driver_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let key = driver_session_day_published_key(day);
+    let pipeline = redis.writer_pool.next().pipeline();
+    for driver_id in driver_ids {
+        let _ = pipeline
+            .sismember::<RedisValue, _, _>(&key, driver_id.0.as_str())
+            .await;
+    }
+    let results: Vec<bool> = pipeline
+        .all()
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(results)
+}
+
+/// Marks the drivers' summaries for the day as published.
+pub async fn set_driver_session_days_published(
+    redis: &RedisConnectionPool,
+    day: &str,
+    driver_ids: &[DriverId],
+    retention_secs: i64,
+) -> Result<(), AppError> {
+    if driver_ids.is_empty() {
+        return Ok(());
+    }
+    let key = driver_session_day_published_key(day);
+    let pipeline = redis.writer_pool.next().pipeline();
+    let _ = pipeline
+        .sadd::<RedisValue, _, _>(
+            &key,
+            driver_ids
+                .iter()
+                .map(|driver_id| driver_id.0.as_str())
+                .collect::<Vec<&str>>(),
+        )
+        .await;
+    let _ = pipeline.expire::<(), _>(&key, retention_secs).await;
+    pipeline
+        .all::<Vec<RedisValue>>()
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok(())
+}
+
+pub async fn is_driver_session_day_closed(
+    redis: &RedisConnectionPool,
+    day: &str,
+) -> Result<bool, AppError> {
+    redis
+        .writer_pool
+        .next()
+        .exists::<bool, _>(driver_session_day_closed_key(day))
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
+pub async fn set_driver_session_day_closed(
+    redis: &RedisConnectionPool,
+    day: &str,
+    retention_secs: i64,
+) -> Result<(), AppError> {
+    redis
+        .writer_pool
+        .next()
+        .set::<(), _, _>(
+            driver_session_day_closed_key(day),
+            Utc::now().to_rfc3339(),
+            Some(Expiration::EX(retention_secs)),
+            None,
+            false,
+        )
+        .await
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
@@ -124,9 +124,13 @@ pub fn on_ride_driver_details_key(RideId(ride_id): &RideId) -> String {
     format!("lts:on_ride_driver_details:{ride_id}")
 }
 
-/// Constructs a Redis key for storing ride status and city details for a driver.
-///
-/// The resulting key is intended to be stored in persistent Redis storage.
+/// Latest `ts` of a ride's trace already sent to the bulk location callback.
+pub fn on_ride_trace_flushed_till_key(RideId(ride_id): &RideId) -> String {
+    format!("lts:on_ride_trace_flushed_till:{ride_id}")
+}
+
+/// Constructs a Redis key for the combined driver details written before they
+/// were split into the keys below. It is only read now, until it expires.
 ///
 /// # Arguments
 ///
@@ -140,6 +144,41 @@ pub fn driver_details_key(DriverId(driver_id): &DriverId) -> String {
     format!("lts:driver_details:{driver_id}")
 }
 
+/// Keys of the split driver details. They share a hash tag, so one MGET reads
+/// them all on a cluster too.
+pub fn driver_location_state_key(DriverId(driver_id): &DriverId) -> String {
+    format!("lts:driver_details:{{{driver_id}}}:location")
+}
+
+pub fn driver_ride_notification_state_key(DriverId(driver_id): &DriverId) -> String {
+    format!("lts:driver_details:{{{driver_id}}}:ride_notification")
+}
+
+pub fn driver_detection_state_key(DriverId(driver_id): &DriverId) -> String {
+    format!("lts:driver_details:{{{driver_id}}}:detection")
+}
+
+pub fn driver_blocked_till_key(DriverId(driver_id): &DriverId) -> String {
+    format!("lts:driver_details:{{{driver_id}}}:blocked_till")
+}
+
+/// Current availability state of a driver and when it was entered.
+pub fn driver_availability_key(DriverId(driver_id): &DriverId) -> String {
+    format!("lts:driver_availability:{driver_id}")
+}
+
+/// Online drivers scored by when they were last seen (unix seconds), swept to
+/// move drivers who stopped pinging to `Offline`.
+pub fn driver_availability_last_seen_key() -> String {
+    "lts:driver_availability_last_seen".to_string()
+}
+
+/// Taken by whichever pod sweeps for ping timeouts in an interval window (unix
+/// seconds / interval).
+pub fn driver_availability_sweep_lock_key(window: i64) -> String {
+    format!("lts:driver_availability_sweep_lock:{window}")
+}
+
 /// Constructs a Redis key specifically for health checks.
 ///
 /// # Returns
@@ -226,8 +265,20 @@ pub fn entity_details_key(
 }
 
 /// Person by entity lookup. entity_type: "ride" | "sos", entity_id: the id string.
-pub fn person_detail_by_entity_key(entity_type: &str, entity_id: &str) -> String {
-    format!("lts:person_detail_by_entity:{entity_type}:{entity_id}")
+/// Riders keep the original un-prefixed key; other person types are namespaced so that
+/// a rider and a driver on the same ride do not overwrite each other.
+pub fn person_detail_by_entity_key(
+    person_type: PersonType,
+    entity_type: &str,
+    entity_id: &str,
+) -> String {
+    match person_type {
+        PersonType::Rider => format!("lts:person_detail_by_entity:{entity_type}:{entity_id}"),
+        _ => format!(
+            "lts:person_detail_by_entity:{}:{entity_type}:{entity_id}",
+            person_type.as_str()
+        ),
+    }
 }
 
 /// Person detail (last location, status).
@@ -321,3 +372,201 @@ pub fn driver_queue_tracking_key(merchant_id: &str, driver_id: &str) -> String {
 pub fn driver_queue_rank_history_key(merchant_id: &str, driver_id: &str) -> String {
     format!("lts:driver_queue_rank_hist:{}:{}", merchant_id, driver_id)
 }
+
+/// HASH of the current runtime config override per scope. Field = scope
+/// (`merchant:<id>:city:<name>` / `merchant:<id>` / `city:<name>` / `global`),
+/// value = JSON `ConfigOverrideEntry`.
+/// No TTL — overrides persist until replaced or rolled back.
+pub fn config_overrides_key() -> String {
+    "lts:config_overrides".to_string()
+}
+
+/// Current `RedisMigrationPhase` (JSON), always kept in the primary Redis.
+pub fn redis_migration_phase_key() -> String {
+    "lts:redis_migration_phase".to_string()
+}
+
+/// SCAN patterns of the long-lived keys the Redis migration copies to the target
+/// before `CutOver`. Other keys expire and are rewritten by live traffic while
+/// writes are mirrored. Queue keys only when the queues live on the primary.
+pub fn redis_migration_backfill_key_patterns(include_queues: bool) -> Vec<String> {
+    let mut patterns = vec![
+        entity_details_key_pattern(),
+        broadcast_trace_targets_key(),
+        broadcast_trace_last_sent_key(),
+        driver_availability_last_seen_key(),
+    ];
+    if include_queues {
+        patterns.extend([
+            special_location_queue_key("*", "*"),
+            driver_queue_tracking_key("*", "*"),
+            driver_queue_last_ts_key("*", "*", "*"),
+            driver_queue_rank_history_key("*", "*"),
+        ]);
+    }
+    patterns
+}
+
+/// Capped LIST of every override version written for a scope, newest-first.
+pub fn config_override_history_key(scope: &str) -> String {
+    format!("lts:config_override_hist:{scope}")
+}
+
+/// Lock serialising override writes for a scope so versions stay monotonic.
+pub fn config_override_lock_key(scope: &str) -> String {
+    format!("lts:config_override_lock:{scope}")
+}
+
+/// HASH of in-progress rides under the rider/driver proximity check. Field =
+/// ride id, value = JSON `RideProximityTracking`. Entries are removed on ride
+/// end, or by the check itself once the driver is no longer on that ride.
+pub fn ride_proximity_tracking_key() -> String {
+    "lts:ride_proximity_tracking".to_string()
+}
+
+/// Taken by whichever pod runs the proximity check for an interval window
+/// (unix seconds / interval). Outlives the window, so a pod whose ticks lag
+/// behind the others cannot run the same window again.
+pub fn ride_proximity_check_lock_key(window: i64) -> String {
+    format!("lts:ride_proximity_check_lock:{window}")
+}
+
+/// Held while a proximity check runs and released when it finishes, so a run
+/// that overruns its interval does not overlap the next window's run.
+pub fn ride_proximity_check_running_key() -> String {
+    "lts:ride_proximity_check_running".to_string()
+}
+
+/// Cached external trace token for a reauth scope. Expires with the token itself.
+pub fn broadcaster_token_key(scope: &str) -> String {
+    format!("lts:broadcaster_token:{scope}")
+}
+
+/// Single-flight lock around the reauth call for a scope.
+pub fn broadcaster_token_lock_key(scope: &str) -> String {
+    format!("lts:broadcaster_token_lock:{scope}")
+}
+
+/// Reauth failure count and next allowed attempt for a scope.
+pub fn broadcaster_token_backoff_key(scope: &str) -> String {
+    format!("lts:broadcaster_token_backoff:{scope}")
+}
+
+fn entity_id_key_part(entity_id: &EntityId) -> String {
+    match entity_id {
+        EntityId::Sos(SosId(id)) => format!("sos:{id}"),
+        EntityId::Ride(RideId(id)) => format!("ride:{id}"),
+    }
+}
+
+/// Capped, newest-first list of broadcast trace `send_ping` attempts for an entity.
+pub fn broadcast_trace_audit_key(entity_id: &EntityId) -> String {
+    format!(
+        "lts:broadcast_trace_audit:{}",
+        entity_id_key_part(entity_id)
+    )
+}
+
+/// Last delivered broadcast trace attempt for an entity. Kept apart from the audit list
+/// so a long failure streak cannot trim it away.
+pub fn broadcast_trace_last_success_key(entity_id: &EntityId) -> String {
+    format!(
+        "lts:broadcast_trace_last_success:{}",
+        entity_id_key_part(entity_id)
+    )
+}
+
+/// HASH of broadcasters the trace scheduler polls for. Field =
+/// `broadcast_trace_target_field`, value = JSON `BroadcastTraceTarget`. Entries are
+/// removed on entity end, or by the scheduler once the broadcaster is gone or expired.
+pub fn broadcast_trace_targets_key() -> String {
+    "lts:broadcast_trace_targets".to_string()
+}
+
+pub fn broadcast_trace_target_field(entity_id: &EntityId, broadcaster_id: &str) -> String {
+    format!("{}:{broadcaster_id}", entity_id_key_part(entity_id))
+}
+
+/// HASH of the epoch seconds of the last delivered trace per broadcaster. Field =
+/// `broadcast_trace_target_field`. Written on delivery so the person's entity
+/// details are never rewritten from the trace path.
+pub fn broadcast_trace_last_sent_key() -> String {
+    "lts:broadcast_trace_last_sent".to_string()
+}
+
+/// Held for one polling interval by whichever pod or path sends a broadcaster's
+/// trace, and released if the send fails so the next attempt can retry.
+pub fn broadcast_trace_send_lock_key(entity_id: &EntityId, broadcaster_id: &str) -> String {
+    format!(
+        "lts:broadcast_trace_send_lock:{}",
+        broadcast_trace_target_field(entity_id, broadcaster_id)
+    )
+}
+
+/// Taken by the pod that runs the broadcast trace poll for the scheduler window
+/// (unix seconds / interval). Outlives the window, like the proximity check lock.
+pub fn broadcast_trace_scheduler_lock_key(window: i64) -> String {
+    format!("lts:broadcast_trace_scheduler_lock:{window}")
+}
+
+/// Taken once per deploy window by the pod that backfills trace targets for
+/// broadcasters started before the scheduler existed.
+pub fn broadcast_trace_backfill_lock_key() -> String {
+    "lts:broadcast_trace_backfill_lock".to_string()
+}
+
+/// SCAN pattern over every `entity_details_key`.
+pub fn entity_details_key_pattern() -> String {
+    "lts:entity_details:*".to_string()
+}
+
+/// HASH of the latest ping per `LocationSource` for a vehicle. Field = source,
+/// value = JSON `VehicleSourceSample`. Read by the vehicle location fusion.
+pub fn vehicle_location_sources_key(vehicle_number: &str) -> String {
+    format!("lts:vehicle_location_sources:{vehicle_number}")
+}
+
+/// HASH of IMEI -> vehicle number for one external GPS vendor. Maintained through
+/// the admin API; consulted before the payload's plate number.
+pub fn external_gps_imei_vehicle_key(vendor: &str) -> String {
+    format!("lts:external_gps:{vendor}:imei_vehicle")
+}
+
+/// Stream of drainer entries for one partition, read by the drainer workers'
+/// consumer group.
+pub fn drainer_stream_key(partition: u32) -> String {
+    format!("lts:drainer_stream:{partition}")
+}
+
+/// Last accounted ping of a driver, JSON `DriverSessionCursor`. Expires after
+/// the session ping gap, which ends the session anyway.
+pub fn driver_session_cursor_key(DriverId(driver_id): &DriverId) -> String {
+    format!("lts:driver_session_cursor:{driver_id}")
+}
+
+/// HASH of a driver's accounted durations (ms), distance and sessions for one
+/// accounting day (`YYYY-MM-DD`).
+pub fn driver_day_session_key(day: &str, DriverId(driver_id): &DriverId) -> String {
+    format!("lts:driver_session:{day}:{driver_id}")
+}
+
+/// SET of the drivers with an accounting entry for the day, walked at day close.
+pub fn driver_session_day_drivers_key(day: &str) -> String {
+    format!("lts:driver_session_drivers:{day}")
+}
+
+/// SET of the drivers whose summary for the day has been published, so a day
+/// close that is retried skips them.
+pub fn driver_session_day_published_key(day: &str) -> String {
+    format!("lts:driver_session_published:{day}")
+}
+
+/// Set once every summary of the day has been published.
+pub fn driver_session_day_closed_key(day: &str) -> String {
+    format!("lts:driver_session_day_closed:{day}")
+}
+
+/// Held by whichever pod is publishing the day's summaries.
+pub fn driver_session_day_close_lock_key(day: &str) -> String {
+    format!("lts:driver_session_day_close_lock:{day}")
+}
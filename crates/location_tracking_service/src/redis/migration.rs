@@ -0,0 +1,312 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Controlled migration of LTS state from the primary Redis to the secondary one.
+//!
+//! The phase is stored in the primary Redis and picked up by every pod:
+//!   - `Off`: the primary only (queues keep following `RUN_IN_SECONDARY_LTS_REDIS`).
+//!   - `DualWrite`: writes go to both clusters, reads to the primary.
+//!   - `ShadowRead`: as `DualWrite`, and reads are repeated on the secondary and
+//!     compared; mismatches are counted and a sample of them logged.
+//!   - `CutOver`: the secondary serves reads and writes. Writes are still
+//!     mirrored to the primary, so stepping back to `ShadowRead` loses nothing.
+//!
+//! Every phase applies to the pool a caller keeps its state on: state on a pool
+//! other than the primary is neither moved nor mirrored.
+//!
+//! Keys written before `DualWrite` started only reach the secondary if they are
+//! written again. The long-lived ones (queues, queue tracking and rank history,
+//! entity details, broadcast trace targets) are copied over by
+//! `backfill_redis_migration` on the way into `CutOver`.
+
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use shared::redis::types::RedisConnectionPool;
+use tracing::{error, info, warn};
+
+use crate::redis::commands::{copy_keys, get_redis_migration_phase, scan_keys};
+use crate::redis::keys::redis_migration_backfill_key_patterns;
+use crate::tools::error::AppError;
+use crate::tools::prometheus::{REDIS_MIGRATION_MIRROR_WRITES, REDIS_MIGRATION_SHADOW_READS};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RedisMigrationPhase {
+    #[default]
+    Off,
+    DualWrite,
+    ShadowRead,
+    CutOver,
+}
+
+impl RedisMigrationPhase {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => RedisMigrationPhase::DualWrite,
+            2 => RedisMigrationPhase::ShadowRead,
+            3 => RedisMigrationPhase::CutOver,
+            _ => RedisMigrationPhase::Off,
+        }
+    }
+
+    /// Phases move one step at a time, forward or back.
+    pub fn can_move_to(self, next: RedisMigrationPhase) -> bool {
+        (self as u8).abs_diff(next as u8) == 1
+    }
+}
+
+pub struct RedisMigration {
+    source: Arc<RedisConnectionPool>,
+    target: Option<Arc<RedisConnectionPool>>,
+    phase: AtomicU8,
+    mismatch_log_every: u64,
+    mismatches: AtomicU64,
+}
+
+impl RedisMigration {
+    /// Without a `target` the migration stays `Off`.
+    pub fn new(
+        source: Arc<RedisConnectionPool>,
+        target: Option<Arc<RedisConnectionPool>>,
+        mismatch_log_every: u64,
+    ) -> Self {
+        RedisMigration {
+            source,
+            target,
+            phase: AtomicU8::new(RedisMigrationPhase::Off as u8),
+            mismatch_log_every: mismatch_log_every.max(1),
+            mismatches: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.target.is_some()
+    }
+
+    pub fn source(&self) -> &Arc<RedisConnectionPool> {
+        &self.source
+    }
+
+    pub fn phase(&self) -> RedisMigrationPhase {
+        RedisMigrationPhase::from_u8(self.phase.load(Ordering::Relaxed))
+    }
+
+    pub fn set_phase(&self, phase: RedisMigrationPhase) {
+        let previous = self.phase.swap(phase as u8, Ordering::Relaxed);
+        if previous != phase as u8 {
+            info!(tag = "[Redis Migration]", from = ?RedisMigrationPhase::from_u8(previous), to = ?phase, "Phase changed");
+        }
+    }
+
+    /// Whether state the caller keeps on `default` is being migrated. State
+    /// already on another pool, such as queues kept on the secondary with
+    /// `RUN_IN_SECONDARY_LTS_REDIS`, stays where it is.
+    pub fn migrates(&self, default: &Arc<RedisConnectionPool>) -> bool {
+        self.target.is_some() && Arc::ptr_eq(default, &self.source)
+    }
+
+    /// Pool serving reads and authoritative writes; `default` while `Off` or
+    /// when `default` is not being migrated.
+    pub fn serving(&self, default: &Arc<RedisConnectionPool>) -> Arc<RedisConnectionPool> {
+        match (self.phase(), &self.target) {
+            (RedisMigrationPhase::CutOver, Some(target)) if self.migrates(default) => {
+                target.clone()
+            }
+            _ => default.clone(),
+        }
+    }
+
+    /// Pool writes to `default` are mirrored to, while migrating.
+    pub fn mirror(&self, default: &Arc<RedisConnectionPool>) -> Option<Arc<RedisConnectionPool>> {
+        if !self.migrates(default) {
+            return None;
+        }
+        match (self.phase(), &self.target) {
+            (RedisMigrationPhase::Off, _) | (_, None) => None,
+            (RedisMigrationPhase::CutOver, Some(_)) => Some(self.source.clone()),
+            (_, Some(target)) => Some(target.clone()),
+        }
+    }
+
+    /// Every pool a write goes to, the serving one first.
+    pub fn write_pools(&self, default: &Arc<RedisConnectionPool>) -> Vec<Arc<RedisConnectionPool>> {
+        let mut pools = vec![self.serving(default)];
+        pools.extend(self.mirror(default));
+        pools
+    }
+
+    /// Secondary pool reads of `default` are repeated on, in `ShadowRead`.
+    pub fn shadow_target(
+        &self,
+        default: &Arc<RedisConnectionPool>,
+    ) -> Option<Arc<RedisConnectionPool>> {
+        match self.phase() {
+            RedisMigrationPhase::ShadowRead if self.migrates(default) => self.target.clone(),
+            _ => None,
+        }
+    }
+
+    /// Counts a shadow read, logging a sample of the mismatches.
+    pub fn compare<T: Serialize>(
+        &self,
+        op: &'static str,
+        primary: &Result<T, AppError>,
+        shadow: &Result<T, AppError>,
+    ) {
+        let (primary, shadow) = match (primary, shadow) {
+            (Ok(primary), Ok(shadow)) => (
+                serde_json::to_value(primary).ok(),
+                serde_json::to_value(shadow).ok(),
+            ),
+            _ => {
+                REDIS_MIGRATION_SHADOW_READS
+                    .with_label_values(&[op, "error"])
+                    .inc();
+                return;
+            }
+        };
+        if primary == shadow {
+            REDIS_MIGRATION_SHADOW_READS
+                .with_label_values(&[op, "match"])
+                .inc();
+            return;
+        }
+        REDIS_MIGRATION_SHADOW_READS
+            .with_label_values(&[op, "mismatch"])
+            .inc();
+        if self.mismatches.fetch_add(1, Ordering::Relaxed) % self.mismatch_log_every == 0 {
+            warn!(tag = "[Redis Migration Mismatch]", op = op, primary = ?primary, shadow = ?shadow);
+        }
+    }
+}
+
+/// Counts a mirrored write; failures are logged.
+pub fn record_mirror_write(op: &'static str, error: Option<&AppError>) {
+    match error {
+        Some(err) => {
+            REDIS_MIGRATION_MIRROR_WRITES
+                .with_label_values(&[op, "failed"])
+                .inc();
+            error!(tag = "[Redis Migration Mirror Write]", op = op, error = %err.message());
+        }
+        None => REDIS_MIGRATION_MIRROR_WRITES
+            .with_label_values(&[op, "ok"])
+            .inc(),
+    }
+}
+
+/// Runs a write on the serving pool and, while migrating, on the mirror too.
+/// Only the serving pool's result is returned.
+///
+/// ```ignore
+/// redis_migration_write!(data.redis_migration, "op", &data.redis, |redis| {
+///     set_something(&redis, &value)
+/// })
+/// ```
+///
+/// `redis` is bound to an `Arc<RedisConnectionPool>`; the write expression is
+/// evaluated once per pool, so it must not move its arguments.
+#[macro_export]
+macro_rules! redis_migration_write {
+    ($migration:expr, $op:expr, $default:expr, |$redis:ident| $write:expr) => {{
+        let migration: &$crate::redis::migration::RedisMigration = &$migration;
+        let serving = async {
+            let $redis = migration.serving($default);
+            $write.await
+        };
+        match migration.mirror($default) {
+            None => serving.await,
+            Some(mirror) => {
+                let mirrored = async {
+                    let $redis = mirror;
+                    $write.await
+                };
+                let (result, mirrored) = ::tokio::join!(serving, mirrored);
+                $crate::redis::migration::record_mirror_write($op, mirrored.as_ref().err());
+                result
+            }
+        }
+    }};
+}
+
+/// Runs a read on the serving pool. In `ShadowRead` it is repeated on the
+/// secondary and the results compared; the primary's result is returned.
+/// Same form as `redis_migration_write!`.
+#[macro_export]
+macro_rules! redis_migration_read {
+    ($migration:expr, $op:expr, $default:expr, |$redis:ident| $read:expr) => {{
+        let migration: &$crate::redis::migration::RedisMigration = &$migration;
+        match migration.shadow_target($default) {
+            None => {
+                let $redis = migration.serving($default);
+                $read.await
+            }
+            Some(target) => {
+                let (result, shadow) = ::tokio::join!(
+                    async {
+                        let $redis = migration.source().clone();
+                        $read.await
+                    },
+                    async {
+                        let $redis = target;
+                        $read.await
+                    }
+                );
+                migration.compare($op, &result, &shadow);
+                result
+            }
+        }
+    }};
+}
+
+/// Copies the long-lived keys from the primary to the secondary and returns how
+/// many were copied. Writes are mirrored meanwhile, so it runs while in
+/// `ShadowRead`; a key written between its copy's DUMP and RESTORE is left with
+/// the older value on the secondary until it is written again. Queue keys are
+/// skipped when `queue_redis` is not being migrated.
+pub async fn backfill_redis_migration(
+    migration: &RedisMigration,
+    queue_redis: &Arc<RedisConnectionPool>,
+) -> Result<usize, AppError> {
+    let Some(target) = &migration.target else {
+        return Ok(0);
+    };
+    let mut copied = 0;
+    for pattern in redis_migration_backfill_key_patterns(migration.migrates(queue_redis)) {
+        let keys = scan_keys(&migration.source, &pattern).await?;
+        let pattern_copied = copy_keys(&migration.source, target, &keys).await?;
+        info!(
+            tag = "[Redis Migration]",
+            pattern = %pattern,
+            "Backfilled {} of {} keys", pattern_copied, keys.len()
+        );
+        copied += pattern_copied;
+    }
+    Ok(copied)
+}
+
+/// Keeps this pod's phase in line with the one stored in the primary Redis.
+pub async fn start_redis_migration_phase_refresh_task(
+    migration: Arc<RedisMigration>,
+    refresh_interval_secs: u64,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(refresh_interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+        match get_redis_migration_phase(migration.source()).await {
+            Ok(phase) => migration.set_phase(phase),
+            Err(err) => error!(
+                tag = "[Redis Migration]",
+                "Failed to refresh migration phase: {}",
+                err.message()
+            ),
+        }
+    }
+}
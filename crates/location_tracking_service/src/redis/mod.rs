@@ -5,5 +5,8 @@
     or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
     the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
+pub mod codec;
 pub mod commands;
 pub mod keys;
+pub mod migration;
+pub mod routing;
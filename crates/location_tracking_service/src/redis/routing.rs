@@ -0,0 +1,73 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::Arc;
+
+use rustc_hash::FxHashMap;
+use shared::redis::types::RedisConnectionPool;
+
+use crate::common::types::CityName;
+
+/// Picks the Redis cluster holding a city's geo buckets. Cities without a
+/// dedicated cluster stay on the primary one. Each dedicated cluster is read
+/// through its own zone replica (`zone_to_redis_replica_mapping`).
+///
+/// This router covers geo buckets only, keyed by `CityName`. It does not move
+/// a city's whole state:
+///
+/// * Driver details (location, ride details, ping and ride state) stay on the
+///   primary cluster. They are looked up by driver or ride id from paths that
+///   carry no city, such as tracking by ride id and the ride proximity check.
+/// * Special-location queues stay on `queue_redis()`, keyed by special
+///   location.
+/// * Routing by `MerchantOperatingCityId` is not supported: the nearby-driver
+///   query that reads the buckets only knows the `CityName`.
+///
+/// A dedicated cluster therefore isolates a city's geo write load, not its
+/// data; losing it loses that city's nearby-driver results until drivers ping
+/// again, while rides and queues keep working off the primary cluster.
+#[derive(Clone)]
+pub struct CityRedisRouter {
+    /// The primary cluster first, then one pool per dedicated cluster.
+    pools: Vec<Arc<RedisConnectionPool>>,
+    city_pools: FxHashMap<CityName, usize>,
+}
+
+impl CityRedisRouter {
+    /// Index in `pools()` of the primary cluster.
+    pub const PRIMARY: usize = 0;
+
+    pub fn new(primary: Arc<RedisConnectionPool>) -> Self {
+        Self {
+            pools: vec![primary],
+            city_pools: FxHashMap::default(),
+        }
+    }
+
+    /// Serves `cities` from `pool`. A city already routed is moved to `pool`.
+    pub fn add_cluster(&mut self, cities: Vec<CityName>, pool: Arc<RedisConnectionPool>) {
+        let index = self.pools.len();
+        self.pools.push(pool);
+        for city in cities {
+            self.city_pools.insert(city, index);
+        }
+    }
+
+    /// Index in `pools()` of the cluster serving `city`.
+    pub fn pool_index(&self, city: &CityName) -> usize {
+        self.city_pools.get(city).copied().unwrap_or(Self::PRIMARY)
+    }
+
+    pub fn for_city(&self, city: &CityName) -> &Arc<RedisConnectionPool> {
+        &self.pools[self.pool_index(city)]
+    }
+
+    pub fn pools(&self) -> &[Arc<RedisConnectionPool>] {
+        &self.pools
+    }
+}
@@ -0,0 +1,625 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Semantic checks for a parsed `AppConfig`, run by `location-tracking-service validate-config`.
+//!
+//! `read_dhall_config` only guarantees the file deserializes; these checks catch
+//! values that parse fine but break (or silently disable) behaviour at runtime.
+
+use std::{collections::BTreeSet, fmt, time::Duration};
+
+use reqwest::Url;
+
+use crate::common::{config_override::DetectionConfigMap, types::*};
+use crate::environment::{AppConfig, GpsTimestampFormat, RedisConfig};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigFinding {
+    pub severity: Severity,
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ConfigValidationReport {
+    pub findings: Vec<ConfigFinding>,
+}
+
+impl ConfigValidationReport {
+    fn error(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.findings.push(ConfigFinding {
+            severity: Severity::Error,
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+
+    fn warning(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.findings.push(ConfigFinding {
+            severity: Severity::Warning,
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| finding.severity == Severity::Error)
+            .count()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.error_count() > 0
+    }
+}
+
+impl fmt::Display for ConfigValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut findings = self.findings.clone();
+        findings.sort_by(|a, b| b.severity.cmp(&a.severity).then(a.path.cmp(&b.path)));
+        for finding in findings {
+            let level = match finding.severity {
+                Severity::Error => "ERROR",
+                Severity::Warning => "WARN ",
+            };
+            writeln!(f, "[{level}] {} : {}", finding.path, finding.message)?;
+        }
+        let warnings = self.findings.len() - self.error_count();
+        write!(
+            f,
+            "{} error(s), {} warning(s)",
+            self.error_count(),
+            warnings
+        )
+    }
+}
+
+/// Runs every offline check against `config`.
+pub fn validate_config(config: &AppConfig) -> ConfigValidationReport {
+    let mut report = ConfigValidationReport::default();
+
+    if config.duration_cache_time_slots.is_empty() {
+        report.error(
+            "duration_cache_time_slots",
+            "must not be empty; the route refresh task never schedules a refresh",
+        );
+    }
+
+    for (path, value) in [
+        ("bucket_size", config.bucket_size),
+        ("nearby_bucket_threshold", config.nearby_bucket_threshold),
+        ("drainer_size", config.drainer_size as u64),
+        ("batch_size", config.batch_size.max(0) as u64),
+    ] {
+        if value == 0 {
+            report.error(path, "must be greater than 0");
+        }
+    }
+
+    if !(config.pickup_notification_threshold <= config.arriving_notification_threshold
+        && config.arriving_notification_threshold
+            <= config.pickup_instruction_notification_threshold)
+    {
+        report.warning(
+            "pickup_notification_threshold",
+            format!(
+                "expected pickup ({}) <= arriving ({}) <= pickup_instruction ({}); \
+                 some ride notifications can never fire",
+                config.pickup_notification_threshold,
+                config.arriving_notification_threshold,
+                config.pickup_instruction_notification_threshold
+            ),
+        );
+    }
+
+    if config
+        .driver_availability_topic
+        .as_ref()
+        .is_some_and(|topic| topic.is_empty())
+    {
+        report.error(
+            "driver_availability_topic",
+            "must not be empty; leave it unset to disable availability tracking",
+        );
+    }
+
+    if let Some(driver_session) = config.driver_session.as_ref() {
+        if !(1..86400).contains(&driver_session.ping_gap_threshold_secs) {
+            report.error(
+                "driver_session.ping_gap_threshold_secs",
+                "must be between 1 and 86399 seconds",
+            );
+        }
+        if driver_session.utc_offset_minutes.abs() >= 24 * 60 {
+            report.error(
+                "driver_session.utc_offset_minutes",
+                "must be less than a day from UTC",
+            );
+        }
+        if driver_session.retention_days == 0 {
+            report.error("driver_session.retention_days", "must be greater than 0");
+        }
+        if driver_session.day_close_topic.is_empty() {
+            report.error("driver_session.day_close_topic", "must not be empty");
+        }
+    }
+
+    validate_detection_configs(
+        "detection_violation_config",
+        &config.detection_violation_config,
+        &mut report,
+    );
+    validate_detection_configs(
+        "detection_anti_violation_config",
+        &config.detection_anti_violation_config,
+        &mut report,
+    );
+    validate_detection_pairs(
+        &config.detection_violation_config,
+        &config.detection_anti_violation_config,
+        &mut report,
+    );
+
+    validate_rider_detection_configs(config, &mut report);
+
+    for (vehicle_type, ride_proximity_config) in &config.ride_proximity_check {
+        if ride_proximity_config.sample_size == 0 {
+            report.error(
+                format!("ride_proximity_check.{vehicle_type}.sample_size"),
+                "must be greater than 0",
+            );
+        }
+    }
+
+    let scheduler = &config.broadcast_trace_scheduler;
+    if scheduler.stale_after_secs > scheduler.signal_lost_after_secs {
+        report.error(
+            "broadcast_trace_scheduler.stale_after_secs",
+            "must not be greater than signal_lost_after_secs",
+        );
+    }
+
+    if let Some(drainer_stream) = &config.drainer_stream {
+        if drainer_stream.partitions == 0 {
+            report.error("drainer_stream.partitions", "must be greater than 0");
+        }
+        if drainer_stream.read_count == 0 {
+            report.error("drainer_stream.read_count", "must be greater than 0");
+        }
+        if drainer_stream.consumer_group.is_empty() {
+            report.error("drainer_stream.consumer_group", "must not be empty");
+        }
+        match drainer_stream.pods {
+            Some(0) => report.error("drainer_stream.pods", "must be greater than 0"),
+            Some(pods) if pods > drainer_stream.partitions => report.warning(
+                "drainer_stream.pods",
+                "more pods than partitions; the extra pods drain nothing",
+            ),
+            _ => {}
+        }
+        if config.drainer_wal_path.is_some() {
+            report.warning(
+                "drainer_wal_path",
+                "unused while drainer_stream is set; the stream is the durable buffer",
+            );
+        }
+    }
+
+    if let Some(ratio) = config.drainer_backpressure.shed_bucketing_ratio {
+        if !(ratio > 0.0 && ratio <= 1.0) {
+            report.error(
+                "drainer_backpressure.shed_bucketing_ratio",
+                "must be in (0, 1]",
+            );
+        }
+    }
+
+    for (vendor, vendor_config) in &config.external_gps_vendors {
+        // The legacy `/external/gps/location` route is matched first.
+        if vendor == "location" {
+            report.error(
+                format!("external_gps_vendors.{vendor}"),
+                "vendor name is shadowed by /external/gps/location",
+            );
+        }
+        if vendor_config.api_key.is_empty() {
+            report.error(
+                format!("external_gps_vendors.{vendor}.api_key"),
+                "must not be empty",
+            );
+        }
+        if let GpsTimestampFormat::Local {
+            utc_offset_minutes, ..
+        } = vendor_config.timestamp_format
+        {
+            if utc_offset_minutes.abs() >= 24 * 60 {
+                report.error(
+                    format!("external_gps_vendors.{vendor}.timestamp_format.utc_offset_minutes"),
+                    "must be within a day of UTC",
+                );
+            }
+        }
+    }
+
+    validate_redis_partitions(config, &mut report);
+
+    for (path, url) in callback_urls(config) {
+        if let Err(err) = url {
+            report.error(path, err);
+        }
+    }
+
+    report
+}
+
+/// Same as `validate_config`, plus a reachability probe of every distinct
+/// callback origin. Any HTTP response counts as reachable; only connection
+/// failures and timeouts are reported.
+pub async fn validate_config_with_url_checks(
+    config: &AppConfig,
+    timeout: Duration,
+) -> ConfigValidationReport {
+    let mut report = validate_config(config);
+
+    let client = match reqwest::Client::builder().timeout(timeout).build() {
+        Ok(client) => client,
+        Err(err) => {
+            report.error(
+                "callback_urls",
+                format!("failed to build HTTP client: {err}"),
+            );
+            return report;
+        }
+    };
+
+    let mut probed = BTreeSet::new();
+    for (path, url) in callback_urls(config) {
+        let Ok(url) = url else { continue };
+        let origin = url.origin().ascii_serialization();
+        if !probed.insert(origin.to_owned()) {
+            continue;
+        }
+        if let Err(err) = client.head(url.as_str()).send().await {
+            report.error(path, format!("{origin} is unreachable: {err}"));
+        }
+    }
+
+    report
+}
+
+fn detection_config_kind(
+    detection_config: &DetectionConfig,
+) -> (DetectionType, Option<(u32, u32)>) {
+    match detection_config {
+        DetectionConfig::StoppedDetection(cfg) => (
+            DetectionType::Stopped,
+            Some((cfg.sample_size, cfg.batch_count)),
+        ),
+        DetectionConfig::OverspeedingDetection(cfg) => (
+            DetectionType::Overspeeding,
+            Some((cfg.sample_size, cfg.batch_count)),
+        ),
+        DetectionConfig::RouteDeviationDetection(cfg) => (
+            DetectionType::RouteDeviation,
+            Some((cfg.sample_size, cfg.batch_count)),
+        ),
+        DetectionConfig::OppositeDirectionDetection(_) => (DetectionType::OppositeDirection, None),
+        DetectionConfig::TripNotStartedDetection(cfg) => (
+            DetectionType::TripNotStarted,
+            Some((cfg.sample_size, cfg.batch_count)),
+        ),
+        DetectionConfig::SafetyCheckDetection(cfg) => (
+            DetectionType::SafetyCheck,
+            Some((cfg.sample_size, cfg.batch_count)),
+        ),
+        DetectionConfig::RideStopReachedDetection(cfg) => (
+            DetectionType::RideStopReached,
+            Some((cfg.sample_size, cfg.batch_count)),
+        ),
+        DetectionConfig::SuddenJumpDetection(_) => (DetectionType::SuddenJump, None),
+        DetectionConfig::DriverDivergenceDetection(cfg) => {
+            (DetectionType::DriverDivergence, Some((cfg.sample_size, 1)))
+        }
+    }
+}
+
+fn validate_detection_configs(
+    name: &str,
+    configs: &DetectionConfigMap,
+    report: &mut ConfigValidationReport,
+) {
+    for (vehicle_type, ride_status_map) in configs {
+        for (ride_status, detection_map) in ride_status_map {
+            for (detection_type, violation_config) in detection_map {
+                validate_detection_entry(
+                    format!("{name}.{vehicle_type}.{ride_status}.{detection_type:?}"),
+                    detection_type,
+                    violation_config,
+                    report,
+                );
+            }
+        }
+    }
+}
+
+fn validate_detection_entry(
+    path: String,
+    detection_type: &DetectionType,
+    violation_config: &ViolationDetectionConfig,
+    report: &mut ConfigValidationReport,
+) {
+    let (config_type, sample_and_batch) = detection_config_kind(&violation_config.detection_config);
+    if config_type != *detection_type {
+        report.error(
+            &path,
+            format!("detection_config is for {config_type:?}, not {detection_type:?}"),
+        );
+    }
+    if let Some((sample_size, batch_count)) = sample_and_batch {
+        if batch_count == 0 {
+            report.error(&path, "batch_count must be greater than 0");
+        } else if sample_size < batch_count {
+            report.error(
+                &path,
+                format!("sample_size ({sample_size}) is smaller than batch_count ({batch_count})"),
+            );
+        }
+    }
+}
+
+/// Rider detections are keyed by detection type only, but follow the same
+/// pairing rule as the driver ones.
+fn validate_rider_detection_configs(config: &AppConfig, report: &mut ConfigValidationReport) {
+    for (name, configs, counterpart, counterpart_name) in [
+        (
+            "rider_detection_violation_config",
+            &config.rider_detection_violation_config,
+            &config.rider_detection_anti_violation_config,
+            "rider_detection_anti_violation_config",
+        ),
+        (
+            "rider_detection_anti_violation_config",
+            &config.rider_detection_anti_violation_config,
+            &config.rider_detection_violation_config,
+            "rider_detection_violation_config",
+        ),
+    ] {
+        for (detection_type, violation_config) in configs {
+            let path = format!("{name}.{detection_type:?}");
+            if !counterpart.contains_key(detection_type) {
+                report.error(
+                    &path,
+                    format!("no matching {counterpart_name} entry; detection is silently skipped"),
+                );
+            }
+            validate_detection_entry(path, detection_type, violation_config, report);
+        }
+    }
+}
+
+/// `check` only runs when both a violation and an anti-violation entry exist
+/// for the same (vehicle, ride status, detection type); a lone entry is dead config.
+fn validate_detection_pairs(
+    violation: &DetectionConfigMap,
+    anti_violation: &DetectionConfigMap,
+    report: &mut ConfigValidationReport,
+) {
+    let mut compare = |from: &DetectionConfigMap, to: &DetectionConfigMap, from_name, to_name| {
+        for (vehicle_type, ride_status_map) in from {
+            for (ride_status, detection_map) in ride_status_map {
+                for detection_type in detection_map.keys() {
+                    let has_pair = to
+                        .get(vehicle_type)
+                        .and_then(|ride_status_map| ride_status_map.get(ride_status))
+                        .is_some_and(|detection_map| detection_map.contains_key(detection_type));
+                    if !has_pair {
+                        report.error(
+                            format!("{from_name}.{vehicle_type}.{ride_status}.{detection_type:?}"),
+                            format!("no matching {to_name} entry; detection is silently skipped"),
+                        );
+                    }
+                }
+            }
+        }
+    };
+    compare(
+        violation,
+        anti_violation,
+        "detection_violation_config",
+        "detection_anti_violation_config",
+    );
+    compare(
+        anti_violation,
+        violation,
+        "detection_anti_violation_config",
+        "detection_violation_config",
+    );
+}
+
+fn validate_redis_partitions(config: &AppConfig, report: &mut ConfigValidationReport) {
+    let primary: &RedisConfig = &config.redis_cfg;
+    if let Some(replica) = config.replica_redis_cfg.as_ref() {
+        if replica.redis_partition != primary.redis_partition {
+            report.error(
+                "replica_redis_cfg.redis_partition",
+                format!(
+                    "replica partition {} does not match primary partition {}; reads would miss writes",
+                    replica.redis_partition, primary.redis_partition
+                ),
+            );
+        }
+    }
+    if let Some(secondary) = config.secondary_redis_cfg.as_ref() {
+        if secondary.redis_partition != primary.redis_partition {
+            report.warning(
+                "secondary_redis_cfg.redis_partition",
+                format!(
+                    "secondary partition {} differs from primary partition {}",
+                    secondary.redis_partition, primary.redis_partition
+                ),
+            );
+        }
+    }
+
+    let mut routed_cities = BTreeSet::new();
+    for (index, cluster) in config.city_geo_redis_clusters.iter().enumerate() {
+        let path = format!("city_geo_redis_clusters[{index}]");
+        if cluster.cities.is_empty() {
+            report.warning(
+                format!("{path}.cities"),
+                "no city is routed to this cluster",
+            );
+        }
+        for CityName(city) in &cluster.cities {
+            if !routed_cities.insert(city.as_str()) {
+                report.error(
+                    format!("{path}.cities"),
+                    format!("{city} is routed to more than one cluster"),
+                );
+            }
+        }
+        if let Some(replica) = cluster.replica_redis_cfg.as_ref() {
+            if replica.redis_partition != cluster.redis_cfg.redis_partition {
+                report.error(
+                    format!("{path}.replica_redis_cfg.redis_partition"),
+                    "replica partition does not match the cluster partition; reads would miss writes",
+                );
+            }
+        }
+    }
+
+    if let Some(migration) = config.redis_migration.as_ref() {
+        if config.secondary_redis_cfg.is_none() {
+            report.error(
+                "redis_migration",
+                "requires secondary_redis_cfg as the migration target",
+            );
+        }
+        for (path, value) in [
+            (
+                "redis_migration.mismatch_log_every",
+                migration.mismatch_log_every,
+            ),
+            (
+                "redis_migration.phase_refresh_interval_secs",
+                migration.phase_refresh_interval_secs,
+            ),
+        ] {
+            if value == 0 {
+                report.error(path, "must be greater than 0");
+            }
+        }
+    }
+}
+
+/// Every outbound URL in the config, parsed. String-typed URLs are otherwise
+/// only parsed (with `expect`) when `AppState` is built.
+fn callback_urls(config: &AppConfig) -> Vec<(String, Result<Url, String>)> {
+    let parse = |url: &str| -> Result<Url, String> {
+        let url = Url::parse(url).map_err(|err| format!("invalid URL {url:?}: {err}"))?;
+        match url.scheme() {
+            "http" | "https" if url.has_host() => Ok(url),
+            _ => Err(format!("{url} must be an http(s) URL with a host")),
+        }
+    };
+
+    let mut urls = vec![
+        ("auth_url".to_string(), parse(&config.auth_url)),
+        (
+            "bulk_location_callback_url".to_string(),
+            parse(&config.bulk_location_callback_url),
+        ),
+        (
+            "trigger_fcm_callback_url".to_string(),
+            parse(&config.trigger_fcm_callback_url),
+        ),
+        (
+            "trigger_fcm_callback_url_bap".to_string(),
+            parse(&config.trigger_fcm_callback_url_bap),
+        ),
+        ("apns_url".to_string(), parse(&config.apns_url)),
+        ("rider_auth_url".to_string(), parse(&config.rider_auth_url)),
+        (
+            "driver_reached_destination_callback_url".to_string(),
+            parse(config.driver_reached_destination_callback_url.as_str()),
+        ),
+        (
+            "driver_source_departed_callback_url".to_string(),
+            parse(config.driver_source_departed_callback_url.as_str()),
+        ),
+        (
+            "osrm_distance_matrix_base_url".to_string(),
+            parse(config.osrm_distance_matrix_base_url.as_str()),
+        ),
+    ];
+    if let Some(url) = config.special_location_list_base_url.as_ref() {
+        urls.push(("special_location_list_base_url".to_string(), parse(url)));
+    }
+    for (vehicle_type, ride_status_map) in &config.stop_detection {
+        for (ride_status, stop_detection_config) in ride_status_map {
+            urls.push((
+                format!("stop_detection.{vehicle_type}.{ride_status}.stop_detection_update_callback_url"),
+                parse(stop_detection_config.stop_detection_update_callback_url.as_str()),
+            ));
+        }
+    }
+    for (name, configs) in [
+        (
+            "detection_violation_config",
+            &config.detection_violation_config,
+        ),
+        (
+            "detection_anti_violation_config",
+            &config.detection_anti_violation_config,
+        ),
+    ] {
+        for (vehicle_type, ride_status_map) in configs {
+            for (ride_status, detection_map) in ride_status_map {
+                for (detection_type, violation_config) in detection_map {
+                    urls.push((
+                        format!(
+                            "{name}.{vehicle_type}.{ride_status}.{detection_type:?}.detection_callback_url"
+                        ),
+                        parse(violation_config.detection_callback_url.as_str()),
+                    ));
+                }
+            }
+        }
+    }
+    for (vehicle_type, ride_proximity_config) in &config.ride_proximity_check {
+        urls.push((
+            format!("ride_proximity_check.{vehicle_type}.ride_proximity_callback_url"),
+            parse(ride_proximity_config.ride_proximity_callback_url.as_str()),
+        ));
+    }
+    for (name, configs) in [
+        (
+            "rider_detection_violation_config",
+            &config.rider_detection_violation_config,
+        ),
+        (
+            "rider_detection_anti_violation_config",
+            &config.rider_detection_anti_violation_config,
+        ),
+    ] {
+        for (detection_type, violation_config) in configs {
+            urls.push((
+                format!("{name}.{detection_type:?}.detection_callback_url"),
+                parse(violation_config.detection_callback_url.as_str()),
+            ));
+        }
+    }
+    urls
+}
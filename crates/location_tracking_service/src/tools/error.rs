@@ -52,7 +52,9 @@ pub enum AppError {
     MissingApiKey,
     InvalidGPSData(String),
     VehicleNotInActiveTrip(String),
+    UnknownGpsVendor(String),
     TraceTokenExpired,
+    TraceDeliveryFailed(u16),
     RiderAuthFailed,
     RiderLocationNotFound,
 }
@@ -99,6 +101,12 @@ impl AppError {
             AppError::AlertRequestFailed(reason) => {
                 format!("Sending Violation Alert Failed : {reason}")
             }
+            AppError::UnknownGpsVendor(vendor) => {
+                format!("Unknown GPS vendor : {vendor}")
+            }
+            AppError::TraceDeliveryFailed(status) => {
+                format!("Broadcast trace HTTP {status}")
+            }
             AppError::RiderAuthFailed => "Rider authentication failed".to_string(),
             AppError::RiderLocationNotFound => "Rider location not found".to_string(),
             _ => "Some Error Occured".to_string(),
@@ -139,7 +147,9 @@ impl AppError {
             AppError::MissingApiKey => "MISSING_API_KEY",
             AppError::InvalidGPSData(_) => "INVALID_GPS_DATA",
             AppError::VehicleNotInActiveTrip(_) => "VEHICLE_NOT_IN_ACTIVE_TRIP",
+            AppError::UnknownGpsVendor(_) => "UNKNOWN_GPS_VENDOR",
             AppError::TraceTokenExpired => "TRACE_TOKEN_EXPIRED",
+            AppError::TraceDeliveryFailed(_) => "TRACE_DELIVERY_FAILED",
             AppError::RiderAuthFailed => "RIDER_AUTH_FAILED",
             AppError::RiderLocationNotFound => "RIDER_LOCATION_NOT_FOUND",
         }
@@ -186,7 +196,9 @@ impl ResponseError for AppError {
             AppError::MissingApiKey => StatusCode::BAD_REQUEST,
             AppError::InvalidGPSData(_) => StatusCode::UNPROCESSABLE_ENTITY,
             AppError::VehicleNotInActiveTrip(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::UnknownGpsVendor(_) => StatusCode::NOT_FOUND,
             AppError::TraceTokenExpired => StatusCode::UNAUTHORIZED,
+            AppError::TraceDeliveryFailed(_) => StatusCode::BAD_GATEWAY,
             AppError::RiderAuthFailed => StatusCode::UNAUTHORIZED,
             AppError::RiderLocationNotFound => StatusCode::NOT_FOUND,
         }
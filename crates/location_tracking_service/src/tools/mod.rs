@@ -6,5 +6,7 @@
     the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
+pub mod config_validation;
 pub mod error;
 pub mod prometheus;
+pub mod replay;
@@ -10,7 +10,8 @@
 use actix_web_prom::PrometheusMetrics;
 use prometheus::{
     histogram_opts, opts, register_histogram, register_histogram_vec, register_int_counter,
-    register_int_counter_vec, Histogram, HistogramVec, IntCounter, IntCounterVec,
+    register_int_counter_vec, register_int_gauge, Histogram, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge,
 };
 pub use shared::tools::prometheus::*;
 
@@ -29,6 +30,31 @@ pub static TOTAL_LOCATION_UPDATES: once_cell::sync::Lazy<IntCounter> =
             .expect("Failed to register total location updates metrics")
     });
 
+/// Entries waiting in the in-process drainer channel, sampled on every send and
+/// on every drainer tick.
+pub static DRAINER_CHANNEL_OCCUPANCY: once_cell::sync::Lazy<IntGauge> =
+    once_cell::sync::Lazy::new(|| {
+        register_int_gauge!(
+            "drainer_channel_occupancy",
+            "Entries waiting in the drainer channel"
+        )
+        .expect("Failed to register drainer channel occupancy metrics")
+    });
+
+/// Backpressure actions taken for the in-process drainer, by action
+/// (`waited` | `coalesced` | `dropped` | `bucketing_shed`).
+pub static DRAINER_BACKPRESSURE: once_cell::sync::Lazy<IntCounterVec> =
+    once_cell::sync::Lazy::new(|| {
+        register_int_counter_vec!(
+            opts!(
+                "drainer_backpressure_total",
+                "Drainer entries affected by backpressure, by action"
+            ),
+            &["action"]
+        )
+        .expect("Failed to register drainer backpressure metrics")
+    });
+
 pub static GPS_UPDATES_IGNORED_NO_ACTIVE_RIDE: once_cell::sync::Lazy<IntCounter> =
     once_cell::sync::Lazy::new(|| {
         register_int_counter!(
@@ -67,6 +93,149 @@ pub static QUEUE_EVICTIONS: once_cell::sync::Lazy<IntCounterVec> = once_cell::sy
     },
 );
 
+/// Rider/driver proximity events raised for in-progress rides, labelled by
+/// `RideProximityEvent` variant.
+pub static RIDE_PROXIMITY_EVENTS: once_cell::sync::Lazy<IntCounterVec> =
+    once_cell::sync::Lazy::new(|| {
+        register_int_counter_vec!(
+            opts!(
+                "ride_proximity_events_total",
+                "Rider/driver proximity events raised for in-progress rides, by event"
+            ),
+            &["event"]
+        )
+        .expect("Failed to register ride proximity events metrics")
+    });
+
+/// Broadcast trace `send_ping` attempts, by provider (`erss` | `webhook` | `mqtt`)
+/// and outcome (`delivered` | `token_expired` | `failed`). Retries after a token
+/// refresh count as separate attempts.
+pub static BROADCAST_TRACE_ATTEMPTS: once_cell::sync::Lazy<IntCounterVec> =
+    once_cell::sync::Lazy::new(|| {
+        register_int_counter_vec!(
+            opts!(
+                "broadcast_trace_attempts_total",
+                "Broadcast trace pings sent to external providers, by provider and outcome"
+            ),
+            &["provider", "outcome"]
+        )
+        .expect("Failed to register broadcast trace attempts metrics")
+    });
+
+/// Drainer stream entries, by outcome (`published` | `publish_failed` |
+/// `drained` | `drain_failed`). Failed drains stay pending and are reclaimed.
+pub static DRAINER_STREAM_ENTRIES: once_cell::sync::Lazy<IntCounterVec> =
+    once_cell::sync::Lazy::new(|| {
+        register_int_counter_vec!(
+            opts!(
+                "drainer_stream_entries_total",
+                "Driver locations passed through the drainer streams, by outcome"
+            ),
+            &["outcome"]
+        )
+        .expect("Failed to register drainer stream entries metrics")
+    });
+
+/// Drainer entries folded into a later one for the same driver within a drain,
+/// by kind (`location` | `special_location` | `queue_enter`).
+pub static DRAINER_COALESCED_ENTRIES: once_cell::sync::Lazy<IntCounterVec> =
+    once_cell::sync::Lazy::new(|| {
+        register_int_counter_vec!(
+            opts!(
+                "drainer_coalesced_entries_total",
+                "Drainer entries coalesced into a later entry of the same driver, by kind"
+            ),
+            &["kind"]
+        )
+        .expect("Failed to register drainer coalesced entries metrics")
+    });
+
+/// Drainer WAL appends refused because the file reached its size cap or its
+/// writer stopped. The refused entries are still drained, just not durable.
+pub static DRAINER_WAL_REFUSED_APPENDS: once_cell::sync::Lazy<IntCounter> =
+    once_cell::sync::Lazy::new(|| {
+        register_int_counter!(
+            "drainer_wal_refused_appends_total",
+            "Drainer entries not appended to the write-ahead log"
+        )
+        .expect("Failed to register drainer WAL refused appends metrics")
+    });
+
+/// Shadow reads during a Redis migration, by op and outcome
+/// (`match` | `mismatch` | `error`).
+pub static REDIS_MIGRATION_SHADOW_READS: once_cell::sync::Lazy<IntCounterVec> =
+    once_cell::sync::Lazy::new(|| {
+        register_int_counter_vec!(
+            opts!(
+                "redis_migration_shadow_reads_total",
+                "Reads repeated on the migration target, by op and outcome"
+            ),
+            &["op", "outcome"]
+        )
+        .expect("Failed to register redis migration shadow reads metrics")
+    });
+
+/// Writes mirrored during a Redis migration, by op and outcome (`ok` | `failed`).
+pub static REDIS_MIGRATION_MIRROR_WRITES: once_cell::sync::Lazy<IntCounterVec> =
+    once_cell::sync::Lazy::new(|| {
+        register_int_counter_vec!(
+            opts!(
+                "redis_migration_mirror_writes_total",
+                "Writes mirrored to the other cluster during a migration, by op and outcome"
+            ),
+            &["op", "outcome"]
+        )
+        .expect("Failed to register redis migration mirror writes metrics")
+    });
+
+pub static DRIVER_DETAILS_DECODED: once_cell::sync::Lazy<IntCounterVec> =
+    once_cell::sync::Lazy::new(|| {
+        register_int_counter_vec!(
+            opts!(
+                "driver_details_decoded_total",
+                "Driver details read from Redis, by stored encoding"
+            ),
+            &["encoding"]
+        )
+        .expect("Failed to register driver details decoded metrics")
+    });
+
+pub static DRIVER_AVAILABILITY_TRANSITIONS: once_cell::sync::Lazy<IntCounterVec> =
+    once_cell::sync::Lazy::new(|| {
+        register_int_counter_vec!(
+            opts!(
+                "driver_availability_transitions_total",
+                "Driver availability transitions, by previous state, new state and validity"
+            ),
+            &["from", "to", "valid"]
+        )
+        .expect("Failed to register driver availability transitions metrics")
+    });
+
+pub static DRIVER_SESSION_DAY_SUMMARIES: once_cell::sync::Lazy<IntCounterVec> =
+    once_cell::sync::Lazy::new(|| {
+        register_int_counter_vec!(
+            opts!(
+                "driver_session_day_summaries_total",
+                "Driver day summaries produced at day close, by outcome"
+            ),
+            &["outcome"]
+        )
+        .expect("Failed to register driver session day summaries metrics")
+    });
+
+/// Counter of on-ride points that arrived after a newer point had already been
+/// processed (offline backfills, retried batches). They are appended to the
+/// ride trace only and never move the live location.
+pub static LATE_LOCATION_UPDATES: once_cell::sync::Lazy<IntCounter> =
+    once_cell::sync::Lazy::new(|| {
+        register_int_counter!(
+            "late_location_updates",
+            "On-ride location points received after newer points"
+        )
+        .expect("Failed to register late location updates metrics")
+    });
+
 /// Histogram of the number of drivers returned per `GET /internal/drivers/nearby`
 /// request.
 ///
@@ -161,5 +330,70 @@ pub fn prometheus_metrics() -> PrometheusMetrics {
         .register(Box::new(NEARBY_DRIVERS_RETURNED.to_owned()))
         .expect("Failed to register nearby drivers returned metrics");
 
+    prometheus
+        .registry
+        .register(Box::new(LATE_LOCATION_UPDATES.to_owned()))
+        .expect("Failed to register late location updates metrics");
+
+    prometheus
+        .registry
+        .register(Box::new(RIDE_PROXIMITY_EVENTS.to_owned()))
+        .expect("Failed to register ride proximity events metrics");
+
+    prometheus
+        .registry
+        .register(Box::new(BROADCAST_TRACE_ATTEMPTS.to_owned()))
+        .expect("Failed to register broadcast trace attempts metrics");
+
+    prometheus
+        .registry
+        .register(Box::new(DRAINER_STREAM_ENTRIES.to_owned()))
+        .expect("Failed to register drainer stream entries metrics");
+
+    prometheus
+        .registry
+        .register(Box::new(DRAINER_CHANNEL_OCCUPANCY.to_owned()))
+        .expect("Failed to register drainer channel occupancy metrics");
+
+    prometheus
+        .registry
+        .register(Box::new(DRAINER_COALESCED_ENTRIES.to_owned()))
+        .expect("Failed to register drainer coalesced entries metrics");
+
+    prometheus
+        .registry
+        .register(Box::new(DRAINER_WAL_REFUSED_APPENDS.to_owned()))
+        .expect("Failed to register drainer WAL refused appends metrics");
+
+    prometheus
+        .registry
+        .register(Box::new(REDIS_MIGRATION_SHADOW_READS.to_owned()))
+        .expect("Failed to register redis migration shadow reads metrics");
+
+    prometheus
+        .registry
+        .register(Box::new(REDIS_MIGRATION_MIRROR_WRITES.to_owned()))
+        .expect("Failed to register redis migration mirror writes metrics");
+
+    prometheus
+        .registry
+        .register(Box::new(DRAINER_BACKPRESSURE.to_owned()))
+        .expect("Failed to register drainer backpressure metrics");
+
+    prometheus
+        .registry
+        .register(Box::new(DRIVER_DETAILS_DECODED.to_owned()))
+        .expect("Failed to register driver details decoded metrics");
+
+    prometheus
+        .registry
+        .register(Box::new(DRIVER_AVAILABILITY_TRANSITIONS.to_owned()))
+        .expect("Failed to register driver availability transitions metrics");
+
+    prometheus
+        .registry
+        .register(Box::new(DRIVER_SESSION_DAY_SUMMARIES.to_owned()))
+        .expect("Failed to register driver session day summaries metrics");
+
     prometheus
 }
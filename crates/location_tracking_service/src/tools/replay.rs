@@ -0,0 +1,548 @@
+/*  Copyright 2022-23, Juspay India Pvt Ltd
+    This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License
+    as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version. This program
+    is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY
+    or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details. You should have received a copy of
+    the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Offline replay of recorded location traffic through the real update
+//! pipeline (`update_driver_location` → `process_driver_locations` → drainer).
+//!
+//! Every outbound callback is routed to an in-process HTTP sink and every Kafka
+//! message to a `KafkaSink`, so a replay only needs a local Redis. The report
+//! lists what the pipeline emitted: detection alerts, ride notifications, stop
+//! and destination events, Kafka location updates and queue rank-history events.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use actix_web::{
+    dev::ServerHandle,
+    web::{self, Data},
+    App, HttpRequest, HttpResponse, HttpServer,
+};
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use rustc_hash::FxHashSet;
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::Instant;
+use tracing::{error, info};
+
+use crate::common::{
+    kafka::{KafkaSink, KafkaSinkRecord},
+    types::*,
+};
+use crate::domain::action::{internal::ride, ui::location::update_driver_location};
+use crate::domain::types::{
+    internal::ride::{RideCreateRequest, RideEndRequest, RideStartRequest},
+    ui::location::UpdateDriverLocationRequest,
+};
+use crate::drainer::run_drainer;
+use crate::environment::{AppConfig, AppState};
+use crate::outbound::types::SpecialLocationFull;
+use crate::redis::commands::get_driver_queue_rank_history;
+use crate::special_location::build_special_location_cache;
+use crate::tools::error::AppError;
+
+/// One line of a capture file.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ReplayEvent {
+    #[serde(rename_all = "camelCase")]
+    Locations {
+        driver_id: DriverId,
+        merchant_id: MerchantId,
+        vehicle_type: VehicleType,
+        driver_mode: DriverMode,
+        group_id: Option<String>,
+        #[serde(default)]
+        location_source: LocationSource,
+        captured_at: Option<TimeStamp>,
+        locations: Vec<UpdateDriverLocationRequest>,
+    },
+    #[serde(rename_all = "camelCase")]
+    RideCreate {
+        ride_id: RideId,
+        captured_at: Option<TimeStamp>,
+        request: RideCreateRequest,
+    },
+    #[serde(rename_all = "camelCase")]
+    RideStart {
+        ride_id: RideId,
+        captured_at: Option<TimeStamp>,
+        request: RideStartRequest,
+    },
+    #[serde(rename_all = "camelCase")]
+    RideEnd {
+        ride_id: RideId,
+        captured_at: Option<TimeStamp>,
+        request: RideEndRequest,
+    },
+}
+
+impl ReplayEvent {
+    /// When the event happened in the capture. Location batches without an
+    /// explicit `capturedAt` use their latest ping.
+    fn captured_at(&self) -> Option<TimeStamp> {
+        match self {
+            ReplayEvent::Locations {
+                captured_at,
+                locations,
+                ..
+            } => captured_at.or_else(|| locations.iter().map(|location| location.ts).max()),
+            ReplayEvent::RideCreate { captured_at, .. }
+            | ReplayEvent::RideStart { captured_at, .. }
+            | ReplayEvent::RideEnd { captured_at, .. } => *captured_at,
+        }
+    }
+}
+
+/// How recorded ping timestamps are rewritten before they are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum TimeWarp {
+    /// Send the recorded timestamps unchanged.
+    None,
+    /// Move the whole capture so it starts now, keeping the gaps between
+    /// pings intact. Detection speeds and durations match the recording.
+    Shift,
+    /// Move the capture to start now and compress the gaps by the replay
+    /// speed, so ping timestamps keep pace with the wall clock.
+    Scale,
+}
+
+impl TimeWarp {
+    pub fn apply(
+        &self,
+        TimeStamp(ts): TimeStamp,
+        capture_start: DateTime<Utc>,
+        replay_start: DateTime<Utc>,
+        speed: f64,
+    ) -> TimeStamp {
+        match self {
+            TimeWarp::None => TimeStamp(ts),
+            TimeWarp::Shift => TimeStamp(replay_start + (ts - capture_start)),
+            TimeWarp::Scale if speed > 0.0 => TimeStamp(
+                replay_start
+                    + chrono::Duration::milliseconds(
+                        ((ts - capture_start).num_milliseconds() as f64 / speed) as i64,
+                    ),
+            ),
+            TimeWarp::Scale => TimeStamp(replay_start + (ts - capture_start)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    pub capture_path: String,
+    /// Playback speed relative to the capture; `0` replays as fast as possible.
+    pub speed: f64,
+    pub time_warp: TimeWarp,
+    /// JSON list in the special location API format, used to replay queue behaviour.
+    pub special_locations_path: Option<String>,
+    /// How long to wait after the last event for spawned callbacks and the drainer.
+    pub settle: Duration,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CallbackRecord {
+    pub kind: String,
+    pub path: String,
+    pub body: serde_json::Value,
+    pub received_at: TimeStamp,
+}
+
+pub type CallbackSink = Arc<Mutex<Vec<CallbackRecord>>>;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueActionRecord {
+    pub merchant_id: MerchantId,
+    pub driver_id: DriverId,
+    pub ts: f64,
+    pub event: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayFailure {
+    pub line: usize,
+    pub error: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayReport {
+    pub events: usize,
+    pub failures: Vec<ReplayFailure>,
+    pub callbacks: Vec<CallbackRecord>,
+    pub kafka: Vec<KafkaSinkRecord>,
+    pub queue_actions: Vec<QueueActionRecord>,
+}
+
+impl ReplayReport {
+    /// One line per callback kind, e.g. `detection: 3`.
+    pub fn summary(&self) -> String {
+        let mut counts: Vec<(String, usize)> = Vec::new();
+        for callback in &self.callbacks {
+            match counts.iter_mut().find(|(kind, _)| *kind == callback.kind) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((callback.kind.to_owned(), 1)),
+            }
+        }
+        counts.sort();
+        let mut lines = vec![
+            format!("events: {}", self.events),
+            format!("failures: {}", self.failures.len()),
+        ];
+        lines.extend(
+            counts
+                .into_iter()
+                .map(|(kind, count)| format!("{kind}: {count}")),
+        );
+        lines.push(format!("kafka: {}", self.kafka.len()));
+        lines.push(format!("queue_actions: {}", self.queue_actions.len()));
+        lines.join("\n")
+    }
+}
+
+pub fn read_capture(path: &str) -> Result<Vec<(usize, ReplayEvent)>, AppError> {
+    let file = File::open(path).map_err(|err| AppError::InternalError(err.to_string()))?;
+    let mut events = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| AppError::InternalError(err.to_string()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = serde_json::from_str::<ReplayEvent>(&line).map_err(|err| {
+            AppError::DeserializationError(format!("{path}:{} : {err}", index + 1))
+        })?;
+        events.push((index + 1, event));
+    }
+    Ok(events)
+}
+
+/// Every callback URL is rewritten to `<sink>/<kind>/<original path>`; the
+/// first path segment tells the sink which kind of callback it received.
+async fn record_callback(
+    req: HttpRequest,
+    body: web::Bytes,
+    sink: Data<CallbackSink>,
+) -> HttpResponse {
+    let path = req.path().trim_start_matches('/');
+    let (kind, original_path) = path.split_once('/').unwrap_or((path, ""));
+    // Distance matrix lookups fall back to straight-line distance when OSRM
+    // is unavailable, which keeps replays deterministic.
+    if kind == "osrm" {
+        return HttpResponse::ServiceUnavailable().finish();
+    }
+    sink.lock().await.push(CallbackRecord {
+        kind: kind.to_string(),
+        path: format!("/{original_path}"),
+        body: serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null),
+        received_at: TimeStamp(Utc::now()),
+    });
+    HttpResponse::Ok().json(APISuccess::default())
+}
+
+async fn start_callback_sink(sink: CallbackSink) -> Result<(Url, ServerHandle), AppError> {
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(Data::new(sink.clone()))
+            .default_service(web::to(record_callback))
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .map_err(|err| AppError::InternalError(err.to_string()))?;
+    let addr = server
+        .addrs()
+        .first()
+        .copied()
+        .ok_or_else(|| AppError::InternalError("Callback sink has no address".to_string()))?;
+    let server = server.run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+    let url = Url::parse(&format!("http://{addr}/"))
+        .map_err(|err| AppError::InternalError(err.to_string()))?;
+    Ok((url, handle))
+}
+
+fn sink_url(sink: &Url, kind: &str, original: &str) -> Result<Url, AppError> {
+    let path = Url::parse(original)
+        .map(|url| url.path().to_string())
+        .unwrap_or_default();
+    sink.join(&format!("{kind}{path}"))
+        .map_err(|err| AppError::InternalError(err.to_string()))
+}
+
+/// Points every callback the location pipeline can fire at the sink.
+pub fn route_callbacks_to_sink(config: &mut AppConfig, sink: &Url) -> Result<(), AppError> {
+    config.bulk_location_callback_url =
+        sink_url(sink, "bulk_location", &config.bulk_location_callback_url)?.to_string();
+    config.trigger_fcm_callback_url =
+        sink_url(sink, "driver_fcm", &config.trigger_fcm_callback_url)?.to_string();
+    config.trigger_fcm_callback_url_bap =
+        sink_url(sink, "notification", &config.trigger_fcm_callback_url_bap)?.to_string();
+    config.driver_reached_destination_callback_url = sink_url(
+        sink,
+        "destination_reached",
+        config.driver_reached_destination_callback_url.as_str(),
+    )?;
+    config.driver_source_departed_callback_url = sink_url(
+        sink,
+        "source_departed",
+        config.driver_source_departed_callback_url.as_str(),
+    )?;
+    config.osrm_distance_matrix_base_url =
+        sink_url(sink, "osrm", config.osrm_distance_matrix_base_url.as_str())?;
+    for ride_status_map in config.stop_detection.values_mut() {
+        for stop_detection_config in ride_status_map.values_mut() {
+            stop_detection_config.stop_detection_update_callback_url = sink_url(
+                sink,
+                "stop_detection",
+                stop_detection_config
+                    .stop_detection_update_callback_url
+                    .as_str(),
+            )?;
+        }
+    }
+    for ride_proximity_config in config.ride_proximity_check.values_mut() {
+        ride_proximity_config.ride_proximity_callback_url = sink_url(
+            sink,
+            "ride_proximity",
+            ride_proximity_config.ride_proximity_callback_url.as_str(),
+        )?;
+    }
+    for configs in [
+        &mut config.detection_violation_config,
+        &mut config.detection_anti_violation_config,
+    ] {
+        for ride_status_map in configs.values_mut() {
+            for detection_map in ride_status_map.values_mut() {
+                for violation_config in detection_map.values_mut() {
+                    violation_config.detection_callback_url = sink_url(
+                        sink,
+                        "detection",
+                        violation_config.detection_callback_url.as_str(),
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn run_replay(
+    mut app_config: AppConfig,
+    options: ReplayOptions,
+) -> Result<ReplayReport, AppError> {
+    let events = read_capture(&options.capture_path)?;
+    let special_locations = match options.special_locations_path.as_ref() {
+        Some(path) => {
+            let file = File::open(path).map_err(|err| AppError::InternalError(err.to_string()))?;
+            Some(
+                serde_json::from_reader::<_, Vec<SpecialLocationFull>>(BufReader::new(file))
+                    .map_err(|err| AppError::DeserializationError(err.to_string()))?,
+            )
+        }
+        None => None,
+    };
+
+    let callbacks: CallbackSink = Arc::new(Mutex::new(Vec::new()));
+    let (sink, sink_handle) = start_callback_sink(callbacks.clone()).await?;
+    route_callbacks_to_sink(&mut app_config, &sink)?;
+    // Special locations come from the file instead of the live API, and the
+    // rate limiter would otherwise reject sped-up batches.
+    app_config.special_location_list_base_url = None;
+    app_config.location_update_limit = usize::MAX;
+
+    let (sender, receiver) = mpsc::channel(app_config.drainer_size);
+    let mut app_state = AppState::new(app_config, sender).await;
+    let kafka: KafkaSink = Arc::new(Mutex::new(Vec::new()));
+    app_state.producer = None;
+    app_state.secondary_producer = None;
+    app_state.kafka_sink = Some(kafka.clone());
+    let data = Data::new(app_state);
+
+    let special_location_cache = match special_locations {
+        Some(list) => {
+            let cache = data.special_location_cache.clone();
+            *cache.write().await = build_special_location_cache(list);
+            Some(cache)
+        }
+        None => None,
+    };
+
+    let graceful_termination_requested = Arc::new(AtomicBool::new(false));
+    let drainer = {
+        let data = data.clone();
+        let graceful_termination_requested = graceful_termination_requested.clone();
+        tokio::spawn(async move {
+            run_drainer(
+                receiver,
+                graceful_termination_requested,
+                data.drainer_size,
+                data.drainer_delay,
+                data.bucket_size,
+                data.nearby_bucket_threshold,
+                data.geo_redis.clone(),
+                data.queue_redis(),
+                data.redis_migration.clone(),
+                special_location_cache,
+                data.enable_special_location_bucketing,
+                data.queue_expiry_seconds,
+                data.queue_exit_hysteresis_threshold,
+                data.enable_queue_cache_empty_guard,
+                data.special_location_entry_ts_ttl_sec,
+                // Replays must not pick up or truncate a live pod's WAL.
+                None,
+                data.drainer_overflow.clone(),
+                data.drainer_backpressure.shed_bucketing_ratio,
+            )
+            .await;
+        })
+    };
+
+    let mut report = ReplayReport {
+        events: events.len(),
+        ..Default::default()
+    };
+    let mut drivers: FxHashSet<(MerchantId, DriverId)> = FxHashSet::default();
+
+    let capture_start = events
+        .iter()
+        .filter_map(|(_, event)| event.captured_at())
+        .min()
+        .map(|TimeStamp(ts)| ts)
+        .unwrap_or_else(Utc::now);
+    let replay_start = Utc::now();
+    let replay_start_instant = Instant::now();
+    let mut last_captured_at = capture_start;
+
+    for (line, event) in events {
+        let captured_at = event
+            .captured_at()
+            .map(|TimeStamp(ts)| ts)
+            .unwrap_or(last_captured_at);
+        last_captured_at = captured_at;
+        if options.speed > 0.0 {
+            let offset = (captured_at - capture_start).num_milliseconds().max(0) as f64;
+            tokio::time::sleep_until(
+                replay_start_instant + Duration::from_millis((offset / options.speed) as u64),
+            )
+            .await;
+        }
+
+        let result = match event {
+            ReplayEvent::Locations {
+                driver_id,
+                merchant_id,
+                vehicle_type,
+                driver_mode,
+                group_id,
+                location_source,
+                locations,
+                ..
+            } => {
+                drivers.insert((merchant_id.to_owned(), driver_id.to_owned()));
+                let locations = locations
+                    .into_iter()
+                    .map(|location| UpdateDriverLocationRequest {
+                        ts: options.time_warp.apply(
+                            location.ts,
+                            capture_start,
+                            replay_start,
+                            options.speed,
+                        ),
+                        ..location
+                    })
+                    .collect();
+                update_driver_location(
+                    driver_id,
+                    merchant_id,
+                    vehicle_type,
+                    data.clone(),
+                    locations,
+                    driver_mode,
+                    group_id,
+                    location_source,
+                )
+                .await
+                .map(|_| ())
+            }
+            ReplayEvent::RideCreate {
+                ride_id, request, ..
+            } => ride::ride_create(ride_id, data.clone(), request)
+                .await
+                .map(|_| ()),
+            ReplayEvent::RideStart {
+                ride_id, request, ..
+            } => ride::ride_start(ride_id, data.clone(), request)
+                .await
+                .map(|_| ()),
+            ReplayEvent::RideEnd {
+                ride_id, request, ..
+            } => ride::ride_end(ride_id, data.clone(), request)
+                .await
+                .map(|_| ()),
+        };
+        if let Err(err) = result {
+            error!(tag = "[Replay]", line = %line, "{}", err.message());
+            report.failures.push(ReplayFailure {
+                line,
+                error: format!("{err}: {}", err.message()),
+            });
+        }
+    }
+
+    // Callbacks fire from spawned tasks and queue writes happen on drainer
+    // ticks, so give both a chance to finish before reading the results.
+    tokio::time::sleep(options.settle).await;
+    graceful_termination_requested.store(true, std::sync::atomic::Ordering::Relaxed);
+    if tokio::time::timeout(
+        Duration::from_secs(data.drainer_delay.saturating_mul(2).max(1)),
+        drainer,
+    )
+    .await
+    .is_err()
+    {
+        error!(tag = "[Replay]", "Drainer did not stop in time");
+    }
+
+    for (merchant_id, driver_id) in drivers {
+        let history =
+            get_driver_queue_rank_history(&data.queue_redis(), &merchant_id.0, &driver_id.0)
+                .await?;
+        // Rank history is newest-first; the report is chronological.
+        report
+            .queue_actions
+            .extend(
+                history
+                    .into_iter()
+                    .rev()
+                    .map(|(ts, event)| QueueActionRecord {
+                        merchant_id: merchant_id.to_owned(),
+                        driver_id: driver_id.to_owned(),
+                        ts,
+                        event,
+                    }),
+            );
+    }
+    report.queue_actions.sort_by(|a, b| a.ts.total_cmp(&b.ts));
+
+    report.callbacks = std::mem::take(&mut *callbacks.lock().await);
+    report.kafka = std::mem::take(&mut *kafka.lock().await);
+    sink_handle.stop(true).await;
+
+    info!(tag = "[Replay]", "{}", report.summary().replace('\n', ", "));
+    Ok(report)
+}
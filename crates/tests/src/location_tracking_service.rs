@@ -486,3 +486,1905 @@ fn test_nested_arc_by_creating_new_arc() {
     println!("map_a pointer: {:p}", Arc::as_ptr(&map_a));
     println!("map_b pointer: {:p}", Arc::as_ptr(&map_b)); // Not same as map_a
 }
+
+#[test]
+fn test_config_override_resolution() {
+    use chrono::Utc;
+    use location_tracking_service::common::config_override::*;
+    use location_tracking_service::common::types::{CityName, MerchantId, TimeStamp};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    let entry = |merchant_id: Option<&MerchantId>, city: Option<&CityName>, config| {
+        let scope = ConfigOverrideScope::new(merchant_id.cloned(), city.cloned());
+        (
+            scope.as_field(),
+            ConfigOverrideEntry {
+                scope,
+                version: 1,
+                config,
+                updated_at: TimeStamp(Utc::now()),
+                updated_by: None,
+                reason: None,
+            },
+        )
+    };
+    let merchant_id = MerchantId("merchant".to_string());
+    let city = CityName("Bangalore".to_string());
+
+    let entries = [
+        entry(
+            Some(&merchant_id),
+            Some(&city),
+            ConfigOverride {
+                arriving_notification_threshold: Some(150.0),
+                ..Default::default()
+            },
+        ),
+        entry(
+            Some(&merchant_id),
+            None,
+            ConfigOverride {
+                pickup_notification_threshold: Some(60.0),
+                arriving_notification_threshold: Some(120.0),
+                ..Default::default()
+            },
+        ),
+        entry(
+            None,
+            Some(&city),
+            ConfigOverride {
+                pickup_notification_threshold: Some(80.0),
+                blacklist_merchants: Some(vec![merchant_id.clone()]),
+                detection_violation_config: Some(Arc::new(HashMap::new())),
+                ..Default::default()
+            },
+        ),
+        entry(
+            None,
+            None,
+            ConfigOverride {
+                location_update_limit: Some(100),
+                pickup_notification_threshold: Some(30.0),
+                ..Default::default()
+            },
+        ),
+    ]
+    .into_iter()
+    .collect();
+
+    let resolved = resolve_from_entries(&entries, Some(&merchant_id), Some(&city));
+    assert_eq!(resolved.arriving_notification_threshold, Some(150.0));
+    assert_eq!(resolved.pickup_notification_threshold, Some(60.0));
+    assert_eq!(
+        resolved.blacklist_merchants,
+        Some(vec![merchant_id.clone()])
+    );
+    assert!(Arc::ptr_eq(
+        resolved.detection_violation_config.as_ref().unwrap(),
+        entries[&ConfigOverrideScope::new(None, Some(city.clone())).as_field()]
+            .config
+            .detection_violation_config
+            .as_ref()
+            .unwrap(),
+    ));
+    assert!(resolved.detection_anti_violation_config.is_none());
+    assert_eq!(resolved.location_update_limit, Some(100));
+
+    let city_only = resolve_from_entries(&entries, None, Some(&city));
+    assert_eq!(city_only.pickup_notification_threshold, Some(80.0));
+    assert_eq!(city_only.arriving_notification_threshold, None);
+
+    let other_merchant = MerchantId("other".to_string());
+    let global = resolve_from_entries(&entries, Some(&other_merchant), None);
+    assert_eq!(global.pickup_notification_threshold, Some(30.0));
+}
+
+#[test]
+fn test_config_validation() {
+    use location_tracking_service::common::types::{DetectionType, RideStatus, VehicleType};
+    use location_tracking_service::common::utils::read_dhall_config;
+    use location_tracking_service::tools::config_validation::validate_config;
+
+    let dhall_config_path = "../../dhall-configs/dev/location_tracking_service.dhall".to_string();
+    let mut app_config = read_dhall_config(&dhall_config_path).unwrap_or_else(|err| {
+        println!("Dhall Config Reading Error : {}", err);
+        std::process::exit(1);
+    });
+
+    app_config.duration_cache_time_slots.clear();
+    if let Some(detection_map) = app_config
+        .detection_anti_violation_config
+        .get_mut(&VehicleType::SEDAN)
+        .and_then(|ride_status_map| ride_status_map.get_mut(&RideStatus::INPROGRESS))
+    {
+        detection_map.remove(&DetectionType::Overspeeding);
+    }
+
+    let report = validate_config(&app_config);
+    assert!(report.has_errors());
+    assert!(report
+        .findings
+        .iter()
+        .any(|finding| finding.path == "duration_cache_time_slots"));
+    let missing_pair_path = format!(
+        "detection_violation_config.{}.{}.{:?}",
+        VehicleType::SEDAN,
+        RideStatus::INPROGRESS,
+        DetectionType::Overspeeding
+    );
+    assert!(report
+        .findings
+        .iter()
+        .any(|finding| finding.path == missing_pair_path
+            && finding.message.contains("detection_anti_violation_config")));
+}
+
+#[test]
+fn test_replay_time_warp() {
+    use chrono::{Duration, TimeZone, Utc};
+    use location_tracking_service::common::types::TimeStamp;
+    use location_tracking_service::tools::replay::TimeWarp;
+    use std::str::FromStr;
+
+    let capture_start = Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap();
+    let replay_start = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+    let ping = TimeStamp(capture_start + Duration::seconds(60));
+
+    assert_eq!(
+        TimeWarp::None.apply(ping, capture_start, replay_start, 4.0),
+        ping
+    );
+    assert_eq!(
+        TimeWarp::Shift.apply(ping, capture_start, replay_start, 4.0),
+        TimeStamp(replay_start + Duration::seconds(60))
+    );
+    assert_eq!(
+        TimeWarp::Scale.apply(ping, capture_start, replay_start, 4.0),
+        TimeStamp(replay_start + Duration::seconds(15))
+    );
+    assert_eq!(TimeWarp::from_str("scale").ok(), Some(TimeWarp::Scale));
+}
+
+#[test]
+fn test_rider_driver_divergence_detection() {
+    use chrono::{Duration, Utc};
+    use location_tracking_service::common::detection::check;
+    use location_tracking_service::common::types::*;
+    use location_tracking_service::outbound::types::DetectionData;
+    use reqwest::Url;
+
+    let config = ViolationDetectionConfig {
+        enabled: true,
+        detection_callback_url: Url::parse("http://127.0.0.1:8013/internal/riderSafetyAlert")
+            .unwrap(),
+        detection_config: DetectionConfig::DriverDivergenceDetection(DriverDivergenceConfig {
+            max_distance: 500,
+            sample_size: 2,
+        }),
+    };
+    let driver_location = Point {
+        lat: Latitude(12.9716),
+        lon: Longitude(77.5946),
+    };
+    let start = Utc::now();
+    let context = |secs| DetectionContext {
+        // ~1.1km north of the driver
+        location: Point {
+            lat: Latitude(12.9816),
+            lon: Longitude(77.5946),
+        },
+        timestamp: TimeStamp(start + Duration::seconds(secs)),
+        speed: None,
+        ride_status: RideStatus::INPROGRESS,
+        ride_info: None,
+        vehicle_type: None,
+        accuracy: Accuracy(5.0),
+        route: None,
+        ride_stops: None,
+        counterpart_location: Some(driver_location.clone()),
+    };
+
+    let (state, anti_state, flag, req) = check(&config, &config, context(0), None, None, None);
+    assert!(req.is_none());
+
+    let (_, _, flag, req) = check(&config, &config, context(5), state, anti_state, flag);
+    assert!(matches!(flag, Some(DetectionStatus::Violated)));
+    let req = req.unwrap();
+    assert!(req.is_violated);
+    assert!(matches!(
+        req.detection_data,
+        DetectionData::DriverDivergenceDetection(_)
+    ));
+}
+
+#[test]
+fn test_ride_proximity_evaluation() {
+    use chrono::Utc;
+    use location_tracking_service::common::ride_proximity::evaluate_ride_proximity;
+    use location_tracking_service::common::types::*;
+    use location_tracking_service::environment::RideProximityCheckConfig;
+    use location_tracking_service::outbound::types::RideProximityEvent;
+    use reqwest::Url;
+
+    let config = RideProximityCheckConfig {
+        ride_proximity_callback_url: Url::parse(
+            "http://127.0.0.1:8013/internal/rideProximityEvent",
+        )
+        .unwrap(),
+        max_distance: 300,
+        sample_size: 2,
+        max_location_age_secs: 60,
+    };
+    let mut tracking = RideProximityTracking {
+        merchant_id: MerchantId("merchant".to_string()),
+        driver_id: DriverId("driver".to_string()),
+        started_at: TimeStamp(Utc::now()),
+        last_sample_ts: None,
+        separated_samples: 0,
+        has_been_together: false,
+        alerted: false,
+    };
+
+    assert_eq!(evaluate_ride_proximity(&mut tracking, 800.0, &config), None);
+    assert_eq!(
+        evaluate_ride_proximity(&mut tracking, 800.0, &config),
+        Some(RideProximityEvent::RiderNotInVehicleAtStart)
+    );
+    // Raised again until the callback succeeds, then once per separation.
+    assert_eq!(
+        evaluate_ride_proximity(&mut tracking, 800.0, &config),
+        Some(RideProximityEvent::RiderNotInVehicleAtStart)
+    );
+    tracking.alerted = true;
+    assert_eq!(evaluate_ride_proximity(&mut tracking, 800.0, &config), None);
+
+    assert_eq!(evaluate_ride_proximity(&mut tracking, 20.0, &config), None);
+    assert_eq!(evaluate_ride_proximity(&mut tracking, 800.0, &config), None);
+    assert_eq!(
+        evaluate_ride_proximity(&mut tracking, 800.0, &config),
+        Some(RideProximityEvent::RiderSeparatedFromVehicle)
+    );
+}
+
+#[test]
+fn test_trace_template_rendering() {
+    use location_tracking_service::common::types::TraceSignal;
+    use location_tracking_service::outbound::provider::{
+        render_json_template, render_template, TraceTemplateValues,
+    };
+
+    let values = TraceTemplateValues {
+        lat: 12.97,
+        lon: 77.59,
+        datetime: "2024-01-01 10:00:00",
+        timestamp: 1704103200,
+        signal: TraceSignal::SignalLost,
+        access_token: "token",
+        external_reference_id: "ref-1",
+    };
+
+    assert_eq!(
+        render_template("/sos/{{externalReferenceId}}/trace", &values),
+        "/sos/ref-1/trace"
+    );
+    assert_eq!(
+        render_json_template(
+            &serde_json::json!({
+                "latitude": "{{lat}}",
+                "longitude": "{{lon}}",
+                "ts": "{{timestamp}}",
+                "packet": "{{datetime}},{{lat}},{{lon}}",
+                "tags": ["{{externalReferenceId}}", 1],
+                "status": "{{signal}}",
+            }),
+            &values
+        ),
+        serde_json::json!({
+            "latitude": 12.97,
+            "longitude": 77.59,
+            "ts": 1704103200,
+            "packet": "2024-01-01 10:00:00,12.97,77.59",
+            "tags": ["ref-1", 1],
+            "status": "SIGNAL_LOST",
+        })
+    );
+}
+
+#[test]
+fn test_broadcaster_token_backoff() {
+    use location_tracking_service::common::broadcast_token::backoff_secs;
+
+    assert_eq!(backoff_secs(1), 5);
+    assert_eq!(backoff_secs(2), 10);
+    assert_eq!(backoff_secs(4), 40);
+    assert_eq!(backoff_secs(7), 300);
+    assert_eq!(backoff_secs(u32::MAX), 300);
+}
+
+#[test]
+fn test_broadcast_trace_delivery_summary() {
+    use location_tracking_service::common::types::{
+        BroadcastTraceAttempt, TimeStamp, TraceRetryOutcome, TraceSignal,
+    };
+    use location_tracking_service::domain::action::internal::ride::summarize_broadcast_trace_delivery;
+    use location_tracking_service::domain::types::internal::ride::BroadcastTraceDeliveryStatus;
+
+    let attempt = |delivered: bool, http_status: Option<u16>| BroadcastTraceAttempt {
+        ts: TimeStamp(chrono::Utc::now()),
+        broadcaster_id: "sos-1".to_string(),
+        provider: "webhook".to_string(),
+        attempt: 1,
+        signal: TraceSignal::Live,
+        delivered,
+        http_status,
+        latency_ms: 120,
+        retry_outcome: TraceRetryOutcome::NotRetried,
+        error: None,
+    };
+
+    assert_eq!(
+        summarize_broadcast_trace_delivery(&[]),
+        (BroadcastTraceDeliveryStatus::NoAttempts, 0)
+    );
+    assert_eq!(
+        summarize_broadcast_trace_delivery(&[attempt(true, Some(200)), attempt(false, Some(401))]),
+        (BroadcastTraceDeliveryStatus::Delivering, 0)
+    );
+    assert_eq!(
+        summarize_broadcast_trace_delivery(&[
+            attempt(false, Some(503)),
+            attempt(false, None),
+            attempt(true, Some(200)),
+            attempt(false, Some(500)),
+        ]),
+        (BroadcastTraceDeliveryStatus::Failing, 2)
+    );
+}
+
+#[test]
+fn test_broadcast_trace_signal() {
+    use location_tracking_service::common::broadcast_trace::trace_signal;
+    use location_tracking_service::common::types::TraceSignal;
+    use location_tracking_service::environment::BroadcastTraceSchedulerConfig;
+
+    let config = BroadcastTraceSchedulerConfig {
+        interval_secs: 5,
+        stale_after_secs: 30,
+        signal_lost_after_secs: 120,
+    };
+
+    assert_eq!(trace_signal(0, &config), TraceSignal::Live);
+    assert_eq!(trace_signal(29, &config), TraceSignal::Live);
+    assert_eq!(trace_signal(30, &config), TraceSignal::Stale);
+    assert_eq!(trace_signal(119, &config), TraceSignal::Stale);
+    assert_eq!(trace_signal(600, &config), TraceSignal::SignalLost);
+}
+
+#[test]
+fn test_location_source_selection() {
+    use chrono::{Duration, Utc};
+    use location_tracking_service::common::location_fusion::select_location_source;
+    use location_tracking_service::common::types::{
+        Accuracy, Latitude, LocationSource, Longitude, Point, TimeStamp, VehicleSourceSample,
+    };
+    use location_tracking_service::environment::VehicleLocationFusionConfig;
+    use std::collections::HashMap;
+
+    let config = VehicleLocationFusionConfig {
+        source_priority: vec![LocationSource::ExternalGps, LocationSource::BusCrewApp],
+        max_sample_age_secs: 30,
+        max_accuracy: 50.0,
+    };
+    let now = Utc::now();
+    let sample = |age_secs: i64, accuracy: f64| VehicleSourceSample {
+        location: Point {
+            lat: Latitude(12.97),
+            lon: Longitude(77.59),
+        },
+        timestamp: TimeStamp(now - Duration::seconds(age_secs)),
+        accuracy: Some(Accuracy(accuracy)),
+    };
+
+    let mut samples = HashMap::new();
+    assert_eq!(select_location_source(&samples, &config, now), None);
+
+    samples.insert(LocationSource::DriverApp, sample(1, 10.0));
+    samples.insert(LocationSource::BusCrewApp, sample(5, 10.0));
+    assert_eq!(
+        select_location_source(&samples, &config, now),
+        Some(LocationSource::BusCrewApp)
+    );
+
+    // An inaccurate preferred source loses to an accurate one.
+    samples.insert(LocationSource::ExternalGps, sample(1, 200.0));
+    assert_eq!(
+        select_location_source(&samples, &config, now),
+        Some(LocationSource::BusCrewApp)
+    );
+
+    // A stale preferred source stops competing.
+    samples.insert(LocationSource::ExternalGps, sample(5, 10.0));
+    assert_eq!(
+        select_location_source(&samples, &config, now),
+        Some(LocationSource::ExternalGps)
+    );
+    samples.insert(LocationSource::ExternalGps, sample(60, 10.0));
+    assert_eq!(
+        select_location_source(&samples, &config, now),
+        Some(LocationSource::BusCrewApp)
+    );
+}
+
+#[test]
+fn test_gps_vendor_adapters() {
+    use chrono::{TimeZone, Utc};
+    use location_tracking_service::domain::action::external::gps_vendor::{
+        make_gps_vendor_adapter, parse_vendor_timestamp,
+    };
+    use location_tracking_service::environment::{
+        ExternalGpsVendorConfig, GpsTimestampFormat, GpsVendorSchema,
+    };
+
+    let fix_time = Utc.with_ymd_and_hms(2025, 9, 16, 7, 21, 23).unwrap();
+    let ist = GpsTimestampFormat::Local {
+        format: "%Y-%m-%d %H:%M:%S".to_string(),
+        utc_offset_minutes: 330,
+    };
+    assert_eq!(
+        parse_vendor_timestamp("2025-09-16 12:51:23", &ist).unwrap(),
+        fix_time
+    );
+    assert_eq!(
+        parse_vendor_timestamp("1758007283", &GpsTimestampFormat::EpochSeconds).unwrap(),
+        fix_time
+    );
+    assert_eq!(
+        parse_vendor_timestamp("2025-09-16T12:51:23+05:30", &GpsTimestampFormat::Rfc3339).unwrap(),
+        fix_time
+    );
+    assert!(parse_vendor_timestamp("16/09/2025", &ist).is_err());
+
+    let adapter = make_gps_vendor_adapter(&ExternalGpsVendorConfig {
+        api_key: "key".to_string(),
+        schema: GpsVendorSchema::Generic,
+        timestamp_format: GpsTimestampFormat::EpochMillis,
+    });
+    let pings = adapter
+        .parse_batch(serde_json::json!([
+            { "imei": "861", "timestamp": 1758007283000_i64, "lat": 12.97, "lon": 77.59, "speed_kmph": 36.0 },
+            { "imei": "862", "plate_number": "KA01AB1234", "timestamp": "1758007283000", "lat": 12.98, "lon": 77.6 }
+        ]))
+        .unwrap();
+    assert_eq!(pings.len(), 2);
+    assert_eq!(pings[0].plate_number, None);
+    assert_eq!(pings[0].location.ts.0, fix_time);
+    assert_eq!(
+        pings[0].location.v.as_ref().map(|speed| speed.0),
+        Some(10.0)
+    );
+    assert_eq!(pings[1].plate_number.as_deref(), Some("KA01AB1234"));
+
+    assert!(adapter
+        .parse_batch(serde_json::json!([
+            { "imei": "861", "timestamp": 1758007283000_i64, "lat": 120.0, "lon": 77.59 }
+        ]))
+        .is_err());
+}
+
+/// A drainer entry for `driver_id` at latitude `lat`, stamped now.
+#[cfg(test)]
+fn drainer_entry(
+    driver_id: &str,
+    lat: f64,
+) -> location_tracking_service::common::drainer_wal::DrainerEntry {
+    use chrono::Utc;
+    use location_tracking_service::common::types::{
+        CityName, Dimensions, DriverId, Latitude, Longitude, MerchantId, MerchantOperatingCityId,
+        TimeStamp, VehicleType,
+    };
+
+    (
+        Dimensions {
+            merchant_id: MerchantId("favorit0-0000-0000-0000-00000favorit".to_string()),
+            city: CityName("bangalore".to_string()),
+            vehicle_type: VehicleType::SEDAN,
+            created_at: Utc::now(),
+            merchant_operating_city_id: MerchantOperatingCityId("moc".to_string()),
+        },
+        Latitude(lat),
+        Longitude(77.59),
+        TimeStamp(Utc::now()),
+        TimeStamp(Utc::now()),
+        DriverId(driver_id.to_string()),
+    )
+}
+
+#[test]
+fn test_drainer_wal_replay() {
+    use location_tracking_service::common::drainer_wal::{DrainerWal, DRAINER_WAL_MAX_BYTES};
+    use location_tracking_service::common::types::DriverId;
+    use location_tracking_service::drainer::DrainerOverflow;
+    use std::collections::VecDeque;
+    use std::io::Write;
+
+    let path =
+        std::env::temp_dir().join(format!("lts-drainer-wal-test-{}.wal", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let entry = |driver_id: &str| drainer_entry(driver_id, 12.97);
+
+    let (mut wal, pending) = DrainerWal::open(&path, DRAINER_WAL_MAX_BYTES).unwrap();
+    assert!(pending.is_empty());
+    wal.append(&entry("d1")).unwrap();
+    wal.acknowledge().unwrap();
+    // A failed drain leaves its entries in place until the retry drains them.
+    wal.append(&entry("d2")).unwrap();
+    wal.append(&entry("d3")).unwrap();
+    drop(wal);
+    let (mut wal, pending) = DrainerWal::open(&path, DRAINER_WAL_MAX_BYTES).unwrap();
+    assert_eq!(pending.len(), 2);
+    wal.acknowledge().unwrap();
+    wal.append(&entry("d4")).unwrap();
+    wal.append(&entry("d5")).unwrap();
+    drop(wal);
+
+    // A write torn by the crash is skipped.
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap()
+        .write_all(b"[{\"merchant_id\"")
+        .unwrap();
+
+    let (mut wal, pending) = DrainerWal::open(&path, DRAINER_WAL_MAX_BYTES).unwrap();
+    assert_eq!(pending.len(), 2);
+    wal.append(&entry("d6")).unwrap();
+    drop(wal);
+
+    let (_, pending) = DrainerWal::open(&path, DRAINER_WAL_MAX_BYTES).unwrap();
+    let drivers: Vec<_> = pending
+        .into_iter()
+        .map(|(.., DriverId(driver_id))| driver_id)
+        .collect();
+    assert_eq!(drivers, vec!["d4", "d5", "d6"]);
+
+    // Appends past the size cap are refused.
+    let _ = std::fs::remove_file(&path);
+    let (mut wal, _) = DrainerWal::open(&path, 1).unwrap();
+    assert!(wal.append(&entry("d7")).is_err());
+    drop(wal);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+    // Overflow entries are logged when set aside and survive the acknowledgement
+    // of a drain that did not include them.
+    let (mut wal, _) = DrainerWal::open(&path, DRAINER_WAL_MAX_BYTES).unwrap();
+    let overflow = DrainerOverflow::default();
+    overflow.attach_wal(wal.appender());
+    overflow.push(entry("o1"));
+    overflow.push(entry("o2"));
+    wal.append(&entry("d8")).unwrap();
+    let taken: VecDeque<_> = overflow
+        .take()
+        .into_iter()
+        .filter(|(.., DriverId(driver_id))| driver_id == "o1")
+        .collect();
+    overflow.push(entry("o3"));
+    overflow.acknowledge_wal(&mut wal, &taken).unwrap();
+    drop(wal);
+    let (_, pending) = DrainerWal::open(&path, DRAINER_WAL_MAX_BYTES).unwrap();
+    let mut drivers: Vec<_> = pending
+        .into_iter()
+        .map(|(.., DriverId(driver_id))| driver_id)
+        .collect();
+    drivers.sort();
+    assert_eq!(drivers, vec!["o1", "o3"]);
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_drainer_stream_partition() {
+    use location_tracking_service::common::types::DriverId;
+    use location_tracking_service::redis::commands::drainer_stream_partition;
+
+    let driver_id = DriverId("favorit-driver-0000-0000-000000000001".to_string());
+    let partition = drainer_stream_partition(&driver_id, 8);
+    assert!(partition < 8);
+    assert_eq!(drainer_stream_partition(&driver_id, 8), partition);
+    assert_eq!(drainer_stream_partition(&driver_id, 1), 0);
+    assert_eq!(drainer_stream_partition(&driver_id, 0), 0);
+
+    let partitions: std::collections::HashSet<_> = (0..64)
+        .map(|i| drainer_stream_partition(&DriverId(format!("driver-{i}")), 8))
+        .collect();
+    assert!(partitions.len() > 1);
+}
+
+#[test]
+fn test_drainer_overflow_coalescing() {
+    use location_tracking_service::common::types::DriverId;
+    use location_tracking_service::drainer::DrainerOverflow;
+
+    let entry = drainer_entry;
+
+    let overflow = DrainerOverflow::default();
+    // Inactive: entries go back to the channel.
+    assert!(overflow.push_if_active(entry("d1", 12.0)).is_some());
+    assert!(overflow.take().is_empty());
+
+    overflow.push(entry("d1", 12.0));
+    assert!(overflow.push_if_active(entry("d1", 12.5)).is_none());
+    assert!(overflow.push_if_active(entry("d2", 13.0)).is_none());
+
+    let mut taken = overflow.take();
+    taken.sort_by(|a, b| a.5 .0.cmp(&b.5 .0));
+    assert_eq!(taken.len(), 2);
+    assert_eq!(taken[0].1 .0, 12.5);
+    assert_eq!(taken[1].5, DriverId("d2".to_string()));
+
+    // Taking deactivates the overflow.
+    assert!(overflow.push_if_active(entry("d1", 14.0)).is_some());
+}
+
+#[test]
+fn test_drain_buffer_coalescing() {
+    use location_tracking_service::common::types::{CityName, DriverId, Latitude, Longitude};
+    use location_tracking_service::drainer::{DrainBuffer, QueueAction};
+
+    let mut buffer = DrainBuffer::default();
+    let city = || CityName("bangalore".to_string());
+    for (bucket_key, driver_id, lat) in [
+        ("bucket:10", "d1", 12.1),
+        ("bucket:10", "d2", 12.2),
+        ("bucket:10", "d1", 12.3),
+        ("bucket:11", "d1", 12.4),
+    ] {
+        buffer.add_driver_location(
+            city(),
+            bucket_key.to_string(),
+            driver_id.to_string(),
+            Latitude(lat),
+            Longitude(77.6),
+        );
+    }
+    let locations = |bucket_key: &str| -> Vec<(Option<String>, f64)> {
+        buffer.driver_locations()[&city()][bucket_key]
+            .iter()
+            .map(|geo_value| (geo_value.member.as_string(), geo_value.coordinates.latitude))
+            .collect()
+    };
+    assert_eq!(
+        locations("bucket:10"),
+        vec![
+            (Some("d1".to_string()), 12.3),
+            (Some("d2".to_string()), 12.2)
+        ]
+    );
+    assert_eq!(locations("bucket:11"), vec![(Some("d1".to_string()), 12.4)]);
+
+    let driver = |id: &str| DriverId(id.to_string());
+    for (driver_id, bucket, score) in [
+        ("d1", 10, 100.0),
+        ("d2", 10, 101.0),
+        ("d1", 10, 102.0),
+        ("d1", 11, 103.0),
+    ] {
+        buffer.add_special_location_entry("airport".to_string(), driver(driver_id), bucket, score);
+    }
+    assert_eq!(
+        buffer.special_location_entries()["airport"],
+        vec![
+            (driver("d1"), 10, 102.0),
+            (driver("d2"), 10, 101.0),
+            (driver("d1"), 11, 103.0),
+        ]
+    );
+
+    let enter_between = |driver_id: &str,
+                         special_location_id: &str,
+                         timestamp: f64,
+                         last_ping_ts: f64,
+                         stream_id: Option<&str>| QueueAction::Enter {
+        merchant_id: "m1".to_string(),
+        driver_id: driver_id.to_string(),
+        special_location_id: special_location_id.to_string(),
+        vehicle_type: "SEDAN".to_string(),
+        timestamp,
+        last_ping_ts,
+        stream_id: stream_id.map(str::to_string),
+    };
+    let enter = |driver_id: &str, special_location_id: &str, timestamp: f64| {
+        enter_between(driver_id, special_location_id, timestamp, timestamp, None)
+    };
+    let exit = |driver_id: &str, timestamp: f64| QueueAction::PossibleExit {
+        merchant_id: "m1".to_string(),
+        driver_id: driver_id.to_string(),
+        timestamp,
+        stream_id: None,
+    };
+    for action in [
+        // Merged, keeping the earliest arrival.
+        enter("d1", "airport", 200.0),
+        enter("d2", "airport", 201.0),
+        enter("d1", "airport", 202.0),
+        // An exit in between keeps the Enters apart.
+        exit("d2", 203.0),
+        enter("d2", "airport", 204.0),
+        // So does an Enter into another queue.
+        enter("d1", "station", 205.0),
+        enter("d1", "airport", 206.0),
+    ] {
+        buffer.add_queue_action(action);
+    }
+    assert_eq!(
+        buffer.queue_actions(),
+        [
+            enter_between("d1", "airport", 200.0, 202.0, None),
+            enter("d2", "airport", 201.0),
+            exit("d2", 203.0),
+            enter("d2", "airport", 204.0),
+            enter("d1", "station", 205.0),
+            enter("d1", "airport", 206.0),
+        ]
+    );
+
+    // A failed drain keeps its locations for the retry; the queue actions were
+    // dispatched already.
+    buffer.retain_for_retry();
+    assert!(buffer.queue_actions().is_empty());
+    assert_eq!(buffer.driver_locations()[&city()]["bucket:11"].len(), 1);
+    buffer.add_queue_action(enter("d1", "airport", 207.0));
+    buffer.add_queue_action(enter("d1", "airport", 208.0));
+    assert_eq!(buffer.queue_actions().len(), 1);
+
+    // Interleaved stream entries, one an older ping from another pod: the rank
+    // keeps the earliest arrival, the tracking the latest ping and entry.
+    let mut buffer = DrainBuffer::default();
+    for (driver_id, timestamp, stream_id) in [
+        ("d1", 300.0, "1000-0"),
+        ("d2", 301.0, "1000-1"),
+        ("d1", 299.0, "1000-2"),
+        ("d2", 303.0, "1000-3"),
+        ("d1", 302.0, "1000-4"),
+    ] {
+        buffer.add_queue_action(enter_between(
+            driver_id,
+            "airport",
+            timestamp,
+            timestamp,
+            Some(stream_id),
+        ));
+    }
+    assert_eq!(
+        buffer.queue_actions(),
+        [
+            enter_between("d1", "airport", 299.0, 302.0, Some("1000-4")),
+            enter_between("d2", "airport", 301.0, 303.0, Some("1000-3")),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_city_redis_router() {
+    use location_tracking_service::common::types::CityName;
+    use location_tracking_service::redis::routing::CityRedisRouter;
+    use shared::redis::types::{RedisConnectionPool, RedisSettings};
+    use std::sync::Arc;
+
+    let pool = || async {
+        Arc::new(
+            RedisConnectionPool::new(RedisSettings::default(), None)
+                .await
+                .expect("Failed to create Redis Connection Pool"),
+        )
+    };
+    let city = |name: &str| CityName(name.to_string());
+    let (primary, south, large) = (pool().await, pool().await, pool().await);
+
+    let mut router = CityRedisRouter::new(primary.clone());
+    router.add_cluster(vec![city("bangalore"), city("chennai")], south.clone());
+    router.add_cluster(vec![city("chennai")], large.clone());
+
+    assert_eq!(router.pools().len(), 3);
+    assert_eq!(router.pool_index(&city("kochi")), 0);
+    assert_eq!(router.pool_index(&city("bangalore")), 1);
+    // A city listed again moves to the later cluster.
+    assert_eq!(router.pool_index(&city("chennai")), 2);
+    assert!(Arc::ptr_eq(router.for_city(&city("kochi")), &primary));
+    assert!(Arc::ptr_eq(router.for_city(&city("bangalore")), &south));
+    assert!(Arc::ptr_eq(router.for_city(&city("chennai")), &large));
+}
+
+#[test]
+fn test_redis_migration_phase_steps() {
+    use location_tracking_service::redis::migration::RedisMigrationPhase::*;
+
+    assert!(Off.can_move_to(DualWrite));
+    assert!(CutOver.can_move_to(ShadowRead));
+    assert!(!DualWrite.can_move_to(CutOver));
+    assert!(!ShadowRead.can_move_to(ShadowRead));
+    assert_eq!(
+        serde_json::from_str::<location_tracking_service::redis::migration::RedisMigrationPhase>(
+            "\"ShadowRead\""
+        )
+        .unwrap(),
+        ShadowRead
+    );
+}
+
+#[tokio::test]
+async fn test_redis_migration_mirrored_writes() {
+    use location_tracking_service::domain::action::ui::location::handle_driver_offline_queue_cleanup;
+    use location_tracking_service::redis::commands::*;
+    use location_tracking_service::redis::keys::{
+        driver_queue_rank_history_key, driver_queue_tracking_key,
+    };
+    use location_tracking_service::redis::migration::{
+        backfill_redis_migration, RedisMigration, RedisMigrationPhase,
+    };
+    use shared::redis::types::{RedisConnectionPool, RedisSettings};
+    use std::sync::Arc;
+
+    // Two databases of the local Redis stand in for the two clusters.
+    let pool = |partition| async move {
+        Arc::new(
+            RedisConnectionPool::new(
+                RedisSettings::new(
+                    "localhost".to_string(),
+                    6379,
+                    2,
+                    partition,
+                    1,
+                    1000,
+                    3600,
+                    3600,
+                    100,
+                    10,
+                ),
+                None,
+            )
+            .await
+            .expect("Failed to create Redis Connection Pool"),
+        )
+    };
+    let (source, target) = (pool(0).await, pool(1).await);
+    let migration = RedisMigration::new(source.clone(), Some(target.clone()), 1);
+    migration.set_phase(RedisMigrationPhase::DualWrite);
+
+    let (merchant_id, driver_id) = ("m-migration", "d-migration");
+    let tracking = DriverQueueTracking {
+        special_location_id: "airport".to_string(),
+        vehicle_type: "SEDAN".to_string(),
+        consecutive_exit_pings: 0,
+        last_recorded_rank: Some(0),
+        last_ping_ts: None,
+        last_stream_id: None,
+    };
+    for redis in [&source, &target] {
+        redis
+            .delete_key(&driver_queue_rank_history_key(merchant_id, driver_id))
+            .await
+            .unwrap();
+        set_driver_queue_tracking(redis, merchant_id, driver_id, &tracking)
+            .await
+            .unwrap();
+        add_driver_to_queue_force(redis, "airport", "SEDAN", driver_id, 1.0, 3600)
+            .await
+            .unwrap();
+    }
+
+    // Going offline takes the driver out of the queue on both clusters.
+    handle_driver_offline_queue_cleanup(&migration, &source, merchant_id, driver_id).await;
+    for redis in [&source, &target] {
+        assert_eq!(
+            get_driver_queue_tracking(redis, merchant_id, driver_id)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            get_driver_queue_position(redis, "airport", "SEDAN", driver_id)
+                .await
+                .unwrap(),
+            None
+        );
+        let history = get_driver_queue_rank_history(redis, merchant_id, driver_id)
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].1, "exit:offline");
+    }
+
+    // A key written before DualWrite is copied over by the backfill.
+    target
+        .delete_key(&driver_queue_tracking_key(merchant_id, driver_id))
+        .await
+        .unwrap();
+    set_driver_queue_tracking(&source, merchant_id, driver_id, &tracking)
+        .await
+        .unwrap();
+    migration.set_phase(RedisMigrationPhase::ShadowRead);
+    assert!(backfill_redis_migration(&migration, &source).await.unwrap() >= 1);
+    assert_eq!(
+        get_driver_queue_tracking(&target, merchant_id, driver_id)
+            .await
+            .unwrap(),
+        Some(tracking)
+    );
+    for redis in [&source, &target] {
+        delete_driver_queue_tracking(redis, merchant_id, driver_id)
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_redis_migration_leaves_secondary_queues_in_place() {
+    use location_tracking_service::redis::commands::*;
+    use location_tracking_service::redis::migration::{RedisMigration, RedisMigrationPhase};
+    use location_tracking_service::{redis_migration_read, redis_migration_write};
+    use shared::redis::types::{RedisConnectionPool, RedisSettings};
+    use std::sync::Arc;
+
+    let pool = |partition| async move {
+        Arc::new(
+            RedisConnectionPool::new(
+                RedisSettings::new(
+                    "localhost".to_string(),
+                    6379,
+                    2,
+                    partition,
+                    1,
+                    1000,
+                    3600,
+                    3600,
+                    100,
+                    10,
+                ),
+                None,
+            )
+            .await
+            .expect("Failed to create Redis Connection Pool"),
+        )
+    };
+    let (source, target) = (pool(0).await, pool(1).await);
+    let migration = RedisMigration::new(source.clone(), Some(target.clone()), 1);
+    let (merchant_id, driver_id) = ("m-secondary-queue", "d-secondary-queue");
+    let tracking = DriverQueueTracking {
+        special_location_id: "airport".to_string(),
+        vehicle_type: "SEDAN".to_string(),
+        consecutive_exit_pings: 0,
+        last_recorded_rank: Some(0),
+        last_ping_ts: None,
+        last_stream_id: None,
+    };
+    for redis in [&source, &target] {
+        delete_driver_queue_tracking(redis, merchant_id, driver_id)
+            .await
+            .unwrap();
+    }
+
+    // Queues kept on the secondary (`RUN_IN_SECONDARY_LTS_REDIS`) stay there
+    // whatever the phase: written, read and shadow read on the secondary only.
+    for phase in [
+        RedisMigrationPhase::DualWrite,
+        RedisMigrationPhase::ShadowRead,
+        RedisMigrationPhase::CutOver,
+    ] {
+        migration.set_phase(phase);
+        assert!(Arc::ptr_eq(&migration.serving(&target), &target));
+        assert!(migration.mirror(&target).is_none());
+        assert!(migration.shadow_target(&target).is_none());
+
+        redis_migration_write!(migration, "queue_tracking", &target, |redis| {
+            set_driver_queue_tracking(&redis, merchant_id, driver_id, &tracking)
+        })
+        .unwrap();
+        let read = redis_migration_read!(migration, "queue_tracking", &target, |redis| {
+            get_driver_queue_tracking(&redis, merchant_id, driver_id)
+        })
+        .unwrap();
+        assert_eq!(read, Some(tracking.clone()));
+        assert_eq!(
+            get_driver_queue_tracking(&source, merchant_id, driver_id)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+    delete_driver_queue_tracking(&target, merchant_id, driver_id)
+        .await
+        .unwrap();
+}
+
+/// Run with `cargo test -p tests test_driver_details_encoding_benchmark -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn test_driver_details_encoding_benchmark() {
+    use std::collections::VecDeque;
+    use std::time::Instant;
+
+    use location_tracking_service::common::types::*;
+    use location_tracking_service::environment::DriverDetailsEncoding;
+    use location_tracking_service::redis::codec::{decode_driver_details, encode_driver_details};
+
+    const ITERATIONS: u32 = 20_000;
+
+    let now = TimeStamp(chrono::Utc::now());
+    let point = |offset: f64| Point {
+        lat: Latitude(12.9716 + offset),
+        lon: Longitude(77.5946 + offset),
+    };
+    let history = (0..10)
+        .map(|i| (point(i as f64 * 0.0001), i))
+        .collect::<VecDeque<(Point, u32)>>();
+
+    let mut detection_state = ViolationDetectionStateMap::default();
+    detection_state.insert(
+        DetectionType::Stopped,
+        ViolationDetectionState::StopDetection(StopDetectionState {
+            avg_speed: Some((0..10).map(|i| (i as f64 * 1.5, i)).collect()),
+            avg_coord_mean: history.clone(),
+            total_datapoints: 120,
+        }),
+    );
+    detection_state.insert(
+        DetectionType::RouteDeviation,
+        ViolationDetectionState::RouteDeviation(RouteDeviationState {
+            deviation_distance: 42.0,
+            total_datapoints: 120,
+            avg_deviation_record: history.clone(),
+        }),
+    );
+    detection_state.insert(
+        DetectionType::TripNotStarted,
+        ViolationDetectionState::TripNotStarted(TripNotStartedState {
+            total_datapoints: 120,
+            avg_coord_mean: history,
+        }),
+    );
+
+    let details = DriverAllDetails {
+        driver_last_known_location: DriverLastKnownLocation {
+            location: point(0.0),
+            timestamp: now,
+            merchant_id: MerchantId("favorit0-0000-0000-0000-00000favorit".to_string()),
+            bear: Some(Direction(90.0)),
+            vehicle_type: Some(VehicleType::SEDAN),
+            group_id: None,
+            group_id2: None,
+        },
+        blocked_till: None,
+        stop_detection: Some(StopDetection {
+            locations: (0..10)
+                .map(|i| DriverLocation {
+                    location: point(i as f64 * 0.0001),
+                    timestamp: now,
+                })
+                .collect(),
+        }),
+        ride_status: Some(RideStatus::INPROGRESS),
+        ride_notification_status: Some(RideNotificationStatus::Idle),
+        driver_pickup_distance: Some(Meters(350)),
+        violation_trigger_flag: None,
+        detection_state: Some(detection_state.clone()),
+        anti_detection_state: Some(detection_state),
+        group_id: None,
+        from_combined_record: false,
+    };
+
+    for encoding in [DriverDetailsEncoding::Json, DriverDetailsEncoding::Binary] {
+        let payload = encode_driver_details(&details, encoding).unwrap();
+        let decoded = decode_driver_details(&payload).unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&details).unwrap()
+        );
+
+        let started = Instant::now();
+        for _ in 0..ITERATIONS {
+            let payload = encode_driver_details(&details, encoding).unwrap();
+            decode_driver_details(&payload).unwrap();
+        }
+        let elapsed = started.elapsed();
+        println!(
+            "{encoding:?}: {} bytes, {:?} per encode + decode",
+            payload.len(),
+            elapsed / ITERATIONS
+        );
+    }
+}
+
+#[test]
+fn test_driver_details_keys_share_hash_tag() {
+    use location_tracking_service::common::types::DriverId;
+    use location_tracking_service::redis::keys::*;
+
+    let driver_id = DriverId("d1".to_string());
+    for key in [
+        driver_location_state_key(&driver_id),
+        driver_ride_notification_state_key(&driver_id),
+        driver_detection_state_key(&driver_id),
+        driver_blocked_till_key(&driver_id),
+    ] {
+        assert!(key.starts_with("lts:driver_details:{d1}:"), "{key}");
+    }
+}
+
+#[test]
+fn test_changed_ping_state() {
+    use location_tracking_service::common::types::*;
+
+    let details = DriverAllDetails {
+        driver_last_known_location: DriverLastKnownLocation {
+            location: Point {
+                lat: Latitude(12.9716),
+                lon: Longitude(77.5946),
+            },
+            timestamp: TimeStamp(chrono::Utc::now()),
+            merchant_id: MerchantId("favorit0-0000-0000-0000-00000favorit".to_string()),
+            bear: None,
+            vehicle_type: Some(VehicleType::SEDAN),
+            group_id: None,
+            group_id2: None,
+        },
+        blocked_till: None,
+        stop_detection: None,
+        ride_status: Some(RideStatus::NEW),
+        ride_notification_status: Some(RideNotificationStatus::DriverOnTheWay),
+        driver_pickup_distance: Some(Meters(350)),
+        violation_trigger_flag: None,
+        detection_state: None,
+        anti_detection_state: None,
+        group_id: None,
+        from_combined_record: false,
+    };
+    let ride_notification = DriverRideNotificationState {
+        ride_notification_status: Some(RideNotificationStatus::DriverOnTheWay),
+        driver_pickup_distance: Some(Meters(350)),
+    };
+    // Empty maps are what an unset detection key reads back as.
+    let detection = DriverDetectionState {
+        violation_trigger_flag: Some(ViolationDetectionTriggerMap::default()),
+        detection_state: Some(ViolationDetectionStateMap::default()),
+        anti_detection_state: Some(ViolationDetectionStateMap::default()),
+    };
+
+    let (changed_ride_notification, changed_detection) =
+        DriverAllDetails::changed_ping_state(Some(&details), &ride_notification, &detection);
+    assert_eq!(changed_ride_notification, None);
+    assert_eq!(changed_detection, None);
+
+    let reached = DriverRideNotificationState {
+        ride_notification_status: Some(RideNotificationStatus::DriverReached),
+        ..ride_notification.clone()
+    };
+    let mut detecting = detection.clone();
+    detecting.violation_trigger_flag = Some(
+        [(DetectionType::Overspeeding, Some(DetectionStatus::Violated))]
+            .into_iter()
+            .collect(),
+    );
+    let (changed_ride_notification, changed_detection) =
+        DriverAllDetails::changed_ping_state(Some(&details), &reached, &detecting);
+    assert_eq!(changed_ride_notification, Some(&reached));
+    assert_eq!(changed_detection, Some(&detecting));
+
+    // Nothing of the driver's own yet: both are written.
+    let combined = DriverAllDetails {
+        from_combined_record: true,
+        ..details
+    };
+    for previous in [None, Some(&combined)] {
+        let (changed_ride_notification, changed_detection) =
+            DriverAllDetails::changed_ping_state(previous, &ride_notification, &detection);
+        assert_eq!(changed_ride_notification, Some(&ride_notification));
+        assert_eq!(changed_detection, Some(&detection));
+    }
+}
+
+#[tokio::test]
+async fn test_driver_details_combined_record_fallback() {
+    use location_tracking_service::common::types::*;
+    use location_tracking_service::environment::DriverDetailsEncoding;
+    use location_tracking_service::redis::codec::encode_driver_details;
+    use location_tracking_service::redis::commands::*;
+    use location_tracking_service::redis::keys::*;
+    use shared::redis::types::{RedisConnectionPool, RedisSettings};
+
+    let redis = RedisConnectionPool::new(RedisSettings::default(), None)
+        .await
+        .expect("Failed to create Redis Connection Pool");
+    let encoding = DriverDetailsEncoding::Json;
+    let location = |lat: f64| DriverLastKnownLocation {
+        location: Point {
+            lat: Latitude(lat),
+            lon: Longitude(77.5946),
+        },
+        timestamp: TimeStamp(chrono::Utc::now()),
+        merchant_id: MerchantId("favorit0-0000-0000-0000-00000favorit".to_string()),
+        bear: None,
+        vehicle_type: Some(VehicleType::SEDAN),
+        group_id: None,
+        group_id2: None,
+    };
+    let combined = |lat: f64| DriverAllDetails {
+        driver_last_known_location: location(lat),
+        blocked_till: None,
+        stop_detection: None,
+        ride_status: Some(RideStatus::INPROGRESS),
+        ride_notification_status: Some(RideNotificationStatus::DriverOnTheWay),
+        driver_pickup_distance: Some(Meters(350)),
+        violation_trigger_flag: None,
+        detection_state: None,
+        anti_detection_state: None,
+        group_id: None,
+        from_combined_record: false,
+    };
+    let (split, combined_only, unknown) = (
+        DriverId("combined-fallback-a".to_string()),
+        DriverId("combined-fallback-b".to_string()),
+        DriverId("combined-fallback-c".to_string()),
+    );
+    for driver_id in [&split, &combined_only, &unknown] {
+        for key in [
+            driver_details_key(driver_id),
+            driver_location_state_key(driver_id),
+            driver_ride_notification_state_key(driver_id),
+            driver_detection_state_key(driver_id),
+            driver_blocked_till_key(driver_id),
+        ] {
+            redis.delete_key(&key).await.unwrap();
+        }
+    }
+    for (driver_id, lat) in [(&split, 12.0), (&combined_only, 13.0)] {
+        let payload = encode_driver_details(&combined(lat), encoding).unwrap();
+        redis
+            .set_key_as_str(
+                &driver_details_key(driver_id),
+                &String::from_utf8(payload).unwrap(),
+                3600,
+            )
+            .await
+            .unwrap();
+    }
+
+    // Without a location key of their own, the driver is read from the combined
+    // record, with the split keys already written taking precedence.
+    let ride_notification = DriverRideNotificationState {
+        ride_notification_status: Some(RideNotificationStatus::Idle),
+        driver_pickup_distance: None,
+    };
+    set_driver_ride_state(
+        &redis,
+        &3600,
+        &3600,
+        &encoding,
+        &split,
+        &ride_notification,
+        None,
+    )
+    .await
+    .unwrap();
+    let blocked_till = TimeStamp(chrono::Utc::now() + chrono::Duration::hours(1));
+    set_driver_blocked_till(&redis, &3600, &encoding, &split, &blocked_till)
+        .await
+        .unwrap();
+    let details = get_driver_location(&redis, &split).await.unwrap().unwrap();
+    assert!(details.from_combined_record);
+    assert_eq!(
+        details.driver_last_known_location.location.lat,
+        Latitude(12.0)
+    );
+    assert_eq!(details.ride_status, Some(RideStatus::INPROGRESS));
+    assert_eq!(
+        details.ride_notification_status,
+        Some(RideNotificationStatus::Idle)
+    );
+    assert_eq!(details.driver_pickup_distance, None);
+    assert_eq!(details.blocked_till, Some(blocked_till));
+
+    // The first ping copies everything to the split keys.
+    let detection = DriverDetectionState::default();
+    let (changed_ride_notification, changed_detection) =
+        DriverAllDetails::changed_ping_state(Some(&details), &ride_notification, &detection);
+    set_driver_ping_state(
+        &redis,
+        &3600,
+        &3600,
+        &encoding,
+        &split,
+        &DriverLocationState {
+            driver_last_known_location: location(12.5),
+            stop_detection: None,
+            ride_status: Some(RideStatus::INPROGRESS),
+            group_id: None,
+        },
+        changed_ride_notification,
+        changed_detection,
+    )
+    .await
+    .unwrap();
+    let details = get_driver_location(&redis, &split).await.unwrap().unwrap();
+    assert!(!details.from_combined_record);
+    assert_eq!(
+        details.driver_last_known_location.location.lat,
+        Latitude(12.5)
+    );
+    assert_eq!(
+        details.ride_notification_status,
+        Some(RideNotificationStatus::Idle)
+    );
+    assert_eq!(details.blocked_till, Some(blocked_till));
+    assert!(get_driver_location(&redis, &unknown)
+        .await
+        .unwrap()
+        .is_none());
+
+    let last_locations =
+        get_all_driver_last_locations(&redis, &[split.clone(), combined_only.clone(), unknown])
+            .await
+            .unwrap();
+    assert_eq!(
+        last_locations
+            .iter()
+            .map(|location| location.as_ref().map(|location| location.location.lat))
+            .collect::<Vec<_>>(),
+        vec![Some(Latitude(12.5)), Some(Latitude(13.0)), None]
+    );
+
+    for driver_id in [&split, &combined_only] {
+        for key in [
+            driver_details_key(driver_id),
+            driver_location_state_key(driver_id),
+            driver_ride_notification_state_key(driver_id),
+            driver_detection_state_key(driver_id),
+            driver_blocked_till_key(driver_id),
+        ] {
+            redis.delete_key(&key).await.unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_driver_availability_transitions() {
+    use location_tracking_service::common::driver_availability::DriverAvailability::{self, *};
+    use location_tracking_service::common::types::{DriverMode, RideStatus};
+
+    assert_eq!(
+        DriverAvailability::from_ping(&DriverMode::SILENT, Some(&RideStatus::INPROGRESS)),
+        OnRide
+    );
+    assert_eq!(
+        DriverAvailability::from_ping(&DriverMode::ONLINE, Some(&RideStatus::CANCELLED)),
+        OnlineIdle
+    );
+    assert!(OnPickup.can_transition_to(OnRide));
+    assert!(!OnlineIdle.can_transition_to(OnRide));
+    assert!(!Blocked.can_transition_to(Queued));
+    assert!(Blocked.can_transition_to(OnlineIdle));
+    assert_eq!(
+        serde_json::to_string(&OnlineIdle).unwrap(),
+        "\"ONLINE_IDLE\""
+    );
+}
+
+#[test]
+fn test_driver_availability_change() {
+    use chrono::{TimeZone, Utc};
+    use location_tracking_service::common::driver_availability::{
+        DriverAvailability::*, DriverAvailabilityChange, DriverAvailabilityRecord, TransitionSource,
+    };
+    use location_tracking_service::common::types::{MerchantId, TimeStamp};
+
+    let merchant_id = MerchantId("favorit0-0000-0000-0000-00000favorit".to_string());
+    let at = |s| TimeStamp(Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, s).unwrap());
+    let idle = DriverAvailabilityRecord {
+        state: OnlineIdle,
+        since: at(0),
+        merchant_id: merchant_id.clone(),
+        last_seen: at(10),
+    };
+    let plan = |next, source, now| {
+        DriverAvailabilityChange::plan(Some(&idle), &merchant_id, next, source, now, 30)
+    };
+
+    assert_eq!(
+        DriverAvailabilityChange::plan(
+            None,
+            &merchant_id,
+            OnlineIdle,
+            TransitionSource::Ping,
+            at(0),
+            30
+        ),
+        DriverAvailabilityChange::Transition {
+            record: DriverAvailabilityRecord {
+                last_seen: at(0),
+                ..idle.clone()
+            },
+            valid: true,
+        }
+    );
+    assert_eq!(
+        plan(OnlineIdle, TransitionSource::Ping, at(39)),
+        DriverAvailabilityChange::Unchanged
+    );
+    assert_eq!(
+        plan(OnlineIdle, TransitionSource::Ping, at(40)),
+        DriverAvailabilityChange::Refresh(DriverAvailabilityRecord {
+            last_seen: at(40),
+            ..idle.clone()
+        })
+    );
+
+    // A ride cannot start without a pickup: a ping saying so is rejected, while
+    // the ride service is followed and the transition flagged.
+    assert_eq!(
+        plan(OnRide, TransitionSource::Ping, at(20)),
+        DriverAvailabilityChange::Rejected
+    );
+    let on_ride = DriverAvailabilityRecord {
+        state: OnRide,
+        since: at(20),
+        merchant_id: merchant_id.clone(),
+        last_seen: at(20),
+    };
+    assert_eq!(
+        plan(OnRide, TransitionSource::Lifecycle, at(20)),
+        DriverAvailabilityChange::Transition {
+            record: on_ride.clone(),
+            valid: false,
+        }
+    );
+    assert_eq!(
+        plan(OnPickup, TransitionSource::Ping, at(20)),
+        DriverAvailabilityChange::Transition {
+            record: DriverAvailabilityRecord {
+                state: OnPickup,
+                ..on_ride
+            },
+            valid: true,
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_compare_and_set_key() {
+    use location_tracking_service::redis::commands::compare_and_set_key;
+    use shared::redis::types::{RedisConnectionPool, RedisSettings};
+
+    let redis = RedisConnectionPool::new(RedisSettings::default(), None)
+        .await
+        .expect("Failed to create Redis Connection Pool");
+    let key = "lts:test:compare_and_set";
+    redis.delete_key(key).await.unwrap();
+
+    assert!(compare_and_set_key(&redis, key, None, "a", 60)
+        .await
+        .unwrap());
+    assert!(!compare_and_set_key(&redis, key, None, "b", 60)
+        .await
+        .unwrap());
+    assert!(!compare_and_set_key(&redis, key, Some("b"), "c", 60)
+        .await
+        .unwrap());
+    assert!(compare_and_set_key(&redis, key, Some("a"), "c", 60)
+        .await
+        .unwrap());
+    assert_eq!(
+        redis.get_key_as_str(key).await.unwrap(),
+        Some("c".to_string())
+    );
+    redis.delete_key(key).await.unwrap();
+}
+
+#[test]
+fn test_driver_session_accounting_splits_days_and_gaps() {
+    use chrono::{FixedOffset, NaiveDate, TimeZone, Utc};
+    use location_tracking_service::common::driver_availability::DriverAvailability::*;
+    use location_tracking_service::common::driver_session::account_driver_pings;
+    use location_tracking_service::common::types::{Latitude, Longitude, Point, TimeStamp};
+
+    let point = Point {
+        lat: Latitude(12.9),
+        lon: Longitude(77.6),
+    };
+    let at = |h, m, s| TimeStamp(Utc.with_ymd_and_hms(2024, 1, 1, h, m, s).unwrap());
+    let utc = FixedOffset::east_opt(0).unwrap();
+
+    let (cursor, days) = account_driver_pings(
+        None,
+        &[
+            (point.clone(), at(23, 59, 0), true),
+            (point.clone(), at(23, 59, 50), true),
+        ],
+        OnlineIdle,
+        120,
+        utc,
+    );
+    let jan_1 = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+    assert_eq!(days[&jan_1].sessions, 1);
+    assert_eq!(days[&jan_1].idle_ms, 50_000);
+
+    // Crosses midnight, booked to the state of the previous ping.
+    let next_day = TimeStamp(Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 30).unwrap());
+    let (cursor, days) =
+        account_driver_pings(cursor, &[(point.clone(), next_day, true)], OnRide, 120, utc);
+    assert_eq!(days[&jan_1].idle_ms, 10_000);
+    assert_eq!(
+        days[&NaiveDate::from_ymd_opt(2024, 1, 2).unwrap()].idle_ms,
+        30_000
+    );
+
+    // A gap over the threshold starts a new session without booking the gap.
+    let later = TimeStamp(Utc.with_ymd_and_hms(2024, 1, 2, 1, 0, 0).unwrap());
+    let (_, days) = account_driver_pings(cursor, &[(point, later, true)], OnlineIdle, 120, utc);
+    let jan_2 = &days[&NaiveDate::from_ymd_opt(2024, 1, 2).unwrap()];
+    assert_eq!((jan_2.sessions, jan_2.on_ride_ms), (1, 0));
+}
+
+#[tokio::test]
+async fn test_driver_session_concurrent_batches() {
+    use chrono::{TimeZone, Utc};
+    use location_tracking_service::common::driver_availability::DriverAvailability::OnlineIdle;
+    use location_tracking_service::common::driver_session::{
+        book_driver_session, DriverDayAccounting,
+    };
+    use location_tracking_service::common::types::*;
+    use location_tracking_service::environment::DriverSessionConfig;
+    use location_tracking_service::redis::commands::{
+        are_driver_session_days_published, get_driver_day_session,
+        set_driver_session_days_published,
+    };
+    use location_tracking_service::redis::keys::*;
+    use shared::redis::types::{RedisConnectionPool, RedisSettings};
+
+    let redis = RedisConnectionPool::new(RedisSettings::default(), None)
+        .await
+        .expect("Failed to create Redis Connection Pool");
+    let config = DriverSessionConfig {
+        ping_gap_threshold_secs: 120,
+        day_close_topic: "driver-day-summary".to_string(),
+        utc_offset_minutes: 0,
+        retention_days: 1,
+        day_close_check_interval_secs: 60,
+    };
+    let merchant_id = MerchantId("merchant".to_string());
+    let driver_id = DriverId("driver-session-concurrent".to_string());
+    let day = "2024-03-01";
+    for key in [
+        driver_session_cursor_key(&driver_id),
+        driver_day_session_key(day, &driver_id),
+        driver_session_day_drivers_key(day),
+        driver_session_day_published_key(day),
+    ] {
+        redis.delete_key(&key).await.unwrap();
+    }
+
+    let ping = |s| {
+        (
+            Point {
+                lat: Latitude(12.9),
+                lon: Longitude(77.6),
+            },
+            TimeStamp(Utc.with_ymd_and_hms(2024, 3, 1, 10, 0, s).unwrap()),
+            false,
+        )
+    };
+    let first = [ping(0), ping(10), ping(20)];
+    let second = [ping(0), ping(10), ping(20), ping(30)];
+    let (first, second) = tokio::join!(
+        book_driver_session(
+            &redis,
+            &config,
+            &merchant_id,
+            &driver_id,
+            OnlineIdle,
+            &first
+        ),
+        book_driver_session(
+            &redis,
+            &config,
+            &merchant_id,
+            &driver_id,
+            OnlineIdle,
+            &second
+        ),
+    );
+    first.unwrap();
+    second.unwrap();
+
+    // Whichever batch lost the race is folded again from the winner's cursor.
+    let accounting = DriverDayAccounting::from_fields(
+        &get_driver_day_session(&redis, day, &driver_id)
+            .await
+            .unwrap(),
+    );
+    assert_eq!((accounting.idle_ms, accounting.sessions), (30_000, 1));
+
+    let drivers = [
+        driver_id.clone(),
+        DriverId("driver-session-other".to_string()),
+    ];
+    set_driver_session_days_published(&redis, day, &drivers[..1], 60)
+        .await
+        .unwrap();
+    assert_eq!(
+        are_driver_session_days_published(&redis, day, &drivers)
+            .await
+            .unwrap(),
+        vec![true, false]
+    );
+}
+
+#[test]
+fn test_late_ride_trace_points() {
+    use chrono::{TimeZone, Utc};
+    use location_tracking_service::common::types::*;
+    use location_tracking_service::domain::action::ui::location::late_ride_trace_points;
+    use location_tracking_service::domain::types::ui::location::UpdateDriverLocationRequest;
+
+    let ping = |minute, acc| UpdateDriverLocationRequest {
+        pt: Point {
+            lat: Latitude(12.9),
+            lon: Longitude(77.6),
+        },
+        ts: TimeStamp(Utc.with_ymd_and_hms(2024, 1, 1, 10, minute, 0).unwrap()),
+        acc: Some(Accuracy(acc)),
+        v: None,
+        bear: None,
+    };
+    let ride_started_at = TimeStamp(Utc.with_ymd_and_hms(2024, 1, 1, 10, 5, 0).unwrap());
+    let late_locations = vec![ping(1, 5.0), ping(6, 5.0), ping(7, 500.0), ping(8, 5.0)];
+
+    let points = late_ride_trace_points(
+        late_locations.clone(),
+        Some(ride_started_at),
+        Accuracy(50.0),
+    );
+    let minutes: Vec<i64> = points
+        .iter()
+        .filter_map(|point| point.ts)
+        .map(|ts| (ts - ride_started_at.inner().timestamp()) / 60)
+        .collect();
+    assert_eq!(minutes, vec![1, 3]);
+
+    // Rides started before the start time was recorded keep every accurate point.
+    assert_eq!(
+        late_ride_trace_points(late_locations, None, Accuracy(50.0)).len(),
+        3
+    );
+}
+
+#[test]
+fn test_bulk_location_late_flag() {
+    use location_tracking_service::common::types::*;
+    use location_tracking_service::outbound::types::{BulkDataReq, LocationUpdate};
+
+    let request = |late| BulkDataReq {
+        ride_id: RideId("r1".to_string()),
+        loc: vec![LocationUpdate {
+            lat: Latitude(12.9),
+            lon: Longitude(77.6),
+            ts: Some(1_700_000_000),
+        }],
+        driver_id: DriverId("d1".to_string()),
+        late,
+    };
+    // Regular batches keep the payload the callback has always received.
+    let regular = serde_json::to_value(request(None)).unwrap();
+    assert!(regular.get("late").is_none());
+    let late = serde_json::to_value(request(Some(true))).unwrap();
+    assert_eq!(late["late"], serde_json::json!(true));
+}
+
+#[test]
+fn test_person_entity_keys_per_person_type() {
+    use location_tracking_service::domain::types::ui::location::PersonType;
+    use location_tracking_service::redis::keys::person_detail_by_entity_key;
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    let person_types = [
+        PersonType::Rider,
+        PersonType::Driver,
+        PersonType::BusConductor,
+        PersonType::BusDriver,
+    ];
+    for person_type in person_types {
+        assert_eq!(PersonType::from_str(person_type.as_str()), Ok(person_type));
+    }
+
+    // Riders keep the key written before other person types were accepted.
+    assert_eq!(
+        person_detail_by_entity_key(PersonType::Rider, "ride", "r1"),
+        "lts:person_detail_by_entity:ride:r1"
+    );
+    // A rider and the driver of the same ride must not share the lookup.
+    let keys: HashSet<String> = person_types
+        .iter()
+        .map(|person_type| person_detail_by_entity_key(*person_type, "ride", "r1"))
+        .collect();
+    assert_eq!(keys.len(), person_types.len());
+}
+
+#[test]
+fn test_broadcaster_token_scope() {
+    use location_tracking_service::common::broadcast_token::token_scope;
+    use location_tracking_service::common::types::*;
+
+    let cfg = BroadcastTraceConfig {
+        broadcaster_id: "erss-ka".to_string(),
+        external_reference_id: "ext-1".to_string(),
+        base_url: "https://erss.example".to_string(),
+        access_token: "token".to_string(),
+        token_expires_at: 0,
+        ny_reauth_url: "https://ny.example/reauth".to_string(),
+        ny_api_key: "api-key-1".to_string(),
+        merchant_operating_city_id: "city-1".to_string(),
+        polling_interval_secs: 10,
+        time_diff_secs: 0,
+        last_trace_ts: None,
+        expires_at: None,
+        provider: TraceProvider::Erss(ErssProviderConfig {
+            auth_id: "auth".to_string(),
+            auth_code: "code".to_string(),
+            mobile_no: "9999999999".to_string(),
+            trace_url_suffix: "/trace".to_string(),
+        }),
+    };
+    let other_entity = BroadcastTraceConfig {
+        external_reference_id: "ext-2".to_string(),
+        ..cfg.clone()
+    };
+    let other_key = BroadcastTraceConfig {
+        ny_api_key: "api-key-2".to_string(),
+        ..cfg.clone()
+    };
+    let other_broadcaster = BroadcastTraceConfig {
+        broadcaster_id: "erss-tn".to_string(),
+        ..cfg.clone()
+    };
+
+    assert_eq!(token_scope(&cfg), token_scope(&other_entity));
+    assert_ne!(token_scope(&cfg), token_scope(&other_key));
+    assert_ne!(token_scope(&cfg), token_scope(&other_broadcaster));
+    assert!(!token_scope(&cfg).contains(&cfg.ny_api_key));
+}
+
+#[test]
+fn test_broadcast_trace_backfill_targets() {
+    use location_tracking_service::common::broadcast_trace::{
+        broadcast_trace_targets, is_trace_due, parse_entity_details_key,
+    };
+    use location_tracking_service::common::types::*;
+    use location_tracking_service::domain::types::ui::location::PersonType;
+    use location_tracking_service::redis::keys::entity_details_key;
+
+    let cfg = BroadcastTraceConfig {
+        broadcaster_id: "sos-1".to_string(),
+        external_reference_id: "ext-1".to_string(),
+        base_url: "https://erss.example".to_string(),
+        access_token: "token".to_string(),
+        token_expires_at: 0,
+        ny_reauth_url: "https://ny.example/reauth".to_string(),
+        ny_api_key: "api-key-1".to_string(),
+        merchant_operating_city_id: "city-1".to_string(),
+        polling_interval_secs: 10,
+        time_diff_secs: 0,
+        last_trace_ts: None,
+        expires_at: None,
+        provider: TraceProvider::Erss(ErssProviderConfig {
+            auth_id: "auth".to_string(),
+            auth_code: "code".to_string(),
+            mobile_no: "9999999999".to_string(),
+            trace_url_suffix: "/trace".to_string(),
+        }),
+    };
+    let entry = EntityEntry {
+        entity_id: EntityId::Sos(SosId("sos-1".to_string())),
+        broadcaster_ids: vec!["sos-1".to_string()],
+        broadcaster_configs: vec![
+            cfg.clone(),
+            BroadcastTraceConfig {
+                broadcaster_id: "removed".to_string(),
+                ..cfg.clone()
+            },
+        ],
+    };
+    let merchant_id = MerchantId("merchant:1".to_string());
+    let person_id = PersonId("rider:42".to_string());
+
+    let targets =
+        broadcast_trace_targets(&merchant_id, PersonType::Rider, &person_id, "sos", &entry);
+    assert_eq!(targets.len(), 1);
+    assert_eq!(targets[0].broadcaster_id, "sos-1");
+    assert_eq!(targets[0].entity_type, "sos");
+
+    let key = entity_details_key(&merchant_id, PersonType::Rider, &person_id);
+    let (person_type, parsed_person_id) =
+        parse_entity_details_key(&key, &merchant_id).expect("key should parse");
+    assert_eq!(person_type, PersonType::Rider);
+    assert_eq!(parsed_person_id, person_id);
+    assert!(parse_entity_details_key(&key, &MerchantId("merchant".to_string())).is_none());
+
+    assert!(is_trace_due(&cfg, None, 100));
+    assert!(!is_trace_due(&cfg, Some(95), 100));
+    assert!(is_trace_due(&cfg, Some(90), 100));
+    let traced = BroadcastTraceConfig {
+        last_trace_ts: Some(95),
+        ..cfg.clone()
+    };
+    assert!(!is_trace_due(&traced, Some(80), 100));
+}
+
+#[test]
+fn test_bus_crew_pings_skip_driver_state() {
+    use location_tracking_service::common::types::LocationSource;
+    use std::str::FromStr;
+
+    assert!(LocationSource::DriverApp.updates_driver_state());
+    assert!(LocationSource::ExternalGps.updates_driver_state());
+    assert!(!LocationSource::BusCrewApp.updates_driver_state());
+    assert!(!LocationSource::from_str("BUS_CREW_APP")
+        .unwrap()
+        .updates_driver_state());
+}
+
+#[test]
+fn test_drainer_stream_pod_partitions() {
+    use location_tracking_service::redis::commands::{drainer_stream_pod_partitions, pod_ordinal};
+
+    assert_eq!(pod_ordinal("lts-drainer-3"), Some(3));
+    assert_eq!(pod_ordinal("lts-drainer"), None);
+    assert_eq!(pod_ordinal("drainer"), None);
+
+    assert_eq!(drainer_stream_pod_partitions(8, 3, 0), vec![0, 3, 6]);
+    assert_eq!(drainer_stream_pod_partitions(8, 3, 2), vec![2, 5]);
+    assert!(drainer_stream_pod_partitions(2, 3, 2).is_empty());
+    let owned: usize = (0..3)
+        .map(|ordinal| drainer_stream_pod_partitions(8, 3, ordinal).len())
+        .sum();
+    assert_eq!(owned, 8);
+}
+
+#[test]
+fn test_queue_action_replay_guard() {
+    use location_tracking_service::drainer::QueueAction;
+    use location_tracking_service::redis::commands::DriverQueueTracking;
+
+    let exit = |timestamp: f64, stream_id: Option<&str>| QueueAction::PossibleExit {
+        merchant_id: "m1".to_string(),
+        driver_id: "d1".to_string(),
+        timestamp,
+        stream_id: stream_id.map(str::to_string),
+    };
+    // Written before stream ids were recorded.
+    let tracking: DriverQueueTracking = serde_json::from_str(
+        r#"{"special_location_id":"sl-1","vehicle_type":"SEDAN","consecutive_exit_pings":1,"last_ping_ts":100.0}"#,
+    )
+    .unwrap();
+    assert!(!exit(100.0, Some("100000-0")).is_applied_to(Some(&tracking), true));
+
+    let tracking = DriverQueueTracking {
+        last_stream_id: Some("100000-1".to_string()),
+        ..tracking
+    };
+    // Pings of the same second, from the in-process drainer or read fresh from
+    // the stream, are always applied.
+    assert!(!exit(100.0, None).is_applied_to(Some(&tracking), false));
+    assert!(!exit(100.0, Some("100000-1")).is_applied_to(Some(&tracking), false));
+    // So are older pings that reach the tracking late from another pod.
+    assert!(!exit(99.0, None).is_applied_to(Some(&tracking), false));
+    assert!(!exit(99.0, Some("99000-0")).is_applied_to(Some(&tracking), false));
+
+    // A reclaimed batch is skipped up to the last entry applied, by stream id
+    // rather than by second.
+    assert!(exit(100.0, Some("100000-0")).is_applied_to(Some(&tracking), true));
+    assert!(exit(100.0, Some("100000-1")).is_applied_to(Some(&tracking), true));
+    assert!(!exit(100.0, Some("100000-2")).is_applied_to(Some(&tracking), true));
+    assert!(!exit(100.0, Some("100001-0")).is_applied_to(Some(&tracking), true));
+    assert!(!exit(100.0, Some("100000-0")).is_applied_to(None, true));
+}